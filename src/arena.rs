@@ -0,0 +1,158 @@
+use std::ops::Range;
+
+/// Labels of the nodes of a tree in heap order, None for a hole. Every label is a span of a
+/// single buffer, so a tree of millions of nodes takes two allocations instead of one per
+/// node. A relabeled node points to its new label at the end of the buffer, the old one is
+/// left behind until there is more garbage than labels and the buffer is compacted
+#[derive(Debug, Clone, Default)]
+pub struct LabelArena {
+    buffer: String,
+    spans: Vec<Option<Span>>,
+    garbage: usize, // bytes of the buffer no node points to
+}
+
+/// Where a label is in the buffer. Offsets are 32 bits, which is plenty for the labels of
+/// any document that fits in `maxFileSize`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    offset: u32,
+    len: u32,
+}
+
+impl Span {
+    fn range(self) -> Range<usize> {
+        self.offset as usize..(self.offset + self.len) as usize
+    }
+}
+
+impl LabelArena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of positions, holes included
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Label of the node, None past the end or for a hole
+    pub fn get(&self, index: usize) -> Option<&str> {
+        let span = (*self.spans.get(index)?)?;
+        Some(&self.buffer[span.range()])
+    }
+
+    /// Labels in heap order, None for the holes
+    pub fn iter(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.spans
+            .iter()
+            .map(|span| span.map(|span| &self.buffer[span.range()]))
+    }
+
+    /// Bytes of the buffer, the labels of relabeled nodes that were not compacted included
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of nodes, holes left out, at the positions in the range
+    pub fn count(&self, range: Range<usize>) -> usize {
+        let end = range.end.min(self.spans.len());
+        let start = range.start.min(end);
+        self.spans[start..end].iter().flatten().count()
+    }
+
+    /// Add a node or a hole at the end
+    pub fn push(&mut self, label: Option<&str>) {
+        let span = label.map(|label| self.store(label));
+        self.spans.push(span);
+    }
+
+    /// Replace the node at the index, which must be below `len`
+    pub fn set(&mut self, index: usize, label: Option<&str>) {
+        self.discard(index);
+        let span = label.map(|label| self.store(label));
+        self.spans[index] = span;
+        self.compact_if_wasteful();
+    }
+
+    /// Leave a hole at the index, returning the label that was there
+    pub fn take(&mut self, index: usize) -> Option<String> {
+        let label = self.get(index)?.to_string();
+        self.discard(index);
+        self.spans[index] = None;
+        self.compact_if_wasteful();
+        Some(label)
+    }
+
+    /// Grow to `len` positions, the new ones are holes
+    pub fn resize(&mut self, len: usize) {
+        if len > self.spans.len() {
+            self.spans.resize(len, None);
+        }
+    }
+
+    /// Remove the positions from `len` on
+    pub fn truncate(&mut self, len: usize) {
+        for index in len..self.spans.len() {
+            self.discard(index);
+        }
+        self.spans.truncate(len);
+        self.compact_if_wasteful();
+    }
+
+    /// Remove the holes at the end
+    pub fn trim_holes(&mut self) {
+        while self.spans.last().is_some_and(Option::is_none) {
+            self.spans.pop();
+        }
+    }
+
+    /// Remove every node, keeping the allocations
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.spans.clear();
+        self.garbage = 0;
+    }
+
+    fn store(&mut self, label: &str) -> Span {
+        let span = Span {
+            offset: u32::try_from(self.buffer.len()).expect("labels fit in 4 GiB"),
+            len: label.len() as u32,
+        };
+        self.buffer.push_str(label);
+        span
+    }
+
+    fn discard(&mut self, index: usize) {
+        if let Some(span) = self.spans[index] {
+            self.garbage += span.len as usize;
+        }
+    }
+
+    fn compact_if_wasteful(&mut self) {
+        if self.garbage <= self.buffer.len() / 2 {
+            return;
+        }
+        let mut buffer = String::with_capacity(self.buffer.len() - self.garbage);
+        for span in self.spans.iter_mut().flatten() {
+            let label = &self.buffer[span.range()];
+            span.offset = buffer.len() as u32;
+            buffer.push_str(label);
+        }
+        self.buffer = buffer;
+        self.garbage = 0;
+    }
+}
+
+impl<'a> FromIterator<Option<&'a str>> for LabelArena {
+    fn from_iter<I: IntoIterator<Item = Option<&'a str>>>(labels: I) -> Self {
+        let mut arena = LabelArena::new();
+        for label in labels {
+            arena.push(label);
+        }
+        arena
+    }
+}
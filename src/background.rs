@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::cancel::CancellationToken;
+use crate::editor::{FileState, FileStateError};
+use crate::rope::Rope;
+
+/// Documents parsed on threads of their own, so a very large document doesn't hold up the
+/// requests about the others. Every parse carries a value `V` for whoever started it, eg. the
+/// progress reported to the client
+pub struct BackgroundParses<V> {
+    parses: HashMap<String, Parse<V>>,
+}
+
+struct Parse<V> {
+    text: Rope, // what is parsed, the result only applies if the document still has this text
+    receiver: Receiver<Result<FileState, FileStateError>>,
+    token: CancellationToken, // stops the thread once the parse is dropped
+    value: V,
+}
+
+impl<V> Parse<V> {
+    /// Stop the thread, returning the value of the parse
+    fn stop(self) -> V {
+        self.token.cancel();
+        self.value
+    }
+}
+
+/// A parse that is done, see `BackgroundParses::take_finished`
+pub struct FinishedParse<V> {
+    pub uri: String,
+    pub text: Rope,
+    pub result: Option<Result<FileState, FileStateError>>, // None if the parsing thread panicked
+    pub value: V,
+}
+
+impl<V> Default for BackgroundParses<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> BackgroundParses<V> {
+    pub fn new() -> Self {
+        BackgroundParses {
+            parses: HashMap::new(),
+        }
+    }
+
+    /// Parse the text of the document on a new thread. A parse of the document that is still
+    /// running is dropped, its value is returned
+    pub fn start(&mut self, uri: String, text: Rope, value: V) -> Option<V> {
+        let (sender, receiver) = mpsc::channel();
+        let parsed = text.clone();
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        thread::spawn(move || {
+            // a dropped parse stops before its next line, its result would have nowhere to go
+            let result = FileState::from_rope_until(parsed, || cancelled.is_cancelled());
+            if let Some(result) = result.transpose() {
+                let _ = sender.send(result);
+            }
+        });
+        let parse = Parse {
+            text,
+            receiver,
+            token,
+            value,
+        };
+        self.parses.insert(uri, parse).map(Parse::stop)
+    }
+
+    /// Drop the parse of the document, returning its value
+    pub fn cancel(&mut self, uri: &str) -> Option<V> {
+        self.parses.remove(uri).map(Parse::stop)
+    }
+
+    pub fn is_parsing(&self, uri: &str) -> bool {
+        self.parses.contains_key(uri)
+    }
+
+    /// Remove and return the parses that are done, sorted by uri
+    pub fn take_finished(&mut self) -> Vec<FinishedParse<V>> {
+        let mut done = Vec::new();
+        for (uri, parse) in &self.parses {
+            match parse.receiver.try_recv() {
+                Ok(result) => done.push((uri.clone(), Some(result))),
+                Err(TryRecvError::Disconnected) => done.push((uri.clone(), None)),
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+        done.sort_by(|a, b| a.0.cmp(&b.0));
+        done.into_iter()
+            .map(|(uri, result)| {
+                let parse = self.parses.remove(&uri).unwrap();
+                FinishedParse {
+                    uri,
+                    text: parse.text,
+                    result,
+                    value: parse.value,
+                }
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.parses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parses.is_empty()
+    }
+}
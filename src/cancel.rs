@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::lsp::RequestId;
+
+/// Flipped when the client no longer wants the result of a request. Handlers with long loops
+/// check it now and then and give up with `Cancelled`. Clones share the flag, so it can be
+/// flipped from another thread while the handler runs
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Err once cancelled, for handlers to bail out with `?`
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// A request was given up because the client cancelled it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "The request was cancelled")
+    }
+}
+
+/// Token of every request being handled, by id. Clones share the tokens, so the transport can
+/// cancel requests while the server is busy
+#[derive(Debug, Clone, Default)]
+pub struct CancellationRegistry {
+    tokens: Arc<Mutex<HashMap<RequestId, CancellationToken>>>,
+}
+
+impl CancellationRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token for a request whose handling starts, already cancelled if it was cancelled while
+    /// queued
+    pub fn begin(&self, id: &RequestId) -> CancellationToken {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(id.clone()).or_default().clone()
+    }
+
+    /// The request is answered, its token is forgotten
+    pub fn finish(&self, id: &RequestId) {
+        self.tokens.lock().unwrap().remove(id);
+    }
+
+    /// Cancel a request being handled. Returns false if there is none with the id, eg. it was
+    /// already answered
+    pub fn cancel(&self, id: &RequestId) -> bool {
+        match self.tokens.lock().unwrap().get(id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make the token of a request that was received but isn't handled yet, so `cancel` finds
+    /// it while it waits for its turn. Must only be called for requests that will be handled,
+    /// or the token is never forgotten
+    pub fn queue(&self, id: &RequestId) {
+        self.begin(id);
+    }
+
+    /// Number of requests being handled or queued
+    pub fn len(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.lock().unwrap().is_empty()
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::editor::EditorState;
+use crate::large_file;
+use crate::logs::LogFormat;
+use crate::lsp::{handle_message, ServerContext};
+use crate::rpc::ReaderLimits;
+
+pub const NAME: &str = env!("CARGO_PKG_NAME");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How the server is started, the log options are shared by `serve` and `repl`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServeOptions {
+    pub log_file: Option<String>,
+    pub log_format: LogFormat,
+    pub session_file: Option<String>, // open documents are restored from it and saved to it on exit
+    pub transport: Transport,
+    pub reader_limits: ReaderLimits, // messages over them are refused
+    pub record_file: Option<String>, // the bytes read and written are appended to it
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            log_file: None,
+            log_format: LogFormat::Text,
+            session_file: None,
+            transport: Transport::Stdio,
+            reader_limits: ReaderLimits::default(),
+            record_file: None,
+        }
+    }
+}
+
+/// How the server talks to the client, see `transport`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    Stdio,
+    Tcp(String),  // address the server listens on, the first client to connect is served
+    Pipe(String), // unix domain socket or windows named pipe the client listens on
+    WebSocket(String), // address the server listens on for a websocket client
+}
+
+/// What the command line asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Serve(ServeOptions),   // talk LSP over stdio, tcp or a pipe, the default
+    Repl(ServeOptions),    // handle one json message per line of stdin
+    Check(Vec<String>),    // arguments of `testing::check::run`
+    Logs(Vec<String>),     // arguments of `logs::run`
+    Validate(Vec<String>), // tree files to validate
+    Help,
+    Version { json: bool },
+}
+
+pub const HELP: &str = "\
+Language server for complete binary tree documents
+
+Usage:
+    server [options] [log file]              talk LSP over stdio, --listen or --pipe
+    server repl [options] [log file]         handle one json message per line of stdin
+    server check <transcript> [--ignore a,b] [--no-color]
+                                             replay a transcript and print the differences
+    server logs <file> [--level L] [--method M]
+                                             print a log written with --log-format json
+    server validate <file>...                check that files are valid trees
+
+Options:
+    --log-format text|json    how the log file is written, text by default
+    --session <file>          reopen the documents saved in the file, save them there on exit
+    --record <file>           append the bytes read and written to the file, replayable
+                              with check
+    --listen <address>        serve the first client connecting over tcp instead of stdio
+    --port <port>             same as --listen 127.0.0.1:<port>
+    --pipe <path>             connect to the client's unix socket or windows named pipe
+    --websocket <address>     serve the first websocket client, if built with that feature
+    --max-message-size <bytes>
+                              refuse messages with a longer content, 64 MiB by default
+    --max-buffer-size <bytes> drop input buffered that long without a header ending, 1 MiB
+    -h, --help                print this help
+    -V, --version [--json]    print the version, as json with --json
+";
+
+/// Parse the arguments after the executable's name
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    match args.first().map(|a| a.as_str()) {
+        Some("check") => Ok(Command::Check(args[1..].to_vec())),
+        Some("logs") => Ok(Command::Logs(args[1..].to_vec())),
+        Some("validate") if args.len() == 1 => {
+            Err("validate expects at least one file".to_string())
+        }
+        Some("validate") => Ok(Command::Validate(args[1..].to_vec())),
+        Some("repl") => parse_serve(&args[1..], Command::Repl),
+        _ => parse_serve(args, Command::Serve),
+    }
+}
+
+/// Options of `serve` and `repl`, which also take `--help` and `--version`
+fn parse_serve(
+    args: &[String],
+    command: impl FnOnce(ServeOptions) -> Command,
+) -> Result<Command, String> {
+    let mut options = ServeOptions::default();
+    let (mut version, mut json) = (false, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => version = true,
+            "--json" => json = true,
+            "--log-format" => match args.next() {
+                Some(format) => options.log_format = format.parse()?,
+                None => return Err("--log-format expects text or json".to_string()),
+            },
+            "--session" => match args.next() {
+                Some(file) => options.session_file = Some(file.to_string()),
+                None => return Err("--session expects a file".to_string()),
+            },
+            "--record" => match args.next() {
+                Some(file) => options.record_file = Some(file.to_string()),
+                None => return Err("--record expects a file".to_string()),
+            },
+            "--listen" => match args.next() {
+                Some(address) => options.transport = Transport::Tcp(listen_address(address)),
+                None => return Err("--listen expects an address or a port".to_string()),
+            },
+            "--pipe" => match args.next() {
+                Some(path) => options.transport = Transport::Pipe(path.to_string()),
+                None => return Err("--pipe expects a path".to_string()),
+            },
+            // how VS Code passes it
+            pipe if pipe.starts_with("--pipe=") => {
+                options.transport = Transport::Pipe(pipe["--pipe=".len()..].to_string())
+            }
+            "--websocket" if !cfg!(feature = "websocket") => {
+                return Err(
+                    "--websocket needs the server built with the websocket feature".to_string(),
+                )
+            }
+            "--websocket" => match args.next() {
+                Some(address) => options.transport = Transport::WebSocket(listen_address(address)),
+                None => return Err("--websocket expects an address or a port".to_string()),
+            },
+            "--port" => match args.next().map(|port| port.parse::<u16>()) {
+                Some(Ok(port)) => options.transport = Transport::Tcp(format!("127.0.0.1:{}", port)),
+                _ => return Err("--port expects a port number".to_string()),
+            },
+            "--max-message-size" => match args.next().map(|size| size.parse()) {
+                Some(Ok(size)) => options.reader_limits.max_content_length = size,
+                _ => return Err("--max-message-size expects a number of bytes".to_string()),
+            },
+            "--max-buffer-size" => match args.next().map(|size| size.parse()) {
+                Some(Ok(size)) => options.reader_limits.max_buffered = size,
+                _ => return Err("--max-buffer-size expects a number of bytes".to_string()),
+            },
+            flag if flag.starts_with('-') => {
+                return Err(format!("unknown option {}, see --help", flag))
+            }
+            file => match options.log_file {
+                Some(_) => return Err(format!("unexpected argument {}, see --help", file)),
+                None => options.log_file = Some(file.to_string()),
+            },
+        }
+    }
+    match (version, json) {
+        (true, _) => Ok(Command::Version { json }),
+        (false, true) => Err("--json only applies to --version".to_string()),
+        (false, false) => Ok(command(options)),
+    }
+}
+
+/// Address to listen on, a bare port is on the loopback interface
+fn listen_address(address: &str) -> String {
+    match address.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// What `--version` prints
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub name: String,
+    pub version: String,
+    pub git_hash: Option<String>, // commit the server was built from, if known
+    pub features: Vec<String>,    // cargo features the server was built with
+    pub transports: Vec<String>,  // ways the server can talk to a client
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        let mut features = Vec::new();
+        if cfg!(feature = "mmap") {
+            features.push("mmap".to_string());
+        }
+        if cfg!(feature = "async") {
+            features.push("async".to_string());
+        }
+        if cfg!(feature = "tracing") {
+            features.push("tracing".to_string());
+        }
+        let mut transports = vec!["stdio".to_string(), "tcp".to_string(), "pipe".to_string()];
+        if cfg!(feature = "websocket") {
+            features.push("websocket".to_string());
+            transports.push("websocket".to_string());
+        }
+        VersionInfo {
+            name: NAME.to_string(),
+            version: VERSION.to_string(),
+            git_hash: option_env!("LSP_RS_GIT_HASH")
+                .filter(|h| !h.is_empty())
+                .map(|h| h.to_string()),
+            features,
+            transports,
+        }
+    }
+}
+
+impl Display for VersionInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)?;
+        if let Some(hash) = &self.git_hash {
+            write!(f, " ({})", hash)?;
+        }
+        write!(
+            f,
+            "\nfeatures: {}\ntransports: {}",
+            match self.features.is_empty() {
+                true => "none".to_string(),
+                false => self.features.join(", "),
+            },
+            self.transports.join(", ")
+        )
+    }
+}
+
+/// Validate every file, printing one line per file. Returns the process exit code
+pub fn validate(paths: &[String], out: &mut impl Write) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        match large_file::validate_file(Path::new(path)) {
+            Ok(Ok(summary)) => writeln!(
+                out,
+                "{}: ok, {} levels, {} nodes",
+                path, summary.lines, summary.nodes
+            ),
+            Ok(Err(e)) => {
+                code = 1;
+                writeln!(out, "{}:{}: {}", path, e.line, e.reason)
+            }
+            Err(e) => {
+                code = 2;
+                writeln!(out, "{}: {}", path, e)
+            }
+        }
+        .unwrap();
+    }
+    code
+}
+
+/// Handle one json message per line of `input`, without the LSP headers. Replies are written
+/// to stdout like when serving
+pub fn repl(input: impl BufRead, logger: &mut impl Write) {
+    let mut editor_state = EditorState::new();
+    let mut context = ServerContext::new();
+    for line in input.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Err(e) = handle_message(line, &mut editor_state, &mut context, logger) {
+            writeln!(logger, "[Error] Error handling message {}", e).unwrap();
+            eprintln!("error: {}", e);
+        }
+    }
+}
+
+/// Print an error about the arguments, returns the exit code for usage errors
+pub fn usage_error(error: &str) -> i32 {
+    let _ = writeln!(io::stderr(), "error: {}", error);
+    2
+}
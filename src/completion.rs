@@ -0,0 +1,293 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::editor::{EditorState, FileState, HOLE};
+
+/// Which completion sources are enabled
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompletionSettings {
+    pub structural: bool,
+    pub bst: bool,
+    pub workspace: bool,
+}
+
+impl Default for CompletionSettings {
+    fn default() -> Self {
+        CompletionSettings {
+            structural: true,
+            bst: true,
+            workspace: true,
+        }
+    }
+}
+
+/// What kind of text a candidate inserts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    Value,     // a node label
+    Structure, // separators or line breaks
+}
+
+/// A suggestion from a single source, `score` is in 0..=1 with higher being more relevant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub label: String,
+    pub insert_text: String,
+    pub detail: String,
+    pub kind: CandidateKind,
+    pub score: f64,
+}
+
+/// Everything a source may look at to produce suggestions
+pub struct CompletionContext<'a> {
+    pub file_state: &'a FileState,
+    pub editor_state: &'a EditorState,
+    pub line: usize,      // line of the cursor, which is the depth in the tree
+    pub character: usize, // column of the cursor
+}
+
+impl CompletionContext<'_> {
+    /// Heap index of the node slot at the cursor, None if it is past the largest tree
+    pub fn index(&self) -> Option<usize> {
+        line_start(self.line)?.checked_add(self.character.div_ceil(2))
+    }
+}
+
+/// Heap index of the first node on the line, None for lines deeper than any tree can be
+fn line_start(line: usize) -> Option<usize> {
+    Some(2usize.checked_pow(u32::try_from(line).ok()?)? - 1)
+}
+
+/// A provider of completion candidates, combined with the others by `CompletionEngine`
+pub trait CompletionSource {
+    /// Name of the source, shown in the candidate's detail
+    fn name(&self) -> &'static str;
+
+    fn complete(&self, context: &CompletionContext) -> Vec<Candidate>;
+}
+
+/// Suggests the text that keeps the document a complete tree: a separator between nodes,
+/// or a new line once the current level is full
+pub struct StructuralSource;
+
+impl CompletionSource for StructuralSource {
+    fn name(&self) -> &'static str {
+        "structure"
+    }
+
+    fn complete(&self, context: &CompletionContext) -> Vec<Candidate> {
+        let (Some(index), Some(level_end)) =
+            (context.index(), line_start(context.line.saturating_add(1)))
+        else {
+            return Vec::new();
+        };
+        if index >= level_end {
+            vec![Candidate {
+                label: "new level".to_string(),
+                insert_text: "\n".to_string(),
+                detail: format!("start level {}", context.line + 1),
+                kind: CandidateKind::Structure,
+                score: 0.1,
+            }]
+        } else if !context.character.is_multiple_of(2) {
+            vec![Candidate {
+                label: "separator".to_string(),
+                insert_text: " ".to_string(),
+                detail: "space between nodes".to_string(),
+                kind: CandidateKind::Structure,
+                score: 0.1,
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Suggests labels that keep the binary search tree ordered: greater than every ancestor the
+/// slot is right of, and smaller than every ancestor it is left of
+pub struct BstSource;
+
+impl BstSource {
+    /// Exclusive (lower, upper) bounds for the label at the index
+    fn bounds(file_state: &FileState, index: usize) -> (Option<&str>, Option<&str>) {
+        let (mut lower, mut upper): (Option<&str>, Option<&str>) = (None, None);
+        let mut child = index;
+        while child > 0 {
+            let parent = (child - 1) / 2;
+            let Some(label) = file_state.get(parent) else {
+                return (None, None);
+            };
+            if child == 2 * parent + 1 {
+                upper = Some(upper.map_or(label, |u| u.min(label)));
+            } else {
+                lower = Some(lower.map_or(label, |l| l.max(label)));
+            }
+            child = parent;
+        }
+        (lower, upper)
+    }
+}
+
+impl CompletionSource for BstSource {
+    fn name(&self) -> &'static str {
+        "bst"
+    }
+
+    fn complete(&self, context: &CompletionContext) -> Vec<Candidate> {
+        let Some(index) = context.index() else {
+            return Vec::new();
+        };
+        if index > context.file_state.len() || !context.character.is_multiple_of(2) {
+            return Vec::new();
+        }
+        let (lower, upper) = Self::bounds(context.file_state, index);
+        let alphabet = ('0'..='9').chain('A'..='Z').chain('a'..='z');
+        alphabet
+            .map(|c| c.to_string())
+            .filter(|c| {
+                lower.is_none_or(|l| c.as_str() > l) && upper.is_none_or(|u| c.as_str() < u)
+            })
+            .map(|c| Candidate {
+                insert_text: c.clone(),
+                detail: format!(
+                    "between {} and {}",
+                    lower.unwrap_or("-"),
+                    upper.unwrap_or("-")
+                ),
+                label: c,
+                kind: CandidateKind::Value,
+                score: 0.5,
+            })
+            .collect()
+    }
+}
+
+/// Suggests labels already used in the open documents, more frequent labels rank higher
+pub struct WorkspaceValueSource;
+
+impl CompletionSource for WorkspaceValueSource {
+    fn name(&self) -> &'static str {
+        "workspace"
+    }
+
+    fn complete(&self, context: &CompletionContext) -> Vec<Candidate> {
+        if !context.character.is_multiple_of(2) {
+            return Vec::new();
+        }
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, file_state) in context.editor_state.documents() {
+            for (_, label) in file_state.iter_bfs() {
+                *counts.entry(label).or_default() += 1;
+            }
+        }
+        let max = counts.values().copied().max().unwrap_or(1) as f64;
+        counts
+            .into_iter()
+            .map(|(label, count)| Candidate {
+                label: label.to_string(),
+                insert_text: label.to_string(),
+                detail: format!("used {} times", count),
+                kind: CandidateKind::Value,
+                score: 0.5 * count as f64 / max,
+            })
+            .collect()
+    }
+}
+
+/// Runs the enabled sources and merges their candidates into one ranked list
+pub struct CompletionEngine {
+    sources: Vec<(Box<dyn CompletionSource>, bool)>, // source and whether it is enabled
+}
+
+impl CompletionEngine {
+    /// Engine with the built in sources, enabled according to the settings
+    pub fn new(settings: &CompletionSettings) -> Self {
+        let mut engine = CompletionEngine {
+            sources: Vec::new(),
+        };
+        engine.add_source(Box::new(StructuralSource), settings.structural);
+        engine.add_source(Box::new(BstSource), settings.bst);
+        engine.add_source(Box::new(WorkspaceValueSource), settings.workspace);
+        engine
+    }
+
+    pub fn add_source(&mut self, source: Box<dyn CompletionSource>, enabled: bool) {
+        self.sources.push((source, enabled));
+    }
+
+    /// Candidates of every enabled source, best first. Candidates suggested by several sources
+    /// are merged, adding up their scores and details
+    pub fn complete(&self, context: &CompletionContext) -> Vec<Candidate> {
+        let mut merged: Vec<Candidate> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (source, _) in self.sources.iter().filter(|(_, enabled)| *enabled) {
+            for mut candidate in source.complete(context) {
+                candidate.detail = format!("[{}] {}", source.name(), candidate.detail);
+                match positions.get(&candidate.insert_text) {
+                    Some(&i) => {
+                        merged[i].score += candidate.score;
+                        merged[i].detail = format!("{}, {}", merged[i].detail, candidate.detail);
+                    }
+                    None => {
+                        positions.insert(candidate.insert_text.clone(), merged.len());
+                        merged.push(candidate);
+                    }
+                }
+            }
+        }
+        merged.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.label.cmp(&b.label))
+        });
+        merged
+    }
+
+    /// Text that fills the line at the cursor up to a full level: every missing node is the
+    /// best label suggested for its slot, or its parent's label if there is none. None if the
+    /// cursor isn't at the end of the last, unfinished level
+    pub fn complete_line(&self, context: &CompletionContext) -> Option<String> {
+        let file_state = context.file_state;
+        let level_start = line_start(context.line)?;
+        let level_end = line_start(context.line.saturating_add(1))?;
+        if file_state.len() < level_start || file_state.len() >= level_end {
+            return None;
+        }
+        let present = file_state.len() - level_start;
+        let typed = (2 * present).saturating_sub(1);
+        let mut text = match context.character {
+            c if c == typed && present > 0 => " ".to_string(),
+            c if c == typed + 1 && present > 0 => String::new(),
+            0 if present == 0 => String::new(),
+            _ => return None,
+        };
+        let mut labels = Vec::new();
+        for slot in file_state.len()..level_end {
+            // nothing can go below a hole
+            if slot > 0 && file_state.parent(slot).is_none() {
+                labels.push(HOLE.to_string());
+                continue;
+            }
+            let slot_context = CompletionContext {
+                character: 2 * (slot - level_start),
+                ..*context
+            };
+            let best = self
+                .complete(&slot_context)
+                .into_iter()
+                .find(|c| c.kind == CandidateKind::Value)
+                .map(|c| c.insert_text);
+            let parent = slot
+                .checked_sub(1)
+                .and_then(|s| file_state.get(s / 2))
+                .map(str::to_string);
+            labels.push(best.or(parent)?);
+        }
+        text.push_str(&labels.join(" "));
+        Some(text)
+    }
+}
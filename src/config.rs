@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::completion::CompletionSettings;
+use crate::telemetry::TelemetrySettings;
+
+/// Name of the section holding this server's settings in the client configuration
+pub const SECTION: &str = "lsp-rs";
+
+/// How much information the hover shows for a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HoverVerbosity {
+    Minimal, // only the parent
+    Normal,  // the parent, or the character count when hovering a space
+    Verbose, // the parent, children and position of the node
+}
+
+/// Minimum severity of the log lines forwarded to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    Log,
+}
+
+/// Classes of diagnostics, each re-validated after its own delay while the user is typing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticRule {
+    Structure, // the document is not a valid complete binary tree
+    FileSize,  // the document is larger than maxFileSize
+}
+
+/// How long a document that fails a rule must be left unchanged before it is re-validated
+/// and the failure is reported, in milliseconds
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DiagnosticsSettings {
+    pub structure_delay: u64,
+    pub file_size_delay: u64,
+}
+
+impl Default for DiagnosticsSettings {
+    fn default() -> Self {
+        DiagnosticsSettings {
+            structure_delay: 500,
+            file_size_delay: 1000,
+        }
+    }
+}
+
+impl DiagnosticRule {
+    /// Code of the rule's diagnostics
+    pub fn code(self) -> &'static str {
+        match self {
+            DiagnosticRule::Structure => "structure",
+            DiagnosticRule::FileSize => "file-size",
+        }
+    }
+}
+
+impl DiagnosticsSettings {
+    pub fn delay(&self, rule: DiagnosticRule) -> Duration {
+        Duration::from_millis(match rule {
+            DiagnosticRule::Structure => self.structure_delay,
+            DiagnosticRule::FileSize => self.file_size_delay,
+        })
+    }
+}
+
+/// Requests that can be turned off, each answered with a hint toward its flag while it is off
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FeatureFlags {
+    pub semantic_tokens: bool,
+    pub inline_completion: bool,
+    pub inline_values: bool,
+    pub inlay_hints: bool,
+    pub call_hierarchy: bool,
+    pub linked_editing: bool,
+    pub monikers: bool,
+    pub workspace_symbols: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        FeatureFlags {
+            semantic_tokens: true,
+            inline_completion: true,
+            inline_values: true,
+            inlay_hints: true,
+            call_hierarchy: true,
+            linked_editing: true,
+            monikers: true,
+            workspace_symbols: true,
+        }
+    }
+}
+
+impl FeatureFlags {
+    /// Name of the flag gating the request method and whether it is on, None if no flag does
+    fn flag(&self, method: &str) -> Option<(&'static str, bool)> {
+        Some(match method {
+            "textDocument/semanticTokens/full" => ("semanticTokens", self.semantic_tokens),
+            "textDocument/inlineCompletion" => ("inlineCompletion", self.inline_completion),
+            "textDocument/inlineValue" => ("inlineValues", self.inline_values),
+            "textDocument/inlayHint" => ("inlayHints", self.inlay_hints),
+            "textDocument/prepareCallHierarchy"
+            | "callHierarchy/incomingCalls"
+            | "callHierarchy/outgoingCalls" => ("callHierarchy", self.call_hierarchy),
+            "textDocument/linkedEditingRange" => ("linkedEditing", self.linked_editing),
+            "textDocument/moniker" => ("monikers", self.monikers),
+            "workspace/symbol" | "workspaceSymbol/resolve" => {
+                ("workspaceSymbols", self.workspace_symbols)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// User settings, from `initializationOptions` and `workspace/didChangeConfiguration`.
+/// Missing fields keep their default value
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    pub enable_hover: bool,
+    pub hover_verbosity: HoverVerbosity,
+    pub max_file_size: usize, // documents larger than this (in bytes) are not parsed
+    pub log_level: LogLevel,
+    pub completion: CompletionSettings, // which completion sources are enabled
+    pub diagnostics: DiagnosticsSettings,
+    pub watch_files: bool,    // reload tree files changed outside of the editor
+    pub preview_depth: usize, // levels shown by subtree previews, deeper levels are summarized
+    pub telemetry: TelemetrySettings, // telemetry/event notifications about the server's health
+    pub disk_cache_size: usize, // unopened documents read from disk for a request that are kept
+    pub lazy_parse_size: usize, // documents at least this large (in bytes) are parsed in the background
+    pub request_timeout: u64, // milliseconds a request to the client is waited for, 0 waits forever, as do requests the user answers
+    pub features: FeatureFlags,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enable_hover: true,
+            hover_verbosity: HoverVerbosity::Normal,
+            max_file_size: 16 << 20,
+            log_level: LogLevel::Info,
+            completion: CompletionSettings::default(),
+            diagnostics: DiagnosticsSettings::default(),
+            watch_files: true,
+            preview_depth: 3,
+            telemetry: TelemetrySettings::default(),
+            disk_cache_size: 32,
+            lazy_parse_size: 4 << 20,
+            request_timeout: 30_000,
+            features: FeatureFlags::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Parse the settings sent by the client, which are either the `lsp-rs` section itself
+    /// or an object containing it
+    pub fn from_value(value: &Value) -> Result<Settings, serde_json::Error> {
+        let section = value.get(SECTION).unwrap_or(value);
+        if section.is_null() {
+            return Ok(Settings::default());
+        }
+        Settings::deserialize(section)
+    }
+
+    /// How long a request sent to the client is waited for, None if forever
+    pub fn request_timeout(&self) -> Option<Duration> {
+        (self.request_timeout > 0).then(|| Duration::from_millis(self.request_timeout))
+    }
+
+    /// If the request method is implemented but turned off by these settings, a hint naming
+    /// the setting that turns it back on
+    pub fn disabled_hint(&self, method: &str) -> Option<String> {
+        match method {
+            "textDocument/hover" if !self.enable_hover => {
+                Some(format!("set {}.enableHover to true", SECTION))
+            }
+            "textDocument/completion"
+                if !(self.completion.structural || self.completion.bst || self.completion.workspace) =>
+            {
+                Some(format!(
+                    "enable one of {0}.completion.structural, {0}.completion.bst or {0}.completion.workspace",
+                    SECTION
+                ))
+            }
+            _ => match self.features.flag(method) {
+                Some((flag, false)) => Some(format!("set {}.features.{} to true", SECTION, flag)),
+                _ => None,
+            },
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+
+use crate::cancel::CancellationRegistry;
+use crate::lsp::{ClientResponse, IncomingMessage, RequestId, ResponseError};
+use crate::rpc::{encode_message, json_from_string, MsgParseError, ReaderLimits};
+use crate::transport::{read_frames, Frame, Framer};
+
+/// A message received from the other side, like `lsp::IncomingMessage` but owning its params
+/// so it can be sent to another thread
+#[derive(Debug)]
+pub enum OwnedMessage {
+    Request {
+        id: RequestId,
+        method: String,
+        params: Value, // Null if the request has none
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+    Response(ClientResponse),
+    Invalid(String), // why a frame could not be read or parsed, the ones after it still are
+}
+
+impl OwnedMessage {
+    /// Parse the content of a message, params included
+    pub fn parse(content: &str) -> Result<Self, MsgParseError> {
+        Ok(match IncomingMessage::parse(content)? {
+            IncomingMessage::Request { id, method, params } => OwnedMessage::Request {
+                id,
+                method,
+                params: parse_params(params)?,
+            },
+            IncomingMessage::Notification { method, params } => OwnedMessage::Notification {
+                method,
+                params: parse_params(params)?,
+            },
+            IncomingMessage::Response(response) => OwnedMessage::Response(response),
+        })
+    }
+
+    /// The message of a frame, `Invalid` if it is not one
+    fn from_frame(frame: Frame) -> Self {
+        frame
+            .map_err(|e| e.to_string())
+            .and_then(|content| Self::parse(&content).map_err(|e| e.to_string()))
+            .unwrap_or_else(OwnedMessage::Invalid)
+    }
+}
+
+fn parse_params(params: Option<&RawValue>) -> Result<Value, MsgParseError> {
+    match params {
+        Some(params) => json_from_string(params.get()).map_err(|e| MsgParseError(e.to_string())),
+        None => Ok(Value::Null),
+    }
+}
+
+/// A message for the other side, written as json once it goes through the connection
+#[derive(Debug)]
+pub enum OutgoingMessage {
+    Request {
+        id: RequestId,
+        method: String,
+        params: Value, // Left out if null
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+    Response {
+        id: RequestId,
+        result: Result<Value, ResponseError>,
+    },
+}
+
+impl OutgoingMessage {
+    /// Content of the message, without its header
+    pub fn to_json(&self) -> Value {
+        let mut message = match self {
+            OutgoingMessage::Request { id, method, params } => {
+                json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+            }
+            OutgoingMessage::Notification { method, params } => {
+                json!({ "jsonrpc": "2.0", "method": method, "params": params })
+            }
+            OutgoingMessage::Response { id, result } => match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+            },
+        };
+        if message.get("params").is_some_and(Value::is_null) {
+            message.as_object_mut().unwrap().remove("params");
+        }
+        message
+    }
+}
+
+/// Both ends of the channels to the other side, for library users that would rather pass
+/// messages than drive a `BufferedReader` and a writer themselves. The connection is closed
+/// once the sender is dropped
+pub struct Connection {
+    pub sender: Sender<OutgoingMessage>,
+    pub receiver: Receiver<OwnedMessage>,
+}
+
+/// Write half of a tcp stream, shut down once the connection is closed so the other side
+/// sees the end of its input
+struct ClosingStream(TcpStream);
+
+impl Write for ClosingStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for ClosingStream {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Write);
+    }
+}
+
+/// Threads moving the messages of a connection, they are done once the input ended and the
+/// sender was dropped
+pub struct IoThreads {
+    reader: JoinHandle<io::Result<()>>,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl IoThreads {
+    /// Wait for both threads, the first error of either is returned
+    pub fn join(self) -> io::Result<()> {
+        let reader = self.reader.join().expect("the reader thread panicked");
+        let writer = self.writer.join().expect("the writer thread panicked");
+        reader.and(writer)
+    }
+}
+
+impl Connection {
+    /// Talk over stdin and stdout
+    pub fn stdio() -> (Connection, IoThreads) {
+        Self::from_io(io::stdin(), io::stdout())
+    }
+
+    /// Talk over a tcp stream, eg. one accepted from a listener
+    pub fn tcp(stream: TcpStream) -> io::Result<(Connection, IoThreads)> {
+        stream.set_nodelay(true)?;
+        let writer = ClosingStream(stream.try_clone()?);
+        Ok(Self::from_io(stream, writer))
+    }
+
+    /// Read LSP messages from the reader and write them to the writer, each on a thread of
+    /// its own. Frames that are not valid messages are received as `Invalid`
+    pub fn from_io(
+        input: impl Read + Send + 'static,
+        mut output: impl Write + Send + 'static,
+    ) -> (Connection, IoThreads) {
+        let (incoming_sender, receiver) = mpsc::channel();
+        let (sender, outgoing_receiver) = mpsc::channel::<OutgoingMessage>();
+        let reader = thread::spawn(move || {
+            let mut framer = Framer::new(ReaderLimits::default(), CancellationRegistry::default());
+            read_frames(input, &mut framer, |_, frames| {
+                // stops once no one is listening anymore
+                frames.into_iter().all(|frame| {
+                    incoming_sender
+                        .send(OwnedMessage::from_frame(frame))
+                        .is_ok()
+                })
+            })
+        });
+        let writer = thread::spawn(move || {
+            for message in outgoing_receiver {
+                let content = message.to_json().to_string();
+                output.write_all(encode_message(content).as_bytes())?;
+                output.flush()?;
+            }
+            Ok(())
+        });
+        (
+            Connection { sender, receiver },
+            IoThreads { reader, writer },
+        )
+    }
+
+    /// Two connections talking to each other in memory, eg. a client and a server in tests.
+    /// What one sends the other receives, as if it went through the wire
+    pub fn memory() -> (Connection, Connection) {
+        let (client_sender, server_receiver) = mpsc::channel();
+        let (server_sender, client_receiver) = mpsc::channel();
+        let (client, server) = (
+            Self::forward(client_receiver),
+            Self::forward(server_receiver),
+        );
+        (
+            Connection {
+                sender: client_sender,
+                receiver: client,
+            },
+            Connection {
+                sender: server_sender,
+                receiver: server,
+            },
+        )
+    }
+
+    /// Receiver of what is sent on the other end, serialized and parsed again on the way
+    fn forward(outgoing: Receiver<OutgoingMessage>) -> Receiver<OwnedMessage> {
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for message in outgoing {
+                let message = OwnedMessage::from_frame(Ok(message.to_json().to_string()));
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        incoming
+    }
+}
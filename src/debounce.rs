@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Delays work for a key until it has been quiet for a while. Scheduling a key again before
+/// its deadline replaces the value and moves the deadline, so a burst of changes runs once
+#[derive(Debug)]
+pub struct Debouncer<K, V> {
+    pending: HashMap<K, (Instant, V)>,
+}
+
+impl<K: Eq + Hash + Clone, V> Default for Debouncer<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V> Debouncer<K, V> {
+    pub fn new() -> Self {
+        Debouncer {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Run the work for `key` with `value` at `deadline`, replacing what was scheduled before
+    pub fn schedule(&mut self, key: K, value: V, deadline: Instant) {
+        self.pending.insert(key, (deadline, value));
+    }
+
+    /// Drop the scheduled work for `key`, returning its value
+    pub fn cancel(&mut self, key: &K) -> Option<V> {
+        self.pending.remove(key).map(|(_, value)| value)
+    }
+
+    pub fn is_scheduled(&self, key: &K) -> bool {
+        self.pending.contains_key(key)
+    }
+
+    /// The earliest deadline, None if nothing is scheduled
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Remove and return the work whose deadline is at or before `now`, earliest first
+    pub fn take_due(&mut self, now: Instant) -> Vec<(K, V)> {
+        let due: Vec<K> = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut due: Vec<(Instant, K, V)> = due
+            .into_iter()
+            .filter_map(|key| {
+                let (deadline, value) = self.pending.remove(&key)?;
+                Some((deadline, key, value))
+            })
+            .collect();
+        due.sort_by_key(|(deadline, _, _)| *deadline);
+        due.into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
@@ -0,0 +1,227 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lsp::Position;
+use crate::rope::Rope;
+
+/// Unit the `character` of a position counts in, negotiated with the client at initialize.
+/// Clients that don't say which they support use utf-16
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum PositionEncoding {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[default]
+    #[serde(rename = "utf-16")]
+    Utf16,
+    #[serde(rename = "utf-32")]
+    Utf32,
+}
+
+impl FromStr for PositionEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf-8" => Ok(PositionEncoding::Utf8),
+            "utf-16" => Ok(PositionEncoding::Utf16),
+            "utf-32" => Ok(PositionEncoding::Utf32),
+            _ => Err(format!("unknown position encoding {}", s)),
+        }
+    }
+}
+
+impl PositionEncoding {
+    pub const ALL: [PositionEncoding; 3] = [
+        PositionEncoding::Utf8,
+        PositionEncoding::Utf16,
+        PositionEncoding::Utf32,
+    ];
+
+    /// The first encoding the client lists, which is the one it prefers, utf-16 if it
+    /// lists none the server knows
+    pub fn negotiate(client_encodings: &[String]) -> Self {
+        client_encodings
+            .iter()
+            .find_map(|e| e.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Number of units of the character in this encoding
+    pub fn char_len(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+// Every conversion between byte offsets and positions goes through `wide_column_to_byte` and
+// `wide_byte_to_column`, which only look at the characters longer than a byte: `LineIndex`
+// keeps those of every line, the other functions find them in the text
+
+/// Byte offset of the column in a line of `len` bytes without its line ending, given the
+/// characters of the line longer than a byte and where they start. None past the end of the
+/// line or in the middle of a character
+pub fn wide_column_to_byte(
+    wide: impl IntoIterator<Item = (usize, char)>,
+    len: usize,
+    column: usize,
+    encoding: PositionEncoding,
+) -> Option<usize> {
+    let mut extra = 0; // bytes minus units before the column
+    for (start, c) in wide {
+        let units = start - extra;
+        if column <= units {
+            break;
+        }
+        if column < units + encoding.char_len(c) {
+            return None;
+        }
+        extra += c.len_utf8() - encoding.char_len(c);
+    }
+    Some(column + extra).filter(|&byte| byte <= len)
+}
+
+/// Column of the byte offset in a line like `wide_column_to_byte`. None past the end of the
+/// line or in the middle of a character
+pub fn wide_byte_to_column(
+    wide: impl IntoIterator<Item = (usize, char)>,
+    len: usize,
+    byte: usize,
+    encoding: PositionEncoding,
+) -> Option<usize> {
+    if byte > len {
+        return None;
+    }
+    let mut extra = 0;
+    for (start, c) in wide.into_iter().take_while(|&(start, _)| start < byte) {
+        if byte < start + c.len_utf8() {
+            return None;
+        }
+        extra += c.len_utf8() - encoding.char_len(c);
+    }
+    Some(byte - extra)
+}
+
+/// The characters of the line longer than a byte, with where they start
+pub fn wide_chars(line: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    line.char_indices().filter(|(_, c)| !c.is_ascii())
+}
+
+/// Byte offset of the column in the line, which has no line ending. None past the end of the
+/// line or in the middle of a character
+pub fn column_to_byte(line: &str, column: usize, encoding: PositionEncoding) -> Option<usize> {
+    wide_column_to_byte(wide_chars(line), line.len(), column, encoding)
+}
+
+/// Column of the byte offset in the line. None past the end of the line or in the middle of
+/// a character
+pub fn byte_to_column(line: &str, byte: usize, encoding: PositionEncoding) -> Option<usize> {
+    wide_byte_to_column(wide_chars(line), line.len(), byte, encoding)
+}
+
+/// Byte offset where every line of the text starts, lines end with `\n` or `\r\n`
+fn line_starts(text: &str) -> impl Iterator<Item = usize> + '_ {
+    std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1))
+}
+
+/// Content of the line starting at `start`, without its line ending
+fn line_at(text: &str, start: usize) -> &str {
+    let line = &text[start..];
+    let line = line.split('\n').next().unwrap_or_default();
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Byte offset of the position in the text. None if the line doesn't exist, or the
+/// character is past the end of the line or in the middle of a character
+pub fn offset_at(text: &str, position: Position, encoding: PositionEncoding) -> Option<usize> {
+    let line = usize::try_from(position.line).ok()?;
+    let column = usize::try_from(position.character).ok()?;
+    let start = line_starts(text).nth(line)?;
+    Some(start + column_to_byte(line_at(text, start), column, encoding)?)
+}
+
+/// Position of the byte offset in the text. None if the offset is past the end of the text,
+/// in the middle of a character or between the `\r` and `\n` of a line ending
+pub fn position_at(text: &str, offset: usize, encoding: PositionEncoding) -> Option<Position> {
+    if !text.is_char_boundary(offset) {
+        return None;
+    }
+    let (line, start) = line_starts(text)
+        .enumerate()
+        .take_while(|&(_, start)| start <= offset)
+        .last()?;
+    let column = byte_to_column(line_at(text, start), offset - start, encoding)?;
+    Some(Position {
+        line: line as i32,
+        character: column as i32,
+    })
+}
+
+/// `offset_at` for a rope
+pub fn position_to_offset(
+    text: &Rope,
+    position: Position,
+    encoding: PositionEncoding,
+) -> Option<usize> {
+    let line = usize::try_from(position.line).ok()?;
+    let column = usize::try_from(position.character).ok()?;
+    Some(text.line_start(line)? + column_to_byte(&text.line(line)?, column, encoding)?)
+}
+
+/// `position_at` for a rope
+pub fn offset_to_position(
+    text: &Rope,
+    offset: usize,
+    encoding: PositionEncoding,
+) -> Option<Position> {
+    if offset > text.len() {
+        return None;
+    }
+    let line = text.line_at(offset);
+    let start = text.line_start(line)?;
+    let column = byte_to_column(&text.line(line)?, offset - start, encoding)?;
+    Some(Position {
+        line: line as i32,
+        character: column as i32,
+    })
+}
+
+/// Byte order mark some editors write at the start of utf-8 files
+pub const BOM: char = '\u{feff}';
+
+/// The text without its leading byte order mark, and whether it had one
+pub fn strip_bom(text: String) -> (String, bool) {
+    match text.strip_prefix(BOM) {
+        Some(rest) => (rest.to_string(), true),
+        None => (text, false),
+    }
+}
+
+/// How the bytes of a file were turned into text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    Latin1,
+}
+
+/// Text of the bytes of a file: utf-8 if they are valid utf-8, Latin-1 otherwise, which any
+/// bytes are. A Latin-1 byte is a single character, so a label stays a single character
+/// instead of turning into replacement characters. A byte order mark is kept, for the
+/// document to record
+pub fn decode(bytes: Vec<u8>) -> (String, TextEncoding) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, TextEncoding::Utf8),
+        Err(e) => {
+            let bytes = e.into_bytes();
+            let (bom, rest) = match bytes.strip_prefix("\u{feff}".as_bytes()) {
+                Some(rest) => (Some(BOM), rest),
+                None => (None, &bytes[..]),
+            };
+            let text = bom.into_iter().chain(rest.iter().map(|&b| char::from(b)));
+            (text.collect(), TextEncoding::Latin1)
+        }
+    }
+}
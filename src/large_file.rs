@@ -0,0 +1,181 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::editor::HOLE;
+
+/// Files smaller than this are read rather than mapped, see `validate_file`
+#[cfg(feature = "mmap")]
+pub const MAP_SIZE: u64 = 1 << 20;
+
+/// Summary of a file that is a valid tree
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LineSummary {
+    pub lines: usize, // number of levels in the tree
+    pub nodes: usize,
+    pub bytes: u64,
+}
+
+/// The first line that keeps the file from being a valid tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+/// Validates a tree one line at a time, so the whole file never has to be in memory.
+/// Uses the same rules as `FileState::new`: every level but the last is full, nodes are
+/// separated by single spaces, and nothing is below a hole
+#[derive(Debug, Default)]
+pub struct LineValidator {
+    summary: LineSummary,
+    short_line: Option<usize>, // a line that was not full, which is only valid as the last line
+    present: Vec<bool>,        // which nodes of the previous line are not holes
+}
+
+impl LineValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Most characters the next line may have, a full level
+    pub fn width(&self) -> usize {
+        usize::pow(2, self.summary.lines as u32 + 1) - 1
+    }
+
+    /// The error for a next line longer than `width`, found before reading all of it
+    pub fn too_long(&self) -> LineError {
+        match self.short_line {
+            Some(short) => Self::missing_nodes(short),
+            None => LineError {
+                line: self.summary.lines,
+                reason: format!("expected at most {} characters, found more", self.width()),
+            },
+        }
+    }
+
+    fn missing_nodes(line: usize) -> LineError {
+        LineError {
+            line,
+            reason: "only the last level may have missing nodes".to_string(),
+        }
+    }
+
+    /// Validate the next line, without its line ending
+    pub fn push_line(&mut self, line: &[u8]) -> Result<(), LineError> {
+        let d = self.summary.lines;
+        if let Some(short) = self.short_line {
+            return Err(Self::missing_nodes(short));
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // a byte order mark is not part of the first level, see `FileState::new`
+        let line = match d {
+            0 => line.strip_prefix("\u{feff}".as_bytes()).unwrap_or(line),
+            _ => line,
+        };
+        let n = self.width();
+        if line.len() > n {
+            return Err(LineError {
+                line: d,
+                reason: format!("expected at most {} characters, found {}", n, line.len()),
+            });
+        }
+        if line.len() < n {
+            self.short_line = Some(d);
+        }
+        if line.iter().skip(1).step_by(2).any(|&c| c != b' ') {
+            return Err(LineError {
+                line: d,
+                reason: "nodes must be separated by a single space".to_string(),
+            });
+        }
+        let present: Vec<bool> = line
+            .iter()
+            .step_by(2)
+            .map(|&c| c != b' ' && c != HOLE as u8)
+            .collect();
+        let orphan = (0..present.len()).find(|&i| d > 0 && present[i] && !self.present[i / 2]);
+        if let Some(i) = orphan {
+            return Err(LineError {
+                line: d,
+                reason: format!("node {} is below a hole", i),
+            });
+        }
+        self.summary.lines += 1;
+        self.summary.nodes += present.iter().filter(|&&p| p).count();
+        self.present = present;
+        Ok(())
+    }
+
+    pub fn finish(self, bytes: u64) -> LineSummary {
+        LineSummary {
+            bytes,
+            ..self.summary
+        }
+    }
+}
+
+/// Validate the lines of an in memory buffer, eg. a memory mapped file
+pub fn validate_bytes(bytes: &[u8]) -> Result<LineSummary, LineError> {
+    let mut validator = LineValidator::new();
+    let content = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    if !content.is_empty() {
+        for line in content.split(|&b| b == b'\n') {
+            validator.push_line(line)?;
+        }
+    }
+    Ok(validator.finish(bytes.len() as u64))
+}
+
+/// Validate the lines of a reader, holding a single line in memory at a time. A line is
+/// read no further than the width of its level, so a file without line breaks is not loaded
+/// whole
+pub fn validate_reader(reader: impl BufRead) -> io::Result<Result<LineSummary, LineError>> {
+    let mut reader = reader;
+    let mut validator = LineValidator::new();
+    let mut line = Vec::new();
+    let mut bytes = 0;
+    loop {
+        line.clear();
+        // the level, its line ending, and a byte order mark before the first
+        let bom = if bytes == 0 { 3 } else { 0 };
+        let limit = (validator.width() + 2 + bom) as u64;
+        let n = (&mut reader).take(limit).read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        bytes += n as u64;
+        if n as u64 == limit && !line.ends_with(b"\n") {
+            return Ok(Err(validator.too_long()));
+        }
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        if let Err(e) = validator.push_line(content) {
+            return Ok(Err(e));
+        }
+    }
+    Ok(Ok(validator.finish(bytes)))
+}
+
+/// Validate a file on disk without loading it into memory. With the `mmap` feature files of at
+/// least `MAP_SIZE` bytes are memory mapped, otherwise, or if mapping fails, the file is read
+/// through a buffered reader
+pub fn validate_file(path: &Path) -> io::Result<Result<LineSummary, LineError>> {
+    let file = File::open(path)?;
+    #[cfg(feature = "mmap")]
+    if file.metadata()?.len() >= MAP_SIZE {
+        // SAFETY: the map is only read, but the file is not locked: if another process
+        // truncates it while it is validated, reading past the new end raises SIGBUS on unix.
+        // Only files too large to read quickly take that risk
+        if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+            return Ok(validate_bytes(&map));
+        }
+    }
+    validate_reader(BufReader::with_capacity(1 << 16, file))
+}
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+/// A subsystem built the first time it is needed instead of when the server starts, so
+/// clients that never use it don't pay for it. Remembers how long building it took
+#[derive(Debug)]
+pub struct Lazy<T> {
+    name: &'static str,
+    value: Option<T>,
+    init_time: Option<Duration>,
+}
+
+impl<T> Lazy<T> {
+    pub fn new(name: &'static str) -> Self {
+        Lazy {
+            name,
+            value: None,
+            init_time: None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The value, built with `init` if this is the first time it is needed
+    pub fn get_or_init(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        if self.value.is_none() {
+            let start = Instant::now();
+            let value = init();
+            self.set(value, start.elapsed());
+        }
+        self.value.as_mut().unwrap()
+    }
+
+    /// Store a value that was built elsewhere, eg. by code that needs the rest of the server
+    pub fn set(&mut self, value: T, init_time: Duration) {
+        self.value = Some(value);
+        self.init_time = Some(init_time);
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Drop the value, eg. when the settings it was built from changed. The next use builds
+    /// it again
+    pub fn reset(&mut self) -> Option<T> {
+        self.value.take()
+    }
+
+    /// How long building the value took the last time, None if it was never built
+    pub fn init_time(&self) -> Option<Duration> {
+        self.init_time
+    }
+}
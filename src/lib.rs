@@ -1,614 +1,8907 @@
+pub mod arena;
+pub mod background;
+pub mod cancel;
+pub mod cli;
+pub mod completion;
+pub mod config;
+pub mod connection;
+pub mod debounce;
+pub mod encoding;
+pub mod large_file;
+pub mod lazy;
+pub mod line_index;
+pub mod logs;
+pub mod lru;
+pub mod middleware;
+#[cfg(windows)]
+pub mod named_pipe;
+pub mod output;
+pub mod preview;
+pub mod record;
+pub mod rope;
+pub mod shedding;
+pub mod telemetry;
+pub mod testing;
+pub mod trace;
+pub mod transport;
+pub mod uri;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod workspace;
+
 pub mod editor {
-    use std::collections::HashMap;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fmt::{self, Display, Formatter};
+    use std::fs;
+    use std::io;
+    use std::ops::Range;
+    use std::path::Path;
+    use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+    use crate::arena::LabelArena;
+    use crate::encoding::strip_bom;
+    use crate::line_index::LineIndex;
+    use crate::rope::Rope;
+    use crate::uri::{CaseSensitivity, Uri};
+
+    /// A document that is a valid tree. The tree is derived from the text, and kept up to date
+    /// as the text is edited. Positions left blank, with `_` or a space, are holes: the node
+    /// is missing, and so is everything below it
+    #[derive(Clone)]
     pub struct FileState {
-        tree: Vec<String>,
-        char_count: usize,
+        text: Rope,
+        tree: LabelArena,
+        eol: LineEnding, // how the text ends its lines, the tree is written back the same way
+        bom: bool, // whether the document started with a byte order mark, which is not in the text
+    }
+
+    /// How a document ends its lines. Either is parsed and counted the same, `\r\n` counts as a
+    /// single line break
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum LineEnding {
+        #[default]
+        Lf,
+        CrLf,
+    }
+
+    impl LineEnding {
+        /// The ending of the first line, `\n` if the text is a single line
+        pub fn detect(text: &Rope) -> Self {
+            match text.line_start(1) {
+                Some(start) if crlf_before(text, start) => LineEnding::CrLf,
+                _ => LineEnding::Lf,
+            }
+        }
+
+        pub fn as_str(self) -> &'static str {
+            match self {
+                LineEnding::Lf => "\n",
+                LineEnding::CrLf => "\r\n",
+            }
+        }
+    }
+
+    /// Whether the line starting at the byte offset follows a `\r\n`
+    fn crlf_before(text: &Rope, start: usize) -> bool {
+        start >= 2 && text.is_char_boundary(start - 2) && text.slice(start - 2..start - 1) == "\r"
+    }
+
+    /// Written in place of a missing node
+    pub const HOLE: char = '_';
+
+    /// Line and character where the label of the node at the index is, in any complete tree.
+    /// Level `l` holds the nodes `2^l - 1` to `2^(l+1) - 2`, separated by single spaces
+    pub fn index_to_position(index: usize) -> (usize, usize) {
+        let line = (index + 1).ilog2() as usize;
+        (line, (index + 1 - (1 << line)) * 2)
+    }
+
+    /// Language identifier of tree documents, documents of other languages are not parsed
+    pub const TREE_LANGUAGE_ID: &str = "abc";
+
+    /// Which child of a node
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Side {
+        Left,
+        Right,
+    }
+
+    /// Metrics of a tree, see `FileState::stats`. Sent as the result of `lsp-rs/documentStats`
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TreeStats {
+        pub node_count: usize,
+        pub leaf_count: usize,
+        pub max_depth: Option<usize>, // depth of the deepest node, None if there are none
+        pub completeness: f64,        // share of a perfect tree of the same height that is filled
+        pub labels: BTreeMap<String, usize>, // number of nodes with every label
+    }
+
+    /// How a node differs between two versions of a tree, see `FileState::diff`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum TreeChange {
+        Added {
+            index: usize,
+            label: String,
+        },
+        Removed {
+            index: usize,
+            label: String,
+        },
+        Relabeled {
+            index: usize,
+            old: String,
+            new: String,
+        },
+    }
+
+    impl TreeChange {
+        pub fn index(&self) -> usize {
+            match self {
+                TreeChange::Added { index, .. }
+                | TreeChange::Removed { index, .. }
+                | TreeChange::Relabeled { index, .. } => *index,
+            }
+        }
+    }
+
+    impl Display for TreeChange {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                TreeChange::Added { index, label } => write!(f, "+{} {}", index, label),
+                TreeChange::Removed { index, label } => write!(f, "-{} {}", index, label),
+                TreeChange::Relabeled { index, old, new } => {
+                    write!(f, "~{} {} -> {}", index, old, new)
+                }
+            }
+        }
+    }
+
+    /// Why a change to the tree was refused, the tree would no longer be complete
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum TreeEditError {
+        NoSuchNode(usize),    // there is no node at the index
+        InvalidLabel(String), // labels are a single character that is not a space or `_`
+        SlotTaken(usize),     // there already is a node where the leaf would go
+    }
+
+    impl Display for TreeEditError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                TreeEditError::NoSuchNode(index) => write!(f, "there is no node {}", index),
+                TreeEditError::InvalidLabel(label) => write!(
+                    f,
+                    "{:?} is not a valid label, expected a single character that is not a space or {}",
+                    label, HOLE
+                ),
+                TreeEditError::SlotTaken(index) => write!(f, "there already is a node {}", index),
+            }
+        }
+    }
+
+    /// Why a text is not a valid tree, the first problem in it
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct FileStateError {
+        pub line: usize,      // level the problem is on
+        pub character: usize, // byte of the line where it starts
+        pub expected: usize, // length of the line, a level but the last has exactly that many bytes
+        pub reason: String,  // what is wrong, readable by users
+    }
+
+    impl Display for FileStateError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(
+                f,
+                "line {}, character {}: {}",
+                self.line, self.character, self.reason
+            )
+        }
     }
 
+    /// Whether the label can be a node, a single character that is not a space or a hole
+    pub fn is_valid_label(label: &str) -> bool {
+        matches!(label.as_bytes(), [c] if c.is_ascii_graphic() && *c as char != HOLE)
+    }
+
+    /// A subtree taken out of the heap order, to be moved elsewhere in the tree
+    struct Subtree {
+        label: String,
+        left: Option<Box<Subtree>>,
+        right: Option<Box<Subtree>>,
+    }
+
+    /// Lay out the nodes, in heap order, as the lines of a document: one level per line,
+    /// labels separated by single spaces, holes written as `_`, and a line break `eol` after
+    /// every level. Holes at the end are left out
+    pub fn layout(nodes: &[Option<&str>], eol: LineEnding) -> String {
+        let len = nodes.iter().rposition(Option::is_some).map_or(0, |i| i + 1);
+        let nodes = &nodes[..len];
+        let hole = HOLE.to_string();
+        let mut text = String::new();
+        let mut start = 0;
+        while start < nodes.len() {
+            let end = (2 * start + 1).min(nodes.len());
+            let labels: Vec<&str> = nodes[start..end]
+                .iter()
+                .map(|node| node.unwrap_or(&hole))
+                .collect();
+            text.push_str(&labels.join(" "));
+            text.push_str(eol.as_str());
+            start = end;
+        }
+        text
+    }
+
+    /// The open documents written to disk by `EditorState::save_session`, so a restarted
+    /// server knows them before the client opens them again
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Session {
+        format: u32, // sessions of another format are not restored
+        documents: Vec<SessionDocument>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct SessionDocument {
+        uri: String,
+        text: String,         // latest text, without the byte order mark
+        tree: Option<String>, // text of the last valid tree, if it was not parsed from `text`
+        bom: bool,
+        version: Option<i64>,
+        language_id: Option<String>,
+        dirty: bool,
+    }
+
+    /// Format of the sessions written by this version of the server
+    const SESSION_FORMAT: u32 = 1;
+
     pub struct EditorState {
-        files: HashMap<String, FileState>,
+        files: HashMap<Uri, FileState>,
+        texts: HashMap<Uri, Rope>, // latest text of every document, also of those that are not a valid tree
+        line_indexes: HashMap<Uri, LineIndex>, // lines of every text, to find positions in it
+        versions: HashMap<Uri, i64>, // version of the open documents, as numbered by the client
+        language_ids: HashMap<Uri, String>, // language of the open documents, as named by the client
+        dirty: HashSet<Uri>, // open documents changed since they were opened or saved
+        open_files: HashSet<Uri>, // files opened in the editor, their content is owned by the client
+        case_sensitivity: CaseSensitivity, // how uris are compared when looking up files
     }
 
     impl FileState {
-        pub fn new(file_content: String) -> Option<Self> {
-            let mut v = Vec::new();
-
-            let lines: Vec<&str> = file_content.lines().collect();
-            let line_count = lines.len();
-            for (d, line) in lines.iter().enumerate() {
-                let n = usize::pow(2, d as u32 + 1) - 1;
-                if (d != line_count - 1 && line.len() != n)
-                    || (d == line_count - 1 && line.len() > n)
-                {
-                    return None;
+        /// Parse the content of a document. A leading byte order mark is recorded and left out
+        /// of the text, so positions count from the first label
+        pub fn new(file_content: String) -> Result<Self, FileStateError> {
+            let (file_content, bom) = strip_bom(file_content);
+            let mut fs = Self::from_rope(Rope::from(file_content))?;
+            fs.bom = bom;
+            Ok(fs)
+        }
+
+        /// Parse the whole text
+        pub fn from_rope(text: Rope) -> Result<Self, FileStateError> {
+            Self::from_rope_until(text, || false).map(|fs| fs.expect("the parse is never given up"))
+        }
+
+        /// Parse the whole text like `from_rope`, giving up with None once `cancelled` returns
+        /// true. It is checked before every line
+        pub fn from_rope_until(
+            text: Rope,
+            cancelled: impl Fn() -> bool,
+        ) -> Result<Option<Self>, FileStateError> {
+            let mut tree = LabelArena::new();
+            if !Self::parse_levels(&mut tree, &text, 0, cancelled)? {
+                return Ok(None);
+            }
+            let eol = LineEnding::detect(&text);
+            let fs = FileState {
+                text,
+                tree,
+                eol,
+                bom: false,
+            };
+            fs.check_parents(0..fs.tree.len())?;
+            Ok(Some(fs))
+        }
+
+        /// Number of levels of the text. Like `str::lines`, a text ending with a line break has
+        /// no empty last level
+        fn levels(text: &Rope) -> usize {
+            let last_start = text.line_start(text.line_count() - 1);
+            text.line_count() - usize::from(!text.is_empty() && last_start == Some(text.len()))
+        }
+
+        /// Parse the levels of the text from `from` on into the tree, which has the nodes of
+        /// the levels above. Returns false if `cancelled` returned true before a line
+        fn parse_levels(
+            tree: &mut LabelArena,
+            text: &Rope,
+            from: usize,
+            cancelled: impl Fn() -> bool,
+        ) -> Result<bool, FileStateError> {
+            let levels = Self::levels(text);
+            for d in from..levels {
+                if cancelled() {
+                    return Ok(false);
+                }
+                let line = text.line(d).unwrap_or_default();
+                for label in Self::parse_line(&line, d, d == levels - 1)? {
+                    tree.push(label);
                 }
-                for c in line.chars().skip(1).step_by(2) {
-                    if c != ' ' {
-                        return None;
+            }
+            Ok(true)
+        }
+
+        /// Labels of the nodes on line `d`, or why the line can't be level `d` of a tree.
+        /// Every level but the last is full, and nodes are separated by single spaces
+        fn parse_line(
+            line: &str,
+            d: usize,
+            last: bool,
+        ) -> Result<Vec<Option<&str>>, FileStateError> {
+            let n = usize::pow(2, d as u32 + 1) - 1;
+            let error = |character, reason| FileStateError {
+                line: d,
+                character,
+                expected: n,
+                reason,
+            };
+            if last && line.len() > n {
+                return Err(error(
+                    0,
+                    format!("expected at most {} characters, found {}", n, line.len()),
+                ));
+            }
+            if !last && line.len() != n {
+                return Err(error(
+                    0,
+                    format!(
+                        "only the last level may be short, expected {} characters, found {}",
+                        n,
+                        line.len()
+                    ),
+                ));
+            }
+            if let Some((i, _)) = line
+                .char_indices()
+                .skip(1)
+                .step_by(2)
+                .find(|&(_, c)| c != ' ')
+            {
+                return Err(error(
+                    i,
+                    "nodes must be separated by a single space".to_string(),
+                ));
+            }
+            Ok(line
+                .char_indices()
+                .step_by(2)
+                .map(|(i, c)| (c != ' ' && c != HOLE).then(|| &line[i..i + c.len_utf8()]))
+                .collect())
+        }
+
+        /// Fails on the first node in the range, or below a hole in the range, without a
+        /// parent
+        fn check_parents(&self, range: Range<usize>) -> Result<(), FileStateError> {
+            let orphan =
+                |i: usize| i > 0 && self.get(i).is_some() && self.get((i - 1) / 2).is_none();
+            let Some(index) = range
+                .flat_map(|i| [i, 2 * i + 1, 2 * i + 2])
+                .find(|&i| orphan(i))
+            else {
+                return Ok(());
+            };
+            let (line, character) = index_to_position(index);
+            Err(FileStateError {
+                line,
+                character,
+                expected: usize::pow(2, line as u32 + 1) - 1,
+                reason: format!(
+                    "{} is below a hole, every node needs a parent",
+                    self.tree.get(index).unwrap_or_default()
+                ),
+            })
+        }
+
+        /// Take the text after an edit that inserted the byte range `inserted`. An edit that
+        /// keeps the lines and their lengths, eg. relabeling a node, only re-parses its line,
+        /// anything else re-parses the levels from the one above the edit on, the ones before
+        /// it didn't change. Fails, keeping the state, if the text is not a valid tree
+        fn update(&mut self, text: Rope, inserted: Range<usize>) -> Result<(), FileStateError> {
+            let d = text.line_at(inserted.start);
+            let levels = Self::levels(&text);
+            let first = (1 << d) - 1;
+            let old_nodes = self.tree.len().saturating_sub(first).min(1 << d);
+            let same_lines = d < levels
+                && text.line_at(inserted.end) == d
+                && text.line_count() == self.text.line_count()
+                && text.line(d).map(|l| l.len()) == self.text.line(d).map(|l| l.len());
+            if same_lines {
+                let line = text.line(d).unwrap();
+                match Self::parse_line(&line, d, d == levels - 1) {
+                    Ok(nodes) if nodes.len() == old_nodes => {
+                        let old: Vec<Option<String>> = (first..first + old_nodes)
+                            .map(|i| self.tree.get(i).map(str::to_string))
+                            .collect();
+                        for (i, node) in nodes.into_iter().enumerate() {
+                            self.tree.set(first + i, node);
+                        }
+                        if let Err(e) = self.check_parents(first..first + old_nodes) {
+                            for (i, node) in old.iter().enumerate() {
+                                self.tree.set(first + i, node.as_deref());
+                            }
+                            return Err(e);
+                        }
+                        // the line may have gained or lost the `\r` of its line break
+                        self.eol = LineEnding::detect(&text);
+                        self.text = text;
+                        return Ok(());
                     }
+                    Ok(_) => (),
+                    Err(e) => return Err(e),
                 }
-                for c in line.chars().step_by(2) {
-                    v.push(c.to_string());
+            }
+            // the level above may have been the last one, which can be short
+            let from = d.saturating_sub(1);
+            let first = (1 << from) - 1;
+            let old: Vec<Option<String>> = (first..self.tree.len())
+                .map(|i| self.tree.get(i).map(str::to_string))
+                .collect();
+            self.tree.truncate(first);
+            let parsed = Self::parse_levels(&mut self.tree, &text, from, || false)
+                .and_then(|_| self.check_parents(first..self.tree.len()));
+            if let Err(e) = parsed {
+                self.tree.truncate(first);
+                for node in &old {
+                    self.tree.push(node.as_deref());
                 }
+                return Err(e);
             }
-            return Some(FileState {
-                tree: v,
-                char_count: file_content.len(),
-            });
+            self.eol = LineEnding::detect(&text);
+            self.text = text;
+            Ok(())
+        }
+
+        /// Text the tree was parsed from
+        pub fn text(&self) -> &Rope {
+            &self.text
+        }
+
+        /// Relabel the node
+        pub fn set_node(&mut self, index: usize, label: &str) -> Result<(), TreeEditError> {
+            if !is_valid_label(label) {
+                return Err(TreeEditError::InvalidLabel(label.to_string()));
+            }
+            if self.get(index).is_none() {
+                return Err(TreeEditError::NoSuchNode(index));
+            }
+            self.tree.set(index, Some(label));
+            self.render_text();
+            Ok(())
+        }
+
+        /// Add a leaf below the parent, in a hole or past the end of the tree, whose positions
+        /// in between become holes. Returns the index of the new leaf
+        pub fn insert_leaf(
+            &mut self,
+            parent: usize,
+            side: Side,
+            label: &str,
+        ) -> Result<usize, TreeEditError> {
+            if self.get(parent).is_none() {
+                return Err(TreeEditError::NoSuchNode(parent));
+            }
+            if !is_valid_label(label) {
+                return Err(TreeEditError::InvalidLabel(label.to_string()));
+            }
+            let index = match side {
+                Side::Left => 2 * parent + 1,
+                Side::Right => 2 * parent + 2,
+            };
+            if self.get(index).is_some() {
+                return Err(TreeEditError::SlotTaken(index));
+            }
+            self.tree.resize(index + 1);
+            self.tree.set(index, Some(label));
+            self.render_text();
+            Ok(index)
+        }
+
+        /// Remove the node and everything below it, leaving holes. Returns the removed labels
+        /// in heap order
+        pub fn delete_subtree(&mut self, index: usize) -> Result<Vec<String>, TreeEditError> {
+            if self.get(index).is_none() {
+                return Err(TreeEditError::NoSuchNode(index));
+            }
+            // level by level, the subtree holds the indices `first..=last`
+            let (mut first, mut last) = (index, index);
+            let mut removed = Vec::new();
+            while first < self.tree.len() {
+                let end = (last + 1).min(self.tree.len());
+                removed.extend((first..end).filter_map(|i| self.tree.take(i)));
+                (first, last) = (2 * first + 1, 2 * last + 2);
+            }
+            self.render_text();
+            Ok(removed)
+        }
+
+        /// Rotate the subtree under the node to the left: its right child takes its place, and
+        /// it becomes the left child of that child. The in order walk of the tree is unchanged
+        pub fn rotate_left(&mut self, index: usize) -> Result<(), TreeEditError> {
+            let right = 2 * index + 2;
+            if self.get(index).is_none() {
+                return Err(TreeEditError::NoSuchNode(index));
+            }
+            if self.get(right).is_none() {
+                return Err(TreeEditError::NoSuchNode(right));
+            }
+            let mut node = self.take_subtree(index).unwrap();
+            let mut child = node.right.take().unwrap();
+            node.right = child.left.take();
+            child.left = Some(node);
+            self.put_subtree(index, Some(child));
+            self.render_text();
+            Ok(())
+        }
+
+        /// Rotate the subtree under the node to the right: its left child takes its place, and
+        /// it becomes the right child of that child. The in order walk of the tree is unchanged
+        pub fn rotate_right(&mut self, index: usize) -> Result<(), TreeEditError> {
+            let left = 2 * index + 1;
+            if self.get(index).is_none() {
+                return Err(TreeEditError::NoSuchNode(index));
+            }
+            if self.get(left).is_none() {
+                return Err(TreeEditError::NoSuchNode(left));
+            }
+            let mut node = self.take_subtree(index).unwrap();
+            let mut child = node.left.take().unwrap();
+            node.left = child.right.take();
+            child.right = Some(node);
+            self.put_subtree(index, Some(child));
+            self.render_text();
+            Ok(())
+        }
+
+        /// Restructure the nodes into a complete tree, without holes, with the same in order
+        /// walk. A binary search tree stays one, with the least height it can have
+        pub fn balance(&mut self) {
+            let labels: Vec<String> = self
+                .iter_dfs_inorder()
+                .map(|(_, label)| label.to_string())
+                .collect();
+            self.tree.clear();
+            for _ in 0..labels.len() {
+                self.tree.push(Some(""));
+            }
+            let order: Vec<usize> = self.iter_dfs_inorder().map(|(i, _)| i).collect();
+            for (index, label) in order.into_iter().zip(labels) {
+                self.tree.set(index, Some(&label));
+            }
+            self.render_text();
+        }
+
+        /// Remove the subtree under the node, leaving holes
+        fn take_subtree(&mut self, index: usize) -> Option<Box<Subtree>> {
+            if index >= self.tree.len() {
+                return None;
+            }
+            let label = self.tree.take(index)?;
+            Some(Box::new(Subtree {
+                label,
+                left: self.take_subtree(2 * index + 1),
+                right: self.take_subtree(2 * index + 2),
+            }))
+        }
+
+        /// Write the subtree under the index, where there must be holes
+        fn put_subtree(&mut self, index: usize, subtree: Option<Box<Subtree>>) {
+            let Some(subtree) = subtree else {
+                return;
+            };
+            self.tree.resize(index + 1);
+            self.tree.set(index, Some(&subtree.label));
+            self.put_subtree(2 * index + 1, subtree.left);
+            self.put_subtree(2 * index + 2, subtree.right);
+        }
+
+        /// Replace the text with the canonical layout of the tree, after the tree was changed
+        fn render_text(&mut self) {
+            // holes at the end are not written
+            self.tree.trim_holes();
+            self.text = Rope::from(self.render());
+        }
+
+        /// The tree in the canonical layout of a document, see `layout`, with the document's
+        /// line endings. Parsing it gives back the same tree
+        pub fn render(&self) -> String {
+            layout(&self.tree.iter().collect::<Vec<_>>(), self.eol)
+        }
+
+        /// Line ending of the text, the tree is rendered with it
+        pub fn line_ending(&self) -> LineEnding {
+            self.eol
+        }
+
+        /// Whether the document started with a byte order mark, see `new`
+        pub fn has_bom(&self) -> bool {
+            self.bom
         }
 
+        /// The tree as a Graphviz digraph, node `n{i}` is the node at index i and points to its
+        /// children. Holes are left out
+        pub fn to_dot(&self) -> String {
+            let mut dot = String::from("digraph tree {\n");
+            for (i, label) in self.iter_bfs() {
+                let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+                dot.push_str(&format!("    n{} [label=\"{}\"];\n", i, label));
+                if i > 0 {
+                    dot.push_str(&format!("    n{} -> n{};\n", (i - 1) / 2, i));
+                }
+            }
+            dot.push_str("}\n");
+            dot
+        }
+
+        /// Number of lines of the text, a text ending with a line break has an empty last line
+        pub fn line_count(&self) -> usize {
+            self.text.line_count()
+        }
+
+        /// Text of the line without its line ending, None past the last line
+        pub fn line(&self, line: usize) -> Option<String> {
+            self.text.line(line)
+        }
+
+        /// Text in the byte range, None if the range is not on character boundaries of the text
+        pub fn slice(&self, range: Range<usize>) -> Option<String> {
+            (range.start <= range.end
+                && self.text.is_char_boundary(range.start)
+                && self.text.is_char_boundary(range.end))
+            .then(|| self.text.slice(range))
+        }
+
+        /// Length of the text with every `\r\n` counted as a single line break, so a document
+        /// counts the same whatever its line endings
         pub fn get_char_count(&self) -> usize {
-            self.char_count
+            let crlf = (1..self.text.line_count())
+                .filter_map(|line| self.text.line_start(line))
+                .filter(|&start| crlf_before(&self.text, start))
+                .count();
+            self.text.len() - crlf
         }
 
-        pub fn get(&self, index: usize) -> Option<&String> {
+        /// Label of the node, None past the end of the tree or for a hole
+        pub fn get(&self, index: usize) -> Option<&str> {
             self.tree.get(index)
         }
 
-        pub fn left_child(&self, index: usize) -> Option<&String> {
-            self.tree.get(2 * index + 1)
+        /// Number of positions in the tree, holes included
+        pub fn len(&self) -> usize {
+            self.tree.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.tree.is_empty()
         }
 
-        pub fn right_child(&self, index: usize) -> Option<&String> {
-            self.tree.get(2 * index + 2)
+        pub fn left_child(&self, index: usize) -> Option<&str> {
+            self.get(2 * index + 1)
         }
 
-        pub fn parent(&self, index: usize) -> Option<&String> {
+        pub fn right_child(&self, index: usize) -> Option<&str> {
+            self.get(2 * index + 2)
+        }
+
+        pub fn parent(&self, index: usize) -> Option<&str> {
             match index {
                 0 => None,
-                _ => self.tree.get((index - 1) / 2),
+                _ => self.get((index - 1) / 2),
             }
         }
-    }
 
-    impl EditorState {
-        pub fn new() -> Self {
-            EditorState {
-                files: HashMap::new(),
-            }
+        /// Nodes level by level, left to right, which is the order they are stored in
+        pub fn iter_bfs(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+            self.tree
+                .iter()
+                .enumerate()
+                .filter_map(|(i, node)| Some((i, node?)))
         }
 
-        pub fn modify_file(&mut self, file_name: String, file_content: String) -> bool {
-            let new_file_state = FileState::new(file_content);
-            match new_file_state {
-                Some(fs) => {
-                    self.files.insert(file_name, fs);
-                    true
+        /// Nodes with every node before its left and then its right subtree
+        pub fn iter_dfs_preorder(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+            let mut stack = vec![0];
+            std::iter::from_fn(move || loop {
+                let index = stack.pop()?;
+                // nothing is below a hole
+                let Some(label) = self.get(index) else {
+                    continue;
+                };
+                stack.extend([2 * index + 2, 2 * index + 1]);
+                return Some((index, label));
+            })
+        }
+
+        /// Nodes with every node between its left and its right subtree, so the labels of a
+        /// binary search tree come out sorted
+        pub fn iter_dfs_inorder(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+            let mut stack = Vec::new();
+            let mut next = 0;
+            std::iter::from_fn(move || {
+                while self.get(next).is_some() {
+                    stack.push(next);
+                    next = 2 * next + 1;
                 }
-                None => false,
+                let index = stack.pop()?;
+                next = 2 * index + 2;
+                Some((index, self.get(index)?))
+            })
+        }
+
+        /// Nodes without children, left to right
+        pub fn leaves(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+            self.iter_dfs_preorder()
+                .filter(|&(index, _)| self.is_leaf(index))
+        }
+
+        /// Level of the node, the root is at depth 0. None if there is no such node
+        pub fn depth(&self, index: usize) -> Option<usize> {
+            self.get(index).map(|_| index_to_position(index).0)
+        }
+
+        /// Levels of the subtree under the node, a leaf has height 1 and a missing node 0
+        pub fn height(&self, index: usize) -> usize {
+            if self.get(index).is_none() {
+                return 0;
             }
+            1 + self.height(2 * index + 1).max(self.height(2 * index + 2))
         }
 
-        pub fn get_file_state(&self, file_name: String) -> Option<&FileState> {
-            self.files.get(&file_name)
+        /// Number of nodes in the subtree under the node, itself included
+        pub fn subtree_size(&self, index: usize) -> usize {
+            let (mut first, mut width, mut size) = (index, 1, 0);
+            while first < self.tree.len() {
+                let end = (first + width).min(self.tree.len());
+                size += self.tree.count(first..end);
+                first = 2 * first + 1;
+                width *= 2;
+            }
+            size
         }
-    }
-}
 
-pub mod rpc {
-    use serde::de::DeserializeOwned;
-    use serde::Serialize;
-    use std::fmt;
-    use std::fmt::{Display, Formatter};
+        /// Whether there is a node at the index, and it has no children
+        pub fn is_leaf(&self, index: usize) -> bool {
+            self.get(index).is_some()
+                && self.left_child(index).is_none()
+                && self.right_child(index).is_none()
+        }
 
-    pub fn json_to_string<T>(json: &T) -> String
-    where
-        T: Serialize,
-    {
-        serde_json::to_string(json).unwrap()
+        /// Counts of the nodes, their depth and their labels. An empty tree is complete
+        pub fn stats(&self) -> TreeStats {
+            let mut labels = BTreeMap::new();
+            for (_, label) in self.iter_bfs() {
+                *labels.entry(label.to_string()).or_insert(0) += 1;
+            }
+            let node_count = self.iter_bfs().count();
+            let height = self.height(0);
+            TreeStats {
+                node_count,
+                leaf_count: self.leaves().count(),
+                max_depth: height.checked_sub(1),
+                completeness: match height {
+                    0 => 1.0,
+                    _ => node_count as f64 / ((1usize << height) - 1) as f64,
+                },
+                labels,
+            }
+        }
+
+        /// Line and character where the node's label starts, None if there is no such node
+        pub fn position_of(&self, index: usize) -> Option<(usize, usize)> {
+            self.get(index).map(|_| index_to_position(index))
+        }
+
+        /// Indices of the nodes with the same label as the node, in document order
+        pub fn occurrences(&self, index: usize) -> Vec<usize> {
+            match self.get(index) {
+                Some(label) => self.find(label),
+                None => Vec::new(),
+            }
+        }
+
+        /// Indices of the nodes with the label, in document order
+        pub fn find(&self, label: &str) -> Vec<usize> {
+            self.iter_bfs()
+                .filter(|&(_, other)| other == label)
+                .map(|(i, _)| i)
+                .collect()
+        }
+
+        /// Nodes that were added, removed or relabeled from `old` to `new`, by index. A node is
+        /// the same in both versions if it is at the same place in the tree, whatever its label
+        pub fn diff(old: &FileState, new: &FileState) -> Vec<TreeChange> {
+            let len = old.tree.len().max(new.tree.len());
+            (0..len)
+                .filter_map(|index| match (old.get(index), new.get(index)) {
+                    (None, Some(label)) => Some(TreeChange::Added {
+                        index,
+                        label: label.to_string(),
+                    }),
+                    (Some(label), None) => Some(TreeChange::Removed {
+                        index,
+                        label: label.to_string(),
+                    }),
+                    (Some(old), Some(new)) if old != new => Some(TreeChange::Relabeled {
+                        index,
+                        old: old.to_string(),
+                        new: new.to_string(),
+                    }),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Labels used by more than one node, with the indices of their nodes
+        pub fn duplicate_labels(&self) -> BTreeMap<&str, Vec<usize>> {
+            let mut labels: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+            for (i, label) in self.iter_bfs() {
+                labels.entry(label).or_default().push(i);
+            }
+            labels.retain(|_, nodes| nodes.len() > 1);
+            labels
+        }
     }
 
-    pub fn json_from_string<'a, T>(s: &String) -> Result<T, serde_json::Error>
-    where
-        T: DeserializeOwned,
-    {
-        serde_json::from_str(s)
+    impl Default for EditorState {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
-    /// Given the content of the message (json), encode it using LSP protocol such that it is ready to send
-    pub fn encode_message(message: String) -> String {
-        let n = message.len();
-        let mut encoded_message = format!("Content-Length: {}\r\n\r\n", n);
-        encoded_message.push_str(&message);
-        return encoded_message;
+    /// An `EditorState` that can be handed to several threads. Any number of handlers can
+    /// read documents at once, notifications that change them take the lock for themselves.
+    /// Texts are ropes, so a snapshot of a document is cheap and can outlive the lock
+    #[derive(Clone, Default)]
+    pub struct SharedEditorState {
+        inner: Arc<RwLock<EditorState>>,
     }
 
-    /// Extract the content specified in the [LSP/LSIF Docs](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#contentPart).
-    /// Pop the whole LSP message from the buffer and return the content part as String.
-    /// If Buffer has not finished filling, header length + 4 + content length > buffer size, return None
-    /// If message doesn't start with `Content-Length: <content length>`, return Err
-    /// Returns the parsed message, with the total message length (including 'Content-Length: ..')
-    pub fn decode_message(message: &String) -> Result<Option<(String, usize)>, MsgParseError> {
-        let Some((header, content)) = message.split_once("\r\n\r\n") else {
-            return Err(MsgParseError(
-                "Invalid format, contains no \\r\\n\\r\\n".to_string(),
-            ));
-        };
-        if !header.starts_with("Content-Length: ") {
-            return Err(MsgParseError(String::from(
-                "Expected header starting with Content-Length",
-            )));
+    impl SharedEditorState {
+        pub fn new(editor_state: EditorState) -> Self {
+            SharedEditorState {
+                inner: Arc::new(RwLock::new(editor_state)),
+            }
         }
-        let content_length_str = header.trim_start_matches("Content-Length: ");
-        let Ok(content_length): Result<usize, _> = content_length_str.parse() else {
-            return Err(MsgParseError(String::from(
-                "Could not parse content length to number",
-            )));
-        };
 
-        if content_length > content.len() {
-            Ok(None)
-        } else {
-            let total_length = header.len() + 4 + content.len();
-            let content = String::from(&content[..content_length]);
-            Ok(Some((content, total_length)))
+        /// A handler that panicked while holding the lock leaves the state as it was at the
+        /// panic, that is still better than refusing every later request
+        pub fn read(&self) -> RwLockReadGuard<'_, EditorState> {
+            self.inner.read().unwrap_or_else(|e| e.into_inner())
         }
-    }
 
-    pub struct BufferedReader {
-        data: String,
+        pub fn write(&self) -> RwLockWriteGuard<'_, EditorState> {
+            self.inner.write().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// Copy of the document's last valid tree, taken under the read lock
+        pub fn snapshot(&self, file_name: &str) -> Option<FileState> {
+            self.read().get_file_state(file_name.to_string()).cloned()
+        }
+
+        /// Copy of the document's latest text, taken under the read lock
+        pub fn text_snapshot(&self, file_name: &str) -> Option<Rope> {
+            self.read().text(file_name).cloned()
+        }
     }
 
-    /// BufferedReader buffers all the recieved content
-    impl BufferedReader {
-        pub fn new() -> BufferedReader {
-            BufferedReader {
-                data: String::new(),
+    impl EditorState {
+        pub fn new() -> Self {
+            EditorState {
+                files: HashMap::new(),
+                texts: HashMap::new(),
+                line_indexes: HashMap::new(),
+                versions: HashMap::new(),
+                language_ids: HashMap::new(),
+                dirty: HashSet::new(),
+                open_files: HashSet::new(),
+                case_sensitivity: CaseSensitivity::Platform,
             }
         }
 
-        /// Write buffer of bytes to BufferReader::data
-        pub fn write(&mut self, buffer: &[u8]) {
-            self.data.push_str(&String::from_utf8_lossy(buffer));
+        /// Change how uris are compared. Files that are already known are kept under the key of
+        /// the uri the client gave, normalized anew
+        pub fn set_case_sensitivity(&mut self, case_sensitivity: CaseSensitivity) {
+            self.case_sensitivity = case_sensitivity;
+            let files = std::mem::take(&mut self.files);
+            for (uri, fs) in files {
+                self.files.insert(self.uri(uri.original()), fs);
+            }
+            let texts = std::mem::take(&mut self.texts);
+            for (uri, text) in texts {
+                self.texts.insert(self.uri(uri.original()), text);
+            }
+            let line_indexes = std::mem::take(&mut self.line_indexes);
+            for (uri, line_index) in line_indexes {
+                self.line_indexes
+                    .insert(self.uri(uri.original()), line_index);
+            }
+            let versions = std::mem::take(&mut self.versions);
+            for (uri, version) in versions {
+                self.versions.insert(self.uri(uri.original()), version);
+            }
+            let language_ids = std::mem::take(&mut self.language_ids);
+            for (uri, language_id) in language_ids {
+                self.language_ids
+                    .insert(self.uri(uri.original()), language_id);
+            }
+            let open_files = std::mem::take(&mut self.open_files);
+            for uri in open_files {
+                self.open_files.insert(self.uri(uri.original()));
+            }
+            let dirty = std::mem::take(&mut self.dirty);
+            for uri in dirty {
+                self.dirty.insert(self.uri(uri.original()));
+            }
+        }
+
+        /// The uri normalized like the keys of the documents, see `set_case_sensitivity`
+        pub fn uri(&self, file_name: &str) -> Uri {
+            Uri::new(file_name, self.case_sensitivity)
+        }
+
+        /// Replace the text of the file and parse it. If it is not a valid tree the text is
+        /// kept, and so is the last valid tree. A byte order mark is left out of the text, like
+        /// in `FileState::new`
+        pub fn modify_file(
+            &mut self,
+            file_name: String,
+            file_content: String,
+        ) -> Result<(), FileStateError> {
+            let (file_content, bom) = strip_bom(file_content);
+            let text = Rope::from(file_content);
+            self.line_indexes
+                .insert(self.uri(&file_name), LineIndex::new(&text));
+            self.texts.insert(self.uri(&file_name), text.clone());
+            let mut fs = FileState::from_rope(text)?;
+            fs.bom = bom;
+            self.files.insert(self.uri(&file_name), fs);
+            Ok(())
+        }
+
+        /// Replace the text of the file without parsing it, the tree stays as it was
+        pub fn set_text(&mut self, file_name: &str, file_content: String) {
+            let (file_content, _) = strip_bom(file_content);
+            let text = Rope::from(file_content);
+            self.line_indexes
+                .insert(self.uri(file_name), LineIndex::new(&text));
+            self.texts.insert(self.uri(file_name), text);
+        }
+
+        /// Latest text of the file, whether or not it is a valid tree
+        pub fn text(&self, file_name: &str) -> Option<&Rope> {
+            self.texts.get(&self.uri(file_name))
+        }
+
+        /// Lines of the file's latest text, kept up to date with it
+        pub fn line_index(&self, file_name: &str) -> Option<&LineIndex> {
+            self.line_indexes.get(&self.uri(file_name))
+        }
+
+        /// Replace the byte range of the file's text without parsing it, like `set_text`.
+        /// Returns false if the file has no text or the range is not on its character boundaries
+        pub fn edit_text(&mut self, file_name: &str, range: Range<usize>, new_text: &str) -> bool {
+            let uri = self.uri(file_name);
+            let Some(text) = self.texts.get_mut(&uri) else {
+                return false;
+            };
+            if range.start > range.end
+                || !text.is_char_boundary(range.start)
+                || !text.is_char_boundary(range.end)
+            {
+                return false;
+            }
+            text.replace(range.clone(), new_text);
+            match self.line_indexes.get_mut(&uri) {
+                Some(line_index) => line_index.update(text, range, new_text.len()),
+                None => {
+                    self.line_indexes.insert(uri, LineIndex::new(text));
+                }
+            }
+            true
         }
 
-        /// Get data from current buffer
-        pub fn get_data(&self) -> &String {
-            &self.data
+        /// Replace the byte range of the file's text and update its tree. None if the edit
+        /// can't be applied, see `edit_text`, otherwise why the text is not a valid tree if it
+        /// isn't
+        pub fn edit_file(
+            &mut self,
+            file_name: &str,
+            range: Range<usize>,
+            new_text: &str,
+        ) -> Option<Result<(), FileStateError>> {
+            let uri = self.uri(file_name);
+            let before = self.texts.get(&uri)?.clone();
+            if !self.edit_text(file_name, range.clone(), new_text) {
+                return None;
+            }
+            let text = self.texts[&uri].clone();
+            let inserted = range.start..range.start + new_text.len();
+            Some(match self.files.get_mut(&uri) {
+                // the tree is only patched if it was parsed from the text that was edited
+                Some(fs) if fs.text.ptr_eq(&before) => fs.update(text, inserted),
+                _ => FileState::from_rope(text).map(|fs| {
+                    self.files.insert(uri, fs);
+                }),
+            })
+        }
+
+        /// Replace the tree of the file with one parsed elsewhere, eg. in the background
+        pub fn set_file_state(&mut self, file_name: &str, fs: FileState) {
+            self.files.insert(self.uri(file_name), fs);
+        }
+
+        pub fn get_file_state(&self, file_name: String) -> Option<&FileState> {
+            self.files.get(&self.uri(&file_name))
         }
 
-        /// Parse the lsp message, and if buffer contains valid lsp message, pop it from the data
-        pub fn pop_message(&mut self) -> Result<Option<String>, MsgParseError> {
-            match decode_message(&self.data) {
-                Ok(Some((content, total_len))) => {
-                    self.data = self.data.chars().skip(total_len).collect();
-                    Ok(Some(content))
+        /// Mark the file as opened or closed in the editor, a closed file has no version and no
+        /// language, and is not dirty. The text of a closed file that is not a tree is forgotten
+        pub fn set_open(&mut self, file_name: &str, open: bool) {
+            let uri = self.uri(file_name);
+            if open {
+                self.dirty.remove(&uri);
+                self.open_files.insert(uri);
+            } else {
+                if !self.is_tree_language(file_name) {
+                    self.texts.remove(&uri);
+                    self.line_indexes.remove(&uri);
                 }
-                Ok(None) => Ok(None),
-                Err(e) => Err(e),
+                self.versions.remove(&uri);
+                self.language_ids.remove(&uri);
+                self.dirty.remove(&uri);
+                self.open_files.remove(&uri);
             }
         }
+
+        /// Whether the file was changed in the editor since it was opened or last saved, so its
+        /// text differs from the file on disk
+        pub fn is_dirty(&self, file_name: &str) -> bool {
+            self.dirty.contains(&self.uri(file_name))
+        }
+
+        /// Mark the file as changed, or as saved. Only open files can be dirty
+        pub fn set_dirty(&mut self, file_name: &str, dirty: bool) {
+            let uri = self.uri(file_name);
+            match dirty && self.open_files.contains(&uri) {
+                true => self.dirty.insert(uri),
+                false => self.dirty.remove(&uri),
+            };
+        }
+
+        /// Language the client gave the file when opening it, None if it is not open
+        pub fn language_id(&self, file_name: &str) -> Option<&str> {
+            self.language_ids
+                .get(&self.uri(file_name))
+                .map(String::as_str)
+        }
+
+        pub fn set_language_id(&mut self, file_name: &str, language_id: &str) {
+            self.language_ids
+                .insert(self.uri(file_name), language_id.to_string());
+        }
+
+        /// Whether the file is parsed as a tree. Open files of other languages only have their
+        /// text kept, files read from disk are trees
+        pub fn is_tree_language(&self, file_name: &str) -> bool {
+            self.language_id(file_name)
+                .is_none_or(|language_id| language_id == TREE_LANGUAGE_ID)
+        }
+
+        /// Version the client gave the file's latest text, None if it is not open
+        pub fn version(&self, file_name: &str) -> Option<i64> {
+            self.versions.get(&self.uri(file_name)).copied()
+        }
+
+        /// Set the version of the file, eg. when it is opened
+        pub fn set_version(&mut self, file_name: &str, version: i64) {
+            self.versions.insert(self.uri(file_name), version);
+        }
+
+        /// Move the file to the version if it is newer than its current one. Returns false for
+        /// a change that was reordered or duplicated, which must not be applied
+        pub fn advance_version(&mut self, file_name: &str, version: i64) -> bool {
+            let uri = self.uri(file_name);
+            match self.versions.get(&uri) {
+                Some(&current) if version <= current => false,
+                _ => {
+                    self.versions.insert(uri, version);
+                    true
+                }
+            }
+        }
+
+        pub fn is_open(&self, file_name: &str) -> bool {
+            self.open_files.contains(&self.uri(file_name))
+        }
+
+        pub fn remove_file(&mut self, file_name: &str) -> Option<FileState> {
+            let uri = self.uri(file_name);
+            self.open_files.remove(&uri);
+            self.texts.remove(&uri);
+            self.line_indexes.remove(&uri);
+            self.versions.remove(&uri);
+            self.language_ids.remove(&uri);
+            self.dirty.remove(&uri);
+            self.files.remove(&uri)
+        }
+
+        /// Uris of the known files that are `file_name` or inside the folder `file_name`
+        fn files_under(&self, file_name: &str) -> Vec<Uri> {
+            let uri = self.uri(file_name);
+            let folder = format!("{}/", uri.as_str().trim_end_matches('/'));
+            let mut uris: Vec<Uri> = self
+                .files
+                .keys()
+                .chain(self.texts.keys())
+                .filter(|key| **key == uri || key.as_str().starts_with(&folder))
+                .cloned()
+                .collect();
+            uris.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            uris.dedup();
+            uris
+        }
+
+        /// Move the file, or every file inside the folder, to its new uri, keeping whether it
+        /// is open. Returns the old and new uri of every moved file
+        pub fn rename_files(&mut self, old_name: &str, new_name: &str) -> Vec<(Uri, Uri)> {
+            let old_prefix = self.uri(old_name).as_str().trim_end_matches('/').len();
+            let new_name = new_name.trim_end_matches('/');
+            let mut renamed = Vec::new();
+            for old_uri in self.files_under(old_name) {
+                let new_uri = self.uri(&format!("{}{}", new_name, &old_uri.as_str()[old_prefix..]));
+                if let Some(fs) = self.files.remove(&old_uri) {
+                    self.files.insert(new_uri.clone(), fs);
+                }
+                if let Some(text) = self.texts.remove(&old_uri) {
+                    self.texts.insert(new_uri.clone(), text);
+                }
+                if let Some(line_index) = self.line_indexes.remove(&old_uri) {
+                    self.line_indexes.insert(new_uri.clone(), line_index);
+                }
+                if let Some(version) = self.versions.remove(&old_uri) {
+                    self.versions.insert(new_uri.clone(), version);
+                }
+                if let Some(language_id) = self.language_ids.remove(&old_uri) {
+                    self.language_ids.insert(new_uri.clone(), language_id);
+                }
+                if self.open_files.remove(&old_uri) {
+                    self.open_files.insert(new_uri.clone());
+                }
+                if self.dirty.remove(&old_uri) {
+                    self.dirty.insert(new_uri.clone());
+                }
+                renamed.push((old_uri, new_uri));
+            }
+            renamed
+        }
+
+        /// Forget the file, or every file inside the folder. Returns the uris of the removed files
+        pub fn remove_files(&mut self, file_name: &str) -> Vec<Uri> {
+            let removed = self.files_under(file_name);
+            for uri in &removed {
+                self.open_files.remove(uri);
+                self.texts.remove(uri);
+                self.line_indexes.remove(uri);
+                self.versions.remove(uri);
+                self.language_ids.remove(uri);
+                self.dirty.remove(uri);
+                self.files.remove(uri);
+            }
+            removed
+        }
+
+        /// Every file that is a valid tree with its uri, in no particular order. Opened files
+        /// and those read from the workspace alike
+        pub fn documents(&self) -> impl Iterator<Item = (&Uri, &FileState)> {
+            self.files.iter()
+        }
+
+        /// Number of files that are a valid tree, see `documents`
+        pub fn len(&self) -> usize {
+            self.files.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.files.is_empty()
+        }
+
+        /// Nodes with the label in every known file, see `FileState::find`. Files are sorted by
+        /// uri, and those without the label are left out
+        pub fn find_in_all(&self, label: &str) -> Vec<(&Uri, Vec<usize>)> {
+            let mut found: Vec<_> = self
+                .files
+                .iter()
+                .map(|(uri, fs)| (uri, fs.find(label)))
+                .filter(|(_, nodes)| !nodes.is_empty())
+                .collect();
+            found.sort_by_key(|(uri, _)| *uri);
+            found
+        }
+
+        /// Inverted index of the documents open in the editor, the nodes of every label by uri
+        pub fn open_label_index(&self) -> BTreeMap<&str, BTreeMap<&Uri, Vec<usize>>> {
+            let mut index: BTreeMap<&str, BTreeMap<&Uri, Vec<usize>>> = BTreeMap::new();
+            for uri in &self.open_files {
+                let Some(fs) = self.files.get(uri) else {
+                    continue;
+                };
+                for (i, label) in fs.iter_bfs() {
+                    index
+                        .entry(label)
+                        .or_default()
+                        .entry(uri)
+                        .or_default()
+                        .push(i);
+                }
+            }
+            index
+        }
+
+        /// Write the open documents to the file: their text, version, language and whether they
+        /// are dirty, with the last valid tree of those whose text is not one
+        pub fn save_session(&self, path: &Path) -> io::Result<()> {
+            let mut documents: Vec<SessionDocument> = self
+                .open_files
+                .iter()
+                .map(|uri| {
+                    let text = self.texts.get(uri);
+                    let fs = self.files.get(uri);
+                    SessionDocument {
+                        uri: uri.original().to_string(),
+                        text: text
+                            .or(fs.map(|fs| &fs.text))
+                            .map(Rope::to_string)
+                            .unwrap_or_default(),
+                        tree: fs
+                            .filter(|fs| text.is_some_and(|text| !fs.text.ptr_eq(text)))
+                            .map(|fs| fs.text.to_string()),
+                        bom: fs.is_some_and(|fs| fs.bom),
+                        version: self.versions.get(uri).copied(),
+                        language_id: self.language_ids.get(uri).cloned(),
+                        dirty: self.dirty.contains(uri),
+                    }
+                })
+                .collect();
+            documents.sort_by(|a, b| a.uri.cmp(&b.uri));
+            let session = Session {
+                format: SESSION_FORMAT,
+                documents,
+            };
+            let json = serde_json::to_string(&session)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // written next to the file first, so a crash midway leaves the old session intact.
+            // The suffix is added to the whole name, `session.tmp` may be a file of its own
+            let mut temp = path.as_os_str().to_owned();
+            temp.push(".tmp");
+            fs::write(&temp, json)?;
+            fs::rename(&temp, path)
+        }
+
+        /// Open the documents of a session written by `save_session`, as if the client had
+        /// opened them. Documents that are already open are left as they are. Returns the
+        /// number of documents restored
+        pub fn restore_session(&mut self, path: &Path) -> io::Result<usize> {
+            let json = fs::read_to_string(path)?;
+            let session: Session = serde_json::from_str(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if session.format != SESSION_FORMAT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown session format {}", session.format),
+                ));
+            }
+            let mut restored = 0;
+            for document in session.documents {
+                let uri = document.uri.as_str();
+                if self.is_open(uri) {
+                    continue;
+                }
+                if let Some(language_id) = &document.language_id {
+                    self.set_language_id(uri, language_id);
+                }
+                match document.tree {
+                    Some(tree) => {
+                        if let Ok(fs) = FileState::from_rope(Rope::from(tree)) {
+                            self.set_file_state(uri, fs);
+                        }
+                        self.set_text(uri, document.text);
+                    }
+                    None if self.is_tree_language(uri) => {
+                        // a text that is not a tree keeps the one of the file on disk, if any
+                        let _ = self.modify_file(uri.to_string(), document.text);
+                    }
+                    None => self.set_text(uri, document.text),
+                }
+                if let Some(fs) = self.files.get_mut(&self.uri(uri)) {
+                    fs.bom = document.bom;
+                }
+                self.set_open(uri, true);
+                if let Some(version) = document.version {
+                    self.set_version(uri, version);
+                }
+                self.set_dirty(uri, document.dirty);
+                restored += 1;
+            }
+            Ok(restored)
+        }
+    }
+}
+
+pub mod rpc {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fmt;
+    use std::fmt::{Display, Formatter};
+    use std::io::{self, Read};
+
+    pub fn json_to_string<T>(json: &T) -> String
+    where
+        T: Serialize,
+    {
+        serde_json::to_string(json).unwrap()
+    }
+
+    pub fn json_from_string<T>(s: &str) -> Result<T, serde_json::Error>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_str(s)
+    }
+
+    /// Given the content of the message (json), encode it using LSP protocol such that it is ready to send
+    pub fn encode_message(message: String) -> String {
+        let n = message.len();
+        let mut encoded_message = format!("Content-Length: {}\r\n\r\n", n);
+        encoded_message.push_str(&message);
+        encoded_message
+    }
+
+    /// Extract the content specified in the [LSP/LSIF Docs](https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#contentPart).
+    /// Pop the whole LSP message from the buffer and return the content part as String.
+    /// If Buffer has not finished filling, header length + 4 + content length > buffer size, return None
+    /// If the header has no `Content-Length` or is not valid, see `parse_header`, return Err
+    /// Returns the parsed message, with the total message length in bytes (including 'Content-Length: ..')
+    pub fn decode_message(message: &[u8]) -> Result<Option<(String, usize)>, MsgParseError> {
+        let Some((header_length, content_length)) = decode_header(message)? else {
+            return Ok(None);
+        };
+        let content = &message[header_length..];
+        if content_length > content.len() {
+            Ok(None)
+        } else {
+            let total_length = header_length + content_length;
+            let content = String::from_utf8_lossy(&content[..content_length]).into_owned();
+            Ok(Some((content, total_length)))
+        }
+    }
+
+    /// Length of the header including the `\r\n\r\n` ending it, and the content length it
+    /// gives. None if the header hasn't been fully received yet
+    fn decode_header(message: &[u8]) -> Result<Option<(usize, usize)>, MsgParseError> {
+        let Some(header_length) = header_end(message, 0) else {
+            return Ok(None);
+        };
+        let content_length = header_content_length(&message[..header_length])?;
+        Ok(Some((header_length, content_length)))
+    }
+
+    /// Length of the header including the `\r\n\r\n` ending it, looking for its end from `from`
+    fn header_end(message: &[u8], from: usize) -> Option<usize> {
+        message[from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| from + i + 4)
+    }
+
+    /// Content length given by a whole header, with the `\r\n\r\n` ending it
+    fn header_content_length(header: &[u8]) -> Result<usize, MsgParseError> {
+        let Ok(header) = std::str::from_utf8(&header[..header.len() - 4]) else {
+            return Err(MsgParseError(String::from("Header is not valid utf-8")));
+        };
+        parse_header(header)
+    }
+
+    /// Content length given by the header part of a message, its fields separated by `\r\n`.
+    /// Field names are case-insensitive and may come in any order, unknown fields are ignored.
+    /// A `Content-Type` must be json-rpc in utf-8, `utf8` is accepted like the spec asks
+    fn parse_header(header: &str) -> Result<usize, MsgParseError> {
+        let mut content_length = None;
+        for field in header.split("\r\n") {
+            let Some((name, value)) = field.split_once(':') else {
+                return Err(MsgParseError(format!(
+                    "Expected a header field, instead got {:?}",
+                    field
+                )));
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Length") {
+                if content_length.is_some() {
+                    return Err(MsgParseError(String::from(
+                        "Content-Length is given more than once",
+                    )));
+                }
+                let Ok(length) = value.parse() else {
+                    return Err(MsgParseError(String::from(
+                        "Could not parse content length to number",
+                    )));
+                };
+                content_length = Some(length);
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                check_content_type(value)?;
+            }
+        }
+        content_length
+            .ok_or_else(|| MsgParseError(String::from("Expected a Content-Length header")))
+    }
+
+    /// Refuse a content type whose charset is not utf-8, the charset defaults to utf-8
+    fn check_content_type(content_type: &str) -> Result<(), MsgParseError> {
+        for parameter in content_type.split(';').skip(1) {
+            let Some((name, value)) = parameter.split_once('=') else {
+                continue;
+            };
+            let charset = value.trim().trim_matches('"');
+            if name.trim().eq_ignore_ascii_case("charset")
+                && !charset.eq_ignore_ascii_case("utf-8")
+                && !charset.eq_ignore_ascii_case("utf8")
+            {
+                return Err(MsgParseError(format!(
+                    "Unsupported charset {}, expected utf-8",
+                    charset
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// How much a client may send before it is refused, so a buggy or malicious client can't
+    /// grow the buffer without bound
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ReaderLimits {
+        pub max_content_length: usize, // of a single message
+        pub max_buffered: usize,       // bytes received while no header is complete
+    }
+
+    impl Default for ReaderLimits {
+        fn default() -> Self {
+            ReaderLimits {
+                max_content_length: 64 << 20,
+                max_buffered: 1 << 20,
+            }
+        }
+    }
+
+    /// Why no message could be popped. The reader already skipped the offending bytes, the
+    /// messages after them can still be popped
+    #[derive(Debug, Clone)]
+    pub enum FrameError {
+        Malformed(MsgParseError),
+        ContentTooLarge { length: usize, limit: usize },
+        HeaderTooLarge { limit: usize },
+    }
+
+    impl Display for FrameError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                FrameError::Malformed(e) => e.fmt(f),
+                FrameError::ContentTooLarge { length, limit } => write!(
+                    f,
+                    "Content-Length {} is over the limit of {} bytes",
+                    length, limit
+                ),
+                FrameError::HeaderTooLarge { limit } => {
+                    write!(f, "No header ended within {} bytes", limit)
+                }
+            }
+        }
+    }
+
+    /// Where the reader is in the message at the start of the data, so that every received
+    /// byte is looked at once even if a message comes in many chunks
+    #[derive(Debug, Clone, Copy)]
+    enum ReadState {
+        Header {
+            scanned: usize, // bytes already searched for the end of the header
+        },
+        Content {
+            header_length: usize,
+            content_length: usize,
+        },
+    }
+
+    /// Bytes received and not yet popped as messages. Popping a message moves a cursor past
+    /// it, the bytes before the cursor are dropped once they are more than half of the buffer,
+    /// so every received byte is moved at most once on average
+    pub struct BufferedReader {
+        data: Vec<u8>,
+        start: usize, // bytes of `data` that were already popped
+        state: ReadState,
+        limits: ReaderLimits,
+        skip: usize, // bytes of a refused message still to be received, dropped as they come
+    }
+
+    impl Default for BufferedReader {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// BufferedReader buffers all the recieved content
+    impl BufferedReader {
+        pub fn new() -> BufferedReader {
+            Self::with_limits(ReaderLimits::default())
+        }
+
+        pub fn with_limits(limits: ReaderLimits) -> BufferedReader {
+            BufferedReader {
+                data: Vec::new(),
+                start: 0,
+                state: ReadState::Header { scanned: 0 },
+                limits,
+                skip: 0,
+            }
+        }
+
+        /// Write buffer of bytes to BufferReader::data
+        pub fn write(&mut self, buffer: &[u8]) {
+            let skipped = self.skip.min(buffer.len());
+            self.skip -= skipped;
+            if self.start > self.data.len() / 2 {
+                self.data.drain(..self.start);
+                self.start = 0;
+            }
+            self.data.extend_from_slice(&buffer[skipped..]);
+        }
+
+        /// Read a chunk of the reader into the buffer, returns how many bytes were read, 0 once
+        /// the reader is at its end
+        pub fn fill_from(&mut self, reader: &mut impl Read) -> io::Result<usize> {
+            let mut chunk = [0; 4096];
+            let n = reader.read(&mut chunk)?;
+            self.write(&chunk[..n]);
+            Ok(n)
+        }
+
+        /// Bytes received that were not popped yet
+        pub fn get_data(&self) -> &[u8] {
+            &self.data[self.start..]
+        }
+
+        /// Parse the lsp message, and if buffer contains valid lsp message, pop it from the data.
+        /// A message over the limits is dropped, its content as it is received. After a header
+        /// that can't be parsed the reader skips to where the next message seems to start
+        pub fn pop_message(&mut self) -> Result<Option<String>, FrameError> {
+            let (header_length, content_length) = match self.state {
+                ReadState::Content {
+                    header_length,
+                    content_length,
+                } => (header_length, content_length),
+                ReadState::Header { scanned } => match self.read_header(scanned)? {
+                    Some(lengths) => lengths,
+                    None => return Ok(None),
+                },
+            };
+            let data = self.get_data();
+            if header_length + content_length > data.len() {
+                self.state = ReadState::Content {
+                    header_length,
+                    content_length,
+                };
+                return Ok(None);
+            }
+            let content = &data[header_length..header_length + content_length];
+            let content = String::from_utf8_lossy(content).into_owned();
+            self.start += header_length + content_length;
+            self.state = ReadState::Header { scanned: 0 };
+            Ok(Some(content))
+        }
+
+        /// Look for the end of the header in the bytes that weren't scanned yet, and parse it
+        /// once it is there. Returns the header length and the content length it gives
+        fn read_header(&mut self, scanned: usize) -> Result<Option<(usize, usize)>, FrameError> {
+            self.state = ReadState::Header { scanned: 0 };
+            let data = self.get_data();
+            // the end of the header may straddle the scanned bytes and the new ones
+            let Some(header_length) = header_end(data, scanned.saturating_sub(3)) else {
+                if data.len() > self.limits.max_buffered {
+                    self.start = self.data.len();
+                    return Err(FrameError::HeaderTooLarge {
+                        limit: self.limits.max_buffered,
+                    });
+                }
+                self.state = ReadState::Header {
+                    scanned: data.len(),
+                };
+                return Ok(None);
+            };
+            let content_length = match header_content_length(&data[..header_length]) {
+                Ok(content_length) => content_length,
+                Err(e) => {
+                    self.resynchronize(header_length);
+                    return Err(FrameError::Malformed(e));
+                }
+            };
+            if content_length > self.limits.max_content_length {
+                let received = (data.len() - header_length).min(content_length);
+                self.start += header_length + received;
+                self.skip = content_length - received;
+                return Err(FrameError::ContentTooLarge {
+                    length: content_length,
+                    limit: self.limits.max_content_length,
+                });
+            }
+            Ok(Some((header_length, content_length)))
+        }
+
+        /// Drop the bytes up to the next `Content-Length:` after the malformed header of
+        /// `header_length` bytes, where the next message most likely starts. If there is none
+        /// everything is dropped, but an end of the data that could be the start of the field
+        fn resynchronize(&mut self, header_length: usize) {
+            const FIELD: &[u8] = b"content-length:";
+            let data = self.get_data();
+            let next = match data
+                .windows(FIELD.len())
+                .skip(header_length)
+                .position(|w| w.eq_ignore_ascii_case(FIELD))
+            {
+                Some(i) => i + header_length,
+                None => (1..FIELD.len())
+                    .rev()
+                    .find(|&n| {
+                        n < data.len() && data[data.len() - n..].eq_ignore_ascii_case(&FIELD[..n])
+                    })
+                    .map_or(data.len(), |n| data.len() - n),
+            };
+            self.start += next;
+        }
+    }
+
+    /// So the reader can be written to like any sink of bytes, eg. by `io::copy`
+    impl io::Write for BufferedReader {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            BufferedReader::write(self, buffer);
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Given the content of the message, return the corresponding object
+    pub fn message_to_object<T>(message: &str) -> Result<T, MsgParseError>
+    where
+        T: DeserializeOwned,
+    {
+        match json_from_string(message) {
+            Ok(msg) => Ok(msg),
+            Err(e) => Err(MsgParseError(e.to_string())),
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct MsgParseError(pub String);
+    impl Display for MsgParseError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+}
+
+pub mod lsp {
+    use serde::de::{DeserializeOwned, IgnoredAny};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::value::RawValue;
+    use serde_json::{json, Value};
+    use std::cell::{Cell, RefCell};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fmt::{self, Display, Formatter};
+    use std::fs;
+    use std::future::Future;
+    use std::io::{self, Write};
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use std::sync::OnceLock;
+    use std::task::{Context, Poll, Waker};
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        background::BackgroundParses,
+        cancel::{CancellationRegistry, CancellationToken, Cancelled},
+        completion::{Candidate, CandidateKind, CompletionContext, CompletionEngine},
+        config::{self, DiagnosticRule, HoverVerbosity, LogLevel, Settings},
+        debounce::Debouncer,
+        editor::{
+            index_to_position, is_valid_label, layout, EditorState, FileState, FileStateError,
+            SharedEditorState, TreeStats,
+        },
+        encoding::{column_to_byte, decode, position_at, PositionEncoding, TextEncoding},
+        large_file,
+        lazy::Lazy,
+        line_index::LineIndex,
+        logs::{LogFormat, LogRecord},
+        lru::LruSet,
+        output::Output,
+        preview,
+        rpc::{encode_message, json_from_string, json_to_string, FrameError, MsgParseError},
+        shedding::{ClientHealth, HealthChange, OptionalTraffic},
+        telemetry::{Telemetry, TelemetryEvent},
+        trace::{self, TraceValue},
+        uri::{path_to_uri, uri_to_path},
+        workspace::{ScanConfig, WorkspaceScanner},
+    };
+
+    /// Documents at least this large report `$/progress` while being parsed
+    pub const LARGE_FILE_THRESHOLD: usize = 1 << 20;
+
+    /// How often the main loop checks whether a background parse is done
+    pub const BACKGROUND_PARSE_POLL: Duration = Duration::from_millis(20);
+
+    /// State of the server that is not tied to a single document
+    pub struct ServerContext {
+        outgoing: OutgoingRequests, // requests sent to the client awaiting a response
+        cancellations: CancellationRegistry, // token of every request from the client being handled
+        cancellation: CancellationToken, // token of the request being handled
+        work_done_progress: bool,   // whether the client accepts server initiated progress
+        show_document: bool,        // whether the client supports window/showDocument
+        configuration: bool,        // whether the client supports workspace/configuration
+        dynamic_registration: HashSet<String>, // methods the client lets the server register at runtime
+        registrations: HashMap<String, String>, // method of every active registration, by id
+        apply_edit: bool,                      // whether the client supports workspace/applyEdit
+        resource_operations: Vec<String>, // file operations the client accepts in a workspace edit
+        workspace_folders: Vec<String>,   // uris of the folders open in the client
+        settings: Settings,               // user configuration consulted by the handlers
+        revalidations: Debouncer<String, String>, // latest text of failing documents by uri
+        invalid_documents: HashMap<String, FileStateError>, // why the latest text isn't a tree, by uri
+        commands: CommandRegistry, // commands run by workspace/executeCommand
+        handlers: BTreeMap<String, Handler>, // methods added by the embedder, by name
+        initialize_received: bool, // whether the client sent `initialize`, before which requests are refused
+        initialized: bool, // whether the client sent `initialized`, after which handlers are fixed
+        startup_warnings: Vec<String>, // shown to the user once the client sent `initialized`
+        trace_value: TraceValue, // how much of the message handling is reported with $/logTrace
+        client_capabilities: ClientCapabilities, // as sent with initialize
+        telemetry: Telemetry, // counts sent with the next telemetry/event, if enabled
+        health: ClientHealth, // whether the client keeps up with the server's output
+        completion_engine: Lazy<CompletionEngine>, // built from the settings on the first completion
+        workspace_index: Lazy<usize>, // tree files read from the workspace folders, on first need
+        loaded_from_disk: LruSet<String>, // unopened documents read when a request needed them
+        background_parses: BackgroundParses<Option<WorkDoneProgress>>, // large documents being parsed, with their progress
+        position_encoding: PositionEncoding,                           // negotiated at initialize
+        output: Output, // where the messages for the client are written
+    }
+
+    /// Called with the client's response to a request sent by the server
+    pub type ResponseCallback =
+        Box<dyn FnOnce(Result<Value, ResponseError>, &mut ServerContext, &mut dyn Write)>;
+
+    struct PendingRequest {
+        method: String,
+        callback: ResponseCallback,
+        deadline: Option<Instant>, // when the request is given up on
+    }
+
+    /// Requests sent from the server to the client, numbered in the order they are sent, with
+    /// what to do with their response. A request left unanswered for longer than the timeout
+    /// is given up on, so a client that never answers doesn't keep it forever
+    #[derive(Default)]
+    pub struct OutgoingRequests {
+        last_id: i64,
+        pending: HashMap<i64, PendingRequest>,
+        timeout: Option<Duration>, // None waits forever
+    }
+
+    impl OutgoingRequests {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Id for the next request, ids are never reused
+        pub fn next_id(&mut self) -> i64 {
+            self.last_id += 1;
+            self.last_id
+        }
+
+        /// How long the requests inserted from now on are waited for, None waits forever
+        pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+            self.timeout = timeout;
+        }
+
+        pub fn timeout(&self) -> Option<Duration> {
+            self.timeout
+        }
+
+        /// Wait for the response to a request for `method`, returning the id to send it with
+        pub fn insert(&mut self, method: &str, callback: ResponseCallback) -> i64 {
+            self.insert_with_timeout(method, callback, self.timeout)
+        }
+
+        /// `insert`, waiting for the response for `timeout` instead of the timeout set
+        pub fn insert_with_timeout(
+            &mut self,
+            method: &str,
+            callback: ResponseCallback,
+            timeout: Option<Duration>,
+        ) -> i64 {
+            let id = self.next_id();
+            self.pending.insert(
+                id,
+                PendingRequest {
+                    method: method.to_string(),
+                    callback,
+                    deadline: timeout.map(|timeout| Instant::now() + timeout),
+                },
+            );
+            id
+        }
+
+        /// When the first of the requests expires, if any can
+        pub fn next_deadline(&self) -> Option<Instant> {
+            self.pending.values().filter_map(|p| p.deadline).min()
+        }
+
+        /// Stop waiting for the requests whose deadline passed, returning their id, method and
+        /// callback in the order they were sent
+        pub fn take_expired(&mut self, now: Instant) -> Vec<(i64, String, ResponseCallback)> {
+            let mut expired: Vec<i64> = self
+                .pending
+                .iter()
+                .filter(|(_, p)| p.deadline.is_some_and(|deadline| deadline <= now))
+                .map(|(&id, _)| id)
+                .collect();
+            expired.sort_unstable();
+            expired
+                .into_iter()
+                .filter_map(|id| {
+                    let pending = self.pending.remove(&id)?;
+                    Some((id, pending.method, pending.callback))
+                })
+                .collect()
+        }
+
+        /// Stop waiting for the request with `id`, returning its method and callback. None if
+        /// the server never sent it or it was already answered
+        pub fn remove(&mut self, id: &RequestId) -> Option<(String, ResponseCallback)> {
+            // the server numbers its requests, a string id can't be one of them
+            let RequestId::Number(id) = id else {
+                return None;
+            };
+            let pending = self.pending.remove(id)?;
+            Some((pending.method, pending.callback))
+        }
+
+        /// Number of requests that haven't been answered
+        pub fn len(&self) -> usize {
+            self.pending.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.pending.is_empty()
+        }
+    }
+
+    /// Response to a request sent with `ServerContext::request`. Can be awaited, or checked with
+    /// `try_take` by code that isn't async
+    pub struct ResponseFuture {
+        slot: Rc<RefCell<ResponseSlot>>,
+    }
+
+    #[derive(Default)]
+    struct ResponseSlot {
+        result: Option<Result<Value, ResponseError>>,
+        waker: Option<Waker>,
+    }
+
+    impl ResponseFuture {
+        /// The response, if the client sent it, taking it out of the future
+        pub fn try_take(&self) -> Option<Result<Value, ResponseError>> {
+            self.slot.borrow_mut().result.take()
+        }
+    }
+
+    impl Future for ResponseFuture {
+        type Output = Result<Value, ResponseError>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut slot = self.slot.borrow_mut();
+            match slot.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    slot.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    impl Default for ServerContext {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ServerContext {
+        pub fn new() -> Self {
+            let mut outgoing = OutgoingRequests::new();
+            outgoing.set_timeout(Settings::default().request_timeout());
+            ServerContext {
+                outgoing,
+                cancellations: CancellationRegistry::new(),
+                cancellation: CancellationToken::new(),
+                work_done_progress: false,
+                show_document: false,
+                configuration: false,
+                dynamic_registration: HashSet::new(),
+                registrations: HashMap::new(),
+                apply_edit: false,
+                resource_operations: Vec::new(),
+                workspace_folders: Vec::new(),
+                settings: Settings::default(),
+                revalidations: Debouncer::new(),
+                invalid_documents: HashMap::new(),
+                commands: CommandRegistry::with_builtins(),
+                handlers: BTreeMap::new(),
+                initialize_received: false,
+                initialized: false,
+                startup_warnings: Vec::new(),
+                trace_value: TraceValue::Off,
+                client_capabilities: ClientCapabilities::default(),
+                telemetry: Telemetry::new(),
+                health: ClientHealth::default(),
+                completion_engine: Lazy::new("completion"),
+                workspace_index: Lazy::new("workspace"),
+                loaded_from_disk: LruSet::new(),
+                background_parses: BackgroundParses::new(),
+                position_encoding: PositionEncoding::default(),
+                output: Output::default(),
+            }
+        }
+
+        /// Write the messages for the client with `output` instead of to stdout
+        pub fn set_output(&mut self, output: Output) {
+            self.output = output;
+        }
+
+        pub fn output(&self) -> &Output {
+            &self.output
+        }
+
+        /// Handle requests for `method` with `handler`, whose result or error is sent back to
+        /// the client. Must be called before the client sends `initialized`
+        pub fn register_request_handler<F>(
+            &mut self,
+            method: &str,
+            handler: F,
+        ) -> Result<(), RegisterHandlerError>
+        where
+            F: Fn(
+                    Value,
+                    &mut EditorState,
+                    &mut ServerContext,
+                    &mut dyn Write,
+                ) -> Result<Value, ResponseError>
+                + 'static,
+        {
+            self.register_handler(method, Handler::Request(Rc::new(handler)))
+        }
+
+        /// Handle notifications for `method` with `handler`.
+        /// Must be called before the client sends `initialized`
+        pub fn register_notification_handler<F>(
+            &mut self,
+            method: &str,
+            handler: F,
+        ) -> Result<(), RegisterHandlerError>
+        where
+            F: Fn(Value, &mut EditorState, &mut ServerContext, &mut dyn Write) + 'static,
+        {
+            self.register_handler(method, Handler::Notification(Rc::new(handler)))
+        }
+
+        fn register_handler(
+            &mut self,
+            method: &str,
+            handler: Handler,
+        ) -> Result<(), RegisterHandlerError> {
+            if self.initialized {
+                return Err(RegisterHandlerError::Initialized);
+            }
+            if router().handles(method) {
+                return Err(RegisterHandlerError::BuiltIn(method.to_string()));
+            }
+            if self.handlers.contains_key(method) {
+                return Err(RegisterHandlerError::AlreadyRegistered(method.to_string()));
+            }
+            self.handlers.insert(method.to_string(), handler);
+            Ok(())
+        }
+
+        /// Token of the request being handled, flipped if the client cancels it. Handlers added
+        /// by the embedder check it in their long loops like the built-in ones
+        pub fn cancellation(&self) -> CancellationToken {
+            self.cancellation.clone()
+        }
+
+        /// Tokens of the requests being handled, shared with the transport so it can cancel
+        /// requests that are still queued
+        pub fn cancellations(&self) -> CancellationRegistry {
+            self.cancellations.clone()
+        }
+
+        /// Unit the client and server count the character of positions in
+        pub fn position_encoding(&self) -> PositionEncoding {
+            self.position_encoding
+        }
+
+        /// How long building each subsystem took, for the ones that were needed so far
+        pub fn lazy_init_times(&self) -> Vec<(&'static str, Duration)> {
+            [
+                (
+                    self.completion_engine.name(),
+                    self.completion_engine.init_time(),
+                ),
+                (
+                    self.workspace_index.name(),
+                    self.workspace_index.init_time(),
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(name, time)| Some((name, time?)))
+            .collect()
+        }
+
+        /// Record how long writing a frame's output took, to notice a client that can't keep up.
+        /// The user is warned the first time optional traffic is shed
+        pub fn record_frame(&mut self, write: Duration, logger: &mut dyn Write) {
+            let queued = self.output.queued();
+            match self.health.record(write, queued) {
+                Some(HealthChange::Slowed { first }) => {
+                    let message = format!(
+                        "The client is slow to read messages (last write {:?}, {} messages queued), log messages and optional notifications are reduced until it catches up",
+                        write, queued
+                    );
+                    writeln!(logger, "[Warning] {}", message).unwrap();
+                    if first {
+                        show_message(MessageType::Warning, message, &self.output, logger);
+                    }
+                }
+                Some(HealthChange::Recovered) => writeln!(
+                    logger,
+                    "[Info] The client caught up, sending all messages again"
+                )
+                .unwrap(),
+                None => (),
+            }
+        }
+
+        /// Whether the client is slow, and optional traffic is being dropped
+        pub fn client_health(&self) -> &ClientHealth {
+            &self.health
+        }
+
+        /// Whether optional traffic should be sent, see `record_frame`
+        pub fn admit(&mut self, traffic: OptionalTraffic) -> bool {
+            self.health.admit(traffic)
+        }
+
+        /// What the connected client supports, empty before initialize
+        pub fn client_capabilities(&self) -> &ClientCapabilities {
+            &self.client_capabilities
+        }
+
+        fn publish_diagnostics_capabilities(
+            &self,
+        ) -> Option<&PublishDiagnosticsClientCapabilities> {
+            self.client_capabilities
+                .text_document
+                .as_ref()
+                .and_then(|t| t.publish_diagnostics.as_ref())
+        }
+
+        /// Whether the client shows the related information of diagnostics
+        pub fn supports_related_information(&self) -> bool {
+            self.publish_diagnostics_capabilities()
+                .and_then(|p| p.related_information)
+                .unwrap_or(false)
+        }
+
+        /// Whether the client renders the diagnostic tag, one of `DiagnosticTag`
+        pub fn supports_diagnostic_tag(&self, tag: u8) -> bool {
+            self.publish_diagnostics_capabilities()
+                .and_then(|p| p.tag_support.as_ref())
+                .is_some_and(|t| t.value_set.contains(&tag))
+        }
+
+        /// Format of hovers, the first the client lists, plaintext if it lists none the
+        /// server knows
+        pub fn hover_markup_kind(&self) -> MarkupKind {
+            let text_document = self.client_capabilities.text_document.as_ref();
+            MarkupKind::negotiate(
+                text_document
+                    .and_then(|t| t.hover.as_ref())
+                    .and_then(|h| h.content_format.as_deref()),
+            )
+        }
+
+        /// Whether the client takes the refresh request of the feature
+        pub fn supports_refresh(&self, kind: RefreshKind) -> bool {
+            self.client_capabilities
+                .workspace
+                .as_ref()
+                .and_then(|w| kind.capability(w))
+                .and_then(|c| c.refresh_support)
+                .unwrap_or(false)
+        }
+
+        /// Ask the client to request the feature's results again, because the state they were
+        /// computed from changed. Returns false without sending anything if the client doesn't
+        /// support it, or is too slow to take optional traffic
+        pub fn refresh(&mut self, kind: RefreshKind, logger: &mut dyn Write) -> bool {
+            if !self.supports_refresh(kind) || !self.admit(OptionalTraffic::Refresh) {
+                return false;
+            }
+            self.send_request(kind.method(), (), Box::new(log_response_error), logger);
+            true
+        }
+
+        /// Refresh every feature the client supports refreshing
+        pub fn refresh_all(&mut self, logger: &mut dyn Write) {
+            for kind in RefreshKind::ALL {
+                self.refresh(kind, logger);
+            }
+        }
+
+        /// Whether the client can fill in the property of workspace symbols later with
+        /// workspaceSymbol/resolve, eg. `location.range`
+        pub fn supports_symbol_resolve(&self, property: &str) -> bool {
+            self.client_capabilities
+                .workspace
+                .as_ref()
+                .and_then(|w| w.symbol.as_ref())
+                .and_then(|s| s.resolve_support.as_ref())
+                .is_some_and(|r| r.properties.iter().any(|p| p == property))
+        }
+
+        /// Format of the documentation of completion items, like `hover_markup_kind`
+        pub fn documentation_markup_kind(&self) -> MarkupKind {
+            let text_document = self.client_capabilities.text_document.as_ref();
+            MarkupKind::negotiate(
+                text_document
+                    .and_then(|t| t.completion.as_ref())
+                    .and_then(|c| c.completion_item.as_ref())
+                    .and_then(|i| i.documentation_format.as_deref()),
+            )
+        }
+
+        pub fn trace_value(&self) -> TraceValue {
+            self.trace_value
+        }
+
+        /// Report a message received from the client with `$/logTrace`, if the client asked
+        /// for it. Verbose traces include the params
+        fn log_trace(
+            &self,
+            method: &str,
+            id: Option<&RequestId>,
+            message: &str,
+            logger: &mut dyn Write,
+        ) {
+            if self.trace_value == TraceValue::Off {
+                return;
+            }
+            let summary = match id {
+                Some(id) => format!("Received request '{} - ({})'.", method, id),
+                None => format!("Received notification '{}'.", method),
+            };
+            let verbose = (self.trace_value == TraceValue::Verbose).then(|| {
+                let params = serde_json::from_str::<Value>(message)
+                    .ok()
+                    .and_then(|mut m| m.get_mut("params").map(Value::take))
+                    .unwrap_or(Value::Null);
+                format!("Params: {}", params)
+            });
+            send_message(
+                &LogTraceNotification::new(summary, verbose),
+                &self.output,
+                logger,
+            );
+        }
+
+        /// Count the time spent handling a frame of the method towards telemetry, sending a
+        /// `telemetry/event` every `interval` frames. Nothing is recorded unless enabled
+        pub fn record_latency(&mut self, method: &str, handler: Duration, logger: &mut dyn Write) {
+            let settings = &self.settings.telemetry;
+            if !settings.enabled {
+                return;
+            }
+            self.telemetry.record_latency(method, handler);
+            if self.telemetry.frames() >= settings.interval.max(1) {
+                let event = self.telemetry.take_event();
+                if self.admit(OptionalTraffic::Telemetry) {
+                    send_message(
+                        &TelemetryEventNotification::new(event),
+                        &self.output,
+                        logger,
+                    );
+                }
+            }
+        }
+
+        /// Show a warning about something that went wrong before the client connected, eg.
+        /// the log file could not be created. Shown once the client sent `initialized`
+        pub fn warn_once_initialized(&mut self, message: String) {
+            self.startup_warnings.push(message);
+        }
+
+        /// Commands offered through `workspace/executeCommand`, register new ones before
+        /// `initialize` so they are advertised to the client
+        pub fn commands_mut(&mut self) -> &mut CommandRegistry {
+            &mut self.commands
+        }
+
+        /// When the next failing document is due for re-validation, see `handle_due_revalidations`
+        pub fn next_revalidation(&self) -> Option<Instant> {
+            // background parses are polled until they are done
+            let poll = (!self.background_parses.is_empty())
+                .then(|| Instant::now() + BACKGROUND_PARSE_POLL);
+            [
+                self.revalidations.next_deadline(),
+                poll,
+                self.outgoing.next_deadline(),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+        }
+
+        pub fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
+        /// Replace the settings with the ones sent by the client
+        pub fn update_settings(&mut self, value: &Value, logger: &mut dyn Write) {
+            match Settings::from_value(value) {
+                Ok(settings) => {
+                    writeln!(logger, "[Settings] {:?}", settings).unwrap();
+                    if settings.completion != self.settings.completion {
+                        self.completion_engine.reset();
+                    }
+                    if !settings.telemetry.enabled {
+                        self.telemetry = Telemetry::new();
+                    }
+                    let changed = settings != self.settings;
+                    self.outgoing.set_timeout(settings.request_timeout());
+                    self.settings = settings;
+                    if self.initialized {
+                        self.update_file_watcher(logger);
+                        // results the client kept may depend on the old settings
+                        if changed {
+                            self.refresh_all(logger);
+                        }
+                    }
+                }
+                Err(e) => {
+                    writeln!(logger, "[Error] invalid settings {}: {}", value, e).unwrap();
+                    show_message(
+                        MessageType::Warning,
+                        format!("Invalid {} settings: {}", config::SECTION, e),
+                        &self.output,
+                        logger,
+                    );
+                }
+            }
+        }
+
+        /// Id for the next request sent from the server to the client
+        pub fn next_request_id(&mut self) -> i64 {
+            self.outgoing.next_id()
+        }
+
+        /// Send a request to the client, the callback is run once the client responds or the
+        /// requestTimeout setting runs out
+        pub fn send_request<P>(
+            &mut self,
+            method: &str,
+            params: P,
+            callback: ResponseCallback,
+            logger: &mut dyn Write,
+        ) -> i64
+        where
+            P: Serialize,
+        {
+            let timeout = self.outgoing.timeout();
+            self.send_request_with_timeout(method, params, timeout, callback, logger)
+        }
+
+        /// `send_request`, giving up after `timeout` instead, None waits forever. For requests
+        /// the user answers, who may take their time
+        pub fn send_request_with_timeout<P>(
+            &mut self,
+            method: &str,
+            params: P,
+            timeout: Option<Duration>,
+            callback: ResponseCallback,
+            logger: &mut dyn Write,
+        ) -> i64
+        where
+            P: Serialize,
+        {
+            let id = self.outgoing.insert_with_timeout(method, callback, timeout);
+            send_message(
+                &OutgoingRequest::new(id, method, params),
+                &self.output,
+                logger,
+            );
+            id
+        }
+
+        /// Send a request to the client, returning a future of its response for the code that
+        /// would rather wait for it than pass a callback
+        pub fn request<P>(
+            &mut self,
+            method: &str,
+            params: P,
+            logger: &mut dyn Write,
+        ) -> ResponseFuture
+        where
+            P: Serialize,
+        {
+            let slot = Rc::new(RefCell::new(ResponseSlot::default()));
+            let filled = slot.clone();
+            let callback: ResponseCallback = Box::new(move |result, _, _| {
+                let mut slot = filled.borrow_mut();
+                slot.result = Some(result);
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+            });
+            self.send_request(method, params, callback, logger);
+            ResponseFuture { slot }
+        }
+
+        /// Give up on the requests the client didn't answer in time: their callback gets a
+        /// REQUEST_TIMED_OUT error, and the client is told it can drop them
+        pub fn expire_requests(&mut self, now: Instant, logger: &mut dyn Write) {
+            for (id, method, callback) in self.outgoing.take_expired(now) {
+                writeln!(logger, "[Response] {} request {} timed out", method, id).unwrap();
+                let params = CancelParams {
+                    id: RequestId::Number(id),
+                };
+                send_message(
+                    &OutgoingNotification::new(CancelNotification::METHOD, params),
+                    &self.output,
+                    logger,
+                );
+                let error = ResponseError {
+                    code: ErrorCode::RequestTimedOut,
+                    message: format!("The client did not answer {} in time", method),
+                    data: None,
+                };
+                callback(Err(error), self, logger);
+            }
+        }
+
+        /// Number of requests sent to the client that haven't been responded to
+        pub fn pending_request_count(&self) -> usize {
+            self.outgoing.len()
+        }
+
+        /// Number of documents being parsed in the background
+        pub fn background_parse_count(&self) -> usize {
+            self.background_parses.len()
+        }
+
+        /// Route a response from the client to the callback of the request it answers
+        pub fn handle_response(
+            &mut self,
+            response: ClientResponse,
+            logger: &mut dyn Write,
+        ) -> Result<(), MsgParseError> {
+            let Some((method, callback)) = self.outgoing.remove(&response.id) else {
+                return Err(MsgParseError(format!(
+                    "Recieved response to unknown request {}",
+                    response.id
+                )));
+            };
+            writeln!(
+                logger,
+                "[Response] {} response to request {}",
+                method, response.id
+            )
+            .unwrap();
+            let result = match response.error {
+                Some(error) => Err(error),
+                None => Ok(response.result.unwrap_or(Value::Null)),
+            };
+            callback(result, self, logger);
+            Ok(())
+        }
+
+        /// Ask the user to pick one of the actions with `window/showMessageRequest`.
+        /// The callback gets the title of the chosen action, or None if the message was dismissed
+        pub fn show_message_request(
+            &mut self,
+            message_type: MessageType,
+            message: String,
+            actions: &[&str],
+            callback: impl FnOnce(Option<String>, &mut ServerContext, &mut dyn Write) + 'static,
+            logger: &mut dyn Write,
+        ) -> i64 {
+            let params = ShowMessageRequestParams {
+                message_type,
+                message,
+                actions: Some(
+                    actions
+                        .iter()
+                        .map(|title| MessageActionItem {
+                            title: title.to_string(),
+                        })
+                        .collect(),
+                ),
+            };
+            let callback: ResponseCallback = Box::new(move |result, context, logger| {
+                let action = match result {
+                    Ok(value) => serde_json::from_value::<Option<MessageActionItem>>(value)
+                        .ok()
+                        .flatten()
+                        .map(|item| item.title),
+                    Err(e) => {
+                        writeln!(logger, "[Error] showMessageRequest failed: {}", e.message)
+                            .unwrap();
+                        None
+                    }
+                };
+                callback(action, context, logger)
+            });
+            // the user picks the action, however long it takes
+            self.send_request_with_timeout(
+                ShowMessageRequest::METHOD,
+                params,
+                None,
+                callback,
+                logger,
+            )
+        }
+
+        /// Ask the client for the settings with `workspace/configuration`, they are applied once
+        /// the client responds. Returns false if the client doesn't support pulling configuration
+        pub fn pull_configuration(&mut self, logger: &mut dyn Write) -> bool {
+            if !self.configuration {
+                return false;
+            }
+            let params = ConfigurationParams {
+                items: vec![ConfigurationItem {
+                    scope_uri: None,
+                    section: Some(config::SECTION.to_string()),
+                }],
+            };
+            let callback: ResponseCallback = Box::new(|result, context, mut logger| {
+                match result.map(serde_json::from_value::<Vec<Value>>) {
+                    Ok(Ok(values)) => {
+                        if let Some(value) = values.first() {
+                            context.update_settings(value, &mut logger);
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        writeln!(logger, "[Error] invalid configuration response: {}", e).unwrap()
+                    }
+                    Err(e) => writeln!(
+                        logger,
+                        "[Error] workspace/configuration failed: {}",
+                        e.message
+                    )
+                    .unwrap(),
+                }
+            });
+            self.send_request(ConfigurationRequest::METHOD, params, callback, logger);
+            true
+        }
+
+        /// Start reporting progress for a long running operation.
+        /// Uses the client supplied `workDoneToken` if there is one, otherwise asks the client to
+        /// create a progress with `window/workDoneProgress/create`, which only begins once the
+        /// client answers. An operation that ends before that shows no progress.
+        /// Returns None if the client supports neither
+        pub fn start_progress(
+            &mut self,
+            token: Option<ProgressToken>,
+            title: &str,
+            logger: &mut dyn Write,
+        ) -> Option<WorkDoneProgress> {
+            if let Some(token) = token {
+                let progress = WorkDoneProgress::new(token, ProgressState::Begun);
+                progress.begin(title.to_string(), &self.output, logger);
+                return Some(progress);
+            }
+            if !self.work_done_progress {
+                return None;
+            }
+            let token = ProgressToken::String(format!("lsp-rs/{}", self.outgoing.last_id));
+            let progress = WorkDoneProgress::new(token.clone(), ProgressState::Creating);
+            let created = WorkDoneProgress {
+                token: token.clone(),
+                state: progress.state.clone(),
+            };
+            let title = title.to_string();
+            let callback: ResponseCallback =
+                Box::new(move |result, context, logger| match result {
+                    Ok(_) if created.state.get() == ProgressState::Creating => {
+                        created.state.set(ProgressState::Begun);
+                        created.begin(title, context.output(), logger);
+                    }
+                    Ok(_) => (),
+                    Err(e) => log_response_error(Err(e), context, logger),
+                });
+            let params = WorkDoneProgressCreateParams { token };
+            self.send_request(
+                WorkDoneProgressCreateRequest::METHOD,
+                params,
+                callback,
+                logger,
+            );
+            Some(progress)
+        }
+
+        /// Ask the client to open the uri with `window/showDocument`, either in the editor or,
+        /// if external, in the default application (eg. a browser for http links).
+        /// Returns false without sending anything if the client doesn't support it
+        pub fn show_document(
+            &mut self,
+            params: ShowDocumentParams,
+            logger: &mut dyn Write,
+        ) -> bool {
+            if !self.show_document {
+                return false;
+            }
+            let callback: ResponseCallback = Box::new(|result, _, logger| {
+                match result.map(serde_json::from_value::<ShowDocumentResult>) {
+                    Ok(Ok(ShowDocumentResult { success: true })) => (),
+                    Ok(_) => writeln!(logger, "[Warning] client could not show document").unwrap(),
+                    Err(e) => {
+                        writeln!(logger, "[Error] showDocument failed: {}", e.message).unwrap()
+                    }
+                }
+            });
+            self.send_request(ShowDocumentRequest::METHOD, params, callback, logger);
+            true
+        }
+
+        /// Ask the client to apply the edit with `workspace/applyEdit`.
+        /// Returns false without sending anything if the client doesn't support it
+        pub fn apply_edit(
+            &mut self,
+            label: &str,
+            edit: WorkspaceEdit,
+            logger: &mut dyn Write,
+        ) -> bool {
+            if !self.apply_edit {
+                return false;
+            }
+            let params = ApplyWorkspaceEditParams {
+                label: Some(label.to_string()),
+                edit,
+            };
+            let callback: ResponseCallback = Box::new(|result, _, logger| {
+                match result.map(serde_json::from_value::<ApplyWorkspaceEditResult>) {
+                    Ok(Ok(ApplyWorkspaceEditResult { applied: true, .. })) => (),
+                    Ok(Ok(ApplyWorkspaceEditResult { failure_reason, .. })) => writeln!(
+                        logger,
+                        "[Warning] client did not apply edit: {}",
+                        failure_reason.unwrap_or_default()
+                    )
+                    .unwrap(),
+                    Ok(Err(e)) => {
+                        writeln!(logger, "[Error] invalid applyEdit result: {}", e).unwrap()
+                    }
+                    Err(e) => writeln!(logger, "[Error] applyEdit failed: {}", e.message).unwrap(),
+                }
+            });
+            // the client may ask the user to confirm the edit
+            self.send_request_with_timeout(
+                ApplyWorkspaceEditRequest::METHOD,
+                params,
+                None,
+                callback,
+                logger,
+            );
+            true
+        }
+
+        /// Whether the client can apply workspace edits with the resource operation, eg. create
+        pub fn supports_resource_operation(&self, kind: &str) -> bool {
+            self.apply_edit && self.resource_operations.iter().any(|k| k == kind)
+        }
+
+        /// Whether the client accepts `client/registerCapability` for the method
+        pub fn supports_dynamic_registration(&self, method: &str) -> bool {
+            self.dynamic_registration.contains(method)
+        }
+
+        /// Whether the registration with the id is active
+        pub fn is_registered(&self, id: &str) -> bool {
+            self.registrations.contains_key(id)
+        }
+
+        /// Register capabilities with the client in a single `client/registerCapability`.
+        /// Registrations for methods the client can't register dynamically, or whose id is
+        /// already active, are skipped. Returns the number of registrations sent
+        pub fn register_capabilities(
+            &mut self,
+            registrations: Vec<Registration>,
+            logger: &mut dyn Write,
+        ) -> usize {
+            let registrations: Vec<Registration> = registrations
+                .into_iter()
+                .filter(|r| {
+                    self.supports_dynamic_registration(&r.method) && !self.is_registered(&r.id)
+                })
+                .collect();
+            if registrations.is_empty() {
+                return 0;
+            }
+            let ids: Vec<String> = registrations.iter().map(|r| r.id.clone()).collect();
+            for registration in &registrations {
+                self.registrations
+                    .insert(registration.id.clone(), registration.method.clone());
+            }
+            let callback: ResponseCallback = Box::new(move |result, context, logger| {
+                if let Err(e) = result {
+                    writeln!(logger, "[Error] registerCapability failed: {}", e.message).unwrap();
+                    for id in ids {
+                        context.registrations.remove(&id);
+                    }
+                }
+            });
+            let count = registrations.len();
+            self.send_request(
+                RegistrationRequest::METHOD,
+                RegistrationParams { registrations },
+                callback,
+                logger,
+            );
+            count
+        }
+
+        /// Remove an active registration with `client/unregisterCapability`.
+        /// Returns false without sending anything if the id isn't registered
+        pub fn unregister_capability(&mut self, id: &str, logger: &mut dyn Write) -> bool {
+            let Some(method) = self.registrations.remove(id) else {
+                return false;
+            };
+            let params = UnregistrationParams {
+                unregisterations: vec![Unregistration {
+                    id: id.to_string(),
+                    method,
+                }],
+            };
+            self.send_request(
+                UnregistrationRequest::METHOD,
+                params,
+                Box::new(log_response_error),
+                logger,
+            );
+            true
+        }
+
+        /// Watch tree files on disk while `watchFiles` is set, and stop watching once it's unset
+        fn update_file_watcher(&mut self, logger: &mut dyn Write) {
+            if !self.settings.watch_files {
+                self.unregister_capability(WATCHED_FILES_REGISTRATION, logger);
+                return;
+            }
+            let registration = Registration {
+                id: WATCHED_FILES_REGISTRATION.to_string(),
+                method: DidChangeWatchedFilesNotification::METHOD.to_string(),
+                register_options: Some(json!({
+                    "watchers": [FileSystemWatcher {
+                        glob_pattern: "**/*.abc".to_string(),
+                        kind: None,
+                    }]
+                })),
+            };
+            self.register_capabilities(vec![registration], logger);
+        }
+    }
+
+    /// Features whose results the client keeps until the server asks it to request them again
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RefreshKind {
+        SemanticTokens,
+        InlayHint,
+        CodeLens,
+        Diagnostic,
+        InlineValue,
+    }
+
+    impl RefreshKind {
+        pub const ALL: [RefreshKind; 5] = [
+            RefreshKind::SemanticTokens,
+            RefreshKind::InlayHint,
+            RefreshKind::CodeLens,
+            RefreshKind::Diagnostic,
+            RefreshKind::InlineValue,
+        ];
+
+        pub fn method(self) -> &'static str {
+            match self {
+                RefreshKind::SemanticTokens => "workspace/semanticTokens/refresh",
+                RefreshKind::InlayHint => "workspace/inlayHint/refresh",
+                RefreshKind::CodeLens => "workspace/codeLens/refresh",
+                RefreshKind::Diagnostic => "workspace/diagnostic/refresh",
+                RefreshKind::InlineValue => "workspace/inlineValue/refresh",
+            }
+        }
+
+        fn capability(
+            self,
+            workspace: &WorkspaceClientCapabilities,
+        ) -> Option<&RefreshClientCapabilities> {
+            match self {
+                RefreshKind::SemanticTokens => workspace.semantic_tokens.as_ref(),
+                RefreshKind::InlayHint => workspace.inlay_hint.as_ref(),
+                RefreshKind::CodeLens => workspace.code_lens.as_ref(),
+                RefreshKind::Diagnostic => workspace.diagnostics.as_ref(),
+                RefreshKind::InlineValue => workspace.inline_value.as_ref(),
+            }
+        }
+    }
+
+    /// Id of the registration asking the client to report changes to tree files on disk
+    pub const WATCHED_FILES_REGISTRATION: &str = "lsp-rs/watchedFiles";
+
+    /// Methods whose capabilities the client lets the server register at runtime
+    fn dynamic_registration_methods(capabilities: &ClientCapabilities) -> HashSet<String> {
+        let mut methods = HashSet::new();
+        if let Some(workspace) = &capabilities.workspace {
+            let flags = [
+                (
+                    DidChangeWatchedFilesNotification::METHOD,
+                    &workspace.did_change_watched_files,
+                ),
+                (
+                    DidChangeConfigurationNotification::METHOD,
+                    &workspace.did_change_configuration,
+                ),
+                (ExecuteCommandRequest::METHOD, &workspace.execute_command),
+            ];
+            for (method, flag) in flags {
+                if flag.as_ref().and_then(|f| f.dynamic_registration) == Some(true) {
+                    methods.insert(method.to_string());
+                }
+            }
+        }
+        // every text document feature has its own dynamicRegistration flag
+        if let Some(text_document) = &capabilities.text_document {
+            for feature in text_document.dynamic_registration() {
+                methods.insert(format!("textDocument/{}", feature));
+            }
+        }
+        methods
+    }
+
+    /// Methods handled by the server itself, which embedders can't replace
+    pub fn builtin_methods() -> impl Iterator<Item = &'static str> {
+        router().methods()
+    }
+
+    /// Handles a request added by the embedder, called with the request's params
+    pub type RequestHandler = Rc<
+        dyn Fn(
+            Value,
+            &mut EditorState,
+            &mut ServerContext,
+            &mut dyn Write,
+        ) -> Result<Value, ResponseError>,
+    >;
+
+    /// Handles a notification added by the embedder, called with the notification's params
+    pub type NotificationHandler =
+        Rc<dyn Fn(Value, &mut EditorState, &mut ServerContext, &mut dyn Write)>;
+
+    #[derive(Clone)]
+    enum Handler {
+        Request(RequestHandler),
+        Notification(NotificationHandler),
+    }
+
+    /// Why a handler could not be registered
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum RegisterHandlerError {
+        BuiltIn(String),           // the server handles the method itself
+        AlreadyRegistered(String), // another handler was registered for the method
+        Initialized,               // the client already sent `initialized`
+    }
+
+    impl Display for RegisterHandlerError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                RegisterHandlerError::BuiltIn(method) => {
+                    write!(f, "{} is handled by the server", method)
+                }
+                RegisterHandlerError::AlreadyRegistered(method) => {
+                    write!(f, "a handler for {} is already registered", method)
+                }
+                RegisterHandlerError::Initialized => {
+                    write!(f, "handlers can't be registered after initialized")
+                }
+            }
+        }
+    }
+
+    /// Runs a command with the arguments the client sent, returning the command's result
+    pub type CommandHandler = Rc<
+        dyn Fn(
+            &[Value],
+            &mut EditorState,
+            &mut ServerContext,
+            &mut dyn Write,
+        ) -> Result<Value, ResponseError>,
+    >;
+
+    /// Balance the document given as the first argument into a complete binary search tree
+    pub const BALANCE_TREE_COMMAND: &str = "lsp-rs.balanceTree";
+    /// Graphviz source of the document given as the first argument
+    pub const EXPORT_DOT_COMMAND: &str = "lsp-rs.exportDot";
+    /// Preview of the subtree under the position given as the optional second argument, the
+    /// root by default, in the document given as the first argument. The optional third
+    /// argument is the depth, at most the previewDepth setting
+    pub const PREVIEW_SUBTREE_COMMAND: &str = "lsp-rs.previewSubtree";
+    /// Create a document holding an example tree, at the uri given as the optional first
+    /// argument or `example.abc` in the first workspace folder
+    pub const NEW_EXAMPLE_COMMAND: &str = "lsp-rs.newExample";
+
+    /// Rename every node labelled with the first argument to the second argument, in all the
+    /// documents open in the editor. Returns the number of renamed nodes
+    pub const RENAME_VALUE_COMMAND: &str = "lsp-rs.renameValueEverywhere";
+
+    /// Contents of the document created by the newExample command
+    pub const EXAMPLE_TREE: &str = "a\nb c\nd e f g\n";
+
+    /// Commands the client can run with `workspace/executeCommand`, by identifier
+    #[derive(Clone, Default)]
+    pub struct CommandRegistry {
+        handlers: BTreeMap<String, CommandHandler>,
+    }
+
+    impl CommandRegistry {
+        /// Registry without any commands
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Registry with the commands built into the server
+        pub fn with_builtins() -> Self {
+            let mut registry = Self::new();
+            registry.register(BALANCE_TREE_COMMAND, balance_tree_command);
+            registry.register(EXPORT_DOT_COMMAND, export_dot_command);
+            registry.register(PREVIEW_SUBTREE_COMMAND, preview_subtree_command);
+            registry.register(NEW_EXAMPLE_COMMAND, new_example_command);
+            registry.register(RENAME_VALUE_COMMAND, rename_value_command);
+            registry
+        }
+
+        /// Add a command, returns false and keeps the existing handler if the identifier is taken
+        pub fn register<F>(&mut self, command: &str, handler: F) -> bool
+        where
+            F: Fn(
+                    &[Value],
+                    &mut EditorState,
+                    &mut ServerContext,
+                    &mut dyn Write,
+                ) -> Result<Value, ResponseError>
+                + 'static,
+        {
+            if self.handlers.contains_key(command) {
+                return false;
+            }
+            self.handlers.insert(command.to_string(), Rc::new(handler));
+            true
+        }
+
+        pub fn get(&self, command: &str) -> Option<CommandHandler> {
+            self.handlers.get(command).cloned()
+        }
+
+        /// Identifiers of every registered command, sorted
+        pub fn commands(&self) -> Vec<String> {
+            self.handlers.keys().cloned().collect()
+        }
+    }
+
+    fn invalid_params(message: String) -> ResponseError {
+        ResponseError {
+            code: ErrorCode::InvalidParams,
+            message,
+            data: None,
+        }
+    }
+
+    /// The document named by the first argument of a command
+    fn command_document<'a>(
+        arguments: &[Value],
+        editor_state: &'a EditorState,
+        context: &ServerContext,
+    ) -> Result<(String, &'a FileState), ResponseError> {
+        let Some(uri) = arguments.first().and_then(|a| a.as_str()) else {
+            return Err(invalid_params(
+                "expected the document uri as the first argument".to_string(),
+            ));
+        };
+        let fs = lookup_document(editor_state, context, uri)?;
+        Ok((uri.to_string(), fs))
+    }
+
+    /// Content of the file on disk, see `encoding::decode`
+    fn read_document(path: &std::path::Path) -> Option<(String, TextEncoding)> {
+        fs::read(path).ok().map(decode)
+    }
+
+    /// Read a document the server doesn't know from disk, so requests can reach files that
+    /// are neither open nor in a scanned workspace folder. At most `diskCacheSize` such files
+    /// are kept, the least recently used closed one is forgotten first
+    fn load_document(
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        uri: &str,
+        logger: &mut dyn Write,
+    ) {
+        if !needs_loading(editor_state, context, uri) {
+            return;
+        }
+        let Some(path) = uri_to_path(uri) else {
+            return;
+        };
+        let len = fs::metadata(&path).map_or(0, |m| m.len());
+        if len > context.settings.max_file_size as u64 {
+            // validated without reading it into memory, so the log says whether it is a tree
+            let validated = match large_file::validate_file(&path) {
+                Ok(Ok(summary)) => format!("a valid tree of {} nodes", summary.nodes),
+                Ok(Err(e)) => format!("not a tree, {}", e),
+                Err(e) => e.to_string(),
+            };
+            writeln!(
+                logger,
+                "[Load] not reading {}, it is larger than maxFileSize: {}",
+                uri, validated
+            )
+            .unwrap();
+            return;
+        }
+        let Some((content, encoding)) = read_document(&path) else {
+            return;
+        };
+        if content.len() > context.settings.max_file_size {
+            writeln!(
+                logger,
+                "[Load] not reading {}, it is larger than maxFileSize",
+                uri
+            )
+            .unwrap();
+            return;
+        }
+        writeln!(logger, "[Load] reading {} from disk", uri).unwrap();
+        if encoding == TextEncoding::Latin1 {
+            writeln!(logger, "[Load] {} is not utf-8, read it as Latin-1", uri).unwrap();
+        }
+        match editor_state.modify_file(uri.to_string(), content) {
+            Ok(()) => context.invalid_documents.remove(uri),
+            Err(e) => context.invalid_documents.insert(uri.to_string(), e),
+        };
+        let capacity = context.settings.disk_cache_size;
+        for evicted in context.loaded_from_disk.insert(uri.to_string(), capacity) {
+            // the client owns the content of open documents
+            if !editor_state.is_open(&evicted) {
+                writeln!(logger, "[Load] forgetting {}", evicted).unwrap();
+                editor_state.remove_file(&evicted);
+                context.invalid_documents.remove(&evicted);
+            }
+        }
+    }
+
+    /// Whether `load_document` would read the document, a document already read from disk
+    /// counts as used
+    fn needs_loading(editor_state: &EditorState, context: &mut ServerContext, uri: &str) -> bool {
+        !context.loaded_from_disk.touch(&uri.to_string()) && editor_state.text(uri).is_none()
+    }
+
+    /// The parsed document, or why there is none
+    fn lookup_document<'a>(
+        editor_state: &'a EditorState,
+        context: &ServerContext,
+        uri: &str,
+    ) -> Result<&'a FileState, TreeError> {
+        // the tree would not match the text until the parse is done
+        if context.background_parses.is_parsing(uri) {
+            return Err(TreeError::IndexingInProgress {
+                uri: uri.to_string(),
+            });
+        }
+        // a document that became invalid keeps its last valid state, which is still useful
+        if let Some(fs) = editor_state.get_file_state(uri.to_string()) {
+            return Ok(fs);
+        }
+        match context.invalid_documents.get(uri) {
+            Some(error) => Err(TreeError::DocumentNotATree {
+                uri: uri.to_string(),
+                range: Range {
+                    start: Position {
+                        line: error.line as i32,
+                        character: 0,
+                    },
+                    end: Position {
+                        line: error.line as i32 + 1,
+                        character: 0,
+                    },
+                },
+                reason: error.reason.clone(),
+            }),
+            None => Err(TreeError::DocumentNotFound {
+                uri: uri.to_string(),
+            }),
+        }
+    }
+
+    /// Position after the last character of the document
+    fn document_end(fs: &FileState, encoding: PositionEncoding) -> Position {
+        let line = fs.line_count() - 1;
+        let text = fs.line(line).unwrap_or_default();
+        Position {
+            line: line as i32,
+            character: text.chars().map(|c| encoding.char_len(c)).sum::<usize>() as i32,
+        }
+    }
+
+    fn balance_tree_command(
+        arguments: &[Value],
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        mut logger: &mut dyn Write,
+    ) -> Result<Value, ResponseError> {
+        let (uri, fs) = command_document(arguments, editor_state, context)?;
+        let mut labels: Vec<&str> = fs.iter_bfs().map(|(_, label)| label).collect();
+        labels.sort();
+
+        // an in order walk of a binary search tree visits the labels sorted
+        let mut balanced = fs.clone();
+        balanced.balance();
+        let mut nodes = vec![None; balanced.len()];
+        for ((index, _), label) in balanced.iter_dfs_inorder().zip(labels) {
+            nodes[index] = Some(label);
+        }
+
+        let edit = TextEdit {
+            range: Range {
+                start: Position {
+                    line: 0,
+                    character: 0,
+                },
+                end: document_end(fs, context.position_encoding),
+            },
+            new_text: layout(&nodes, fs.line_ending()),
+        };
+        let edit = WorkspaceEdit {
+            changes: HashMap::from([(uri, vec![edit])]),
+            document_changes: None,
+        };
+        if context.apply_edit("Balance tree", edit, &mut logger) {
+            Ok(Value::Null)
+        } else {
+            Err(ResponseError {
+                code: ErrorCode::RequestFailed,
+                message: "the client does not support workspace/applyEdit".to_string(),
+                data: None,
+            })
+        }
+    }
+
+    fn new_example_command(
+        arguments: &[Value],
+        _: &mut EditorState,
+        context: &mut ServerContext,
+        mut logger: &mut dyn Write,
+    ) -> Result<Value, ResponseError> {
+        let uri = match arguments.first().and_then(|a| a.as_str()) {
+            Some(uri) => uri.to_string(),
+            None => match context.workspace_folders.first() {
+                Some(folder) => format!("{}/example.abc", folder.trim_end_matches('/')),
+                None => {
+                    return Err(invalid_params(
+                        "expected the uri of the new document, there is no workspace folder"
+                            .to_string(),
+                    ))
+                }
+            },
+        };
+        if !context.supports_resource_operation("create") {
+            return Err(ResponseError {
+                code: ErrorCode::RequestFailed,
+                message: "the client does not support creating files with workspace/applyEdit"
+                    .to_string(),
+                data: None,
+            });
+        }
+        // creating fails if the file exists, which keeps the whole edit from being applied
+        let edit = WorkspaceEdit {
+            changes: HashMap::new(),
+            document_changes: Some(vec![
+                DocumentChange::Operation(ResourceOperation::Create(CreateFile {
+                    uri: uri.clone(),
+                    options: None,
+                })),
+                DocumentChange::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri.clone(),
+                        version: None,
+                    },
+                    edits: vec![TextEdit {
+                        range: Range {
+                            start: Position {
+                                line: 0,
+                                character: 0,
+                            },
+                            end: Position {
+                                line: 0,
+                                character: 0,
+                            },
+                        },
+                        new_text: EXAMPLE_TREE.to_string(),
+                    }],
+                }),
+            ]),
+        };
+        context.apply_edit("New example tree", edit, &mut logger);
+        Ok(Value::String(uri))
+    }
+
+    /// Why `label` can't be used as a node label, a label is a single printable ascii
+    /// character that is not a space
+    pub(crate) fn validate_label(label: &str) -> Result<(), String> {
+        match is_valid_label(label) {
+            true => Ok(()),
+            false => Err(format!(
+                "{:?} is not a valid label, expected a single character that is not a space",
+                label
+            )),
+        }
+    }
+
+    fn rename_value_command(
+        arguments: &[Value],
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        mut logger: &mut dyn Write,
+    ) -> Result<Value, ResponseError> {
+        let (Some(old), Some(new)) = (
+            arguments.first().and_then(|a| a.as_str()),
+            arguments.get(1).and_then(|a| a.as_str()),
+        ) else {
+            return Err(invalid_params(
+                "expected the old and the new value as arguments".to_string(),
+            ));
+        };
+        validate_label(new).map_err(invalid_params)?;
+        let index = editor_state.open_label_index();
+        let Some(documents) = index.get(old).filter(|_| old != new) else {
+            return Ok(json!(0));
+        };
+        let mut renamed = 0;
+        let changes = documents
+            .iter()
+            .map(|(uri, nodes)| {
+                renamed += nodes.len();
+                let edits = nodes
+                    .iter()
+                    .map(|&i| TextEdit {
+                        range: node_range(i),
+                        new_text: new.to_string(),
+                    })
+                    .collect();
+                (uri.original().to_string(), edits)
+            })
+            .collect();
+        let edit = WorkspaceEdit {
+            changes,
+            document_changes: None,
+        };
+        if context.apply_edit(&format!("Rename {} to {}", old, new), edit, &mut logger) {
+            Ok(json!(renamed))
+        } else {
+            Err(ResponseError {
+                code: ErrorCode::RequestFailed,
+                message: "the client does not support workspace/applyEdit".to_string(),
+                data: None,
+            })
+        }
+    }
+
+    /// Preview of the subtree, its root is shown in the editor if the client can be asked to
+    fn preview_subtree_command(
+        arguments: &[Value],
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<Value, ResponseError> {
+        let (uri, fs) = command_document(arguments, editor_state, context)?;
+        let index = match arguments.get(1).filter(|p| !p.is_null()) {
+            None => 0,
+            Some(position) => {
+                let position: Position = serde_json::from_value(position.clone())
+                    .map_err(|e| invalid_params(format!("invalid position: {}", e)))?;
+                node_at(fs, &position).ok_or(TreeError::PositionNotANode {
+                    uri: uri.clone(),
+                    position,
+                })?
+            }
+        };
+        let limit = context.settings.preview_depth;
+        let depth = match arguments.get(2).and_then(|d| d.as_u64()) {
+            Some(depth) if depth as usize > limit => {
+                return Err(TreeError::DepthLimitExceeded {
+                    uri,
+                    depth: depth as usize,
+                    limit,
+                }
+                .into())
+            }
+            Some(depth) => depth as usize,
+            None => limit,
+        };
+        let preview = preview::subtree(fs, index, depth);
+        let params = ShowDocumentParams {
+            uri,
+            take_focus: Some(true),
+            selection: Some(node_range(index)),
+            ..Default::default()
+        };
+        context.show_document(params, logger);
+        Ok(Value::String(preview))
+    }
+
+    fn export_dot_command(
+        arguments: &[Value],
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        _: &mut dyn Write,
+    ) -> Result<Value, ResponseError> {
+        let (_, fs) = command_document(arguments, editor_state, context)?;
+        Ok(Value::String(fs.to_dot()))
+    }
+
+    /// Response callback for requests whose result is not needed, only logs errors
+    fn log_response_error(
+        result: Result<Value, ResponseError>,
+        _: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        if let Err(e) = result {
+            writeln!(logger, "[Error] request failed: {} {}", e.code, e.message).unwrap();
+        }
+    }
+
+    /// Encode the message in LSP format and write it for the client, with `output`
+    fn send_message<T>(message: &T, output: &Output, logger: &mut dyn Write)
+    where
+        T: Serialize,
+    {
+        send_content(json_to_string(message), output, logger);
+    }
+
+    /// Send the content of a message, once the outgoing interceptors saw it
+    fn send_content(content: String, output: &Output, logger: &mut dyn Write) {
+        let encoded_message = encode_message(output.middleware().outgoing(content));
+        writeln!(
+            logger,
+            "[Sent Message] {}{:?}",
+            trace::tag(),
+            encoded_message
+        )
+        .unwrap();
+        write_message(encoded_message, output);
+    }
+
+    /// Write the encoded message for the client without logging it
+    fn write_message(encoded_message: String, output: &Output) {
+        let _span = trace::enter_write(encoded_message.len());
+        let start = Instant::now();
+        // the output keeps the error for the transport, which stops once the client is gone
+        let _ = output.write_message(encoded_message.as_bytes());
+        trace::record_write(start.elapsed());
+    }
+
+    /// Logging sink that writes to the inner logger, and mirrors every complete line at or above
+    /// `level` to the client as a `window/logMessage` notification
+    pub struct ClientLogger<W: Write> {
+        inner: W,
+        level: MessageType,
+        format: LogFormat, // how lines are written to the inner logger
+        line: Vec<u8>,     // bytes of the current line that hasn't ended yet
+        output: Output,    // where the lines are mirrored to the client
+    }
+
+    impl<W: Write> ClientLogger<W> {
+        pub fn new(inner: W, level: MessageType) -> Self {
+            ClientLogger {
+                inner,
+                level,
+                format: LogFormat::Text,
+                line: Vec::new(),
+                output: Output::default(),
+            }
+        }
+
+        pub fn set_level(&mut self, level: MessageType) {
+            self.level = level;
+        }
+
+        pub fn set_format(&mut self, format: LogFormat) {
+            self.format = format;
+        }
+
+        pub fn set_output(&mut self, output: Output) {
+            self.output = output;
+        }
+
+        pub fn into_inner(self) -> W {
+            self.inner
+        }
+
+        fn forward(&mut self, line: &str) {
+            let message_type = MessageType::from_log_line(line);
+            // lower MessageType values are more severe
+            if message_type as u8 <= self.level as u8 {
+                let notification = LogMessageNotification::new(message_type, line.to_string());
+                let content = self
+                    .output
+                    .middleware()
+                    .outgoing(json_to_string(&notification));
+                write_message(encode_message(content), &self.output);
+            }
+        }
+    }
+
+    impl<W: Write> Write for ClientLogger<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.format == LogFormat::Text {
+                self.inner.write_all(buf)?;
+            }
+            self.line.extend_from_slice(buf);
+            while let Some(n) = self.line.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.line.drain(..=n).collect();
+                let line = String::from_utf8_lossy(&line[..n]).to_string();
+                if self.format == LogFormat::Json {
+                    let level = MessageType::from_log_line(&line).into();
+                    let record = LogRecord::new(&line, level);
+                    writeln!(self.inner, "{}", json_to_string(&record))?;
+                }
+                self.forward(&line);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Show a message to the user with a `window/showMessage` notification
+    pub fn show_message(
+        message_type: MessageType,
+        message: String,
+        output: &Output,
+        logger: &mut dyn Write,
+    ) {
+        send_message(
+            &ShowMessageNotification::new(message_type, message),
+            output,
+            logger,
+        );
+    }
+
+    /// Read the tree files in the workspace folders into the editor state, once the client
+    /// sent `initialized` or when a request needs documents the client didn't open before that,
+    /// so the initialize response isn't delayed
+    fn ensure_workspace_scanned(
+        token: Option<ProgressToken>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        if context.workspace_index.is_initialized() {
+            return;
+        }
+        let start = Instant::now();
+        let folders = context.workspace_folders.clone();
+        let parsed = scan_workspace(folders, token, editor_state, context, logger);
+        context.workspace_index.set(parsed, start.elapsed());
+    }
+
+    /// Read the tree files in the workspace folders into the editor state, returns the number
+    /// of valid trees
+    fn scan_workspace(
+        folders: Vec<String>,
+        token: Option<ProgressToken>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> usize {
+        let roots: Vec<_> = folders.iter().filter_map(|uri| uri_to_path(uri)).collect();
+        if roots.is_empty() {
+            return 0;
+        }
+        let mut total = 0;
+        let progress = context.start_progress(token, "Indexing workspace", logger);
+        let scanner = WorkspaceScanner::new(ScanConfig {
+            max_file_size: context.settings.max_file_size as u64,
+            ..Default::default()
+        });
+        for (i, root) in roots.iter().enumerate() {
+            let (files, stats) = scanner.scan(root);
+            let mut parsed = 0;
+            for (path, content) in files {
+                parsed += editor_state
+                    .modify_file(path_to_uri(&path), content)
+                    .is_ok() as usize;
+            }
+            writeln!(
+                logger,
+                "[Workspace] scanned {}: {}, {} valid trees",
+                root.display(),
+                stats,
+                parsed
+            )
+            .unwrap();
+            total += parsed;
+            if let Some(progress) = &progress {
+                let percentage = ((i + 1) * 100 / roots.len()) as u32;
+                progress.report(
+                    Some(root.display().to_string()),
+                    Some(percentage),
+                    context.output(),
+                    logger,
+                );
+            }
+        }
+        if let Some(progress) = progress {
+            progress.end(None, context.output(), logger);
+        }
+        total
+    }
+
+    /// A change the client made to a document, the whole new text or an edit of a range
+    enum ContentChange {
+        Full(String),
+        Edit(Range, String),
+    }
+
+    /// Byte range of the range in the text, None if a position is not in the text
+    fn byte_range(
+        lines: &LineIndex,
+        range: Range,
+        encoding: PositionEncoding,
+    ) -> Option<std::ops::Range<usize>> {
+        let start = lines.offset(range.start, encoding)?;
+        let end = lines.offset(range.end, encoding)?;
+        (start <= end).then_some(start..end)
+    }
+
+    /// Sync the file with the editor state, reporting progress if the file is large.
+    /// Edits only re-parse what they touch, see `EditorState::edit_file`.
+    /// With `deferred`, a failing document is not reported right away but re-validated once it
+    /// has been left unchanged for the rule's delay, so diagnostics don't flicker while typing.
+    /// Fails with the rule the document breaks
+    fn sync_file(
+        uri: &str,
+        changes: Vec<ContentChange>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+        deferred: bool,
+    ) -> Result<(), DiagnosticRule> {
+        let total_len: usize = changes
+            .iter()
+            .map(|change| match change {
+                ContentChange::Full(text) => text.len(),
+                ContentChange::Edit(_, text) => text.len(),
+            })
+            .sum();
+        let progress = if total_len >= LARGE_FILE_THRESHOLD {
+            context.start_progress(None, "Parsing document", logger)
+        } else {
+            None
+        };
+
+        let max_file_size = context.settings.max_file_size;
+        let change_count = changes.len();
+        let mut parse_error = None; // why the text is not a tree, if the last parsed change made it so
+        let mut too_large = false; // whether the text after the last change is over maxFileSize
+        let mut background = false; // whether the text is parsed once the changes are applied
+
+        // documents of other languages only keep their text in sync
+        let tree_language = editor_state.is_tree_language(uri);
+        for (i, change) in changes.into_iter().enumerate() {
+            // a document larger than maxFileSize keeps its text in sync, but is not parsed
+            let len = match &change {
+                ContentChange::Full(text) => Some(text.len()),
+                ContentChange::Edit(range, text) => editor_state.text(uri).and_then(|current| {
+                    let lines = editor_state.line_index(uri)?;
+                    let bytes = byte_range(lines, *range, context.position_encoding)?;
+                    Some(current.len() - bytes.len() + text.len())
+                }),
+            };
+            // a later change of the batch may bring the document back under the limit
+            if let Some(len) = len.filter(|_| tree_language) {
+                too_large = len > max_file_size;
+                if too_large {
+                    writeln!(
+                        logger,
+                        "[Warning] {} is {} bytes, larger than maxFileSize {}",
+                        uri, len, max_file_size
+                    )
+                    .unwrap();
+                }
+            }
+            // a document that takes long to parse is parsed on its own thread, see `BackgroundParses`
+            background = tree_language
+                && !too_large
+                && len.is_some_and(|len| len >= context.settings.lazy_parse_size);
+            let parse = tree_language && !too_large && !background;
+            match change {
+                ContentChange::Full(text) if !parse => editor_state.set_text(uri, text),
+                ContentChange::Full(text) => {
+                    parse_error = editor_state.modify_file(uri.to_string(), text).err();
+                }
+                ContentChange::Edit(range, text) => {
+                    let applied = editor_state
+                        .line_index(uri)
+                        .and_then(|lines| byte_range(lines, range, context.position_encoding))
+                        .and_then(|bytes| match parse {
+                            true => editor_state.edit_file(uri, bytes, &text),
+                            false => editor_state.edit_text(uri, bytes, &text).then_some(Ok(())),
+                        });
+                    match applied {
+                        Some(Ok(())) => parse_error = None,
+                        Some(Err(e)) => parse_error = Some(e),
+                        None => writeln!(
+                            logger,
+                            "[Error] could not apply the edit of {:?} to {}, it is not in the document",
+                            range, uri
+                        )
+                        .unwrap(),
+                    }
+                }
+            }
+            if let Some(progress) = &progress {
+                let percentage = ((i + 1) * 100 / change_count) as u32;
+                progress.report(None, Some(percentage), context.output(), logger);
+            }
+        }
+        if let Some(progress) = progress {
+            progress.end(Some(format!("Parsed {}", uri)), context.output(), logger);
+        }
+        // the diagnostics are published once the parse is done
+        if background {
+            if let Some(text) = editor_state.text(uri).cloned() {
+                writeln!(logger, "[Parse] parsing {} in the background", uri).unwrap();
+                context.revalidations.cancel(&uri.to_string());
+                let progress = context.start_progress(None, "Indexing document", logger);
+                if let Some(Some(old)) =
+                    context
+                        .background_parses
+                        .start(uri.to_string(), text, progress)
+                {
+                    old.end(None, context.output(), logger);
+                }
+                return Ok(());
+            }
+        } else if let Some(Some(progress)) = context.background_parses.cancel(uri) {
+            progress.end(None, context.output(), logger);
+        }
+
+        let failed_rule = if too_large {
+            Some(DiagnosticRule::FileSize)
+        } else {
+            parse_error.is_some().then_some(DiagnosticRule::Structure)
+        };
+        // the text is only needed to report a failure
+        let failed_text = failed_rule
+            .and_then(|_| editor_state.text(uri))
+            .map(|text| text.to_string());
+        match failed_rule {
+            None => {
+                context.invalid_documents.remove(uri);
+            }
+            Some(DiagnosticRule::Structure) => {
+                if let Some(error) = parse_error {
+                    context.invalid_documents.insert(uri.to_string(), error);
+                }
+                if context.settings.telemetry.enabled {
+                    context.telemetry.record_parse_failure();
+                }
+            }
+            Some(DiagnosticRule::FileSize) => (),
+        }
+
+        match (failed_rule, failed_text) {
+            (Some(rule), Some(text)) if deferred => {
+                let delay = context.settings.diagnostics.delay(rule);
+                writeln!(
+                    logger,
+                    "[Diagnostics] {} failed {:?}, re-validating in {} ms",
+                    uri,
+                    rule,
+                    delay.as_millis()
+                )
+                .unwrap();
+                context
+                    .revalidations
+                    .schedule(uri.to_string(), text, Instant::now() + delay);
+            }
+            (rule, text) => {
+                context.revalidations.cancel(&uri.to_string());
+                let diagnostics = match rule {
+                    Some(rule) => {
+                        rule_diagnostics(uri, rule, text.as_deref().unwrap_or(""), context)
+                    }
+                    None => Vec::new(),
+                };
+                publish_diagnostics(uri, diagnostics, context.output(), logger);
+            }
+        }
+        failed_rule.map_or(Ok(()), Err)
+    }
+
+    /// Tell the user why the document is not parsed
+    fn show_sync_failure(
+        uri: &str,
+        rule: DiagnosticRule,
+        context: &ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        let (typ, message) = match rule {
+            DiagnosticRule::Structure => (
+                MessageType::Error,
+                format!("{} is not a valid complete binary tree", uri),
+            ),
+            DiagnosticRule::FileSize => (
+                MessageType::Warning,
+                format!(
+                    "{} is larger than maxFileSize ({} bytes) and is not parsed",
+                    uri, context.settings.max_file_size
+                ),
+            ),
+        };
+        show_message(typ, message, context.output(), logger);
+    }
+
+    /// Re-validate the documents whose delay ran out at `now`, publishing their diagnostics.
+    /// Documents whose background parse is done get their tree
+    pub fn handle_due_revalidations(
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+        now: Instant,
+    ) {
+        finish_background_parses(editor_state, context, logger);
+        context.expire_requests(now, logger);
+        for (uri, text) in context.revalidations.take_due(now) {
+            writeln!(logger, "[Diagnostics] re-validating {}", uri).unwrap();
+            if let Err(rule) = sync_file(
+                &uri,
+                vec![ContentChange::Full(text)],
+                editor_state,
+                context,
+                logger,
+                false,
+            ) {
+                show_sync_failure(&uri, rule, context, logger);
+            }
+        }
+    }
+
+    /// Install the trees of the documents whose background parse is done and publish their
+    /// diagnostics. A parse of a text the document no longer has is dropped
+    fn finish_background_parses(
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        for finished in context.background_parses.take_finished() {
+            let uri = finished.uri;
+            if let Some(progress) = finished.value {
+                progress.end(Some(format!("Parsed {}", uri)), context.output(), logger);
+            }
+            let Some(result) = finished.result else {
+                writeln!(logger, "[Error] the background parse of {} panicked", uri).unwrap();
+                continue;
+            };
+            if !editor_state
+                .text(&uri)
+                .is_some_and(|text| text.ptr_eq(&finished.text))
+            {
+                continue;
+            }
+            writeln!(logger, "[Parse] parsed {} in the background", uri).unwrap();
+            let diagnostics = match result {
+                Ok(fs) => {
+                    editor_state.set_file_state(&uri, fs);
+                    context.invalid_documents.remove(&uri);
+                    Vec::new()
+                }
+                Err(e) => {
+                    context.invalid_documents.insert(uri.clone(), e);
+                    if context.settings.telemetry.enabled {
+                        context.telemetry.record_parse_failure();
+                    }
+                    let text = finished.text.to_string();
+                    rule_diagnostics(&uri, DiagnosticRule::Structure, &text, context)
+                }
+            };
+            publish_diagnostics(&uri, diagnostics, context.output(), logger);
+        }
+    }
+
+    /// Send the diagnostics of the document, an empty list clears them
+    fn publish_diagnostics(
+        uri: &str,
+        diagnostics: Vec<Diagnostic>,
+        output: &Output,
+        logger: &mut dyn Write,
+    ) {
+        let params = PublishDiagnosticsParams {
+            uri: uri.to_string(),
+            version: None,
+            diagnostics,
+        };
+        send_message(
+            &PublishDiagnosticsNotification::new(PublishDiagnosticsNotification::METHOD, params),
+            output,
+            logger,
+        );
+    }
+
+    /// Diagnostics of a document that failed the rule. A malformed tree is reported on the line
+    /// that broke it, related to the parent level that decides how long the line may be, and
+    /// nodes past the end of a level are marked unnecessary if the client shows such tags
+    fn rule_diagnostics(
+        uri: &str,
+        rule: DiagnosticRule,
+        text: &str,
+        context: &ServerContext,
+    ) -> Vec<Diagnostic> {
+        let encoding = context.position_encoding;
+        let (severity, message) = match rule {
+            DiagnosticRule::Structure => (
+                DiagnosticSeverity::ERROR,
+                "Not a valid complete binary tree",
+            ),
+            DiagnosticRule::FileSize => (
+                DiagnosticSeverity::WARNING,
+                "Document is larger than maxFileSize and was not parsed",
+            ),
+        };
+        let mut diagnostic = Diagnostic {
+            range: Range {
+                start: Position {
+                    line: 0,
+                    character: 0,
+                },
+                end: Position {
+                    line: 0,
+                    character: 0,
+                },
+            },
+            severity: Some(severity),
+            code: Some(DiagnosticCode::String(rule.code().to_string())),
+            code_description: None,
+            source: Some("lsp-rs".to_string()),
+            message: message.to_string(),
+            tags: None,
+            related_information: None,
+        };
+        let error = match (rule, context.invalid_documents.get(uri)) {
+            (DiagnosticRule::Structure, Some(error)) => error,
+            _ => return vec![diagnostic],
+        };
+        diagnostic.range = line_range(text, error.line, error.character, encoding);
+        diagnostic.message = format!("{}: {}", message, error.reason);
+        if error.line > 0 && context.supports_related_information() {
+            let parent = error.line - 1;
+            diagnostic.related_information = Some(vec![DiagnosticRelatedInformation {
+                location: Location {
+                    uri: uri.to_string(),
+                    range: line_range(text, parent, 0, encoding),
+                },
+                message: format!(
+                    "the parent level has {} nodes, so this level has at most {}",
+                    1usize << parent,
+                    1usize << error.line
+                ),
+            }]);
+        }
+        let mut diagnostics = vec![diagnostic];
+
+        let full_len = error.expected;
+        let line_len = text
+            .split('\n')
+            .nth(error.line)
+            .map_or(0, |line| line.trim_end_matches('\r').len());
+        if line_len > full_len && context.supports_diagnostic_tag(DiagnosticTag::UNNECESSARY) {
+            diagnostics.push(Diagnostic {
+                range: line_range(text, error.line, full_len, encoding),
+                severity: Some(DiagnosticSeverity::HINT),
+                code: Some(DiagnosticCode::String(rule.code().to_string())),
+                code_description: None,
+                source: Some("lsp-rs".to_string()),
+                message: "Nodes past the end of the level".to_string(),
+                tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+                related_information: None,
+            });
+        }
+        diagnostics
+    }
+
+    /// Range from the byte `from` of the line to its end, without the line ending. Empty at
+    /// the end of the text if the line is past it
+    fn line_range(text: &str, line: usize, from: usize, encoding: PositionEncoding) -> Range {
+        let mut start = 0;
+        for _ in 0..line {
+            match text[start..].find('\n') {
+                Some(i) => start += i + 1,
+                None => start = text.len(),
+            }
+        }
+        let content = text[start..].split('\n').next().unwrap_or("");
+        let end = start + content.trim_end_matches('\r').len();
+        let position = |offset: usize| {
+            position_at(text, offset, encoding).unwrap_or(Position {
+                line: line as i32,
+                character: 0,
+            })
+        };
+        Range {
+            start: position((start + from).min(end)),
+            end: position(end),
+        }
+    }
+
+    /// Sync files changed outside of the editor with the disk.
+    /// Files open in the editor are skipped, the client sends their content
+    fn reload_watched_files(
+        changes: Vec<FileEvent>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        for change in changes {
+            if editor_state.is_open(&change.uri) {
+                continue;
+            }
+            let content = match change.change_type {
+                FileChangeType::DELETED => None,
+                _ => uri_to_path(&change.uri)
+                    .and_then(|path| read_document(&path))
+                    .map(|(content, _)| content),
+            };
+            match content {
+                Some(content) => {
+                    writeln!(logger, "[Watched] reloading {}", change.uri).unwrap();
+                    // the diagnostics tell the user if the file is no longer valid
+                    let _ = sync_file(
+                        &change.uri,
+                        vec![ContentChange::Full(content)],
+                        editor_state,
+                        context,
+                        logger,
+                        false,
+                    );
+                }
+                None => {
+                    writeln!(logger, "[Watched] removing {}", change.uri).unwrap();
+                    context.invalid_documents.remove(&change.uri);
+                    if editor_state.remove_file(&change.uri).is_some() {
+                        publish_diagnostics(&change.uri, Vec::new(), context.output(), logger);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the uri is `folder` or inside it, as spelled by the client
+    fn is_under(uri: &str, folder: &str) -> bool {
+        uri == folder || uri.starts_with(&format!("{}/", folder.trim_end_matches('/')))
+    }
+
+    /// Follow files and folders renamed by the client, documents keep their state under
+    /// their new uri and the diagnostics of the old uri are cleared
+    fn rename_documents(
+        renames: Vec<FileRename>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        for rename in renames {
+            for (old_uri, new_uri) in editor_state.rename_files(&rename.old_uri, &rename.new_uri) {
+                writeln!(logger, "[FileOperations] moving {} to {}", old_uri, new_uri).unwrap();
+                publish_diagnostics(old_uri.original(), Vec::new(), context.output(), logger);
+            }
+            let invalid: Vec<String> = context
+                .invalid_documents
+                .keys()
+                .filter(|uri| is_under(uri, &rename.old_uri))
+                .cloned()
+                .collect();
+            let old_prefix = rename.old_uri.trim_end_matches('/').len();
+            for uri in invalid {
+                let error = context.invalid_documents.remove(&uri).unwrap();
+                context.revalidations.cancel(&uri);
+                let new_uri = format!(
+                    "{}{}",
+                    rename.new_uri.trim_end_matches('/'),
+                    &uri[old_prefix..]
+                );
+                context.invalid_documents.insert(new_uri, error);
+                publish_diagnostics(&uri, Vec::new(), context.output(), logger);
+            }
+        }
+    }
+
+    /// Forget files and folders deleted by the client, and clear their diagnostics
+    fn delete_documents(
+        deletes: Vec<FileDelete>,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) {
+        for delete in deletes {
+            for uri in editor_state.remove_files(&delete.uri) {
+                writeln!(logger, "[FileOperations] removing {}", uri).unwrap();
+                publish_diagnostics(uri.original(), Vec::new(), context.output(), logger);
+            }
+            let invalid: Vec<String> = context
+                .invalid_documents
+                .keys()
+                .filter(|uri| is_under(uri, &delete.uri))
+                .cloned()
+                .collect();
+            for uri in invalid {
+                context.invalid_documents.remove(&uri);
+                context.revalidations.cancel(&uri);
+                publish_diagnostics(&uri, Vec::new(), context.output(), logger);
+            }
+        }
+    }
+
+    /// Dynamically register the language features handled by the embedder, so clients send
+    /// them for tree files. Custom methods outside the LSP namespaces need no registration
+    fn register_handlers(context: &mut ServerContext, logger: &mut dyn Write) {
+        let registrations: Vec<Registration> = context
+            .handlers
+            .keys()
+            .filter(|method| {
+                method.starts_with("textDocument/") || method.starts_with("workspace/")
+            })
+            .map(|method| Registration {
+                id: format!("{}/{}", config::SECTION, method),
+                method: method.clone(),
+                register_options: method
+                    .starts_with("textDocument/")
+                    .then(|| json!({ "documentSelector": [{ "pattern": "**/*.abc" }] })),
+            })
+            .collect();
+        context.register_capabilities(registrations, logger);
+    }
+
+    /// Text shown when hovering the position, depending on the configured verbosity.
+    /// As markdown, labels are bold and the subtree is in a code block. None if there is
+    /// nothing to show
+    fn hover_text(
+        fs: &FileState,
+        position: &Position,
+        settings: &Settings,
+        kind: MarkupKind,
+        encoding: PositionEncoding,
+    ) -> Option<String> {
+        let verbosity = settings.hover_verbosity;
+        let line_num = position.line as u32;
+        // labels and separators alternate, so every even byte of a line is a node. Past the
+        // end of the line there is nothing
+        let column = usize::try_from(position.line)
+            .ok()
+            .and_then(|line| fs.line(line))
+            .zip(usize::try_from(position.character).ok())
+            .and_then(|(line, character)| column_to_byte(&line, character, encoding))?;
+        let n = usize::pow(2, line_num) - 1;
+        let index = n + column / 2;
+        if !column.is_multiple_of(2) {
+            return match verbosity {
+                HoverVerbosity::Minimal => None,
+                _ => Some(format!("Character count: {}", fs.get_char_count())),
+            };
+        }
+
+        let label = |c: &str| match kind {
+            MarkupKind::PlainText => c.to_string(),
+            MarkupKind::Markdown => format!("**{}**", escape_markdown(c)),
+        };
+        let parent = match fs.parent(index) {
+            Some(c) => format!("Parent: {}", label(c)),
+            None if index == 0 => "Root node".to_string(),
+            None => format!("Could not find parent to {} {}", index, (index - 1) / 2),
+        };
+        match verbosity {
+            HoverVerbosity::Verbose => {
+                let child = |c: Option<&str>| c.map_or("none".to_string(), &label);
+                let subtree = preview::subtree(fs, index, settings.preview_depth);
+                let lines = [
+                    format!(
+                        "Node: {} (index {})",
+                        fs.get(index).map_or("none".to_string(), &label),
+                        index
+                    ),
+                    parent,
+                    format!("Left child: {}", child(fs.left_child(index))),
+                    format!("Right child: {}", child(fs.right_child(index))),
+                ];
+                Some(match kind {
+                    MarkupKind::PlainText => format!("{}\nSubtree:\n{}", lines.join("\n"), subtree),
+                    // markdown needs an empty line to break the line
+                    MarkupKind::Markdown => {
+                        format!(
+                            "{}\n\nSubtree:\n```\n{}\n```",
+                            lines.join("\n\n"),
+                            subtree.trim_end()
+                        )
+                    }
+                })
+            }
+            _ => Some(parent),
+        }
+    }
+
+    /// Fill in the detail and the documentation of the item, the subtree the completed node
+    /// would have. Items without data, or whose document is gone, are returned unchanged
+    fn resolve_completion_item(
+        mut item: CompletionItem,
+        editor_state: &EditorState,
+        kind: MarkupKind,
+        depth: usize,
+    ) -> CompletionItem {
+        let Some(data) = item
+            .data
+            .as_ref()
+            .and_then(|data| serde_json::from_value::<CompletionItemData>(data.clone()).ok())
+        else {
+            return item;
+        };
+        let Some(fs) = editor_state.get_file_state(data.uri) else {
+            return item;
+        };
+        item.detail = Some(data.detail);
+        // the existing subtree under the node, with the completed label as its root
+        let label = item.label.trim();
+        let subtree = match fs.get(data.index).is_some() {
+            true => {
+                let subtree = preview::subtree(fs, data.index, depth);
+                let (_, below) = subtree.split_once('\n').unwrap_or_default();
+                match below.is_empty() {
+                    true => label.to_string(),
+                    false => format!("{}\n{}", label, below),
+                }
+            }
+            false => label.to_string(),
+        };
+        item.documentation = Some(preview_markup(subtree, kind));
+        item
+    }
+
+    /// A subtree preview in the markup the client shows, as a code block in markdown
+    fn preview_markup(subtree: String, kind: MarkupKind) -> MarkupContent {
+        MarkupContent {
+            kind,
+            value: match kind {
+                MarkupKind::PlainText => subtree,
+                MarkupKind::Markdown => format!("```\n{}\n```", subtree),
+            },
+        }
+    }
+
+    /// Escape the characters markdown would format
+    fn escape_markdown(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if "\\`*_{}[]()#+-.!|<>~".contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// Index of the node under the position, None on separators or outside of the tree
+    pub(crate) fn node_at(fs: &FileState, position: &Position) -> Option<usize> {
+        let (line, character) = (position.line as u32, position.character as usize);
+        if position.line < 0 || position.character < 0 || !character.is_multiple_of(2) {
+            return None;
+        }
+        // lines past the last level of the largest tree don't overflow
+        let width = 2usize.checked_pow(line)?;
+        let index = width - 1 + character / 2;
+        (character / 2 < width && fs.get(index).is_some()).then_some(index)
+    }
+
+    /// Range of the node's label in the document
+    pub(crate) fn node_range(index: usize) -> Range {
+        let (line, character) = index_to_position(index);
+        Range {
+            start: Position {
+                line: line as i32,
+                character: character as i32,
+            },
+            end: Position {
+                line: line as i32,
+                character: character as i32 + 1,
+            },
+        }
+    }
+
+    /// Indices of the leaves under the node, left to right. A leaf is its own only leaf
+    pub(crate) fn leaf_descendants(fs: &FileState, index: usize) -> Vec<usize> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![index];
+        while let Some(i) = stack.pop() {
+            if fs.get(i).is_none() {
+                continue;
+            }
+            if fs.is_leaf(i) {
+                leaves.push(i);
+            } else {
+                stack.push(2 * i + 2);
+                stack.push(2 * i + 1);
+            }
+        }
+        leaves
+    }
+
+    /// Index of the first node with the same label as the node, where the label is declared.
+    /// Nodes are stored in document order
+    pub(crate) fn first_occurrence(fs: &FileState, index: usize) -> Option<usize> {
+        fs.occurrences(index).first().copied()
+    }
+
+    /// Every node with the same label as the node, in the document first and then in the other
+    /// known documents by uri, one chunk per document. Without `include_declaration` the
+    /// first occurrence in the document is left out. Gives up between documents once cancelled
+    fn references(
+        editor_state: &EditorState,
+        uri: &str,
+        fs: &FileState,
+        index: usize,
+        include_declaration: bool,
+        cancellation: &CancellationToken,
+    ) -> Result<Vec<Vec<Location>>, Cancelled> {
+        let locations = |uri: &str, nodes: Vec<usize>| -> Vec<Location> {
+            nodes
+                .into_iter()
+                .map(|i| Location {
+                    uri: uri.to_string(),
+                    range: node_range(i),
+                })
+                .collect()
+        };
+        let Some(label) = fs.get(index) else {
+            return Ok(Vec::new());
+        };
+        let mut nodes = fs.occurrences(index);
+        if !include_declaration && !nodes.is_empty() {
+            nodes.remove(0);
+        }
+        let mut chunks = vec![locations(uri, nodes)];
+        let key = editor_state.uri(uri);
+        for (other_uri, nodes) in editor_state.find_in_all(label) {
+            cancellation.check()?;
+            if *other_uri != key {
+                chunks.push(locations(other_uri.original(), nodes));
+            }
+        }
+        chunks.retain(|chunk| !chunk.is_empty());
+        Ok(chunks)
+    }
+
+    /// Nodes whose label contains the query ignoring case, one chunk per document by uri.
+    /// With `resolve_ranges` the ranges are left out, for workspaceSymbol/resolve to fill in.
+    /// Gives up between documents once cancelled
+    fn workspace_symbols(
+        editor_state: &EditorState,
+        query: &str,
+        resolve_ranges: bool,
+        cancellation: &CancellationToken,
+    ) -> Result<Vec<Vec<WorkspaceSymbol>>, Cancelled> {
+        let query = query.to_lowercase();
+        let mut files: Vec<_> = editor_state.documents().collect();
+        files.sort_by_key(|(uri, _)| *uri);
+        files
+            .into_iter()
+            .map(|(uri, fs)| {
+                cancellation.check()?;
+                Ok(fs
+                    .iter_bfs()
+                    .filter(|(_, label)| label.to_lowercase().contains(&query))
+                    .map(|(i, label)| WorkspaceSymbol {
+                        name: label.to_string(),
+                        kind: SymbolKind::VARIABLE,
+                        container_name: Some(format!("node {}", i)),
+                        location: match resolve_ranges {
+                            true => SymbolLocation::Uri {
+                                uri: uri.original().to_string(),
+                            },
+                            false => SymbolLocation::Full(Location {
+                                uri: uri.original().to_string(),
+                                range: node_range(i),
+                            }),
+                        },
+                        data: resolve_ranges.then(|| json!({ "index": i })),
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .filter(|chunk| chunk.as_ref().map_or(true, |chunk| !chunk.is_empty()))
+            .collect()
+    }
+
+    /// Fill in the range of a symbol returned without one, if its node still has its label
+    fn resolve_workspace_symbol(
+        mut symbol: WorkspaceSymbol,
+        editor_state: &EditorState,
+    ) -> WorkspaceSymbol {
+        let SymbolLocation::Uri { uri } = &symbol.location else {
+            return symbol;
+        };
+        let index = symbol
+            .data
+            .as_ref()
+            .and_then(|data| data.get("index"))
+            .and_then(|index| index.as_u64())
+            .map(|index| index as usize);
+        let Some(fs) = editor_state.get_file_state(uri.clone()) else {
+            return symbol;
+        };
+        if let Some(index) = index.filter(|&i| fs.get(i) == Some(&symbol.name)) {
+            symbol.location = SymbolLocation::Full(Location {
+                uri: uri.clone(),
+                range: node_range(index),
+            });
+        }
+        symbol
+    }
+
+    /// Semantic tokens of the nodes, one chunk per level. The position of a token is relative
+    /// to the token before it, also across chunks, so the chunks add up to the whole result
+    fn semantic_tokens(fs: &FileState) -> Vec<SemanticTokensPartialResult> {
+        let mut chunks: Vec<SemanticTokensPartialResult> = Vec::new();
+        let mut previous = Position {
+            line: 0,
+            character: 0,
+        };
+        let mut labels = HashSet::new();
+        for (index, label) in fs.iter_bfs() {
+            let start = node_range(index).start;
+            let delta_line = (start.line - previous.line) as u32;
+            let delta_start = match delta_line {
+                0 => start.character - previous.character,
+                _ => start.character,
+            } as u32;
+            let token_type = match index {
+                0 => SemanticTokenType::Root,
+                _ if fs.left_child(index).is_some() || fs.right_child(index).is_some() => {
+                    SemanticTokenType::Node
+                }
+                _ => SemanticTokenType::Leaf,
+            };
+            // the first node with a label declares it, like for textDocument/declaration
+            let modifiers = u32::from(labels.insert(label));
+            if chunks.is_empty() || delta_line > 0 {
+                chunks.push(SemanticTokensPartialResult { data: Vec::new() });
+            }
+            let chunk = chunks.last_mut().unwrap();
+            chunk
+                .data
+                .extend([delta_line, delta_start, 1, token_type as u32, modifiers]);
+            previous = start;
+        }
+        chunks
+    }
+
+    /// Chunks of the result of a request, sent with `$/progress` if the client gave a
+    /// `partialResultToken`. Returns the chunks left for the final result, none if they were
+    /// sent. Stops sending chunks once cancelled
+    fn stream_partial_results<C: Serialize>(
+        token: Option<&ProgressToken>,
+        chunks: Vec<C>,
+        cancellation: &CancellationToken,
+        output: &Output,
+        logger: &mut dyn Write,
+    ) -> Result<Vec<C>, Cancelled> {
+        match token {
+            Some(token) => {
+                for chunk in chunks {
+                    cancellation.check()?;
+                    send_message(
+                        &PartialResultNotification::new(token.clone(), chunk),
+                        output,
+                        logger,
+                    );
+                }
+                Ok(Vec::new())
+            }
+            None => Ok(chunks),
+        }
+    }
+
+    /// Path from the root to the node, `L` for every left child and `R` for every right child.
+    /// The root's path is empty
+    pub(crate) fn node_path(index: usize) -> String {
+        let mut path = Vec::new();
+        let mut i = index;
+        while i > 0 {
+            path.push(if i % 2 == 1 { 'L' } else { 'R' });
+            i = (i - 1) / 2;
+        }
+        path.iter().rev().collect()
+    }
+
+    /// Call hierarchy item for a node, the node index is kept in `data` to find it again
+    pub(crate) fn call_hierarchy_item(
+        uri: &str,
+        fs: &FileState,
+        index: usize,
+    ) -> CallHierarchyItem {
+        CallHierarchyItem {
+            name: fs.get(index).map(str::to_string).unwrap_or_default(),
+            kind: SymbolKind::FUNCTION,
+            detail: Some(format!("node {}", index)),
+            uri: uri.to_string(),
+            range: node_range(index),
+            selection_range: node_range(index),
+            data: Some(json!({ "index": index })),
+        }
+    }
+
+    /// Node of a call hierarchy item sent back by the client, by its stored index as long as
+    /// the label there is unchanged, otherwise by the node at its position
+    pub(crate) fn call_hierarchy_node(fs: &FileState, item: &CallHierarchyItem) -> Option<usize> {
+        let stored = item
+            .data
+            .as_ref()
+            .and_then(|data| data.get("index"))
+            .and_then(|index| index.as_u64())
+            .map(|index| index as usize);
+        match stored {
+            Some(index) if fs.get(index) == Some(&item.name) => Some(index),
+            _ => node_at(fs, &item.selection_range.start)
+                .filter(|&index| fs.get(index) == Some(&item.name)),
+        }
+    }
+
+    /// Answer a message that is neither a request, a notification nor a response: with a
+    /// parse error if it is not json, otherwise with an invalid request to its id if it has one
+    fn invalid_message_response(message: &str, error: &MsgParseError) -> String {
+        let (code, id) = match json_from_string::<Value>(message) {
+            Err(_) => (ErrorCode::ParseError, Value::Null),
+            Ok(value) => (
+                ErrorCode::InvalidRequest,
+                value
+                    .get("id")
+                    .filter(|id| id.is_i64() || id.is_string())
+                    .cloned()
+                    .unwrap_or(Value::Null),
+            ),
+        };
+        let error = ResponseError {
+            code,
+            message: error.to_string(),
+            data: None,
+        };
+        json_to_string(&json!({ "jsonrpc": "2.0", "id": id, "error": error }))
+    }
+
+    /// Answer a frame the reader skipped without reading its content, the id of the message
+    /// is unknown so the error is sent with a null id
+    pub fn reply_to_skipped_frame(error: &FrameError, output: &Output, logger: &mut dyn Write) {
+        let (code, data) = match error {
+            FrameError::Malformed(_) => (ErrorCode::ParseError, None),
+            FrameError::ContentTooLarge { limit, .. } | FrameError::HeaderTooLarge { limit } => {
+                (ErrorCode::InvalidRequest, Some(json!({ "limit": limit })))
+            }
+        };
+        let error = ResponseError {
+            code,
+            message: error.to_string(),
+            data,
+        };
+        send_message(
+            &json!({ "jsonrpc": "2.0", "id": null, "error": error }),
+            output,
+            logger,
+        );
+    }
+
+    /// Given an arbitrary message (with method field), handle the message accordingly
+    /// If initialize request, send the initialize response
+    /// If didOpen or didChange, sync the editor_state
+    /// If hover request, resond with hover response
+    /// A message that can't be handled is answered with an error response if it is a request
+    /// A JSON-RPC batch is handled element by element, see `handle_batch`
+    /// Writing debugging information to the logger is optional
+    pub fn handle_message(
+        message: String,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<(), MsgParseError> {
+        handle_message_with(message, editor_state, context, logger)
+    }
+
+    /// Handle a message like `handle_message`, with an editor state other threads read too.
+    /// Requests that only read documents take the read lock, everything else the write lock
+    pub fn handle_shared_message(
+        message: String,
+        editor_state: &SharedEditorState,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<(), MsgParseError> {
+        handle_message_with(message, &mut editor_state.clone(), context, logger)
+    }
+
+    /// How a handler gets to the editor state. Queries only read it, everything else may
+    /// change it
+    trait EditorAccess {
+        fn query<T>(&mut self, f: impl FnOnce(&EditorState) -> T) -> T;
+        fn update<T>(&mut self, f: impl FnOnce(&mut EditorState) -> T) -> T;
+    }
+
+    impl EditorAccess for EditorState {
+        fn query<T>(&mut self, f: impl FnOnce(&EditorState) -> T) -> T {
+            f(self)
+        }
+
+        fn update<T>(&mut self, f: impl FnOnce(&mut EditorState) -> T) -> T {
+            f(self)
+        }
+    }
+
+    /// The lock is only held while the handler runs
+    impl EditorAccess for SharedEditorState {
+        fn query<T>(&mut self, f: impl FnOnce(&EditorState) -> T) -> T {
+            f(&self.read())
+        }
+
+        fn update<T>(&mut self, f: impl FnOnce(&mut EditorState) -> T) -> T {
+            f(&mut self.write())
+        }
+    }
+
+    fn handle_message_with(
+        message: String,
+        editor_state: &mut impl EditorAccess,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<(), MsgParseError> {
+        if message.trim_start().starts_with('[') {
+            return handle_batch(message, editor_state, context, logger);
+        }
+        let (response, result) = handle_single_message(message, editor_state, context, logger);
+        if let Some(response) = response {
+            send_content(response, context.output(), logger);
+        }
+        result
+    }
+
+    /// Handle every message of the batch in order, and answer with a single array of the
+    /// responses, nothing if there are none. Messages of the batch that can't be handled are
+    /// only logged, an invalid or empty batch is answered with an error
+    fn handle_batch(
+        message: String,
+        editor_state: &mut impl EditorAccess,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<(), MsgParseError> {
+        // the elements are only split apart, each is parsed when it is handled
+        let messages = match serde_json::from_str::<Vec<&RawValue>>(&message) {
+            Ok(messages) if messages.is_empty() => Err(MsgParseError(String::from("Empty batch"))),
+            Ok(messages) => Ok(messages),
+            Err(e) => Err(MsgParseError(e.to_string())),
+        };
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(e) => {
+                send_content(
+                    invalid_message_response(&message, &e),
+                    context.output(),
+                    logger,
+                );
+                return Err(e);
+            }
+        };
+        writeln!(logger, "[Batch] {} messages", messages.len()).unwrap();
+        let mut responses = Vec::new();
+        for message in messages {
+            // elements of a batch are never batches themselves
+            let (response, result) =
+                handle_single_message(message.get().to_string(), editor_state, context, logger);
+            if let Err(e) = result {
+                writeln!(logger, "[Error] Error handling message in batch {}", e).unwrap();
+            }
+            // the interceptors see each response, as if it was sent on its own
+            responses
+                .extend(response.map(|response| context.output().middleware().outgoing(response)));
+        }
+        writeln!(logger, "[Batch] replied with {} responses", responses.len()).unwrap();
+        if !responses.is_empty() {
+            let encoded_message = encode_message(format!("[{}]", responses.join(",")));
+            writeln!(
+                logger,
+                "[Sent Message] {}{:?}",
+                trace::tag(),
+                encoded_message
+            )
+            .unwrap();
+            write_message(encoded_message, context.output());
+        }
+        Ok(())
+    }
+
+    /// Handle a message that is not a batch. Returns the content of the response, if the
+    /// message needs one, for the caller to send, and an Err if it couldn't be handled
+    fn handle_single_message(
+        message: String,
+        editor_state: &mut impl EditorAccess,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> (Option<String>, Result<(), MsgParseError>) {
+        let message = context.output().middleware().incoming(message);
+        let (method, request_id, params) = match IncomingMessage::parse(&message) {
+            Ok(IncomingMessage::Request { id, method, params }) => (method, Some(id), params),
+            Ok(IncomingMessage::Notification { method, params }) => (method, None, params),
+            Ok(IncomingMessage::Response(response)) => {
+                return (None, context.handle_response(response, logger))
+            }
+            Err(e) => return (Some(invalid_message_response(&message, &e)), Err(e)),
+        };
+        trace::set_method(&method);
+        writeln!(logger, "[Method] {}{}", trace::tag(), method).unwrap();
+        writeln!(logger, "[Content] {}", message).unwrap();
+        context.log_trace(&method, request_id.as_ref(), &message, logger);
+        if context.settings.telemetry.enabled {
+            context
+                .telemetry
+                .record_message(&method, request_id.is_some());
+        }
+        let Some(id) = request_id else {
+            context.cancellation = CancellationToken::new();
+            let _span = trace::enter_handler(&method);
+            // notifications have no response
+            let result = dispatch_message(&method, None, params, editor_state, context, logger);
+            return (None, result.map(|_| ()));
+        };
+        let _span = trace::enter_handler(&method);
+        // the token may already be cancelled, if the client cancelled the request while queued
+        context.cancellation = context.cancellations.begin(&id);
+        let result = if context.cancellation.is_cancelled() {
+            writeln!(logger, "[Cancel] {} was cancelled before it started", id).unwrap();
+            Ok(Some(json_to_string(&ErrorResponse::new(
+                id.clone(),
+                Cancelled.into(),
+            ))))
+        } else if !context.initialize_received && method != InitializeRequest::METHOD {
+            let error = ResponseError {
+                code: ErrorCode::ServerNotInitialized,
+                message: format!("{} was sent before initialize", method),
+                data: None,
+            };
+            Ok(Some(json_to_string(&ErrorResponse::new(id.clone(), error))))
+        } else if let Some(hint) = context.settings.disabled_hint(&method) {
+            let error = ResponseError {
+                code: ErrorCode::FeatureDisabled,
+                message: format!("{} is disabled by the configuration", method),
+                data: Some(json!({ "hint": hint })),
+            };
+            Ok(Some(json_to_string(&ErrorResponse::new(id.clone(), error))))
+        } else {
+            dispatch_message(
+                &method,
+                Some(id.clone()),
+                params,
+                editor_state,
+                context,
+                logger,
+            )
+        };
+        context.cancellations.finish(&id);
+        match result {
+            Ok(response) => (response, Ok(())),
+            // a request whose params could not be parsed still gets its response
+            Err(e) => {
+                let error = ResponseError {
+                    code: ErrorCode::InvalidParams,
+                    message: e.to_string(),
+                    data: None,
+                };
+                let response = json_to_string(&ErrorResponse::new(id, error));
+                (Some(response), Err(e))
+            }
+        }
+    }
+
+    /// Handlers of the methods the server implements itself, see `router`
+    mod handlers {
+        use super::*;
+
+        pub fn initialize(
+            params: InitializeParams,
+            _: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<InitializeResult, ResponseError> {
+            let start = Instant::now();
+            writeln!(
+                logger,
+                "[Initialize] Recieved from {:?}",
+                params.client_info
+            )
+            .unwrap();
+            context.initialize_received = true;
+            let capabilities = params.capabilities.as_ref();
+            let workspace = capabilities.and_then(|c| c.workspace.as_ref());
+            context.configuration = workspace.and_then(|w| w.configuration).unwrap_or(false);
+            context.dynamic_registration = capabilities
+                .map(dynamic_registration_methods)
+                .unwrap_or_default();
+            let window = capabilities.and_then(|c| c.window.as_ref());
+            context.work_done_progress = window
+                .as_ref()
+                .and_then(|w| w.work_done_progress)
+                .unwrap_or(false);
+            context.apply_edit = workspace.and_then(|w| w.apply_edit).unwrap_or(false);
+            context.trace_value = params.trace.unwrap_or_default();
+            context.position_encoding = PositionEncoding::negotiate(
+                capabilities
+                    .and_then(|c| c.general.as_ref())
+                    .and_then(|g| g.position_encodings.as_deref())
+                    .unwrap_or_default(),
+            );
+            context.resource_operations = workspace
+                .and_then(|w| w.workspace_edit.as_ref())
+                .and_then(|e| e.resource_operations.clone())
+                .unwrap_or_default();
+            context.show_document = window
+                .and_then(|w| w.show_document.as_ref())
+                .is_some_and(|s| s.support);
+            if let Some(options) = &params.initialization_options {
+                context.update_settings(options, logger);
+            }
+            let mut result = InitializeResult::new("LSP-Server".to_string(), "0".to_string());
+            result.capabilities.execute_command_provider = Some(ExecuteCommandOptions {
+                commands: context.commands.commands(),
+            });
+            result.capabilities.position_encoding = Some(context.position_encoding);
+            result.server_info.startup_time_ms = Some(start.elapsed().as_secs_f64() * 1000.0);
+
+            // the folders are scanned once the client sent `initialized`
+            context.workspace_folders = match params.workspace_folders {
+                Some(folders) => folders.into_iter().map(|f| f.uri).collect(),
+                None => params.root_uri.into_iter().collect(),
+            };
+            context.client_capabilities = params.capabilities.unwrap_or_default();
+            Ok(result)
+        }
+
+        pub fn initialized(
+            _: IgnoredAny,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            if context.initialized {
+                writeln!(logger, "[Initialized] ignoring a repeated initialized").unwrap();
+                return;
+            }
+            // the client only takes requests from the server once it sent `initialized`,
+            // and work done here doesn't delay the initialize response
+            context.pull_configuration(logger);
+            context.update_file_watcher(logger);
+            register_handlers(context, logger);
+            context.initialized = true;
+            for warning in std::mem::take(&mut context.startup_warnings) {
+                show_message(MessageType::Warning, warning, context.output(), logger);
+            }
+            // only workspace completion needs the other documents
+            if context.settings.completion.workspace {
+                ensure_workspace_scanned(None, editor_state, context, logger);
+            }
+        }
+
+        pub fn shutdown(
+            _: IgnoredAny,
+            _: &EditorState,
+            _: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<(), ResponseError> {
+            Ok(())
+        }
+
+        pub fn did_open(
+            params: DidOpenTextDocumentParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            let document = params.text_document;
+            writeln!(
+                logger,
+                "[Initialize] Recieved didOpen on file {} with version {}",
+                document.uri, document.version
+            )
+            .unwrap();
+            editor_state.set_open(&document.uri, true);
+            editor_state.set_version(&document.uri, document.version);
+            editor_state.set_language_id(&document.uri, &document.language_id);
+            let synced = sync_file(
+                &document.uri,
+                vec![ContentChange::Full(document.text.clone())],
+                editor_state,
+                context,
+                logger,
+                false,
+            );
+            if let Err(rule) = synced {
+                writeln!(
+                    logger,
+                    "[Error] open {} file with text {:?} not successful",
+                    document.uri, document.text
+                )
+                .unwrap();
+                show_sync_failure(&document.uri, rule, context, logger);
+            } else {
+                writeln!(
+                    logger,
+                    "[DidOpen] open {} file with text {:?} successful",
+                    document.uri, document.text
+                )
+                .unwrap();
+            }
+        }
+
+        pub fn did_change(
+            params: DidChangeTextDocumentParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            writeln!(
+                logger,
+                "[DidChange] Recieved didChange on file {} with version {}",
+                params.text_document.uri, params.text_document.version
+            )
+            .unwrap();
+            let uri = &params.text_document.uri;
+            let version = params.text_document.version.into();
+            if !editor_state.advance_version(uri, version) {
+                writeln!(
+                    logger,
+                    "[Warning] ignoring didChange of {} with version {}, it is already at version {}",
+                    uri,
+                    version,
+                    editor_state.version(uri).unwrap_or_default()
+                )
+                .unwrap();
+                return;
+            }
+            editor_state.set_dirty(uri, true);
+            let changes = params
+                .content_changes
+                .into_iter()
+                .map(|change| match change.range {
+                    Some(range) => ContentChange::Edit(range, change.text),
+                    None => ContentChange::Full(change.text),
+                })
+                .collect();
+            let synced = sync_file(uri, changes, editor_state, context, logger, true);
+            if synced.is_err() {
+                // reported by handle_due_revalidations if the document stays invalid
+                writeln!(
+                    logger,
+                    "[DidChange] modify {} file with text not successful",
+                    uri
+                )
+                .unwrap();
+            } else {
+                writeln!(logger, "[DidChange] modify {} file successful", uri).unwrap();
+            }
+        }
+
+        pub fn set_trace(
+            params: SetTraceParams,
+            _: &mut EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) {
+            context.trace_value = params.value;
+        }
+
+        pub fn cancel_request(
+            params: CancelParams,
+            _: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            // the request may have been answered already, which is fine
+            if context.cancellations.cancel(&params.id) {
+                writeln!(logger, "[Cancel] cancelled request {}", params.id).unwrap();
+            }
+        }
+
+        pub fn did_change_watched_files(
+            params: DidChangeWatchedFilesParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            reload_watched_files(params.changes, editor_state, context, logger);
+        }
+
+        pub fn did_create_files(
+            params: CreateFilesParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            // created files are read from the disk, like watched files
+            let changes = params
+                .files
+                .into_iter()
+                .map(|file| FileEvent {
+                    uri: file.uri,
+                    change_type: FileChangeType::CREATED,
+                })
+                .collect();
+            reload_watched_files(changes, editor_state, context, logger);
+        }
+
+        pub fn will_rename_files(
+            _: RenameFilesParams,
+            _: &EditorState,
+            _: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<WorkspaceEdit>, ResponseError> {
+            // documents don't refer to each other, so moving one needs no edits
+            Ok(None)
+        }
+
+        pub fn did_rename_files(
+            params: RenameFilesParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            rename_documents(params.files, editor_state, context, logger);
+        }
+
+        pub fn will_delete_files(
+            _: DeleteFilesParams,
+            _: &EditorState,
+            _: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<WorkspaceEdit>, ResponseError> {
+            Ok(None)
+        }
+
+        pub fn did_delete_files(
+            params: DeleteFilesParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            delete_documents(params.files, editor_state, context, logger);
+        }
+
+        pub fn did_close(
+            params: DidCloseTextDocumentParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) {
+            editor_state.set_open(&params.text_document.uri, false);
+            context.revalidations.cancel(&params.text_document.uri);
+        }
+
+        pub fn did_save(
+            params: DidSaveTextDocumentParams,
+            editor_state: &mut EditorState,
+            _: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            writeln!(logger, "[DidSave] saved {}", params.text_document.uri).unwrap();
+            editor_state.set_dirty(&params.text_document.uri, false);
+        }
+
+        pub fn did_change_configuration(
+            params: DidChangeConfigurationParams,
+            _: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) {
+            // clients that support pulling often only signal that something changed
+            if !params.settings.is_null() || !context.pull_configuration(logger) {
+                context.update_settings(&params.settings, logger);
+            }
+        }
+
+        pub fn completion(
+            params: CompletionParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<CompletionList, ResponseError> {
+            if context.settings.completion.workspace {
+                let token = params.work_done.work_done_token.clone();
+                ensure_workspace_scanned(token, editor_state, context, logger);
+            }
+            let uri = &params.pos_params.text_document.uri;
+            load_document(editor_state, context, uri, logger);
+            let fs = lookup_document(editor_state, context, uri)?;
+            let completion_context = CompletionContext {
+                file_state: fs,
+                editor_state,
+                line: params.pos_params.position.line as usize,
+                character: params.pos_params.position.character as usize,
+            };
+            let engine = context
+                .completion_engine
+                .get_or_init(|| CompletionEngine::new(&context.settings.completion));
+            let index = completion_context.index();
+            let items = engine
+                .complete(&completion_context)
+                .into_iter()
+                .enumerate()
+                .map(|(rank, candidate)| {
+                    let mut item = CompletionItem::new(rank, candidate);
+                    // there is nothing to resolve past the largest tree
+                    if let Some(index) = index {
+                        let data = CompletionItemData {
+                            uri: uri.clone(),
+                            index,
+                            detail: item.detail.take().unwrap_or_default(),
+                        };
+                        item.data = serde_json::to_value(data).ok();
+                    }
+                    item
+                })
+                .collect();
+            Ok(CompletionList {
+                is_incomplete: false,
+                items,
+            })
+        }
+
+        pub fn resolve_completion(
+            item: CompletionItem,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<CompletionItem, ResponseError> {
+            let kind = context.documentation_markup_kind();
+            let depth = context.settings.preview_depth;
+            Ok(resolve_completion_item(item, editor_state, kind, depth))
+        }
+
+        pub fn hover(
+            params: HoverParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<Option<HoverResult>, ResponseError> {
+            writeln!(
+                logger,
+                "[HoverRequest] Recieved from {:?}",
+                params.pos_params.text_document.uri
+            )
+            .unwrap();
+
+            let uri = &params.pos_params.text_document.uri;
+            let kind = context.hover_markup_kind();
+            // documents of other languages only have a text
+            if let Some(text) = editor_state
+                .text(uri)
+                .filter(|_| !editor_state.is_tree_language(uri))
+            {
+                return Ok(Some(HoverResult {
+                    contents: MarkupContent {
+                        kind,
+                        value: format!("Character count: {}", text.len()),
+                    },
+                    range: None,
+                }));
+            }
+            let fs = lookup_document(editor_state, context, uri)?;
+
+            let hover_rsp_msg = hover_text(
+                fs,
+                &params.pos_params.position,
+                &context.settings,
+                kind,
+                context.position_encoding,
+            );
+
+            // the client shows nothing for a null result, not even an empty popup
+            Ok(hover_rsp_msg.map(|value| HoverResult {
+                contents: MarkupContent { kind, value },
+                range: node_at(fs, &params.pos_params.position).map(node_range),
+            }))
+        }
+
+        pub fn execute_command(
+            params: ExecuteCommandParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<Value, ResponseError> {
+            writeln!(logger, "[ExecuteCommand] {}", params.command).unwrap();
+            // commands may be run on documents the client didn't open
+            let token = params.work_done.work_done_token.clone();
+            ensure_workspace_scanned(token, editor_state, context, logger);
+            let arguments = params.arguments.unwrap_or_default();
+            if let Some(uri) = arguments.first().and_then(|a| a.as_str()) {
+                load_document(editor_state, context, uri, logger);
+            }
+            match context.commands.get(&params.command) {
+                Some(handler) => handler(&arguments, editor_state, context, logger),
+                None => Err(invalid_params(format!(
+                    "Unknown command {}",
+                    params.command
+                ))),
+            }
+        }
+
+        pub fn type_definition(
+            params: TypeDefinitionParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<Location>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            // every node has the type of the tree it is in, defined by the root
+            Ok(node_at(fs, &params.pos_params.position).map(|_| Location {
+                uri: uri.clone(),
+                range: node_range(0),
+            }))
+        }
+
+        pub fn implementation(
+            params: ImplementationParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<Location>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            Ok(node_at(fs, &params.pos_params.position)
+                .map(|index| leaf_descendants(fs, index))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|leaf| Location {
+                    uri: uri.clone(),
+                    range: node_range(leaf),
+                })
+                .collect())
+        }
+
+        pub fn prepare_call_hierarchy(
+            params: CallHierarchyPrepareParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<Vec<CallHierarchyItem>>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            Ok(node_at(fs, &params.pos_params.position)
+                .map(|index| vec![call_hierarchy_item(uri, fs, index)]))
+        }
+
+        pub fn incoming_calls(
+            params: CallHierarchyCallsParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<CallHierarchyIncomingCall>, ResponseError> {
+            let item = &params.item;
+            let fs = lookup_document(editor_state, context, &item.uri)?;
+            // a node is called by its parent, expanding further walks up the chain
+            Ok(call_hierarchy_node(fs, item)
+                .filter(|&index| index > 0)
+                .map(|index| {
+                    let parent = (index - 1) / 2;
+                    CallHierarchyIncomingCall {
+                        from: call_hierarchy_item(&item.uri, fs, parent),
+                        from_ranges: vec![node_range(parent)],
+                    }
+                })
+                .into_iter()
+                .collect())
+        }
+
+        pub fn outgoing_calls(
+            params: CallHierarchyCallsParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<CallHierarchyOutgoingCall>, ResponseError> {
+            let item = &params.item;
+            let fs = lookup_document(editor_state, context, &item.uri)?;
+            // a node calls its children
+            Ok(call_hierarchy_node(fs, item)
+                .map(|index| vec![2 * index + 1, 2 * index + 2])
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|&child| fs.get(child).is_some())
+                .map(|child| CallHierarchyOutgoingCall {
+                    to: call_hierarchy_item(&item.uri, fs, child),
+                    from_ranges: vec![node_range(child)],
+                })
+                .collect())
+        }
+
+        pub fn linked_editing_range(
+            params: LinkedEditingRangeParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<LinkedEditingRanges>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            // only labels used by several nodes are linked
+            Ok(node_at(fs, &params.pos_params.position)
+                .map(|index| fs.occurrences(index))
+                .filter(|nodes| nodes.len() > 1)
+                .map(|nodes| LinkedEditingRanges {
+                    ranges: nodes.into_iter().map(node_range).collect(),
+                    word_pattern: Some(LABEL_PATTERN.to_string()),
+                }))
+        }
+
+        pub fn inline_completion(
+            params: InlineCompletionParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<InlineCompletionItem>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            let position = params.pos_params.position;
+            let completion_context = CompletionContext {
+                file_state: fs,
+                editor_state,
+                line: position.line as usize,
+                character: position.character as usize,
+            };
+            let engine = context
+                .completion_engine
+                .get_or_init(|| CompletionEngine::new(&context.settings.completion));
+            Ok(engine
+                .complete_line(&completion_context)
+                .filter(|text| !text.is_empty())
+                .map(|text| {
+                    vec![InlineCompletionItem {
+                        insert_text: text,
+                        filter_text: None,
+                        range: None,
+                    }]
+                })
+                .unwrap_or_default())
+        }
+
+        pub fn inline_value(
+            params: InlineValueParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<InlineValue>, ResponseError> {
+            let uri = &params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            let range = params.range;
+            Ok(fs
+                .iter_bfs()
+                .map(|(index, _)| (index, node_range(index)))
+                .filter(|(_, node)| range.start <= node.start && node.end <= range.end)
+                .map(|(index, node)| {
+                    InlineValue::Text(InlineValueText {
+                        range: node,
+                        text: format!("index {}, subtree size {}", index, fs.subtree_size(index)),
+                    })
+                })
+                .collect())
+        }
+
+        /// The size of the subtree after every node with children, with a preview of the
+        /// subtree as the tooltip
+        pub fn inlay_hint(
+            params: InlayHintParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Vec<InlayHint>, ResponseError> {
+            let uri = &params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            let kind = context.hover_markup_kind();
+            let depth = context.settings.preview_depth;
+            let range = params.range;
+            Ok(fs
+                .iter_bfs()
+                .filter(|&(index, _)| !fs.is_leaf(index))
+                .map(|(index, _)| (index, node_range(index)))
+                .filter(|(_, node)| range.start <= node.start && node.end <= range.end)
+                .map(|(index, node)| InlayHint {
+                    position: node.end,
+                    label: format!("({})", fs.subtree_size(index)),
+                    tooltip: Some(preview_markup(preview::subtree(fs, index, depth), kind)),
+                    padding_left: true,
+                })
+                .collect())
+        }
+
+        pub fn moniker(
+            params: MonikerParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<Vec<Moniker>>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            Ok(node_at(fs, &params.pos_params.position).map(|index| {
+                vec![Moniker {
+                    scheme: MONIKER_SCHEME.to_string(),
+                    identifier: node_path(index),
+                    unique: UniquenessLevel::Document,
+                    kind: None,
+                }]
+            }))
+        }
+
+        pub fn document_stats(
+            params: DocumentStatsParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<TreeStats, ResponseError> {
+            let uri = &params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            Ok(fs.stats())
+        }
+
+        pub fn declaration(
+            params: DeclarationParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<Option<Location>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            Ok(node_at(fs, &params.pos_params.position)
+                .and_then(|index| first_occurrence(fs, index))
+                .map(|first| Location {
+                    uri: uri.clone(),
+                    range: node_range(first),
+                }))
+        }
+
+        pub fn references(
+            params: ReferenceParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<Vec<Location>, ResponseError> {
+            let uri = &params.pos_params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            let cancellation = context.cancellation();
+            let token = params.partial_result.partial_result_token.as_ref();
+            let chunks = match node_at(fs, &params.pos_params.position) {
+                Some(index) => {
+                    let include_declaration = params.context.include_declaration;
+                    super::references(
+                        editor_state,
+                        uri,
+                        fs,
+                        index,
+                        include_declaration,
+                        &cancellation,
+                    )?
+                }
+                None => Vec::new(),
+            };
+            let chunks =
+                stream_partial_results(token, chunks, &cancellation, context.output(), logger)?;
+            Ok(chunks.into_iter().flatten().collect())
+        }
+
+        pub fn workspace_symbol(
+            params: WorkspaceSymbolParams,
+            editor_state: &mut EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<Vec<WorkspaceSymbol>, ResponseError> {
+            let token = params.work_done.work_done_token.clone();
+            ensure_workspace_scanned(token, editor_state, context, logger);
+            let resolve_ranges = context.supports_symbol_resolve("location.range");
+            let cancellation = context.cancellation();
+            let token = params.partial_result.partial_result_token.as_ref();
+            let chunks =
+                workspace_symbols(editor_state, &params.query, resolve_ranges, &cancellation)?;
+            let chunks =
+                stream_partial_results(token, chunks, &cancellation, context.output(), logger)?;
+            Ok(chunks.into_iter().flatten().collect())
+        }
+
+        pub fn resolve_workspace_symbol(
+            symbol: WorkspaceSymbol,
+            editor_state: &EditorState,
+            _: &mut ServerContext,
+            _: &mut dyn Write,
+        ) -> Result<WorkspaceSymbol, ResponseError> {
+            Ok(super::resolve_workspace_symbol(symbol, editor_state))
+        }
+
+        pub fn semantic_tokens(
+            params: SemanticTokensParams,
+            editor_state: &EditorState,
+            context: &mut ServerContext,
+            logger: &mut dyn Write,
+        ) -> Result<SemanticTokens, ResponseError> {
+            let uri = &params.text_document.uri;
+            let fs = lookup_document(editor_state, context, uri)?;
+            let cancellation = context.cancellation();
+            let token = params.partial_result.partial_result_token.as_ref();
+            let chunks = super::semantic_tokens(fs);
+            let chunks =
+                stream_partial_results(token, chunks, &cancellation, context.output(), logger)?;
+            Ok(SemanticTokens {
+                result_id: None,
+                data: chunks.into_iter().flat_map(|chunk| chunk.data).collect(),
+            })
+        }
+    }
+
+    /// Answers a request, returning the content of the response, or runs a notification. The
+    /// params are not parsed yet
+    enum Route {
+        Request(Box<RequestRoute>),
+        Query(Box<QueryRoute>),
+        Notification(Box<NotificationRoute>),
+    }
+
+    type RequestRoute = dyn Fn(
+            RequestId,
+            Option<&RawValue>,
+            &mut EditorState,
+            &mut ServerContext,
+            &mut dyn Write,
+        ) -> Result<String, MsgParseError>
+        + Send
+        + Sync;
+
+    type QueryRoute = dyn Fn(
+            RequestId,
+            Option<&RawValue>,
+            &EditorState,
+            &mut ServerContext,
+            &mut dyn Write,
+        ) -> Result<String, MsgParseError>
+        + Send
+        + Sync;
+
+    type NotificationRoute = dyn Fn(
+            Option<&RawValue>,
+            &mut EditorState,
+            &mut ServerContext,
+            &mut dyn Write,
+        ) -> Result<(), MsgParseError>
+        + Send
+        + Sync;
+
+    /// A handler in `handlers`, taking the parsed params
+    type Handle<P, T> = fn(P, &mut EditorState, &mut ServerContext, &mut dyn Write) -> T;
+
+    /// A handler in `handlers` that only reads the editor state
+    type Query<P, T> = fn(P, &EditorState, &mut ServerContext, &mut dyn Write) -> T;
+
+    /// The handlers of the methods the server implements itself, keyed by method
+    #[derive(Default)]
+    pub(crate) struct Router {
+        routes: HashMap<&'static str, Route>,
+    }
+
+    impl Router {
+        /// Answer the requests of `R` with what `handler` returns
+        fn on<R>(
+            &mut self,
+            handler: Handle<R::Params, Result<R::Result, ResponseError>>,
+        ) -> &mut Self
+        where
+            R: LspRequest + 'static,
+            R::Params: DeserializeOwned,
+            R::Result: Serialize,
+        {
+            let route: Box<RequestRoute> =
+                Box::new(move |id, params, editor_state, context, logger| {
+                    let params = parse_params::<R, R::Params>(params)?;
+                    Ok(match handler(params, editor_state, context, logger) {
+                        Ok(result) => response::<R>(id, result),
+                        Err(error) => json_to_string(&ErrorResponse::new(id, error)),
+                    })
+                });
+            self.routes.insert(R::METHOD, Route::Request(route));
+            self
+        }
+
+        /// Answer the requests of `R` like `on`, with a handler that doesn't change the editor
+        /// state. The document in the params is read from disk before it runs, see
+        /// `query_document`
+        fn on_query<R>(
+            &mut self,
+            handler: Query<R::Params, Result<R::Result, ResponseError>>,
+        ) -> &mut Self
+        where
+            R: LspRequest + 'static,
+            R::Params: DeserializeOwned,
+            R::Result: Serialize,
+        {
+            let route: Box<QueryRoute> =
+                Box::new(move |id, params, editor_state, context, logger| {
+                    let params = parse_params::<R, R::Params>(params)?;
+                    Ok(match handler(params, editor_state, context, logger) {
+                        Ok(result) => response::<R>(id, result),
+                        Err(error) => json_to_string(&ErrorResponse::new(id, error)),
+                    })
+                });
+            self.routes.insert(R::METHOD, Route::Query(route));
+            self
+        }
+
+        /// Run `handler` on the notifications of `N`
+        fn on_notification<N>(&mut self, handler: Handle<N::Params, ()>) -> &mut Self
+        where
+            N: LspNotification + 'static,
+            N::Params: DeserializeOwned,
+        {
+            let route: Box<NotificationRoute> =
+                Box::new(move |params, editor_state, context, logger| {
+                    let params = parse_params::<N, N::Params>(params)?;
+                    handler(params, editor_state, context, logger);
+                    Ok(())
+                });
+            self.routes.insert(N::METHOD, Route::Notification(route));
+            self
+        }
+
+        /// The methods with a handler
+        pub(crate) fn methods(&self) -> impl Iterator<Item = &'static str> + '_ {
+            self.routes.keys().copied()
+        }
+
+        /// Whether the method has a handler
+        pub(crate) fn handles(&self, method: &str) -> bool {
+            self.routes.contains_key(method)
+        }
+    }
+
+    /// The handlers of the builtin methods, see `builtin_methods`
+    pub(crate) fn router() -> &'static Router {
+        static ROUTER: OnceLock<Router> = OnceLock::new();
+        ROUTER.get_or_init(|| {
+            let mut router = Router::default();
+            router
+                .on::<InitializeRequest>(handlers::initialize)
+                .on_notification::<InitializedNotification>(handlers::initialized)
+                .on_query::<ShutdownRequest>(handlers::shutdown)
+                .on_notification::<DidOpenTextDocumentNotification>(handlers::did_open)
+                .on_notification::<TextDocumentDidChangeNotification>(handlers::did_change)
+                .on_notification::<DidCloseTextDocumentNotification>(handlers::did_close)
+                .on_notification::<DidSaveTextDocumentNotification>(handlers::did_save)
+                .on_notification::<SetTraceNotification>(handlers::set_trace)
+                .on_notification::<CancelNotification>(handlers::cancel_request)
+                .on_notification::<DidChangeConfigurationNotification>(
+                    handlers::did_change_configuration,
+                )
+                .on_notification::<DidChangeWatchedFilesNotification>(
+                    handlers::did_change_watched_files,
+                )
+                .on_notification::<DidCreateFilesNotification>(handlers::did_create_files)
+                .on_query::<WillRenameFilesRequest>(handlers::will_rename_files)
+                .on_notification::<DidRenameFilesNotification>(handlers::did_rename_files)
+                .on_query::<WillDeleteFilesRequest>(handlers::will_delete_files)
+                .on_notification::<DidDeleteFilesNotification>(handlers::did_delete_files)
+                .on::<CompletionRequest>(handlers::completion)
+                .on_query::<CompletionItemResolveRequest>(handlers::resolve_completion)
+                .on_query::<HoverRequest>(handlers::hover)
+                .on::<ExecuteCommandRequest>(handlers::execute_command)
+                .on_query::<TypeDefinitionRequest>(handlers::type_definition)
+                .on_query::<ImplementationRequest>(handlers::implementation)
+                .on_query::<CallHierarchyPrepareRequest>(handlers::prepare_call_hierarchy)
+                .on_query::<CallHierarchyIncomingCallsRequest>(handlers::incoming_calls)
+                .on_query::<CallHierarchyOutgoingCallsRequest>(handlers::outgoing_calls)
+                .on_query::<LinkedEditingRangeRequest>(handlers::linked_editing_range)
+                .on_query::<InlineCompletionRequest>(handlers::inline_completion)
+                .on_query::<InlineValueRequest>(handlers::inline_value)
+                .on_query::<InlayHintRequest>(handlers::inlay_hint)
+                .on_query::<MonikerRequest>(handlers::moniker)
+                .on_query::<DocumentStatsRequest>(handlers::document_stats)
+                .on_query::<DeclarationRequest>(handlers::declaration)
+                .on_query::<ReferencesRequest>(handlers::references)
+                .on::<WorkspaceSymbolRequest>(handlers::workspace_symbol)
+                .on_query::<WorkspaceSymbolResolveRequest>(handlers::resolve_workspace_symbol)
+                .on_query::<SemanticTokensRequest>(handlers::semantic_tokens);
+            router
+        })
+    }
+
+    /// The document the params of a query are about, the item of a call hierarchy request
+    fn query_document(params: Option<&RawValue>) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Document {
+            uri: String,
+        }
+        #[derive(Deserialize)]
+        struct Params {
+            #[serde(rename = "textDocument", alias = "item")]
+            document: Option<Document>,
+        }
+        let params = json_from_string::<Params>(params?.get()).ok()?;
+        params.document.map(|document| document.uri)
+    }
+
+    /// Parse the params of the message `M`, missing params are null. The error names the
+    /// message type
+    fn parse_params<M, P: DeserializeOwned>(params: Option<&RawValue>) -> Result<P, MsgParseError> {
+        json_from_string::<P>(params.map_or("null", RawValue::get)).map_err(|e| {
+            let name = std::any::type_name::<M>()
+                .rsplit("::")
+                .next()
+                .unwrap_or_default();
+            MsgParseError(format!("Could not parse {}, error {}", name, e))
+        })
+    }
+
+    /// Run the handler of the method, returning the content of the response if there is an
+    /// id. Params that can't be parsed are an Err, the caller answers the request with it
+    fn dispatch_message(
+        method: &str,
+        request_id: Option<RequestId>,
+        params: Option<&RawValue>,
+        editor_state: &mut impl EditorAccess,
+        context: &mut ServerContext,
+        logger: &mut dyn Write,
+    ) -> Result<Option<String>, MsgParseError> {
+        let not_a_request = |id| {
+            let error = ResponseError {
+                code: ErrorCode::MethodNotFound,
+                message: format!("{} is a notification", method),
+                data: None,
+            };
+            Ok(Some(json_to_string(&ErrorResponse::new(id, error))))
+        };
+        let without_id = || {
+            Err(MsgParseError(format!(
+                "{} is a request but was sent without an id",
+                method
+            )))
+        };
+        match (router().routes.get(method), request_id) {
+            (Some(Route::Request(route)), Some(id)) => editor_state
+                .update(|editor_state| route(id, params, editor_state, context, logger))
+                .map(Some),
+            (Some(Route::Query(route)), Some(id)) => {
+                // only a document the server doesn't know yet needs the write lock
+                if let Some(uri) = query_document(params) {
+                    if editor_state.query(|editor_state| needs_loading(editor_state, context, &uri))
+                    {
+                        editor_state.update(|editor_state| {
+                            load_document(editor_state, context, &uri, logger)
+                        });
+                    }
+                }
+                editor_state
+                    .query(|editor_state| route(id, params, editor_state, context, logger))
+                    .map(Some)
+            }
+            (Some(Route::Notification(route)), None) => editor_state
+                .update(|editor_state| route(params, editor_state, context, logger))
+                .map(|_| None),
+            (Some(Route::Notification(_)), Some(id)) => not_a_request(id),
+            (Some(Route::Request(_) | Route::Query(_)), None) => without_id(),
+            (None, request_id) if context.handlers.contains_key(method) => {
+                let params = parse_params::<Value, Value>(params)?;
+                match (context.handlers[method].clone(), request_id) {
+                    (Handler::Request(handler), Some(id)) => {
+                        let result = editor_state
+                            .update(|editor_state| handler(params, editor_state, context, logger));
+                        let response = match result {
+                            Ok(result) => json_to_string(
+                                &json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                            ),
+                            Err(error) => json_to_string(&ErrorResponse::new(id, error)),
+                        };
+                        Ok(Some(response))
+                    }
+                    (Handler::Notification(handler), None) => {
+                        editor_state
+                            .update(|editor_state| handler(params, editor_state, context, logger));
+                        Ok(None)
+                    }
+                    (Handler::Notification(_), Some(id)) => not_a_request(id),
+                    (Handler::Request(_), None) => without_id(),
+                }
+            }
+            // requests always need a response, unknown notifications are ignored
+            (None, Some(id)) => {
+                let error = ResponseError {
+                    code: ErrorCode::MethodNotFound,
+                    message: format!("Unhandled method {}", method),
+                    data: None,
+                };
+                Ok(Some(json_to_string(&ErrorResponse::new(id, error))))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    // This code defines various structs used for representing messages within the LSP
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Message {
+        // The LSP message header specifying the JSON RPC version ("2.0")
+        pub jsonrpc: String,
+    }
+
+    // Notification messages are sent from the client to the server
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Notification {
+        #[serde(flatten)]
+        pub message: Message,
+        pub method: String, // The specific notification method name (e.g., "textDocument/didOpen")
+    }
+
+    impl Notification {
+        pub fn new(method: &str) -> Self {
+            Notification {
+                message: Message {
+                    jsonrpc: "2.0".to_string(),
+                },
+                method: method.to_string(),
+            }
+        }
+    }
+
+    // Identifier of a request, which the client may send as a number or a string. Requests
+    // sent by the server are numbered
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum RequestId {
+        Number(i64),
+        String(String),
+    }
+
+    impl From<i64> for RequestId {
+        fn from(id: i64) -> Self {
+            RequestId::Number(id)
+        }
+    }
+
+    impl From<&str> for RequestId {
+        fn from(id: &str) -> Self {
+            RequestId::String(id.to_string())
+        }
+    }
+
+    impl Display for RequestId {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                RequestId::Number(id) => id.fmt(f),
+                RequestId::String(id) => write!(f, "{:?}", id),
+            }
+        }
+    }
+
+    // Request messages are sent from the client to the server and expect a response
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct RequestMessage {
+        #[serde(flatten)]
+        pub base_message: Notification, // Contains message header and method
+        pub id: RequestId, // Unique identifier for the request
+    }
+
+    impl RequestMessage {
+        pub fn new(id: RequestId, method: &str) -> Self {
+            RequestMessage {
+                base_message: Notification::new(method),
+                id,
+            }
+        }
+    }
+
+    // Requests sent from the server to the client
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct OutgoingRequest<P> {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        #[serde(default, skip_serializing_if = "is_null")]
+        pub params: P, // Left out if null, eg. for requests without params sent with `()`
+    }
+
+    fn is_null<P: Serialize>(params: &P) -> bool {
+        serde_json::to_value(params).is_ok_and(|v| v.is_null())
+    }
+
+    impl<P> OutgoingRequest<P> {
+        pub fn new(id: i64, method: &str, params: P) -> Self {
+            OutgoingRequest {
+                request: RequestMessage::new(RequestId::Number(id), method),
+                params,
+            }
+        }
+    }
+
+    // Notifications sent from the server to the client
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct OutgoingNotification<P> {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: P,
+    }
+
+    impl<P> OutgoingNotification<P> {
+        pub fn new(method: &str, params: P) -> Self {
+            OutgoingNotification {
+                notification: Notification::new(method),
+                params,
+            }
+        }
+    }
+
+    // Diagnostics of a document pushed to the client
+    pub type PublishDiagnosticsNotification = OutgoingNotification<PublishDiagnosticsParams>;
+
+    impl LspNotification for PublishDiagnosticsNotification {
+        const METHOD: &'static str = "textDocument/publishDiagnostics";
+        type Params = PublishDiagnosticsParams;
+    }
+
+    /// A request, implemented by the type of the whole message as it is sent
+    pub trait LspRequest {
+        const METHOD: &'static str;
+        type Params;
+        type Result; // of the response
+    }
+
+    /// A notification, implemented by the type of the whole message as it is sent
+    pub trait LspNotification {
+        const METHOD: &'static str;
+        type Params;
+    }
+
+    // Response to a request, with the result type of the request
+    #[derive(Debug, Serialize)]
+    struct TypedResponse<T> {
+        #[serde(flatten)]
+        response: ResponseMessage,
+        result: T,
+    }
+
+    /// Content of the response to a request of `R`
+    fn response<R>(id: RequestId, result: R::Result) -> String
+    where
+        R: LspRequest,
+        R::Result: Serialize,
+    {
+        json_to_string(&TypedResponse {
+            response: ResponseMessage {
+                message: Message {
+                    jsonrpc: "2.0".to_string(),
+                },
+                id,
+            },
+            result,
+        })
+    }
+
+    // Response from the client to a request sent by the server, carries either result or error
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ClientResponse {
+        pub id: RequestId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<ResponseError>,
+    }
+
+    // Any message received from the client. A message with a method is a request if it has an
+    // id and a notification otherwise, one without a method is a response if it has an id and a
+    // result or an error. Only the method and id are read, the params are kept as they were sent
+    // until the handler of the method parses them into their type
+    #[derive(Debug)]
+    pub enum IncomingMessage<'a> {
+        Request {
+            id: RequestId,
+            method: String,
+            params: Option<&'a RawValue>, // None if the request has none
+        },
+        Notification {
+            method: String,
+            params: Option<&'a RawValue>,
+        },
+        Response(ClientResponse),
+    }
+
+    // Fields of any incoming message, a field that is present is Some even if it is null
+    #[derive(Deserialize)]
+    struct MessageFields<'a> {
+        id: Option<RequestId>,
+        method: Option<String>,
+        #[serde(default, borrow, deserialize_with = "present")]
+        params: Option<&'a RawValue>,
+        #[serde(default, borrow, deserialize_with = "present")]
+        result: Option<&'a RawValue>,
+        #[serde(default, borrow, deserialize_with = "present")]
+        error: Option<&'a RawValue>,
+    }
+
+    fn present<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<&'de RawValue>, D::Error> {
+        <&RawValue>::deserialize(deserializer).map(Some)
+    }
+
+    impl<'a> IncomingMessage<'a> {
+        /// Read the kind, method and id of the message, without parsing its params
+        pub fn parse(message: &'a str) -> Result<Self, MsgParseError> {
+            let invalid = |e: serde_json::Error| MsgParseError(e.to_string());
+            let raw = serde_json::from_str::<MessageFields>(message).map_err(invalid)?;
+            match (raw.method, raw.id) {
+                (Some(method), Some(id)) => Ok(IncomingMessage::Request {
+                    id,
+                    method,
+                    params: raw.params,
+                }),
+                (Some(method), None) => Ok(IncomingMessage::Notification {
+                    method,
+                    params: raw.params,
+                }),
+                (None, Some(id)) if raw.result.is_some() || raw.error.is_some() => {
+                    let response = ClientResponse {
+                        id,
+                        result: raw
+                            .result
+                            .map(|r| json_from_string(r.get()))
+                            .transpose()
+                            .map_err(invalid)?,
+                        error: raw
+                            .error
+                            .map(|e| json_from_string(e.get()))
+                            .transpose()
+                            .map_err(invalid)?,
+                    };
+                    Ok(IncomingMessage::Response(response))
+                }
+                (None, _) => Err(MsgParseError(String::from(
+                    "expected a request, a notification or a response",
+                ))),
+            }
+        }
+    }
+
+    // Error returned when a request failed
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ResponseError {
+        pub code: ErrorCode, // Type of error that occurred
+        pub message: String, // Short description of the error
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub data: Option<Value>, // Additional information about the error
+    }
+
+    // Code of a ResponseError, sent as its number. Codes the server doesn't define, eg. in
+    // errors from the client, are kept as Other
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(from = "i64", into = "i64")]
+    pub enum ErrorCode {
+        // Defined by JSON-RPC
+        ParseError,
+        InvalidRequest,
+        MethodNotFound,
+        InvalidParams,
+        InternalError,
+
+        // Defined by LSP
+        ServerNotInitialized,
+        RequestCancelled,
+        ContentModified,
+        RequestFailed,
+
+        // Errors about the tree documents, see `is_tree_error`. Their data is the TreeError
+        DocumentNotFound,
+        DocumentNotATree,
+        PositionNotANode,
+        DepthLimitExceeded,
+        IndexingInProgress,
+
+        // The method is implemented but turned off, data.hint names the setting enabling it
+        FeatureDisabled,
+        // A request sent to the client got no response within the requestTimeout setting
+        RequestTimedOut,
+
+        Other(i64),
+    }
+
+    impl ErrorCode {
+        // Every code but Other, to look codes up by their number
+        const DEFINED: [ErrorCode; 16] = [
+            ErrorCode::ParseError,
+            ErrorCode::InvalidRequest,
+            ErrorCode::MethodNotFound,
+            ErrorCode::InvalidParams,
+            ErrorCode::InternalError,
+            ErrorCode::ServerNotInitialized,
+            ErrorCode::RequestCancelled,
+            ErrorCode::ContentModified,
+            ErrorCode::RequestFailed,
+            ErrorCode::DocumentNotFound,
+            ErrorCode::DocumentNotATree,
+            ErrorCode::PositionNotANode,
+            ErrorCode::DepthLimitExceeded,
+            ErrorCode::IndexingInProgress,
+            ErrorCode::FeatureDisabled,
+            ErrorCode::RequestTimedOut,
+        ];
+
+        // The server's own codes are in -33099..=-33000, the first half for the errors about
+        // the tree documents
+        const TREE_ERRORS: std::ops::RangeInclusive<i64> = -33049..=-33000;
+
+        pub fn code(self) -> i64 {
+            match self {
+                ErrorCode::ParseError => -32700,
+                ErrorCode::InvalidRequest => -32600,
+                ErrorCode::MethodNotFound => -32601,
+                ErrorCode::InvalidParams => -32602,
+                ErrorCode::InternalError => -32603,
+                ErrorCode::ServerNotInitialized => -32002,
+                ErrorCode::RequestCancelled => -32800,
+                ErrorCode::ContentModified => -32801,
+                ErrorCode::RequestFailed => -32803,
+                ErrorCode::DocumentNotFound => -33000,
+                ErrorCode::DocumentNotATree => -33001,
+                ErrorCode::PositionNotANode => -33002,
+                ErrorCode::DepthLimitExceeded => -33003,
+                ErrorCode::IndexingInProgress => -33004,
+                ErrorCode::FeatureDisabled => -33050,
+                ErrorCode::RequestTimedOut => -33051,
+                ErrorCode::Other(code) => code,
+            }
+        }
+
+        pub fn is_tree_error(self) -> bool {
+            Self::TREE_ERRORS.contains(&self.code())
+        }
+    }
+
+    impl From<i64> for ErrorCode {
+        fn from(code: i64) -> Self {
+            Self::DEFINED
+                .into_iter()
+                .find(|defined| defined.code() == code)
+                .unwrap_or(ErrorCode::Other(code))
+        }
+    }
+
+    impl From<ErrorCode> for i64 {
+        fn from(code: ErrorCode) -> Self {
+            code.code()
+        }
+    }
+
+    impl Display for ErrorCode {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            self.code().fmt(f)
+        }
+    }
+
+    // Failures specific to tree documents, sent as the data of the error so clients can
+    // handle them without matching on the message
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(tag = "kind", rename_all = "camelCase")]
+    pub enum TreeError {
+        DocumentNotFound {
+            uri: String,
+        },
+        DocumentNotATree {
+            uri: String,
+            range: Range,   // Lines that keep the document from being a tree
+            reason: String, // What is wrong with them
+        },
+        PositionNotANode {
+            uri: String,
+            position: Position,
+        },
+        DepthLimitExceeded {
+            uri: String,
+            depth: usize, // Depth that was asked for
+            limit: usize, // Deepest allowed
+        },
+        IndexingInProgress {
+            uri: String, // Large document parsed in the background, ask again once it is done
+        },
+    }
+
+    impl TreeError {
+        pub fn code(&self) -> ErrorCode {
+            match self {
+                TreeError::DocumentNotFound { .. } => ErrorCode::DocumentNotFound,
+                TreeError::DocumentNotATree { .. } => ErrorCode::DocumentNotATree,
+                TreeError::PositionNotANode { .. } => ErrorCode::PositionNotANode,
+                TreeError::DepthLimitExceeded { .. } => ErrorCode::DepthLimitExceeded,
+                TreeError::IndexingInProgress { .. } => ErrorCode::IndexingInProgress,
+            }
+        }
+    }
+
+    impl Display for TreeError {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                TreeError::DocumentNotFound { uri } => write!(f, "Could not find file {}", uri),
+                TreeError::DocumentNotATree { uri, range, reason } => write!(
+                    f,
+                    "{} is not a valid complete binary tree, line {}: {}",
+                    uri, range.start.line, reason
+                ),
+                TreeError::PositionNotANode { position, .. } => {
+                    write!(f, "{} is not on a node", position)
+                }
+                TreeError::DepthLimitExceeded { depth, limit, .. } => {
+                    write!(f, "depth {} is over the limit of {}", depth, limit)
+                }
+                TreeError::IndexingInProgress { uri } => {
+                    write!(f, "Indexing {} is in progress", uri)
+                }
+            }
+        }
+    }
+
+    impl From<TreeError> for ResponseError {
+        fn from(error: TreeError) -> Self {
+            ResponseError {
+                code: error.code(),
+                message: error.to_string(),
+                data: serde_json::to_value(&error).ok(),
+            }
+        }
+    }
+
+    impl From<Cancelled> for ResponseError {
+        fn from(cancelled: Cancelled) -> Self {
+            ResponseError {
+                code: ErrorCode::RequestCancelled,
+                message: cancelled.to_string(),
+                data: None,
+            }
+        }
+    }
+
+    // Response sent when a request failed
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ErrorResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub error: ResponseError,
+    }
+
+    impl ErrorResponse {
+        pub fn new(id: RequestId, error: ResponseError) -> Self {
+            ErrorResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                error,
+            }
+        }
+    }
+
+    // Response messages are sent from the server to the client in response to requests
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ResponseMessage {
+        #[serde(flatten)]
+        pub message: Message,
+        pub id: RequestId, // The id that matches the original request
+    }
+
+    // Sent by the client once it got the initialize response, has no params, whatever is
+    // sent is ignored
+    pub struct InitializedNotification;
+
+    impl LspNotification for InitializedNotification {
+        const METHOD: &'static str = "initialized";
+        type Params = IgnoredAny;
+    }
+
+    // Sent by the client before it asks the server to exit, has no params, whatever is sent
+    // is ignored
+    pub struct ShutdownRequest;
+
+    impl LspRequest for ShutdownRequest {
+        const METHOD: &'static str = "shutdown";
+        type Params = IgnoredAny;
+        type Result = ();
+    }
+
+    // Initialize request is sent by the client to the server during initialization
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InitializeRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage, // Contains message header, method, and id
+        pub params: InitializeParams, // Specific parameters for initialization
+    }
+
+    impl LspRequest for InitializeRequest {
+        const METHOD: &'static str = "initialize";
+        type Params = InitializeParams;
+        type Result = InitializeResult;
+    }
+
+    // Parameters for the InitializeRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InitializeParams {
+        pub process_id: i64, // process ID of the client process (different from id)
+        pub client_info: Option<Info>, // Optional information about the client
+        pub capabilities: Option<ClientCapabilities>, // Capabilities provided by the client
+        pub root_uri: Option<String>, // Root of the workspace, deprecated in favour of workspace_folders
+        pub workspace_folders: Option<Vec<WorkspaceFolder>>, // Folders open in the client
+        pub initialization_options: Option<Value>, // Settings provided by the user
+        pub trace: Option<TraceValue>, // Initial trace setting, off if missing
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // A workspace folder open in the client
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceFolder {
+        pub uri: String,
+        pub name: String,
+    }
+
+    // Capabilities the client advertises during initialization
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClientCapabilities {
+        pub workspace: Option<WorkspaceClientCapabilities>, // Workspace specific client capabilities
+        pub window: Option<WindowClientCapabilities>,       // Window specific client capabilities
+        pub text_document: Option<TextDocumentClientCapabilities>, // Capabilities of each document feature
+        pub general: Option<GeneralClientCapabilities>, // Capabilities that apply to every feature
+    }
+
+    // Client capabilities shared by every feature
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct GeneralClientCapabilities {
+        pub position_encodings: Option<Vec<String>>, // Encodings the client supports, preferred first
+        pub markdown: Option<MarkdownClientCapabilities>, // Markdown parser used to render markup
+    }
+
+    // Markdown parser of the client
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct MarkdownClientCapabilities {
+        pub parser: String,
+        pub version: Option<String>,
+    }
+
+    // Workspace specific client capabilities
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceClientCapabilities {
+        pub configuration: Option<bool>, // Whether the client supports workspace/configuration
+        pub apply_edit: Option<bool>,    // Whether the client supports workspace/applyEdit
+        pub workspace_folders: Option<bool>, // Whether the client supports several workspace folders
+        pub workspace_edit: Option<WorkspaceEditClientCapabilities>,
+        pub did_change_watched_files: Option<DynamicRegistrationCapabilities>,
+        pub did_change_configuration: Option<DynamicRegistrationCapabilities>,
+        pub execute_command: Option<DynamicRegistrationCapabilities>,
+        pub symbol: Option<WorkspaceSymbolClientCapabilities>,
+        pub semantic_tokens: Option<RefreshClientCapabilities>,
+        pub inlay_hint: Option<RefreshClientCapabilities>,
+        pub code_lens: Option<RefreshClientCapabilities>,
+        pub diagnostics: Option<RefreshClientCapabilities>,
+        pub inline_value: Option<RefreshClientCapabilities>,
+        pub file_operations: Option<FileOperationClientCapabilities>,
+    }
+
+    // What the client supports in a WorkspaceEdit
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceEditClientCapabilities {
+        pub document_changes: Option<bool>,
+        pub resource_operations: Option<Vec<String>>, // Any of create, rename and delete
+    }
+
+    // File operations the client sends requests and notifications for
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FileOperationClientCapabilities {
+        pub dynamic_registration: Option<bool>,
+        pub did_create: Option<bool>,
+        pub will_create: Option<bool>,
+        pub did_rename: Option<bool>,
+        pub will_rename: Option<bool>,
+        pub did_delete: Option<bool>,
+        pub will_delete: Option<bool>,
+    }
+
+    // Whether the client takes the workspace/*/refresh request of a feature
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RefreshClientCapabilities {
+        pub refresh_support: Option<bool>,
+    }
+
+    // Client capabilities for workspace/symbol
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceSymbolClientCapabilities {
+        pub dynamic_registration: Option<bool>,
+        pub resolve_support: Option<ResolveSupportCapabilities>, // eg. `location.range`
+    }
+
+    // Shared by capabilities that only tell whether they can be registered dynamically
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DynamicRegistrationCapabilities {
+        pub dynamic_registration: Option<bool>,
+    }
+
+    // Text document specific client capabilities, by feature. Features the server doesn't
+    // know are kept in `other`, so embedders can register them dynamically
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub synchronization: Option<TextDocumentSyncClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub completion: Option<CompletionClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub hover: Option<HoverClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub declaration: Option<LinkClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub type_definition: Option<LinkClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub implementation: Option<LinkClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub call_hierarchy: Option<DynamicRegistrationCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub linked_editing_range: Option<DynamicRegistrationCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub moniker: Option<DynamicRegistrationCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub inline_value: Option<DynamicRegistrationCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub inline_completion: Option<DynamicRegistrationCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub publish_diagnostics: Option<PublishDiagnosticsClientCapabilities>,
+        #[serde(flatten)]
+        pub other: BTreeMap<String, Value>,
+    }
+
+    impl TextDocumentClientCapabilities {
+        /// Features the client lets the server register at runtime, eg. `hover`
+        pub fn dynamic_registration(&self) -> Vec<String> {
+            let Ok(Value::Object(features)) = serde_json::to_value(self) else {
+                return Vec::new();
+            };
+            features
+                .into_iter()
+                .filter(|(_, capability)| {
+                    capability.get("dynamicRegistration") == Some(&Value::Bool(true))
+                })
+                .map(|(feature, _)| feature)
+                .collect()
+        }
+    }
+
+    // Client capabilities for document synchronization
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentSyncClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dynamic_registration: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub will_save: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub will_save_wait_until: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub did_save: Option<bool>,
+    }
+
+    // Client capabilities for textDocument/completion
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CompletionClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dynamic_registration: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub completion_item: Option<CompletionItemClientCapabilities>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub context_support: Option<bool>, // Whether the client sends the trigger of the completion
+    }
+
+    // What the client supports in a CompletionItem
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CompletionItemClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub snippet_support: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub documentation_format: Option<Vec<String>>, // Markup kinds, preferred first
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub resolve_support: Option<ResolveSupportCapabilities>,
+    }
+
+    // Properties the client can fill in lazily with a resolve request
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct ResolveSupportCapabilities {
+        pub properties: Vec<String>,
+    }
+
+    // Client capabilities for textDocument/hover
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct HoverClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dynamic_registration: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub content_format: Option<Vec<String>>, // Markup kinds, preferred first
+    }
+
+    // Client capabilities for the goto requests
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct LinkClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub dynamic_registration: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub link_support: Option<bool>, // Whether the client takes LocationLinks
+    }
+
+    // Client capabilities for textDocument/publishDiagnostics
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PublishDiagnosticsClientCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub related_information: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version_support: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tag_support: Option<DiagnosticTagSupport>,
+    }
+
+    // Diagnostic tags the client can render
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DiagnosticTagSupport {
+        pub value_set: Vec<u8>,
+    }
+
+    // Window specific client capabilities
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WindowClientCapabilities {
+        pub work_done_progress: Option<bool>, // Whether the client supports server initiated progress
+        pub show_document: Option<ShowDocumentClientCapabilities>, // Support for window/showDocument
+        pub show_message: Option<ShowMessageRequestClientCapabilities>, // Support for window/showMessageRequest
+    }
+
+    // Client capabilities for the window/showDocument request
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct ShowDocumentClientCapabilities {
+        pub support: bool, // Whether the client supports the request
+    }
+
+    // Client capabilities for the window/showMessageRequest request
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ShowMessageRequestClientCapabilities {
+        pub message_action_item: Option<Value>, // Extra properties of the actions the client keeps
+    }
+
+    // Information about the client/server application
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Info {
+        pub name: String,
+        pub version: String,
+        // Milliseconds the server took to handle initialize, set in the server's own info
+        #[serde(rename = "startupTimeMs", skip_serializing_if = "Option::is_none")]
+        pub startup_time_ms: Option<f64>,
+    }
+
+    // Initialize response sent by the server after initialization
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InitializeResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: InitializeResult,
+    }
+
+    // Result of the initialization process
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InitializeResult {
+        pub capabilities: ServerCapabilities, // Capabilities offered by the server
+        pub server_info: Info,                // Information about the server
+    }
+
+    // Helper function to create an InitializeResponse message
+    impl InitializeResponse {
+        pub fn new(id: RequestId, name: String, version: String) -> InitializeResponse {
+            InitializeResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: String::from("2.0"),
+                    },
+                },
+                result: InitializeResult::new(name, version),
+            }
+        }
+    }
+
+    impl InitializeResult {
+        pub fn new(name: String, version: String) -> InitializeResult {
+            InitializeResult {
+                capabilities: ServerCapabilities {
+                    text_document_sync: TextDocumentSyncOptions {
+                        open_close: true,
+                        change: TextDocumentSyncKind::INCREMENTAL,
+                        save: Some(SaveOptions {
+                            include_text: false,
+                        }),
+                    },
+                    hover_provider: true,
+                    type_definition_provider: true,
+                    implementation_provider: true,
+                    declaration_provider: true,
+                    references_provider: true,
+                    call_hierarchy_provider: true,
+                    linked_editing_range_provider: true,
+                    moniker_provider: true,
+                    inline_value_provider: true,
+                    inlay_hint_provider: true,
+                    inline_completion_provider: true,
+                    workspace: Some(WorkspaceServerCapabilities::tree_files()),
+                    position_encoding: None,
+                    completion_provider: Some(CompletionOptions {
+                        trigger_characters: Some(vec![" ".to_string()]),
+                        resolve_provider: Some(true),
+                    }),
+                    execute_command_provider: None,
+                    workspace_symbol_provider: Some(WorkspaceSymbolOptions {
+                        resolve_provider: true,
+                    }),
+                    semantic_tokens_provider: Some(SemanticTokensOptions {
+                        legend: SemanticTokensLegend {
+                            token_types: SemanticTokenType::LEGEND.map(str::to_string).to_vec(),
+                            token_modifiers: vec!["declaration".to_string()],
+                        },
+                        full: true,
+                    }),
+                },
+                server_info: Info {
+                    name,
+                    version,
+                    startup_time_ms: None,
+                },
+            }
+        }
+    }
+
+    // Different TextDocumentSync options (currently only FULL is supported)
+    pub struct TextDocumentSyncKind {}
+
+    impl TextDocumentSyncKind {
+        const _NONE: usize = 0;
+        const _FULL: usize = 1;
+        const INCREMENTAL: usize = 2;
+    }
+
+    // Description of the server's capabilities
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ServerCapabilities {
+        pub text_document_sync: TextDocumentSyncOptions, // How documents are synchronized
+        pub hover_provider: bool, // Whether the server can provide hover information
+        pub type_definition_provider: bool, // Whether the server can go to the type of a node
+        pub implementation_provider: bool, // Whether the server can go to the leaves under a node
+        pub declaration_provider: bool, // Whether the server can go to the first use of a label
+        pub references_provider: bool, // Whether the server can find the nodes with the same label
+        pub call_hierarchy_provider: bool, // Whether the server can show parents and children as calls
+        pub linked_editing_range_provider: bool, // Whether the server can link nodes with the same label
+        pub moniker_provider: bool, // Whether the server can name nodes by their path from the root
+        pub inline_value_provider: bool, // Whether the server can show the index and size of nodes
+        pub inlay_hint_provider: bool, // Whether the server can show subtree sizes with a preview
+        pub inline_completion_provider: bool, // Whether the server can complete the rest of a level
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub workspace: Option<WorkspaceServerCapabilities>, // File operations the server follows
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub position_encoding: Option<PositionEncoding>, // Unit of the character of positions
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub completion_provider: Option<CompletionOptions>, // Options if the server provides completion
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub execute_command_provider: Option<ExecuteCommandOptions>, // Commands the server can run
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub workspace_symbol_provider: Option<WorkspaceSymbolOptions>, // Options if the server finds nodes by label
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub semantic_tokens_provider: Option<SemanticTokensOptions>, // Options if the server colors nodes by their place in the tree
+    }
+
+    // Notifications the server wants to keep documents in sync
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentSyncOptions {
+        pub open_close: bool, // Whether didOpen and didClose are sent
+        pub change: usize,    // TextDocumentSyncKind of didChange
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub save: Option<SaveOptions>, // Whether didSave is sent
+    }
+
+    // Options of the didSave notification
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SaveOptions {
+        pub include_text: bool, // Whether the saved text is sent along
+    }
+
+    // Commands the server advertises for workspace/executeCommand
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ExecuteCommandOptions {
+        pub commands: Vec<String>,
+    }
+
+    // Completion options the server advertises
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CompletionOptions {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub trigger_characters: Option<Vec<String>>, // Characters that trigger completion automatically
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub resolve_provider: Option<bool>, // Whether items are completed with completionItem/resolve
+    }
+
+    // Notification sent by the client when a document is opened
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DidOpenTextDocumentNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: DidOpenTextDocumentParams, // Parameters for the notification
+    }
+
+    impl LspNotification for DidOpenTextDocumentNotification {
+        const METHOD: &'static str = "textDocument/didOpen";
+        type Params = DidOpenTextDocumentParams;
+    }
+
+    // Parameters for the DidOpenTextDocumentNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DidOpenTextDocumentParams {
+        pub text_document: TextDocumentItem,
+    }
+
+    // Notification sent by the client when a text document is changed
+    #[derive(Debug, Deserialize, Serialize)]
+    struct TextDocumentDidChangeNotification {
+        #[serde(flatten)]
+        notification: Notification,
+        params: DidChangeTextDocumentParams, // Change-specific parameters
+    }
+
+    impl LspNotification for TextDocumentDidChangeNotification {
+        const METHOD: &'static str = "textDocument/didChange";
+        type Params = DidChangeTextDocumentParams;
+    }
+
+    // Parameters for the TextDocumentDidChangeNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct DidChangeTextDocumentParams {
+        text_document: VersionTextDocumentIdentifier, // Identifier of the changed document
+        content_changes: Vec<TextDocumentContentChangeEvent>, // Array of changes made to the document
+    }
+
+    // Identifies a text document using a URI and a version
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct VersionTextDocumentIdentifier {
+        uri: String,
+        version: i32, // Version of the document
+    }
+
+    // Describes a change made to a text document
+    #[derive(Debug, Deserialize, Serialize)]
+    struct TextDocumentContentChangeEvent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        range: Option<Range>, // Range that is replaced, the entire document if there is none
+        text: String,
+    }
+
+    // Represents a text document within the LSP
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentItem {
+        pub uri: String,
+        pub language_id: String, // Identifier of the language used in the document
+        pub version: i64,        // Version of the document, usually starts at 0
+        pub text: String,        // The text content of the document
+    }
+
+    // Request for hover information at a specific text position
+    #[derive(Debug, Deserialize, Serialize)]
+    struct HoverRequest {
+        #[serde(flatten)]
+        request: RequestMessage,
+        params: HoverParams, // Parameters containing the position for hover
+    }
+
+    impl LspRequest for HoverRequest {
+        const METHOD: &'static str = "textDocument/hover";
+        type Params = HoverParams;
+        type Result = Option<HoverResult>;
+    }
+
+    // Parameters for the HoverRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    struct HoverParams {
+        #[serde(flatten)]
+        pos_params: TextDocumentPositionParams, // Position information within a text document
+        #[serde(flatten)]
+        work_done: WorkDoneProgressParams,
+    }
+
+    // Structure holding the actual hover information
+    #[derive(Debug, Deserialize, Serialize)]
+    struct HoverResult {
+        contents: MarkupContent, // Content to be displayed in the hover tooltip
+        #[serde(skip_serializing_if = "Option::is_none")]
+        range: Option<Range>, // The node the hover is about, highlighted by the editor
+    }
+
+    // How the client should render a text
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum MarkupKind {
+        #[serde(rename = "plaintext")]
+        PlainText,
+        #[serde(rename = "markdown")]
+        Markdown,
+    }
+
+    impl MarkupKind {
+        /// The first kind the client lists, plaintext if it lists none the server knows
+        pub fn negotiate(formats: Option<&[String]>) -> Self {
+            formats
+                .unwrap_or_default()
+                .iter()
+                .find_map(|f| match f.as_str() {
+                    "markdown" => Some(MarkupKind::Markdown),
+                    "plaintext" => Some(MarkupKind::PlainText),
+                    _ => None,
+                })
+                .unwrap_or(MarkupKind::PlainText)
+        }
+    }
+
+    // Text with the format it is written in
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct MarkupContent {
+        pub kind: MarkupKind,
+        pub value: String,
+    }
+
+    // Request for the location of the type of the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct TypeDefinitionRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: TypeDefinitionParams,
+    }
+
+    impl LspRequest for TypeDefinitionRequest {
+        const METHOD: &'static str = "textDocument/typeDefinition";
+        type Params = TypeDefinitionParams;
+        type Result = Option<Location>;
+    }
+
+    // Parameters for the TypeDefinitionRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct TypeDefinitionParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Request for the leaves under the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ImplementationRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: ImplementationParams,
+    }
+
+    impl LspRequest for ImplementationRequest {
+        const METHOD: &'static str = "textDocument/implementation";
+        type Params = ImplementationParams;
+        type Result = Vec<Location>;
+    }
+
+    // Parameters for the ImplementationRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ImplementationParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Request for the first occurrence of the label at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DeclarationRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: DeclarationParams,
+    }
+
+    impl LspRequest for DeclarationRequest {
+        const METHOD: &'static str = "textDocument/declaration";
+        type Params = DeclarationParams;
+        type Result = Option<Location>;
+    }
+
+    // Parameters for the DeclarationRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DeclarationParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Request for every node with the same label as the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ReferencesRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: ReferenceParams,
+    }
+
+    impl LspRequest for ReferencesRequest {
+        const METHOD: &'static str = "textDocument/references";
+        type Params = ReferenceParams;
+        type Result = Vec<Location>;
+    }
+
+    // Parameters for the ReferencesRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ReferenceParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+        #[serde(flatten)]
+        pub partial_result: PartialResultParams,
+        pub context: ReferenceContext,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ReferenceContext {
+        pub include_declaration: bool, // Whether the first occurrence in the document is included
+    }
+
+    // A range inside a document
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Location {
+        pub uri: String,
+        pub range: Range,
+    }
+
+    // Response to the goto requests, null if there is nothing to go to
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LocationResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Option<Location>,
+    }
+
+    impl LocationResponse {
+        pub fn new(id: RequestId, location: Option<Location>) -> Self {
+            LocationResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: location,
+            }
+        }
+    }
+
+    // Response to the goto requests that can have several targets
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LocationsResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<Location>,
+    }
+
+    impl LocationsResponse {
+        pub fn new(id: RequestId, locations: Vec<Location>) -> Self {
+            LocationsResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: locations,
+            }
+        }
+    }
+
+    // Request for ghost text completing the line at a position, new in LSP 3.18
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlineCompletionRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: InlineCompletionParams,
+    }
+
+    impl LspRequest for InlineCompletionRequest {
+        const METHOD: &'static str = "textDocument/inlineCompletion";
+        type Params = InlineCompletionParams;
+        type Result = Vec<InlineCompletionItem>;
+    }
+
+    // Parameters for the InlineCompletionRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlineCompletionParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        pub context: InlineCompletionContext,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlineCompletionContext {
+        pub trigger_kind: i32, // 1 when invoked by the user, 2 when typing
+        pub selected_completion_info: Option<SelectedCompletionInfo>,
+    }
+
+    // Completion item selected in the client's completion widget, if it is shown
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SelectedCompletionInfo {
+        pub range: Range,
+        pub text: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlineCompletionItem {
+        pub insert_text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub filter_text: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub range: Option<Range>, // Replaced range, the cursor position if None
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlineCompletionResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<InlineCompletionItem>,
+    }
+
+    impl InlineCompletionResponse {
+        pub fn new(id: RequestId, items: Vec<InlineCompletionItem>) -> Self {
+            InlineCompletionResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: items,
+            }
+        }
+    }
+
+    // Request for the values shown next to the nodes in a range, eg. while debugging
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlineValueRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: InlineValueParams,
+    }
+
+    impl LspRequest for InlineValueRequest {
+        const METHOD: &'static str = "textDocument/inlineValue";
+        type Params = InlineValueParams;
+        type Result = Vec<InlineValue>;
+    }
+
+    // Parameters for the InlineValueRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlineValueParams {
+        pub text_document: TextDocumentIdentifier,
+        pub range: Range, // Visible part of the document
+        pub context: InlineValueContext,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlineValueContext {
+        pub frame_id: i64,           // Stack frame of the debugger
+        pub stopped_location: Range, // Where the execution stopped
+    }
+
+    // Text shown at the end of a range
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct InlineValueText {
+        pub range: Range,
+        pub text: String,
+    }
+
+    // Variable looked up by the client's debugger, with the variable name in the range if None
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlineValueVariableLookup {
+        pub range: Range,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub variable_name: Option<String>,
+        pub case_sensitive_lookup: bool,
+    }
+
+    // Expression evaluated by the client's debugger, the text in the range if None
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct InlineValueEvaluatableExpression {
+        pub range: Range,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub expression: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum InlineValue {
+        Text(InlineValueText),
+        VariableLookup(InlineValueVariableLookup),
+        EvaluatableExpression(InlineValueEvaluatableExpression),
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlineValueResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<InlineValue>,
+    }
+
+    impl InlineValueResponse {
+        pub fn new(id: RequestId, values: Vec<InlineValue>) -> Self {
+            InlineValueResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: values,
+            }
+        }
+    }
+
+    // Request for the hints shown between the nodes in a range
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct InlayHintRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: InlayHintParams,
+    }
+
+    impl LspRequest for InlayHintRequest {
+        const METHOD: &'static str = "textDocument/inlayHint";
+        type Params = InlayHintParams;
+        type Result = Vec<InlayHint>;
+    }
+
+    // Parameters for the InlayHintRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlayHintParams {
+        pub text_document: TextDocumentIdentifier,
+        pub range: Range, // Visible part of the document
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Label shown at a position, with a tooltip on hover
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InlayHint {
+        pub position: Position,
+        pub label: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tooltip: Option<MarkupContent>,
+        pub padding_left: bool, // Space between the node and the label
+    }
+
+    // Request for the monikers of the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MonikerRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: MonikerParams,
+    }
+
+    impl LspRequest for MonikerRequest {
+        const METHOD: &'static str = "textDocument/moniker";
+        type Params = MonikerParams;
+        type Result = Option<Vec<Moniker>>;
+    }
+
+    // Parameters for the MonikerRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MonikerParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    /// Scheme of the monikers of nodes, whose identifier is the path from the root
+    pub const MONIKER_SCHEME: &str = "lsp-rs";
+
+    // Scope in which the identifier of a moniker is unique
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub enum UniquenessLevel {
+        Document,
+        Project,
+        Group,
+        Scheme,
+        Global,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub enum MonikerKind {
+        Import,
+        Export,
+        Local,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct Moniker {
+        pub scheme: String,
+        pub identifier: String,
+        pub unique: UniquenessLevel,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub kind: Option<MonikerKind>,
+    }
+
+    // Response to the MonikerRequest, null if there is no node at the position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MonikerResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Option<Vec<Moniker>>,
+    }
+
+    impl MonikerResponse {
+        pub fn new(id: RequestId, monikers: Option<Vec<Moniker>>) -> Self {
+            MonikerResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: monikers,
+            }
+        }
+    }
+
+    /// Custom request for the metrics of a document's tree, for editor extensions to show
+    pub const DOCUMENT_STATS_METHOD: &str = "lsp-rs/documentStats";
+
+    // Request for the metrics of a document, see DOCUMENT_STATS_METHOD
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DocumentStatsRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: DocumentStatsParams,
+    }
+
+    impl LspRequest for DocumentStatsRequest {
+        const METHOD: &'static str = DOCUMENT_STATS_METHOD;
+        type Params = DocumentStatsParams;
+        type Result = TreeStats;
+    }
+
+    // Parameters for the DocumentStatsRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DocumentStatsParams {
+        pub text_document: TextDocumentIdentifier,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DocumentStatsResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: TreeStats,
+    }
+
+    impl DocumentStatsResponse {
+        pub fn new(id: RequestId, stats: TreeStats) -> Self {
+            DocumentStatsResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: stats,
+            }
+        }
+    }
+
+    // Request for the ranges edited together with the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LinkedEditingRangeRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: LinkedEditingRangeParams,
+    }
+
+    impl LspRequest for LinkedEditingRangeRequest {
+        const METHOD: &'static str = "textDocument/linkedEditingRange";
+        type Params = LinkedEditingRangeParams;
+        type Result = Option<LinkedEditingRanges>;
+    }
+
+    // Parameters for the LinkedEditingRangeRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LinkedEditingRangeParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    /// What a label may be changed to while linked editing, a single non space character
+    pub const LABEL_PATTERN: &str = "[^\\s]";
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct LinkedEditingRanges {
+        pub ranges: Vec<Range>, // Nodes with the same label
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub word_pattern: Option<String>,
+    }
+
+    // Response to the LinkedEditingRangeRequest, null if the label is not duplicated
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LinkedEditingRangeResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Option<LinkedEditingRanges>,
+    }
+
+    impl LinkedEditingRangeResponse {
+        pub fn new(id: RequestId, ranges: Option<LinkedEditingRanges>) -> Self {
+            LinkedEditingRangeResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: ranges,
+            }
+        }
+    }
+
+    // Request for the call hierarchy item of the node at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyPrepareRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: CallHierarchyPrepareParams,
+    }
+
+    impl LspRequest for CallHierarchyPrepareRequest {
+        const METHOD: &'static str = "textDocument/prepareCallHierarchy";
+        type Params = CallHierarchyPrepareParams;
+        type Result = Option<Vec<CallHierarchyItem>>;
+    }
+
+    // Parameters for the CallHierarchyPrepareRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyPrepareParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Kinds of symbols, only the ones used by the server
+    pub struct SymbolKind {}
+
+    impl SymbolKind {
+        pub const FUNCTION: i32 = 12;
+        pub const VARIABLE: i32 = 13;
+    }
+
+    // Request for the nodes whose label matches a query, in every known document
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceSymbolRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: WorkspaceSymbolParams,
+    }
+
+    impl LspRequest for WorkspaceSymbolRequest {
+        const METHOD: &'static str = "workspace/symbol";
+        type Params = WorkspaceSymbolParams;
+        type Result = Vec<WorkspaceSymbol>;
+    }
+
+    // Parameters for the WorkspaceSymbolRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceSymbolParams {
+        pub query: String, // Matched against labels ignoring case, empty matches every node
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+        #[serde(flatten)]
+        pub partial_result: PartialResultParams,
+    }
+
+    // Where a workspace symbol is, the range is left for workspaceSymbol/resolve if the client
+    // supports it
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum SymbolLocation {
+        Full(Location),
+        Uri { uri: String },
+    }
+
+    // A node found by a workspace symbol query
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceSymbol {
+        pub name: String,
+        pub kind: i32, // One of SymbolKind
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub container_name: Option<String>,
+        pub location: SymbolLocation,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub data: Option<Value>, // The node's index, to resolve the range
+    }
+
+    // Response to the WorkspaceSymbolRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceSymbolResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<WorkspaceSymbol>,
+    }
+
+    impl WorkspaceSymbolResponse {
+        pub fn new(id: RequestId, symbols: Vec<WorkspaceSymbol>) -> Self {
+            WorkspaceSymbolResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: symbols,
+            }
+        }
+    }
+
+    // Request for the range of a workspace symbol the user selected
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceSymbolResolveRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: WorkspaceSymbol,
+    }
+
+    impl LspRequest for WorkspaceSymbolResolveRequest {
+        const METHOD: &'static str = "workspaceSymbol/resolve";
+        type Params = WorkspaceSymbol;
+        type Result = WorkspaceSymbol;
+    }
+
+    // Response with the resolved workspace symbol
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkspaceSymbolResolveResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: WorkspaceSymbol,
+    }
+
+    impl WorkspaceSymbolResolveResponse {
+        pub fn new(id: RequestId, symbol: WorkspaceSymbol) -> Self {
+            WorkspaceSymbolResolveResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: symbol,
+            }
+        }
+    }
+
+    // Options of workspace/symbol the server advertises
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceSymbolOptions {
+        pub resolve_provider: bool, // Whether ranges are filled in with workspaceSymbol/resolve
+    }
+
+    // Request for the semantic tokens of a whole document
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SemanticTokensRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: SemanticTokensParams,
+    }
+
+    impl LspRequest for SemanticTokensRequest {
+        const METHOD: &'static str = "textDocument/semanticTokens/full";
+        type Params = SemanticTokensParams;
+        type Result = SemanticTokens;
+    }
+
+    // Parameters for the SemanticTokensRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SemanticTokensParams {
+        pub text_document: TextDocumentIdentifier,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+        #[serde(flatten)]
+        pub partial_result: PartialResultParams,
+    }
+
+    // Tokens of a document, 5 numbers per token: line and start relative to the token before,
+    // length, index of the type in the legend and modifiers as bits
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SemanticTokens {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub result_id: Option<String>,
+        pub data: Vec<u32>,
+    }
+
+    // Chunk of SemanticTokens sent with $/progress, its data is appended to the data so far
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SemanticTokensPartialResult {
+        pub data: Vec<u32>,
+    }
+
+    // Semantic tokens options the server advertises
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SemanticTokensOptions {
+        pub legend: SemanticTokensLegend,
+        pub full: bool, // Whether tokens are computed for whole documents
+    }
+
+    // Names of the token types and modifiers, the tokens refer to them by index
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct SemanticTokensLegend {
+        pub token_types: Vec<String>,
+        pub token_modifiers: Vec<String>,
+    }
+
+    // Type of the token of a node, its index in the legend
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SemanticTokenType {
+        Root = 0,
+        Node = 1, // a node with children
+        Leaf = 2,
+    }
+
+    impl SemanticTokenType {
+        // Standard token types the nodes are shown as, by index
+        pub const LEGEND: [&'static str; 3] = ["namespace", "class", "variable"];
+    }
+
+    // A node as an item of the call hierarchy
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CallHierarchyItem {
+        pub name: String, // Label of the node
+        pub kind: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub detail: Option<String>,
+        pub uri: String,
+        pub range: Range,
+        pub selection_range: Range,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub data: Option<Value>, // Kept by the client between requests, holds the node index
+    }
+
+    // Response to the CallHierarchyPrepareRequest, null if there is no node at the position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyPrepareResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Option<Vec<CallHierarchyItem>>,
+    }
+
+    impl CallHierarchyPrepareResponse {
+        pub fn new(id: RequestId, items: Option<Vec<CallHierarchyItem>>) -> Self {
+            CallHierarchyPrepareResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: items,
+            }
+        }
+    }
+
+    // Parameters of both call hierarchy requests, the item is one sent by the server
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyCallsParams {
+        pub item: CallHierarchyItem,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Request for the callers of an item, its parent
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyIncomingCallsRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: CallHierarchyCallsParams,
+    }
+
+    impl LspRequest for CallHierarchyIncomingCallsRequest {
+        const METHOD: &'static str = "callHierarchy/incomingCalls";
+        type Params = CallHierarchyCallsParams;
+        type Result = Vec<CallHierarchyIncomingCall>;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CallHierarchyIncomingCall {
+        pub from: CallHierarchyItem,
+        pub from_ranges: Vec<Range>, // Where the call is, in the caller
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyIncomingCallsResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<CallHierarchyIncomingCall>,
+    }
+
+    impl CallHierarchyIncomingCallsResponse {
+        pub fn new(id: RequestId, calls: Vec<CallHierarchyIncomingCall>) -> Self {
+            CallHierarchyIncomingCallsResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: calls,
+            }
+        }
+    }
+
+    // Request for the items called by an item, its children
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyOutgoingCallsRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: CallHierarchyCallsParams,
+    }
+
+    impl LspRequest for CallHierarchyOutgoingCallsRequest {
+        const METHOD: &'static str = "callHierarchy/outgoingCalls";
+        type Params = CallHierarchyCallsParams;
+        type Result = Vec<CallHierarchyOutgoingCall>;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CallHierarchyOutgoingCall {
+        pub to: CallHierarchyItem,
+        pub from_ranges: Vec<Range>, // Where the call is, in the item that was asked about
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CallHierarchyOutgoingCallsResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: Vec<CallHierarchyOutgoingCall>,
+    }
+
+    impl CallHierarchyOutgoingCallsResponse {
+        pub fn new(id: RequestId, calls: Vec<CallHierarchyOutgoingCall>) -> Self {
+            CallHierarchyOutgoingCallsResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: calls,
+            }
+        }
+    }
+
+    // Parameters used to specify a position within a text document
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentPositionParams {
+        pub text_document: TextDocumentIdentifier, // Identifier of the text document
+        pub position: Position,                    // Line and character position
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct TextDocumentIdentifier {
+        pub uri: String,
+    }
+
+    // Ordered by line, then character
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+    pub struct Position {
+        pub line: i32,      // Line number within the text document
+        pub character: i32, // Character offset within the line
+    }
+
+    // Written as `line:character`
+    impl Display for Position {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "{}:{}", self.line, self.character)
+        }
+    }
+
+    impl FromStr for Position {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let invalid = || format!("invalid position {}, expected line:character", s);
+            let (line, character) = s.split_once(':').ok_or_else(invalid)?;
+            Ok(Position {
+                line: line.trim().parse().map_err(|_| invalid())?,
+                character: character.trim().parse().map_err(|_| invalid())?,
+            })
+        }
+    }
+
+    // A range in a text document, the end position is exclusive. Ordered by start, then end
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+    pub struct Range {
+        pub start: Position,
+        pub end: Position,
+    }
+
+    // Written as `line:character-line:character`
+    impl Display for Range {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+
+    impl FromStr for Range {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (start, end) = s
+                .split_once('-')
+                .ok_or_else(|| format!("invalid range {}, expected start-end", s))?;
+            Ok(Range {
+                start: start.parse()?,
+                end: end.parse()?,
+            })
+        }
+    }
+
+    // Token used to report progress, either chosen by the client or created by the server
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum ProgressToken {
+        Number(i64),
+        String(String),
+    }
+
+    // Mixed into request params that can report work done progress
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkDoneProgressParams {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub work_done_token: Option<ProgressToken>, // Token supplied by the client
+    }
+
+    // Mixed into request params whose results can be streamed with $/progress
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PartialResultParams {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub partial_result_token: Option<ProgressToken>, // Token supplied by the client
+    }
+
+    // $/progress notification sent from the server with a chunk of a request's result
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PartialResultNotification<T> {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: PartialResultProgress<T>,
+    }
+
+    impl<T> LspNotification for PartialResultNotification<T> {
+        const METHOD: &'static str = "$/progress";
+        type Params = PartialResultProgress<T>;
+    }
+
+    impl<T> PartialResultNotification<T> {
+        pub fn new(token: ProgressToken, value: T) -> Self {
+            PartialResultNotification {
+                notification: Notification::new(Self::METHOD),
+                params: PartialResultProgress { token, value },
+            }
+        }
+    }
+
+    // Parameters for the PartialResultNotification, the value is added to the result so far
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PartialResultProgress<T> {
+        pub token: ProgressToken,
+        pub value: T,
+    }
+
+    // Request sent from the server to ask the client to create a progress
+    pub type WorkDoneProgressCreateRequest = OutgoingRequest<WorkDoneProgressCreateParams>;
+
+    impl LspRequest for WorkDoneProgressCreateRequest {
+        const METHOD: &'static str = "window/workDoneProgress/create";
+        type Params = WorkDoneProgressCreateParams;
+        type Result = ();
+    }
+
+    // Parameters for the WorkDoneProgressCreateRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkDoneProgressCreateParams {
+        pub token: ProgressToken, // The token to be used to report progress
+    }
+
+    // $/progress notification sent from the server to report progress
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ProgressNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: ProgressParams,
+    }
+
+    impl LspNotification for ProgressNotification {
+        const METHOD: &'static str = "$/progress";
+        type Params = ProgressParams;
+    }
+
+    impl ProgressNotification {
+        pub fn new(token: ProgressToken, value: WorkDoneProgressValue) -> Self {
+            ProgressNotification {
+                notification: Notification::new(Self::METHOD),
+                params: ProgressParams { token, value },
+            }
+        }
+    }
+
+    // Parameters for the ProgressNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ProgressParams {
+        pub token: ProgressToken, // The progress token provided by the client or server
+        pub value: WorkDoneProgressValue, // The progress data
+    }
+
+    // The payload of a work done progress, discriminated by the kind field
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(tag = "kind", rename_all = "lowercase")]
+    pub enum WorkDoneProgressValue {
+        Begin(WorkDoneProgressBegin),
+        Report(WorkDoneProgressReport),
+        End(WorkDoneProgressEnd),
+    }
+
+    // Signals the start of a progress, the title is shown in the client's progress bar
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkDoneProgressBegin {
+        pub title: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cancellable: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub percentage: Option<u32>, // Percentage in range 0..=100
+    }
+
+    // Reports an update of an ongoing progress
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkDoneProgressReport {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub cancellable: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub percentage: Option<u32>,
+    }
+
+    // Signals the end of a progress
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WorkDoneProgressEnd {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+    }
+
+    /// An ongoing work done progress, started with `ServerContext::start_progress`.
+    /// Sends `$/progress` notifications with its token until `end` is called, once it began
+    pub struct WorkDoneProgress {
+        token: ProgressToken,
+        state: Rc<Cell<ProgressState>>, // shared with the callback of the create request
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ProgressState {
+        Creating, // waiting for the client to create the token
+        Begun,
+        Ended,
+    }
+
+    impl WorkDoneProgress {
+        fn new(token: ProgressToken, state: ProgressState) -> Self {
+            WorkDoneProgress {
+                token,
+                state: Rc::new(Cell::new(state)),
+            }
+        }
+
+        fn begin(&self, title: String, output: &Output, logger: &mut dyn Write) {
+            let value = WorkDoneProgressValue::Begin(WorkDoneProgressBegin {
+                title,
+                cancellable: Some(false),
+                message: None,
+                percentage: Some(0),
+            });
+            send_message(
+                &ProgressNotification::new(self.token.clone(), value),
+                output,
+                logger,
+            );
+        }
+
+        pub fn token(&self) -> &ProgressToken {
+            &self.token
+        }
+
+        /// Report how far the operation got, dropped until the progress began
+        pub fn report(
+            &self,
+            message: Option<String>,
+            percentage: Option<u32>,
+            output: &Output,
+            logger: &mut dyn Write,
+        ) {
+            if self.state.get() != ProgressState::Begun {
+                return;
+            }
+            let value = WorkDoneProgressValue::Report(WorkDoneProgressReport {
+                cancellable: None,
+                message,
+                percentage,
+            });
+            send_message(
+                &ProgressNotification::new(self.token.clone(), value),
+                output,
+                logger,
+            );
+        }
+
+        /// End the progress, it never begins if the client didn't create it yet
+        pub fn end(self, message: Option<String>, output: &Output, logger: &mut dyn Write) {
+            if self.state.replace(ProgressState::Ended) != ProgressState::Begun {
+                return;
+            }
+            let value = WorkDoneProgressValue::End(WorkDoneProgressEnd { message });
+            send_message(
+                &ProgressNotification::new(self.token, value),
+                output,
+                logger,
+            );
+        }
+    }
+
+    // Type of a message shown or logged in the client, serialized as its number
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MessageType {
+        Error = 1,
+        Warning = 2,
+        Info = 3,
+        Log = 4,
+    }
+
+    impl MessageType {
+        /// Severity of a line written to the logger, based on its `[Tag]` prefix.
+        /// Raw traffic is only Log level since it is mostly useful when debugging the server
+        pub fn from_log_line(line: &str) -> Self {
+            if line.starts_with("[Error]") || line.starts_with("[Err]") {
+                MessageType::Error
+            } else if line.starts_with("[Warning]") {
+                MessageType::Warning
+            } else if line.starts_with("[Method]")
+                || line.starts_with("[Content]")
+                || line.starts_with("[Sent Message]")
+                || line.starts_with("[Trace]")
+            {
+                MessageType::Log
+            } else {
+                MessageType::Info
+            }
+        }
+    }
+
+    impl From<LogLevel> for MessageType {
+        fn from(level: LogLevel) -> Self {
+            match level {
+                LogLevel::Error => MessageType::Error,
+                LogLevel::Warning => MessageType::Warning,
+                LogLevel::Info => MessageType::Info,
+                LogLevel::Log => MessageType::Log,
+            }
+        }
+    }
+
+    impl From<MessageType> for LogLevel {
+        fn from(message_type: MessageType) -> Self {
+            match message_type {
+                MessageType::Error => LogLevel::Error,
+                MessageType::Warning => LogLevel::Warning,
+                MessageType::Info => LogLevel::Info,
+                MessageType::Log => LogLevel::Log,
+            }
+        }
+    }
+
+    impl Serialize for MessageType {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_u8(*self as u8)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MessageType {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            match u8::deserialize(deserializer)? {
+                1 => Ok(MessageType::Error),
+                2 => Ok(MessageType::Warning),
+                3 => Ok(MessageType::Info),
+                4 => Ok(MessageType::Log),
+                n => Err(serde::de::Error::custom(format!(
+                    "invalid MessageType {}",
+                    n
+                ))),
+            }
+        }
+    }
+
+    // telemetry/event notification sent from the server, only if the user enabled telemetry
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct TelemetryEventNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: TelemetryEvent,
+    }
+
+    impl LspNotification for TelemetryEventNotification {
+        const METHOD: &'static str = "telemetry/event";
+        type Params = TelemetryEvent;
+    }
+
+    impl TelemetryEventNotification {
+        pub fn new(event: TelemetryEvent) -> Self {
+            TelemetryEventNotification {
+                notification: Notification::new(Self::METHOD),
+                params: event,
+            }
+        }
+    }
+
+    // $/setTrace notification sent by the client to change the trace setting
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SetTraceNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: SetTraceParams,
+    }
+
+    impl LspNotification for SetTraceNotification {
+        const METHOD: &'static str = "$/setTrace";
+        type Params = SetTraceParams;
+    }
+
+    // Parameters for the SetTraceNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SetTraceParams {
+        pub value: TraceValue,
+    }
+
+    // $/cancelRequest notification sent by the client when it no longer needs a response
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CancelNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: CancelParams,
+    }
+
+    impl LspNotification for CancelNotification {
+        const METHOD: &'static str = "$/cancelRequest";
+        type Params = CancelParams;
+    }
+
+    // Parameters for the CancelNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CancelParams {
+        pub id: RequestId,
+    }
+
+    /// Flip the token of the request the message cancels, or make the token of the request
+    /// the message is. Lets the transport cancel requests as soon as the cancellation is read,
+    /// while they are queued or handled
+    pub fn track_cancellation(message: &str, cancellations: &CancellationRegistry) {
+        if let Some(id) = cancelled_request(message) {
+            cancellations.cancel(&id);
+        } else if let Ok(IncomingMessage::Request { id, .. }) = IncomingMessage::parse(message) {
+            cancellations.queue(&id);
+        }
+    }
+
+    /// Id of the request cancelled by the message, if it is a `$/cancelRequest` notification
+    fn cancelled_request(message: &str) -> Option<RequestId> {
+        // most messages aren't cancellations, skip parsing them
+        if !message.contains("$/cancelRequest") {
+            return None;
+        }
+        match json_from_string::<CancelNotification>(message) {
+            Ok(msg) if msg.notification.method == "$/cancelRequest" => Some(msg.params.id),
+            _ => None,
+        }
+    }
+
+    // $/logTrace notification sent from the server to trace the messages it handles
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LogTraceNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: LogTraceParams,
+    }
+
+    impl LspNotification for LogTraceNotification {
+        const METHOD: &'static str = "$/logTrace";
+        type Params = LogTraceParams;
+    }
+
+    impl LogTraceNotification {
+        pub fn new(message: String, verbose: Option<String>) -> Self {
+            LogTraceNotification {
+                notification: Notification::new(Self::METHOD),
+                params: LogTraceParams { message, verbose },
+            }
+        }
+    }
+
+    // Parameters for the LogTraceNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LogTraceParams {
+        pub message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub verbose: Option<String>, // Only sent when the trace is verbose
+    }
+
+    // window/showMessage notification sent from the server to display a message to the user
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ShowMessageNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: ShowMessageParams,
+    }
+
+    impl LspNotification for ShowMessageNotification {
+        const METHOD: &'static str = "window/showMessage";
+        type Params = ShowMessageParams;
+    }
+
+    impl ShowMessageNotification {
+        pub fn new(message_type: MessageType, message: String) -> Self {
+            ShowMessageNotification {
+                notification: Notification::new(Self::METHOD),
+                params: ShowMessageParams {
+                    message_type,
+                    message,
+                },
+            }
+        }
+    }
+
+    // Parameters for the ShowMessageNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ShowMessageParams {
+        #[serde(rename = "type")]
+        pub message_type: MessageType, // Severity of the message
+        pub message: String, // The actual message
+    }
+
+    // window/showDocument request sent from the server to open a document in the client
+    pub type ShowDocumentRequest = OutgoingRequest<ShowDocumentParams>;
+
+    impl LspRequest for ShowDocumentRequest {
+        const METHOD: &'static str = "window/showDocument";
+        type Params = ShowDocumentParams;
+        type Result = ShowDocumentResult;
+    }
+
+    // Parameters for the ShowDocumentRequest
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ShowDocumentParams {
+        pub uri: String, // The uri to show, can be a file or an external url
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub external: Option<bool>, // Show the uri in an external program, eg. the browser
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub take_focus: Option<bool>, // Whether the editor showing the document should take focus
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub selection: Option<Range>, // Range to select if the document is a text document
+    }
+
+    // Result of the ShowDocumentRequest sent back by the client
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ShowDocumentResult {
+        pub success: bool, // Whether the document was successfully shown
+    }
+
+    // window/logMessage notification sent from the server to log a message in the client
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LogMessageNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: LogMessageParams,
+    }
+
+    impl LspNotification for LogMessageNotification {
+        const METHOD: &'static str = "window/logMessage";
+        type Params = LogMessageParams;
+    }
+
+    impl LogMessageNotification {
+        pub fn new(message_type: MessageType, message: String) -> Self {
+            LogMessageNotification {
+                notification: Notification::new(Self::METHOD),
+                params: LogMessageParams {
+                    message_type,
+                    message,
+                },
+            }
+        }
+    }
+
+    // Parameters for the LogMessageNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct LogMessageParams {
+        #[serde(rename = "type")]
+        pub message_type: MessageType, // Severity of the message
+        pub message: String, // The actual message
+    }
+
+    // window/showMessageRequest request sent from the server, the user picks one of the actions
+    pub type ShowMessageRequest = OutgoingRequest<ShowMessageRequestParams>;
+
+    impl LspRequest for ShowMessageRequest {
+        const METHOD: &'static str = "window/showMessageRequest";
+        type Params = ShowMessageRequestParams;
+        type Result = Option<MessageActionItem>;
+    }
+
+    // Parameters for the window/showMessageRequest request, shows a message with action buttons
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ShowMessageRequestParams {
+        #[serde(rename = "type")]
+        pub message_type: MessageType, // Severity of the message
+        pub message: String, // The actual message
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub actions: Option<Vec<MessageActionItem>>, // Buttons shown with the message
+    }
+
+    // An action shown with a window/showMessageRequest, the chosen one is sent back as result
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MessageActionItem {
+        pub title: String,
+    }
+
+    // Notification sent by the client when the configuration changed
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DidChangeConfigurationNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: DidChangeConfigurationParams,
+    }
+
+    impl LspNotification for DidChangeConfigurationNotification {
+        const METHOD: &'static str = "workspace/didChangeConfiguration";
+        type Params = DidChangeConfigurationParams;
+    }
+
+    // Parameters for the DidChangeConfigurationNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DidChangeConfigurationParams {
+        pub settings: Value, // The changed settings, the layout is up to the server
+    }
+
+    // Request for completion items at a position
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: CompletionParams,
+    }
+
+    impl LspRequest for CompletionRequest {
+        const METHOD: &'static str = "textDocument/completion";
+        type Params = CompletionParams;
+        type Result = CompletionList;
+    }
+
+    // Parameters for the CompletionRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionParams {
+        #[serde(flatten)]
+        pub pos_params: TextDocumentPositionParams, // Position where completion was requested
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Response containing the completion items
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: CompletionList,
+    }
+
+    impl CompletionResponse {
+        pub fn new(id: RequestId, items: Vec<CompletionItem>) -> Self {
+            CompletionResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: CompletionList {
+                    is_incomplete: false,
+                    items,
+                },
+            }
+        }
+    }
+
+    // List of completion items
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CompletionList {
+        pub is_incomplete: bool, // Whether typing more should recompute the list
+        pub items: Vec<CompletionItem>,
+    }
+
+    // A single completion suggestion
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CompletionItem {
+        pub label: String, // Text shown in the completion list
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub kind: Option<usize>, // One of CompletionItemKind
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub detail: Option<String>, // Additional information, eg. which source suggested it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub sort_text: Option<String>, // Used instead of label when sorting
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub insert_text: Option<String>, // Text inserted instead of label
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub documentation: Option<MarkupContent>, // Filled in by completionItem/resolve
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub data: Option<Value>, // Kept by the client and sent back with completionItem/resolve
+    }
+
+    // What completionItem/resolve needs to fill in an item, round-tripped in its data
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionItemData {
+        pub uri: String,
+        pub index: usize, // node the item completes
+        pub detail: String,
+    }
+
+    // Request for the details of a completion item the user selected
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionItemResolveRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: CompletionItem,
+    }
+
+    impl LspRequest for CompletionItemResolveRequest {
+        const METHOD: &'static str = "completionItem/resolve";
+        type Params = CompletionItem;
+        type Result = CompletionItem;
+    }
+
+    // Response with the resolved completion item
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CompletionItemResolveResponse {
+        #[serde(flatten)]
+        pub response: ResponseMessage,
+        pub result: CompletionItem,
+    }
+
+    impl CompletionItemResolveResponse {
+        pub fn new(id: RequestId, item: CompletionItem) -> Self {
+            CompletionItemResolveResponse {
+                response: ResponseMessage {
+                    id,
+                    message: Message {
+                        jsonrpc: "2.0".to_string(),
+                    },
+                },
+                result: item,
+            }
+        }
+    }
+
+    impl CompletionItem {
+        /// Item for a ranked candidate, the rank decides the order in the client.
+        /// The detail is left for completionItem/resolve, see `CompletionItemData`
+        pub fn new(rank: usize, candidate: Candidate) -> Self {
+            let kind = match candidate.kind {
+                CandidateKind::Value => CompletionItemKind::VALUE,
+                CandidateKind::Structure => CompletionItemKind::TEXT,
+            };
+            CompletionItem {
+                label: candidate.label,
+                kind: Some(kind),
+                detail: Some(candidate.detail),
+                sort_text: Some(format!("{:04}", rank)),
+                insert_text: Some(candidate.insert_text),
+                documentation: None,
+                data: None,
+            }
+        }
+    }
+
+    // Kinds of completion items, decides the icon shown by the client
+    pub struct CompletionItemKind {}
+
+    impl CompletionItemKind {
+        pub const TEXT: usize = 1;
+        pub const VALUE: usize = 12;
+    }
+
+    // workspace/executeCommand request sent by the client to run one of the server's commands
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ExecuteCommandRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: ExecuteCommandParams,
+    }
+
+    impl LspRequest for ExecuteCommandRequest {
+        const METHOD: &'static str = "workspace/executeCommand";
+        type Params = ExecuteCommandParams;
+        type Result = Value;
+    }
+
+    // Parameters for the ExecuteCommandRequest
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ExecuteCommandParams {
+        pub command: String, // Identifier of the command, one of ExecuteCommandOptions.commands
+        pub arguments: Option<Vec<Value>>,
+        #[serde(flatten)]
+        pub work_done: WorkDoneProgressParams,
+    }
+
+    // Textual change to a document
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextEdit {
+        pub range: Range, // Replaced range, an empty range inserts
+        pub new_text: String,
+    }
+
+    // Changes to several documents, applied by the client
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WorkspaceEdit {
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        pub changes: HashMap<String, Vec<TextEdit>>, // Edits by document uri
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub document_changes: Option<Vec<DocumentChange>>, // Edits and file operations, in order
+    }
+
+    // One step of the document changes of a WorkspaceEdit
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum DocumentChange {
+        Operation(ResourceOperation),
+        Edit(TextDocumentEdit),
+    }
+
+    // Edits to a single document, at the version given if there is one
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TextDocumentEdit {
+        pub text_document: OptionalVersionedTextDocumentIdentifier,
+        pub edits: Vec<TextEdit>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct OptionalVersionedTextDocumentIdentifier {
+        pub uri: String,
+        pub version: Option<i32>, // null if the edit applies to any version
+    }
+
+    // Operations on files, only applied by clients listing their kind in resourceOperations
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(tag = "kind", rename_all = "camelCase")]
+    pub enum ResourceOperation {
+        Create(CreateFile),
+        Rename(RenameFile),
+        Delete(DeleteFile),
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct CreateFile {
+        pub uri: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub options: Option<FileOperationOptions>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RenameFile {
+        pub old_uri: String,
+        pub new_uri: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub options: Option<FileOperationOptions>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    pub struct DeleteFile {
+        pub uri: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub options: Option<DeleteFileOptions>,
+    }
+
+    // Options of creating and renaming files, overwrite wins over ignoreIfExists
+    #[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct FileOperationOptions {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub overwrite: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ignore_if_exists: Option<bool>,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DeleteFileOptions {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub recursive: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ignore_if_not_exists: Option<bool>,
     }
 
-    /// Given the content of the message, return the corresponding object
-    pub fn message_to_object<T>(message: &String) -> Result<T, MsgParseError>
-    where
-        T: DeserializeOwned,
-    {
-        match json_from_string(message) {
-            Ok(msg) => Ok(msg),
-            Err(e) => Err(MsgParseError(e.to_string())),
-        }
+    pub type ApplyWorkspaceEditRequest = OutgoingRequest<ApplyWorkspaceEditParams>;
+
+    impl LspRequest for ApplyWorkspaceEditRequest {
+        const METHOD: &'static str = "workspace/applyEdit";
+        type Params = ApplyWorkspaceEditParams;
+        type Result = ApplyWorkspaceEditResult;
     }
 
-    #[derive(Debug, Clone)]
-    pub struct MsgParseError(pub String);
-    impl Display for MsgParseError {
-        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-            self.0.fmt(f)
-        }
+    // Parameters for the workspace/applyEdit request sent from the server
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ApplyWorkspaceEditParams {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub label: Option<String>, // Shown in the undo stack of the client
+        pub edit: WorkspaceEdit,
     }
-}
 
-pub mod lsp {
-    use serde::{Deserialize, Serialize};
-    use std::io::{self, Write};
+    // Result of the ApplyWorkspaceEditRequest sent back by the client
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ApplyWorkspaceEditResult {
+        pub applied: bool,
+        pub failure_reason: Option<String>,
+    }
 
-    use crate::{
-        editor::EditorState,
-        rpc::{encode_message, json_from_string, json_to_string, message_to_object, MsgParseError},
-    };
+    // workspace/configuration request sent from the server to pull the settings
+    pub type ConfigurationRequest = OutgoingRequest<ConfigurationParams>;
 
-    /// Given an arbitrary message (with method field), handle the message accordingly
-    /// If initialize request, send the initialize response
-    /// If didOpen or didChange, sync the editor_state
-    /// If hover request, resond with hover response
-    /// Writing debugging information to the logger is optional
-    pub fn handle_message(
-        message: String,
-        editor_state: &mut EditorState,
-        logger: &mut impl Write,
-    ) -> Result<(), MsgParseError> {
-        let method = match message_to_object::<Notification>(&message) {
-            Ok(msg) => msg.method,
-            Err(e) => return Err(MsgParseError(e.to_string())),
-        };
-        writeln!(logger, "[Method] {}", method).unwrap();
-        writeln!(logger, "[Content] {}", message).unwrap();
-        match method.as_str() {
-            "initialize" => match json_from_string::<InitializeRequest>(&message) {
-                Ok(msg) => {
-                    writeln!(
-                        logger,
-                        "[Initialize] Recieved from {:?} with id {}",
-                        msg.params.client_info, msg.request.id
-                    )
-                    .unwrap();
-                    let response = InitializeResponse::new(
-                        msg.request.id,
-                        "LSP-Server".to_string(),
-                        "0".to_string(),
-                    );
-                    let response_str = json_to_string(&response);
-                    let encoded_response = encode_message(response_str);
-                    writeln!(logger, "[Sent Response] {:?}", encoded_response).unwrap();
+    impl LspRequest for ConfigurationRequest {
+        const METHOD: &'static str = "workspace/configuration";
+        type Params = ConfigurationParams;
+        type Result = Vec<Value>;
+    }
 
-                    io::stdout().write(encoded_response.as_bytes()).unwrap();
-                    io::stdout().flush().unwrap();
-                    Ok(())
-                }
-                Err(e) => Err(MsgParseError(format!(
-                    "Could not parse InitializeRequest, error {}",
-                    e.to_string()
-                ))),
-            },
-            "textDocument/didOpen" => {
-                match json_from_string::<DidOpenTextDocumentNotification>(&message) {
-                    Ok(msg) => {
-                        writeln!(
-                            logger,
-                            "[Initialize] Recieved didOpen on file {} with version {}",
-                            msg.params.text_document.uri, msg.params.text_document.version
-                        )
-                        .unwrap();
-                        let modify_success = editor_state.modify_file(
-                            msg.params.text_document.uri.clone(),
-                            msg.params.text_document.text.clone(),
-                        );
-                        if !modify_success {
-                            writeln!(
-                                logger,
-                                "[Error] open {} file with text {:?} not successful",
-                                msg.params.text_document.uri, msg.params.text_document.text
-                            )
-                            .unwrap();
-                        } else {
-                            writeln!(
-                                logger,
-                                "[DidOpen] open {} file with text {:?} successful",
-                                msg.params.text_document.uri, msg.params.text_document.text
-                            )
-                            .unwrap();
-                        }
-                        Ok(())
-                    }
-                    Err(e) => Err(MsgParseError(format!(
-                        "Could not parse DidOpenNotification, error {}",
-                        e.to_string()
-                    ))),
-                }
-            }
-            "textDocument/didChange" => {
-                match json_from_string::<TextDocumentDidChangeNotification>(&message) {
-                    Ok(msg) => {
-                        writeln!(
-                            logger,
-                            "[DidChange] Recieved didChange on file {} with version {}",
-                            msg.params.text_document.uri, msg.params.text_document.version
-                        )
-                        .unwrap();
-                        let mut modify_success = true;
-                        for change in msg.params.content_changes {
-                            modify_success &= editor_state.modify_file(
-                                msg.params.text_document.uri.clone(),
-                                change.text.clone(),
-                            );
-                        }
-                        if !modify_success {
-                            writeln!(
-                                logger,
-                                "[Error] modify {} file with text not successful",
-                                msg.params.text_document.uri
-                            )
-                            .unwrap();
-                        } else {
-                            writeln!(
-                                logger,
-                                "[DidChange] modify {} file successful",
-                                msg.params.text_document.uri
-                            )
-                            .unwrap();
-                        }
-                        Ok(())
-                    }
-                    Err(e) => Err(MsgParseError(format!(
-                        "[Err] Could not parse DidOpenNotification, error {}",
-                        e.to_string()
-                    ))),
-                }
-            }
-            "textDocument/hover" => match json_from_string::<HoverRequest>(&message) {
-                Ok(msg) => {
-                    writeln!(
-                        logger,
-                        "[HoverRequest] Recieved from {:?}",
-                        msg.params.pos_params.text_document.uri
-                    )
-                    .unwrap();
+    // Parameters for the workspace/configuration request sent from the server
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct ConfigurationParams {
+        pub items: Vec<ConfigurationItem>,
+    }
 
-                    let Some(fs) = editor_state
-                        .get_file_state(msg.params.pos_params.text_document.uri.clone())
-                    else {
-                        return Err(MsgParseError(format!(
-                            "Could not find file {}",
-                            msg.params.pos_params.text_document.uri
-                        )));
-                    };
+    // A configuration section to fetch, the client responds with one value per item
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ConfigurationItem {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub scope_uri: Option<String>, // Resource the configuration is for
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub section: Option<String>, // The configuration section asked for
+    }
 
-                    let line_num = msg.params.pos_params.position.line as u32;
-                    let char_num = msg.params.pos_params.position.character as usize;
-                    let n = usize::pow(2, line_num) - 1;
-                    let index = n + char_num / 2;
-                    let hover_rsp_msg = if char_num % 2 != 0 {
-                        format!("Character count: {}", fs.get_char_count())
-                    } else {
-                        if let Some(c) = fs.parent(index) {
-                            format!("Parent: {}", c)
-                        } else {
-                            format!("Could not find parent to {} {}", index, (index - 1) / 2)
-                        }
-                    };
+    // client/registerCapability request sent from the server
+    pub type RegistrationRequest = OutgoingRequest<RegistrationParams>;
 
-                    let response = HoverResponse::new(msg.request.id, hover_rsp_msg);
-                    let response_str = json_to_string(&response);
-                    let encoded_response = encode_message(response_str);
-                    writeln!(logger, "[Sent Response] {:?}", encoded_response).unwrap();
+    impl LspRequest for RegistrationRequest {
+        const METHOD: &'static str = "client/registerCapability";
+        type Params = RegistrationParams;
+        type Result = ();
+    }
 
-                    io::stdout().write(encoded_response.as_bytes()).unwrap();
-                    io::stdout().flush().unwrap();
-                    Ok(())
-                }
-                Err(e) => Err(MsgParseError(format!(
-                    "Could not parse HoverRequest, error {}",
-                    e.to_string()
-                ))),
-            },
+    // Parameters for client/registerCapability
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct RegistrationParams {
+        pub registrations: Vec<Registration>,
+    }
 
-            _ => Ok(()),
-        }
+    // A capability registered with the client after initialization
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Registration {
+        pub id: String,     // Id used to unregister the capability again
+        pub method: String, // The method the capability is for
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub register_options: Option<Value>, // Options specific to the method
     }
 
-    // This code defines various structs used for representing messages within the LSP
+    // client/unregisterCapability request sent from the server
+    pub type UnregistrationRequest = OutgoingRequest<UnregistrationParams>;
 
-    #[derive(Debug, Deserialize, Serialize)]
-    pub struct Message {
-        // The LSP message header specifying the JSON RPC version ("2.0")
-        pub jsonrpc: String,
+    impl LspRequest for UnregistrationRequest {
+        const METHOD: &'static str = "client/unregisterCapability";
+        type Params = UnregistrationParams;
+        type Result = ();
     }
 
-    // Notification messages are sent from the client to the server
+    // Parameters for client/unregisterCapability
     #[derive(Debug, Deserialize, Serialize)]
-    pub struct Notification {
-        #[serde(flatten)]
-        pub message: Message,
-        pub method: String, // The specific notification method name (e.g., "textDocument/didOpen")
+    pub struct UnregistrationParams {
+        pub unregisterations: Vec<Unregistration>, // Misspelled in the specification
     }
 
-    // Request messages are sent from the client to the server and expect a response
+    // A registration to remove, by the id it was registered with
     #[derive(Debug, Deserialize, Serialize)]
-    pub struct RequestMessage {
-        #[serde(flatten)]
-        pub base_message: Notification, // Contains message header and method
-        pub id: i64, // Unique identifier for the request
+    pub struct Unregistration {
+        pub id: String,
+        pub method: String,
     }
 
-    // Response messages are sent from the server to the client in response to requests
+    // A file watcher registered with workspace/didChangeWatchedFiles
     #[derive(Debug, Deserialize, Serialize)]
-    pub struct ResponseMessage {
-        #[serde(flatten)]
-        pub message: Message,
-        pub id: i64, // The id that matches the original request
+    #[serde(rename_all = "camelCase")]
+    pub struct FileSystemWatcher {
+        pub glob_pattern: String, // Files to watch, relative to the workspace folders
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub kind: Option<u8>, // Events to watch, defaults to create, change and delete
     }
 
-    // Initialize request is sent by the client to the server during initialization
+    // Notification sent by the client when watched files changed on disk
     #[derive(Debug, Deserialize, Serialize)]
-    pub struct InitializeRequest {
+    pub struct DidChangeWatchedFilesNotification {
         #[serde(flatten)]
-        pub request: RequestMessage, // Contains message header, method, and id
-        pub params: InitializeParams, // Specific parameters for initialization
+        pub notification: Notification,
+        pub params: DidChangeWatchedFilesParams,
     }
 
-    // Parameters for the InitializeRequest
-    #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct InitializeParams {
-        pub process_id: i64, // process ID of the client process (different from id)
-        pub client_info: Option<Info>, // Optional information about the client
+    impl LspNotification for DidChangeWatchedFilesNotification {
+        const METHOD: &'static str = "workspace/didChangeWatchedFiles";
+        type Params = DidChangeWatchedFilesParams;
     }
 
-    // Information about the client/server application
+    // Parameters for the DidChangeWatchedFilesNotification
     #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct Info {
-        pub name: String,
-        pub version: String,
+    pub struct DidChangeWatchedFilesParams {
+        pub changes: Vec<FileEvent>,
     }
 
-    // Initialize response sent by the server after initialization
+    // A change to a single watched file
     #[derive(Debug, Deserialize, Serialize)]
+    pub struct FileEvent {
+        pub uri: String,
+        #[serde(rename = "type")]
+        pub change_type: u8, // One of FileChangeType
+    }
+
+    // Types of changes to watched files
+    pub struct FileChangeType {}
+
+    impl FileChangeType {
+        pub const CREATED: u8 = 1;
+        pub const CHANGED: u8 = 2;
+        pub const DELETED: u8 = 3;
+    }
+
+    // Files the server wants file operation requests and notifications for
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct FileOperationRegistrationOptions {
+        pub filters: Vec<FileOperationFilter>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct FileOperationFilter {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub scheme: Option<String>, // Uri scheme of the files, eg. `file`
+        pub pattern: FileOperationPattern,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct FileOperationPattern {
+        pub glob: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub matches: Option<String>, // `file` or `folder`, both if missing
+    }
+
+    // File operations the server is interested in, by operation
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
-    pub struct InitializeResponse {
-        #[serde(flatten)]
-        pub response: ResponseMessage,
-        pub result: InitializeResult,
+    pub struct FileOperationsServerCapabilities {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub did_create: Option<FileOperationRegistrationOptions>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub will_rename: Option<FileOperationRegistrationOptions>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub did_rename: Option<FileOperationRegistrationOptions>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub will_delete: Option<FileOperationRegistrationOptions>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub did_delete: Option<FileOperationRegistrationOptions>,
     }
 
-    // Result of the initialization process
-    #[derive(Debug, Deserialize, Serialize)]
+    // Workspace features of the server
+    #[derive(Debug, Clone, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
-    pub struct InitializeResult {
-        pub capabilities: ServerCapabilities, // Capabilities offered by the server
-        pub server_info: Info,                // Information about the server
+    pub struct WorkspaceServerCapabilities {
+        pub file_operations: FileOperationsServerCapabilities,
     }
 
-    // Helper function to create an InitializeResponse message
-    impl InitializeResponse {
-        pub fn new(id: i64, name: String, version: String) -> InitializeResponse {
-            InitializeResponse {
-                response: ResponseMessage {
-                    id,
-                    message: Message {
-                        jsonrpc: String::from("2.0"),
+    impl WorkspaceServerCapabilities {
+        /// Tree files, and folders which may contain some
+        fn tree_files() -> Self {
+            let options = FileOperationRegistrationOptions {
+                filters: vec![
+                    FileOperationFilter {
+                        scheme: Some("file".to_string()),
+                        pattern: FileOperationPattern {
+                            glob: "**/*.abc".to_string(),
+                            matches: Some("file".to_string()),
+                        },
                     },
-                },
-                result: InitializeResult {
-                    capabilities: ServerCapabilities {
-                        text_document_sync: TextDocumentSyncKind::FULL,
-                        hover_provider: true,
+                    FileOperationFilter {
+                        scheme: Some("file".to_string()),
+                        pattern: FileOperationPattern {
+                            glob: "**/*".to_string(),
+                            matches: Some("folder".to_string()),
+                        },
                     },
-                    server_info: Info { name, version },
+                ],
+            };
+            WorkspaceServerCapabilities {
+                file_operations: FileOperationsServerCapabilities {
+                    did_create: Some(options.clone()),
+                    will_rename: Some(options.clone()),
+                    did_rename: Some(options.clone()),
+                    will_delete: Some(options.clone()),
+                    did_delete: Some(options),
                 },
             }
         }
     }
 
-    // Different TextDocumentSync options (currently only FULL is supported)
-    pub struct TextDocumentSyncKind {}
+    // Notification sent by the client after it created files
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct DidCreateFilesNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: CreateFilesParams,
+    }
 
-    impl TextDocumentSyncKind {
-        const _NONE: usize = 0;
-        const FULL: usize = 1;
-        const _INCREMENTAL: usize = 2;
+    impl LspNotification for DidCreateFilesNotification {
+        const METHOD: &'static str = "workspace/didCreateFiles";
+        type Params = CreateFilesParams;
     }
 
-    // Description of the server's capabilities
     #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct ServerCapabilities {
-        pub text_document_sync: usize, // Type of text document synchronization supported
-        pub hover_provider: bool,      // Whether the server can provide hover information
+    pub struct CreateFilesParams {
+        pub files: Vec<FileCreate>,
     }
 
-    // Notification sent by the client when a document is opened
     #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct DidOpenTextDocumentNotification {
-        #[serde(flatten)]
-        pub notification: Notification,
-        pub params: DidOpenTextDocumentParams, // Parameters for the notification
+    pub struct FileCreate {
+        pub uri: String,
     }
 
-    // Parameters for the DidOpenTextDocumentNotification
+    // Request sent by the client before it renames files, answered with the edits to apply first
     #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct DidOpenTextDocumentParams {
-        pub text_document: TextDocumentItem,
+    pub struct WillRenameFilesRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: RenameFilesParams,
     }
 
-    // Notification sent by the client when a text document is changed
+    impl LspRequest for WillRenameFilesRequest {
+        const METHOD: &'static str = "workspace/willRenameFiles";
+        type Params = RenameFilesParams;
+        type Result = Option<WorkspaceEdit>;
+    }
+
+    // Notification sent by the client after it renamed files
     #[derive(Debug, Deserialize, Serialize)]
-    struct TextDocumentDidChangeNotification {
+    pub struct DidRenameFilesNotification {
         #[serde(flatten)]
-        notification: Notification,
-        params: DidChangeTextDocumentParams, // Change-specific parameters
+        pub notification: Notification,
+        pub params: RenameFilesParams,
     }
 
-    // Parameters for the TextDocumentDidChangeNotification
+    impl LspNotification for DidRenameFilesNotification {
+        const METHOD: &'static str = "workspace/didRenameFiles";
+        type Params = RenameFilesParams;
+    }
+
+    // Parameters for the WillRenameFilesRequest and the DidRenameFilesNotification
     #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    struct DidChangeTextDocumentParams {
-        text_document: VersionTextDocumentIdentifier, // Identifier of the changed document
-        content_changes: Vec<TextDocumentContentChangeEvent>, // Array of changes made to the document
+    pub struct RenameFilesParams {
+        pub files: Vec<FileRename>,
     }
 
-    // Identifies a text document using a URI and a version
     #[derive(Debug, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
-    struct VersionTextDocumentIdentifier {
-        uri: String,
-        version: i32, // Version of the document
+    pub struct FileRename {
+        pub old_uri: String,
+        pub new_uri: String,
     }
 
-    // Describes a change made to a text document
+    // Request sent by the client before it deletes files, answered with the edits to apply first
     #[derive(Debug, Deserialize, Serialize)]
-    struct TextDocumentContentChangeEvent {
-        text: String, // The new text content of the entire document
+    pub struct WillDeleteFilesRequest {
+        #[serde(flatten)]
+        pub request: RequestMessage,
+        pub params: DeleteFilesParams,
     }
 
-    // Represents a text document within the LSP
-    #[derive(Debug, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct TextDocumentItem {
-        pub uri: String,
-        pub language_id: String, // Identifier of the language used in the document
-        pub version: i64,        // Version of the document, usually starts at 0
-        pub text: String,        // The text content of the document
+    impl LspRequest for WillDeleteFilesRequest {
+        const METHOD: &'static str = "workspace/willDeleteFiles";
+        type Params = DeleteFilesParams;
+        type Result = Option<WorkspaceEdit>;
     }
 
-    // Request for hover information at a specific text position
+    // Notification sent by the client after it deleted files
     #[derive(Debug, Deserialize, Serialize)]
-    struct HoverRequest {
+    pub struct DidDeleteFilesNotification {
         #[serde(flatten)]
-        request: RequestMessage,
-        params: HoverParams, // Parameters containing the position for hover
+        pub notification: Notification,
+        pub params: DeleteFilesParams,
     }
 
-    // Parameters for the HoverRequest
+    impl LspNotification for DidDeleteFilesNotification {
+        const METHOD: &'static str = "workspace/didDeleteFiles";
+        type Params = DeleteFilesParams;
+    }
+
+    // Parameters for the WillDeleteFilesRequest and the DidDeleteFilesNotification
     #[derive(Debug, Deserialize, Serialize)]
-    struct HoverParams {
-        #[serde(flatten)]
-        pos_params: TextDocumentPositionParams, // Position information within a text document
+    pub struct DeleteFilesParams {
+        pub files: Vec<FileDelete>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct FileDelete {
+        pub uri: String,
     }
 
-    // Response containing hover information
+    // Response to the WillRenameFilesRequest and the WillDeleteFilesRequest
     #[derive(Debug, Deserialize, Serialize)]
-    struct HoverResponse {
+    pub struct WillFileOperationResponse {
         #[serde(flatten)]
-        response: ResponseMessage,
-        result: HoverResult, // The hover information itself
+        pub response: ResponseMessage,
+        pub result: Option<WorkspaceEdit>,
     }
 
-    // Helper function to create a HoverResponse message
-    impl HoverResponse {
-        pub fn new(id: i64, response_str: String) -> Self {
-            HoverResponse {
+    impl WillFileOperationResponse {
+        pub fn new(id: RequestId, edit: Option<WorkspaceEdit>) -> Self {
+            WillFileOperationResponse {
                 response: ResponseMessage {
                     id,
                     message: Message {
                         jsonrpc: "2.0".to_string(),
                     },
                 },
-                result: HoverResult {
-                    contents: response_str,
-                },
+                result: edit,
             }
         }
     }
 
-    // Structure holding the actual hover information
+    // Notification sent by the client when a document is closed
     #[derive(Debug, Deserialize, Serialize)]
-    struct HoverResult {
-        contents: String, // Textual content to be displayed in the hover tooltip
+    pub struct DidCloseTextDocumentNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: DidCloseTextDocumentParams,
     }
 
-    // Parameters used to specify a position within a text document
+    impl LspNotification for DidCloseTextDocumentNotification {
+        const METHOD: &'static str = "textDocument/didClose";
+        type Params = DidCloseTextDocumentParams;
+    }
+
+    // Parameters for the DidCloseTextDocumentNotification
     #[derive(Debug, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
-    struct TextDocumentPositionParams {
-        text_document: TextDocumentIdentifier, // Identifier of the text document
-        position: Position,                    // Line and character position
+    pub struct DidCloseTextDocumentParams {
+        pub text_document: TextDocumentIdentifier,
     }
 
+    // Notification sent by the client when a document is saved
     #[derive(Debug, Deserialize, Serialize)]
-    struct TextDocumentIdentifier {
-        uri: String,
+    pub struct DidSaveTextDocumentNotification {
+        #[serde(flatten)]
+        pub notification: Notification,
+        pub params: DidSaveTextDocumentParams,
+    }
+
+    impl LspNotification for DidSaveTextDocumentNotification {
+        const METHOD: &'static str = "textDocument/didSave";
+        type Params = DidSaveTextDocumentParams;
+    }
+
+    // Parameters for the DidSaveTextDocumentNotification
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DidSaveTextDocumentParams {
+        pub text_document: TextDocumentIdentifier,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub text: Option<String>, // Content when saved, if the server asked for it
+    }
+
+    // Parameters for the textDocument/publishDiagnostics notification
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PublishDiagnosticsParams {
+        pub uri: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version: Option<i64>, // Version of the document the diagnostics are for
+        pub diagnostics: Vec<Diagnostic>,
+    }
+
+    // A problem in a document, such as a malformed tree
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Diagnostic {
+        pub range: Range, // Range the problem applies to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub severity: Option<u8>, // One of DiagnosticSeverity
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub code: Option<DiagnosticCode>, // Rule that produced the diagnostic, eg. "structure"
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub code_description: Option<CodeDescription>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub source: Option<String>, // Who produced the diagnostic, eg. "lsp-rs"
+        pub message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tags: Option<Vec<u8>>, // Any of DiagnosticTag
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub related_information: Option<Vec<DiagnosticRelatedInformation>>,
+    }
+
+    // Code of a Diagnostic, shown by the client next to the message
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+    #[serde(untagged)]
+    pub enum DiagnosticCode {
+        Number(i64),
+        String(String),
+    }
+
+    // Where to read more about a diagnostic code
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CodeDescription {
+        pub href: String,
     }
 
+    // Another location that is part of a diagnostic, eg. the line that constrains the faulty one
     #[derive(Debug, Deserialize, Serialize)]
-    struct Position {
-        line: i32,      // Line number within the text document
-        character: i32, // Character offset within the line
+    pub struct DiagnosticRelatedInformation {
+        pub location: Location,
+        pub message: String,
+    }
+
+    // Tags of a Diagnostic, changing how the client renders its range
+    pub struct DiagnosticTag {}
+
+    impl DiagnosticTag {
+        pub const UNNECESSARY: u8 = 1; // Unused or unnecessary code, shown faded out
+        pub const DEPRECATED: u8 = 2; // Deprecated code, shown struck through
+    }
+
+    // Severities of a Diagnostic
+    pub struct DiagnosticSeverity {}
+
+    impl DiagnosticSeverity {
+        pub const ERROR: u8 = 1;
+        pub const WARNING: u8 = 2;
+        pub const INFORMATION: u8 = 3;
+        pub const HINT: u8 = 4;
     }
 }
 
@@ -0,0 +1,102 @@
+use std::ops::Range;
+
+use crate::encoding::{wide_byte_to_column, wide_chars, wide_column_to_byte, PositionEncoding};
+use crate::lsp::Position;
+use crate::rope::Rope;
+
+/// Where every line of a document starts and which of its characters are not ascii, so a
+/// position is found with a binary search over the lines instead of scanning the text.
+/// Kept next to the text and patched on every edit, see `update`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    lines: Vec<Line>,
+    len: usize, // bytes of the whole text
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    start: usize,             // byte offset of the line in the text
+    len: usize,               // bytes of the line without its line ending
+    wide: Vec<(usize, char)>, // characters longer than a byte and where they start, in order
+}
+
+impl Line {
+    fn new(start: usize, content: &str) -> Self {
+        Line {
+            start,
+            len: content.len(),
+            wide: wide_chars(content).collect(),
+        }
+    }
+
+    fn column_to_byte(&self, column: usize, encoding: PositionEncoding) -> Option<usize> {
+        wide_column_to_byte(self.wide.iter().copied(), self.len, column, encoding)
+    }
+
+    fn byte_to_column(&self, byte: usize, encoding: PositionEncoding) -> Option<usize> {
+        wide_byte_to_column(self.wide.iter().copied(), self.len, byte, encoding)
+    }
+}
+
+impl LineIndex {
+    pub fn new(text: &Rope) -> Self {
+        LineIndex {
+            lines: Self::lines(text, 0..text.line_count()),
+            len: text.len(),
+        }
+    }
+
+    /// The lines of the text in the range
+    fn lines(text: &Rope, lines: Range<usize>) -> Vec<Line> {
+        lines
+            .filter_map(|i| Some(Line::new(text.line_start(i)?, &text.line(i)?)))
+            .collect()
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Line the byte offset is on, the last line past the end of the text
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.lines.partition_point(|line| line.start <= offset) - 1
+    }
+
+    /// Byte offset of the position in the text. None if the line doesn't exist or the
+    /// character is not in it, like `encoding::position_to_offset`
+    pub fn offset(&self, position: Position, encoding: PositionEncoding) -> Option<usize> {
+        let line = self.lines.get(usize::try_from(position.line).ok()?)?;
+        let column = usize::try_from(position.character).ok()?;
+        Some(line.start + line.column_to_byte(column, encoding)?)
+    }
+
+    /// Position of the byte offset in the text. None past the end of the text, in the middle
+    /// of a character or inside a line ending, like `encoding::offset_to_position`
+    pub fn position(&self, offset: usize, encoding: PositionEncoding) -> Option<Position> {
+        if offset > self.len {
+            return None;
+        }
+        let i = self.line_at(offset);
+        let line = &self.lines[i];
+        let column = line.byte_to_column(offset - line.start, encoding)?;
+        Some(Position {
+            line: i as i32,
+            character: column as i32,
+        })
+    }
+
+    /// Patch the index after `removed` bytes of the old text were replaced by `inserted_len`
+    /// bytes, `text` being the new text. Only the lines the edit touched are read again, the
+    /// ones after it are moved
+    pub fn update(&mut self, text: &Rope, removed: Range<usize>, inserted_len: usize) {
+        let first = self.line_at(removed.start);
+        let old_last = self.line_at(removed.end);
+        let new_last = text.line_at(removed.start + inserted_len);
+        let lines = Self::lines(text, first..new_last + 1);
+        self.lines.splice(first..old_last + 1, lines);
+        for line in &mut self.lines[new_last + 1..] {
+            line.start = line.start + inserted_len - removed.len();
+        }
+        self.len = text.len();
+    }
+}
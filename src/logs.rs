@@ -0,0 +1,247 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::LogLevel;
+use crate::trace;
+
+/// Payloads longer than this many characters are cut in structured logs
+pub const PAYLOAD_EXCERPT_LEN: usize = 200;
+
+/// How log lines are written to the log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text, // the lines as they are logged
+    Json, // one `LogRecord` per line
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+/// A log line in the structured format. The payload is kept as a string, so cut off json
+/// with unmatched braces doesn't break tools reading the log
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LogRecord {
+    pub ts: u64, // milliseconds since the unix epoch
+    pub level: LogLevel,
+    pub method: Option<String>, // method of the message being handled
+    pub id: Option<u64>,        // trace id of the message being handled
+    pub event: String,          // tag of the line, eg. `Method` for `[Method] ...`
+    pub payload_excerpt: String,
+}
+
+impl LogRecord {
+    /// Record of a text log line, attributed to the message being handled on this thread
+    pub fn new(line: &str, level: LogLevel) -> Self {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let (event, payload) = match line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+            Some((event, payload)) => (event.to_string(), payload.trim_start()),
+            None => ("log".to_string(), line),
+        };
+        // the trace tag is already in `id`
+        let payload = match trace::current() {
+            Some(_) => payload.strip_prefix(&trace::tag()).unwrap_or(payload),
+            None => payload,
+        };
+        LogRecord {
+            ts,
+            level,
+            method: trace::current_method(),
+            id: trace::current().map(|id| id.0),
+            event,
+            payload_excerpt: excerpt(payload, PAYLOAD_EXCERPT_LEN),
+        }
+    }
+}
+
+/// The first `max` characters of the text, marked with `…` if anything was cut
+pub fn excerpt(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn severity(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 1,
+        LogLevel::Warning => 2,
+        LogLevel::Info => 3,
+        LogLevel::Log => 4,
+    }
+}
+
+/// Options of the log viewer
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    pub level: LogLevel,        // records less severe than this are hidden
+    pub method: Option<String>, // only show records of this method
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogFilter {
+            level: LogLevel::Log,
+            method: None,
+        }
+    }
+}
+
+impl LogFilter {
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        severity(record.level) <= severity(self.level)
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|m| record.method.as_ref() == Some(m))
+    }
+}
+
+/// Where the log ended up, after falling back from the requested file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    File(PathBuf), // the requested file, or a file in the temp dir if it could not be created
+    Stderr,
+    Disabled, // no log file was requested
+}
+
+/// The opened log, and why it is not the requested file if it couldn't be created
+pub struct LogOutput {
+    pub writer: Box<dyn Write>,
+    pub target: LogTarget,
+    pub warning: Option<String>,
+}
+
+/// Open the log file, falling back to a file of the same name in `fallback_dir`, then to
+/// stderr. Editors often start the server in a read only directory, which should not keep
+/// the server from running
+pub fn open_log(requested: Option<&str>, fallback_dir: &Path) -> LogOutput {
+    let Some(requested) = requested else {
+        return LogOutput {
+            writer: Box::new(io::empty()),
+            target: LogTarget::Disabled,
+            warning: None,
+        };
+    };
+    let error = match File::create(requested) {
+        Ok(file) => {
+            return LogOutput {
+                writer: Box::new(file),
+                target: LogTarget::File(PathBuf::from(requested)),
+                warning: None,
+            }
+        }
+        Err(e) => e,
+    };
+    let file_name = Path::new(requested)
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "lsp-rs.log".into());
+    let fallback = fallback_dir.join(file_name);
+    match File::create(&fallback) {
+        Ok(file) => LogOutput {
+            writer: Box::new(file),
+            warning: Some(format!(
+                "Could not create log file {}: {}, logging to {}",
+                requested,
+                error,
+                fallback.display()
+            )),
+            target: LogTarget::File(fallback),
+        },
+        Err(_) => LogOutput {
+            writer: Box::new(io::stderr()),
+            target: LogTarget::Stderr,
+            warning: Some(format!(
+                "Could not create log file {}: {}, logging to stderr",
+                requested, error
+            )),
+        },
+    }
+}
+
+/// A record as a single human readable line
+pub fn format_record(record: &LogRecord) -> String {
+    let level = serde_json::to_value(record.level)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default();
+    let mut line = format!("{} {:<7} [{}]", record.ts, level, record.event);
+    if let Some(id) = record.id {
+        line.push_str(&format!(" #{}", id));
+    }
+    if let Some(method) = &record.method {
+        line.push_str(&format!(" {}", method));
+    }
+    format!("{}: {}", line, record.payload_excerpt)
+}
+
+/// `logs <file> [--level error|warning|info|log] [--method name]`: print a log written with
+/// `--log-format json`. Returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let mut filter = LogFilter::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => {
+                let level = args
+                    .next()
+                    .and_then(|l| serde_json::from_value(serde_json::json!(l)).ok());
+                match level {
+                    Some(level) => filter.level = level,
+                    None => {
+                        eprintln!("--level expects one of error, warning, info or log");
+                        return 2;
+                    }
+                }
+            }
+            "--method" => match args.next() {
+                Some(method) => filter.method = Some(method.to_string()),
+                None => {
+                    eprintln!("--method expects a method name");
+                    return 2;
+                }
+            },
+            file if path.is_none() => path = Some(file.to_string()),
+            other => {
+                eprintln!("unexpected argument {}", other);
+                return 2;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("usage: logs <file> [--level error|warning|info|log] [--method name]");
+        return 2;
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            return 2;
+        }
+    };
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str::<LogRecord>(line) {
+            Ok(record) if filter.matches(&record) => println!("{}", format_record(&record)),
+            Ok(_) => (),
+            Err(_) => println!("{}", line), // written with --log-format text
+        }
+    }
+    0
+}
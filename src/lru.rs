@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+
+/// Keys in the order they were last used, so the least recently used can be evicted once
+/// there are too many. Meant for a few dozen keys, lookups are linear
+#[derive(Debug)]
+pub struct LruSet<K> {
+    keys: VecDeque<K>, // least recently used first
+}
+
+impl<K: PartialEq> Default for LruSet<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: PartialEq> LruSet<K> {
+    pub fn new() -> Self {
+        LruSet {
+            keys: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Mark the key as the most recently used, returns false if it is not in the set
+    pub fn touch(&mut self, key: &K) -> bool {
+        match self.keys.iter().position(|k| k == key) {
+            Some(i) => {
+                let key = self.keys.remove(i).unwrap();
+                self.keys.push_back(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add the key as the most recently used, then evict the least recently used keys until
+    /// at most `capacity` are left. Returns the evicted keys, oldest first
+    pub fn insert(&mut self, key: K, capacity: usize) -> Vec<K> {
+        if !self.touch(&key) {
+            self.keys.push_back(key);
+        }
+        let excess = self.keys.len().saturating_sub(capacity);
+        self.keys.drain(..excess).collect()
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.keys.iter().position(|k| k == key) {
+            Some(i) => self.keys.remove(i).is_some(),
+            None => false,
+        }
+    }
+}
@@ -1,51 +1,79 @@
-use std::{
-    env,
-    fs::File,
-    io::{self, Read, Write},
-};
+use std::{env, io, net::TcpListener, process};
 
-use server::{editor::EditorState, lsp::handle_message, rpc::BufferedReader};
+use server::{
+    cli::{self, Command, Transport, VersionInfo},
+    logs,
+    testing::check,
+    transport::{self, open_logger},
+};
 
-/// Takes LSP instructions from stdin, and replies in stdout
+/// Takes LSP instructions from stdin, and replies in stdout, or over tcp with `--listen`
+/// or the client's socket with `--pipe`
 /// If supplied with command line arguments, use that as file to
-/// output logs to
+/// output logs to, logs are also mirrored to the client with window/logMessage.
+/// If the file can't be created the log goes to the temp dir, then stderr.
+/// `--log-format json` writes the log as json lines, which `logs <file>` prints readably.
+/// `--session <file>` reopens the documents that were open when the server last exited.
+/// `--record <file>` appends the bytes read and written to the file, see `record`.
+/// The other subcommands are listed by `--help`, see `cli`
 fn main() {
-    let args = env::args().collect::<Vec<String>>();
-    let mut logger: Box<dyn Write> = if let Some(filename) = args.get(1) {
-        Box::new(File::create(filename).expect("Failed to create logger file"))
-    } else {
-        Box::new(io::empty())
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let options = match cli::parse(&args) {
+        Ok(Command::Serve(options)) => options,
+        Ok(Command::Repl(options)) => {
+            let (mut logger, _) = open_logger(&options);
+            cli::repl(io::stdin().lock(), &mut logger);
+            return;
+        }
+        Ok(Command::Check(args)) => process::exit(check::run(&args)),
+        Ok(Command::Logs(args)) => process::exit(logs::run(&args)),
+        Ok(Command::Validate(paths)) => process::exit(cli::validate(&paths, &mut io::stdout())),
+        Ok(Command::Help) => {
+            print!("{}", cli::HELP);
+            return;
+        }
+        Ok(Command::Version { json }) => {
+            let info = VersionInfo::current();
+            match json {
+                true => println!("{}", serde_json::to_string(&info).unwrap()),
+                false => println!("{}", info),
+            }
+            return;
+        }
+        Err(e) => process::exit(cli::usage_error(&e)),
     };
-
-    let mut editor_state = EditorState::new(); // used to sync state of the editor w/ server
-    let mut buff_reader = BufferedReader::new(); // in case messages come in chunks, similar to implementation seen in class
-
-    let mut buff = [0; 512];
-    let mut handle = io::stdin().lock();
-    while let Ok(n) = handle.read(&mut buff) {
-        if n == 0 {
-            break;
+    match &options.transport {
+        #[cfg(feature = "async")]
+        Transport::Stdio => transport::run_async_stdio(&options),
+        #[cfg(not(feature = "async"))]
+        Transport::Stdio => transport::run_stdio(&options),
+        Transport::Tcp(address) => {
+            listen(address, |listener| transport::run_tcp(&options, listener))
         }
-        buff_reader.write(&buff[..n]);
-        let res = buff_reader.pop_message(); // try to retrieve an lsp message from BufferedReader
-        match res {
-            Ok(Some(content)) => match handle_message(content, &mut editor_state, &mut logger) {
-                Ok(()) => (),
-                Err(e) => writeln!(
-                    &mut logger,
-                    "[Error] Error handling message {}",
-                    e.to_string()
-                )
-                .unwrap(),
-            },
-            Ok(None) => (),
-            Err(e) => writeln!(
-                &mut logger,
-                "[Error] Could not pop message: {}",
-                e.to_string()
-            )
-            .unwrap(),
+        #[cfg(feature = "websocket")]
+        Transport::WebSocket(address) => listen(address, |listener| {
+            server::websocket::run_websocket(&options, listener)
+        }),
+        #[cfg(not(feature = "websocket"))]
+        Transport::WebSocket(_) => unreachable!("--websocket is refused by cli::parse"),
+        Transport::Pipe(path) => {
+            if let Err(e) = transport::run_pipe(&options, path) {
+                eprintln!("error: could not connect to {}: {}", path, e);
+                process::exit(1);
+            }
         }
-        buff.fill(0);
+    }
+}
+
+/// Serve the first client connecting to the address, exits if it can't be listened on
+fn listen(address: &str, serve: impl FnOnce(TcpListener) -> io::Result<()>) {
+    let served = TcpListener::bind(address).and_then(|listener| {
+        // the address may have a port picked by the system
+        eprintln!("listening on {}", listener.local_addr()?);
+        serve(listener)
+    });
+    if let Err(e) = served {
+        eprintln!("error: could not serve on {}: {}", address, e);
+        process::exit(1);
     }
 }
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::rpc::json_from_string;
+
+/// The json content of a message going through the server, without its header. Interceptors
+/// get the content as it was received, before it is parsed, and as it is sent, once it was
+/// serialized, and may rewrite it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub content: String,
+}
+
+impl RawMessage {
+    /// The content parsed as json, None if it is not json
+    pub fn json(&self) -> Option<Value> {
+        json_from_string(&self.content).ok()
+    }
+
+    /// Replace the content with the json
+    pub fn set_json(&mut self, json: &Value) {
+        self.content = json.to_string();
+    }
+}
+
+/// Code run on every message the server receives or sends, for concerns that cut across the
+/// handlers: timing, metrics, redacting document text, rewriting messages. The received message
+/// is intercepted before it is logged and dispatched, the sent one before it is logged and
+/// written
+pub trait Interceptor {
+    fn on_incoming(&mut self, _message: &mut RawMessage) {}
+
+    fn on_outgoing(&mut self, _message: &mut RawMessage) {}
+}
+
+/// Sees every message the server receives or sends without changing it, eg. to log or count
+/// them. Received messages are seen as the interceptors left them, sent ones as they are written
+pub trait Observer {
+    fn on_incoming(&mut self, _message: &str) {}
+
+    fn on_outgoing(&mut self, _message: &str) {}
+}
+
+/// The interceptors and observers of a server, run in the order they were added. Clones share
+/// them, the server's `Output` carries one so every message sent goes through it
+#[derive(Clone, Default)]
+pub struct Middleware {
+    interceptors: Rc<RefCell<Vec<Box<dyn Interceptor>>>>,
+    observers: Rc<RefCell<Vec<Box<dyn Observer>>>>,
+}
+
+impl Middleware {
+    /// Run the interceptor on the messages, after the ones added before it
+    pub fn add_interceptor(&self, interceptor: Box<dyn Interceptor>) {
+        self.interceptors.borrow_mut().push(interceptor);
+    }
+
+    /// Show the messages to the observer, after the ones added before it
+    pub fn add_observer(&self, observer: Box<dyn Observer>) {
+        self.observers.borrow_mut().push(observer);
+    }
+
+    /// The content of a received message, as rewritten by the interceptors
+    pub fn incoming(&self, content: String) -> String {
+        let content = intercept(&self.interceptors, content, |interceptor, message| {
+            interceptor.on_incoming(message)
+        });
+        observe(&self.observers, &content, |observer, message| {
+            observer.on_incoming(message)
+        });
+        content
+    }
+
+    /// The content of a message about to be sent, as rewritten by the interceptors
+    pub fn outgoing(&self, content: String) -> String {
+        let content = intercept(&self.interceptors, content, |interceptor, message| {
+            interceptor.on_outgoing(message)
+        });
+        observe(&self.observers, &content, |observer, message| {
+            observer.on_outgoing(message)
+        });
+        content
+    }
+}
+
+/// Run the hook of every interceptor on the message. An interceptor sending a message itself
+/// doesn't see it
+fn intercept(
+    interceptors: &RefCell<Vec<Box<dyn Interceptor>>>,
+    content: String,
+    hook: impl Fn(&mut dyn Interceptor, &mut RawMessage),
+) -> String {
+    let Ok(mut interceptors) = interceptors.try_borrow_mut() else {
+        return content;
+    };
+    let mut message = RawMessage { content };
+    for interceptor in interceptors.iter_mut() {
+        hook(interceptor.as_mut(), &mut message);
+    }
+    message.content
+}
+
+/// Run the hook of every observer on the message, like `intercept`
+fn observe(
+    observers: &RefCell<Vec<Box<dyn Observer>>>,
+    content: &str,
+    hook: impl Fn(&mut dyn Observer, &str),
+) {
+    if let Ok(mut observers) = observers.try_borrow_mut() {
+        for observer in observers.iter_mut() {
+            hook(observer.as_mut(), content);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::ptr;
+use std::sync::Arc;
+
+use windows_sys::Win32::Foundation::{
+    CloseHandle, ERROR_BROKEN_PIPE, ERROR_IO_PENDING, HANDLE, TRUE,
+};
+use windows_sys::Win32::Storage::FileSystem::{ReadFile, WriteFile, FILE_FLAG_OVERLAPPED};
+use windows_sys::Win32::System::Threading::CreateEventW;
+use windows_sys::Win32::System::IO::{GetOverlappedResult, OVERLAPPED};
+
+/// Client end of a named pipe, opened for overlapped io. A pipe opened without it handles one
+/// read or write at a time, a read waiting for the client would hold up every write. Clones
+/// share the handle, so one thread can block reading while another writes
+#[derive(Clone)]
+pub struct NamedPipe {
+    file: Arc<File>,
+}
+
+impl NamedPipe {
+    /// Open the pipe the client created
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(path)?;
+        Ok(NamedPipe {
+            file: Arc::new(file),
+        })
+    }
+
+    /// Start the read or write and wait for it to complete, returning the bytes moved
+    fn wait(&self, start: impl FnOnce(HANDLE, *mut OVERLAPPED) -> i32) -> io::Result<usize> {
+        let event = Event::new()?;
+        let mut overlapped = OVERLAPPED {
+            hEvent: event.0,
+            ..Default::default()
+        };
+        let handle = self.file.as_raw_handle() as HANDLE;
+        if start(handle, &mut overlapped) == 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_IO_PENDING as i32) {
+                return Err(e);
+            }
+        }
+        let mut moved = 0;
+        // SAFETY: the buffer and `overlapped` outlive the operation, which is waited for here
+        if unsafe { GetOverlappedResult(handle, &overlapped, &mut moved, TRUE) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(moved as usize)
+    }
+}
+
+impl Read for NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        // SAFETY: the buffer is valid for `len` bytes until `wait` returns
+        let read = self.wait(|handle, overlapped| unsafe {
+            ReadFile(handle, buf.as_mut_ptr(), len, ptr::null_mut(), overlapped)
+        });
+        match read {
+            // the client closed its end
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+            read => read,
+        }
+    }
+}
+
+impl Write for NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(u32::MAX as usize) as u32;
+        // SAFETY: the buffer is valid for `len` bytes until `wait` returns
+        self.wait(|handle, overlapped| unsafe {
+            WriteFile(handle, buf.as_ptr(), len, ptr::null_mut(), overlapped)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // the writes are done once they return, waiting for the client to read is not flushing
+        Ok(())
+    }
+}
+
+/// Event signalled once an operation completes, closed when dropped
+struct Event(HANDLE);
+
+impl Event {
+    fn new() -> io::Result<Self> {
+        // SAFETY: a manual reset event without attributes or name
+        let handle = unsafe { CreateEventW(ptr::null(), TRUE, 0, ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Event(handle))
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        // SAFETY: the handle was created by `new` and is closed once
+        unsafe { CloseHandle(self.0) };
+    }
+}
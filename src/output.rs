@@ -0,0 +1,292 @@
+use std::cell::RefCell;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::middleware::Middleware;
+use crate::rpc::decode_message;
+
+/// Where the messages for the client are written, owned by the transport. Messages are
+/// already encoded, header included
+pub trait MessageWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()>;
+
+    /// Wait until the messages written so far reached the client, for writers that hold
+    /// them back
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Messages written that haven't reached the client yet, for writers that hold them back
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+/// Writes to stdout, flushing after every message so the client gets it right away
+pub struct StdoutWriter;
+
+impl MessageWriter for StdoutWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(encoded_message)?;
+        stdout.flush()
+    }
+}
+
+/// Writes to a stream, eg. a socket, flushing after every message
+pub struct StreamWriter<W: Write>(pub W);
+
+impl<W: Write> MessageWriter for StreamWriter<W> {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        self.0.write_all(encoded_message)?;
+        self.0.flush()
+    }
+}
+
+/// Keeps the messages in memory, eg. for tests. Clones share the messages, so a clone can
+/// be given to an `Output` and the other one read
+#[derive(Clone, Default)]
+pub struct MemoryWriter {
+    messages: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl MemoryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Content of the messages written so far, without their header
+    pub fn messages(&self) -> Vec<String> {
+        self.messages
+            .borrow()
+            .iter()
+            .filter_map(|message| Some(decode_message(message).ok()??.0))
+            .collect()
+    }
+
+    /// Remove the messages written so far, returning their content
+    pub fn take(&self) -> Vec<String> {
+        let messages = self.messages();
+        self.messages.borrow_mut().clear();
+        messages
+    }
+}
+
+impl MessageWriter for MemoryWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        self.messages.borrow_mut().push(encoded_message.to_vec());
+        Ok(())
+    }
+}
+
+/// When a `BufferedWriter` flushes what it wrote
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// After every message, the client never waits for the next one
+    EveryMessage,
+    /// Once no message is queued, or `max_batch` bytes were written without a flush. A
+    /// burst of messages, eg. diagnostics for every file, goes out in a few writes
+    WhenIdle { max_batch: usize },
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::WhenIdle {
+            max_batch: 64 * 1024,
+        }
+    }
+}
+
+enum Command {
+    Message(Vec<u8>),
+    Flush(Sender<io::Result<()>>),
+}
+
+/// Writes the messages on a thread of its own, fed by a channel, so a slow client doesn't
+/// hold up the handlers. Messages are written whole and in the order they were sent, clones
+/// can be handed to other threads without their messages interleaving. The thread stops
+/// once every clone is dropped, after writing what is left. The messages waiting in the
+/// channel are counted, see `queued`, a client that doesn't read lets them pile up
+#[derive(Clone)]
+pub struct BufferedWriter {
+    sender: Sender<Command>,
+    queued: Arc<AtomicUsize>, // messages sent to the thread that it didn't write yet
+}
+
+impl BufferedWriter {
+    pub fn new<W: Write + Send + 'static>(out: W, policy: FlushPolicy) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let written = queued.clone();
+        // once the client is gone the receiver is dropped, and so the senders fail
+        thread::spawn(move || write_queued(BufWriter::new(out), receiver, policy, &written));
+        BufferedWriter { sender, queued }
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.sender
+            .send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone"))
+    }
+}
+
+impl MessageWriter for BufferedWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.send(Command::Message(encoded_message.to_vec()))
+            .inspect_err(|_| {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+            })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.send(Command::Flush(done))?;
+        wait.recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone"))?
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Write the commands until every sender is gone or the client can't be written to
+fn write_queued<W: Write>(
+    mut out: BufWriter<W>,
+    receiver: Receiver<Command>,
+    policy: FlushPolicy,
+    queued: &AtomicUsize,
+) -> io::Result<()> {
+    let mut unflushed = 0;
+    while let Ok(mut command) = receiver.recv() {
+        // the handlers send bursts, what is already queued is written before flushing
+        loop {
+            match command {
+                Command::Message(message) => {
+                    out.write_all(&message)?;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    unflushed += message.len();
+                    let flush = match policy {
+                        FlushPolicy::EveryMessage => true,
+                        FlushPolicy::WhenIdle { max_batch } => unflushed >= max_batch,
+                    };
+                    if flush {
+                        out.flush()?;
+                        unflushed = 0;
+                    }
+                }
+                Command::Flush(done) => {
+                    unflushed = 0;
+                    if let Err(e) = out.flush() {
+                        // the error goes to the one waiting for the flush
+                        let _ = done.send(Err(e));
+                        return Ok(());
+                    }
+                    let _ = done.send(Ok(()));
+                }
+            }
+            match receiver.try_recv() {
+                Ok(next) => command = next,
+                Err(_) => break,
+            }
+        }
+        if unflushed > 0 {
+            out.flush()?;
+            unflushed = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Where the server writes the messages for the client, handed to it by the transport.
+/// Clones share the writer and the middleware. Once a write fails, eg. the client is gone, the
+/// error is kept for the transport, see `take_error`, and the messages after it are dropped
+#[derive(Clone)]
+pub struct Output {
+    state: Rc<RefCell<OutputState>>,
+    middleware: Middleware, // run on the messages before they are written
+}
+
+struct OutputState {
+    writer: Box<dyn MessageWriter>,
+    error: Option<io::Error>, // of the first write that failed
+}
+
+impl Output {
+    pub fn new(writer: Box<dyn MessageWriter>) -> Self {
+        Output {
+            state: Rc::new(RefCell::new(OutputState {
+                writer,
+                error: None,
+            })),
+            middleware: Middleware::default(),
+        }
+    }
+
+    /// The interceptors and observers of the messages, received ones included
+    pub fn middleware(&self) -> &Middleware {
+        &self.middleware
+    }
+
+    /// Write the messages with `writer` from now on, returning the one it replaces
+    pub fn set_writer(&self, writer: Box<dyn MessageWriter>) -> Box<dyn MessageWriter> {
+        std::mem::replace(&mut self.state.borrow_mut().writer, writer)
+    }
+
+    /// Replace the writer with what `wrap` makes of it, eg. to record what it writes
+    pub fn wrap(&self, wrap: impl FnOnce(Box<dyn MessageWriter>) -> Box<dyn MessageWriter>) {
+        let writer = self.set_writer(Box::new(StdoutWriter));
+        self.set_writer(wrap(writer));
+    }
+
+    /// Write the encoded message
+    pub fn write_message(&self, encoded_message: &[u8]) -> io::Result<()> {
+        self.state
+            .borrow_mut()
+            .write(|writer| writer.write_message(encoded_message))
+    }
+
+    /// Wait until the messages written so far reached the client
+    pub fn flush(&self) -> io::Result<()> {
+        self.state.borrow_mut().write(|writer| writer.flush())
+    }
+
+    /// Messages written that haven't reached the client yet, see `MessageWriter::queued`
+    pub fn queued(&self) -> usize {
+        self.state.borrow().writer.queued()
+    }
+
+    /// The error of the first write that failed, if any since the last call
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.state.borrow_mut().error.take()
+    }
+}
+
+/// Messages go to stdout, like for `repl`
+impl Default for Output {
+    fn default() -> Self {
+        Output::new(Box::new(StdoutWriter))
+    }
+}
+
+impl OutputState {
+    fn write(
+        &mut self,
+        write: impl FnOnce(&mut dyn MessageWriter) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.error.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "an earlier write failed",
+            ));
+        }
+        write(self.writer.as_mut()).inspect_err(|e| {
+            self.error = Some(io::Error::new(e.kind(), e.to_string()));
+        })
+    }
+}
@@ -0,0 +1,33 @@
+use crate::editor::FileState;
+
+/// Render the subtree under the node as indented lines, at most `max_depth` levels deep.
+/// Cut off subtrees end with a `… N more levels` line, so deep trees stay small. Shared by
+/// every feature previewing a subtree: the verbose hover, completion documentation, inlay hint
+/// tooltips and the preview command
+pub fn subtree(fs: &FileState, index: usize, max_depth: usize) -> String {
+    let mut lines = Vec::new();
+    render(fs, index, 0, max_depth.max(1), &mut lines);
+    lines.join("\n")
+}
+
+fn render(fs: &FileState, index: usize, depth: usize, max_depth: usize, lines: &mut Vec<String>) {
+    let Some(label) = fs.get(index) else {
+        return;
+    };
+    let indent = "  ".repeat(depth);
+    lines.push(format!("{}{}", indent, label));
+    if depth + 1 < max_depth {
+        render(fs, 2 * index + 1, depth + 1, max_depth, lines);
+        render(fs, 2 * index + 2, depth + 1, max_depth, lines);
+        return;
+    }
+    let hidden = fs.height(index) - 1;
+    if hidden > 0 {
+        lines.push(format!(
+            "{}  … {} more level{}",
+            indent,
+            hidden,
+            if hidden == 1 { "" } else { "s" }
+        ));
+    }
+}
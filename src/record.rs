@@ -0,0 +1,140 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::output::MessageWriter;
+use crate::rpc::{json_from_string, BufferedReader, ReaderLimits};
+use crate::testing::check::TranscriptEntry;
+
+/// Appends the bytes the server reads and writes to a file, headers included, one json object
+/// per line: `{"ts": <milliseconds since the unix epoch>, "dir": "in", "data": <bytes>}` for
+/// bytes read from the client and `"out"` for bytes written to it. The bytes are a string, or
+/// an array of numbers if they are not utf-8. Bytes read are recorded as they come, frames the
+/// reader refuses or skips included, see `transcript` for the messages. Clones share the file,
+/// so the thread reading the input records with one
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// Record to the end of the file, which is created if needed
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Record bytes read from the client
+    pub fn record_in(&self, bytes: &[u8]) {
+        self.record("in", bytes);
+    }
+
+    /// Record bytes written for the client
+    pub fn record_out(&self, bytes: &[u8]) {
+        self.record("out", bytes);
+    }
+
+    /// The writer, recording every frame written with it
+    pub fn writer(&self, inner: Box<dyn MessageWriter>) -> Box<dyn MessageWriter> {
+        Box::new(RecordingWriter {
+            inner,
+            recorder: self.clone(),
+        })
+    }
+
+    fn record(&self, direction: &str, bytes: &[u8]) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let data = match std::str::from_utf8(bytes) {
+            Ok(text) => Value::from(text),
+            Err(_) => Value::from(bytes),
+        };
+        // each line is written whole, a recording cut short by a crash is still readable
+        let line = format!("{}\n", json!({ "ts": ts, "dir": direction, "data": data }));
+        // the server keeps serving if the recording can't be written
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Records the frames before handing them to the writer the transport set
+struct RecordingWriter {
+    inner: Box<dyn MessageWriter>,
+    recorder: Recorder,
+}
+
+impl MessageWriter for RecordingWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        self.recorder.record_out(encoded_message);
+        self.inner.write_message(encoded_message)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn queued(&self) -> usize {
+        self.inner.queued()
+    }
+}
+
+/// The messages of a recording, in the order their last byte was recorded, so it can be
+/// replayed with `check`. Frames that can't be read are left out. Content that is not json is
+/// a string
+pub fn transcript(recording: &str) -> Result<Vec<TranscriptEntry>, String> {
+    // the recording has what was read, however large
+    let limits = ReaderLimits {
+        max_content_length: usize::MAX,
+        max_buffered: usize::MAX,
+    };
+    let mut read = BufferedReader::with_limits(limits);
+    let mut written = BufferedReader::with_limits(limits);
+    let mut entries = Vec::new();
+    for (i, line) in recording.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let bytes = match &value["data"] {
+            Value::String(text) => text.as_bytes().to_vec(),
+            data => serde_json::from_value::<Vec<u8>>(data.clone())
+                .map_err(|e| format!("line {}: {}", i + 1, e))?,
+        };
+        let (reader, entry): (_, fn(Value) -> TranscriptEntry) = match value["dir"].as_str() {
+            Some("in") => (&mut read, TranscriptEntry::In),
+            Some("out") => (&mut written, TranscriptEntry::Out),
+            _ => return Err(format!("line {}: expected \"dir\" to be in or out", i + 1)),
+        };
+        reader.write(&bytes);
+        loop {
+            match reader.pop_message() {
+                Ok(Some(content)) => {
+                    let message = json_from_string::<Value>(&content)
+                        .unwrap_or_else(|_| Value::from(content));
+                    entries.push(entry(message));
+                }
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether the content is a recording rather than a transcript, going by its first line
+pub fn is_recording(content: &str) -> bool {
+    content
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| json_from_string::<Value>(line).ok())
+        .is_some_and(|line| line.get("dir").is_some())
+}
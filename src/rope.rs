@@ -0,0 +1,361 @@
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Leaves hold at most this many bytes, unless a single character is longer
+const MAX_LEAF: usize = 1024;
+
+/// Text of a document as a balanced tree of chunks, so an edit costs O(log n) instead of
+/// copying the whole text. Nodes are shared, cloning is O(1) and an edit only copies the
+/// path to the changed leaves
+#[derive(Debug, Clone)]
+pub struct Rope {
+    root: Arc<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Leaf(String),
+    Branch {
+        left: Arc<Node>,
+        right: Arc<Node>,
+        len: usize,      // bytes below the node
+        newlines: usize, // `\n` below the node
+        depth: usize,    // the subtrees' depths differ by at most one
+    },
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(text) => text.len(),
+            Node::Branch { len, .. } => *len,
+        }
+    }
+
+    fn newlines(&self) -> usize {
+        match self {
+            Node::Leaf(text) => text.bytes().filter(|&b| b == b'\n').count(),
+            Node::Branch { newlines, .. } => *newlines,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Node::Leaf(_) => 0,
+            Node::Branch { depth, .. } => *depth,
+        }
+    }
+
+    fn branch(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        Arc::new(Node::Branch {
+            len: left.len() + right.len(),
+            newlines: left.newlines() + right.newlines(),
+            depth: left.depth().max(right.depth()) + 1,
+            left,
+            right,
+        })
+    }
+
+    /// Balanced tree of the text, split into leaves at character boundaries
+    fn build(text: &str) -> Arc<Node> {
+        let mut chunks = Vec::new();
+        let mut rest = text;
+        while rest.len() > MAX_LEAF {
+            let mut at = MAX_LEAF;
+            while !rest.is_char_boundary(at) {
+                at += 1;
+            }
+            let (chunk, tail) = rest.split_at(at);
+            chunks.push(chunk);
+            rest = tail;
+        }
+        chunks.push(rest);
+        Node::build_chunks(&chunks)
+    }
+
+    fn build_chunks(chunks: &[&str]) -> Arc<Node> {
+        match chunks {
+            [chunk] => Arc::new(Node::Leaf(chunk.to_string())),
+            _ => {
+                let (left, right) = chunks.split_at(chunks.len() / 2);
+                Node::branch(Node::build_chunks(left), Node::build_chunks(right))
+            }
+        }
+    }
+
+    fn children(&self) -> Option<(&Arc<Node>, &Arc<Node>)> {
+        match self {
+            Node::Leaf(_) => None,
+            Node::Branch { left, right, .. } => Some((left, right)),
+        }
+    }
+
+    /// Concatenation of the trees, rebalanced along the spine where they meet
+    fn join(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        if left.len() == 0 {
+            return right;
+        }
+        if right.len() == 0 {
+            return left;
+        }
+        if let (Node::Leaf(l), Node::Leaf(r)) = (&*left, &*right) {
+            if l.len() + r.len() <= MAX_LEAF {
+                return Arc::new(Node::Leaf(format!("{}{}", l, r)));
+            }
+        }
+        if left.depth() > right.depth() + 1 {
+            let (ll, lr) = left.children().unwrap();
+            let joined = Node::join(lr.clone(), right);
+            Node::balance(ll.clone(), joined)
+        } else if right.depth() > left.depth() + 1 {
+            let (rl, rr) = right.children().unwrap();
+            let joined = Node::join(left, rl.clone());
+            Node::balance(joined, rr.clone())
+        } else {
+            Node::branch(left, right)
+        }
+    }
+
+    /// Branch of two trees whose depths differ by at most two, rotated to be balanced
+    fn balance(left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        if left.depth() > right.depth() + 1 {
+            let (ll, lr) = left.children().unwrap();
+            if ll.depth() >= lr.depth() {
+                Node::branch(ll.clone(), Node::branch(lr.clone(), right))
+            } else {
+                let (lrl, lrr) = lr.children().unwrap();
+                Node::branch(
+                    Node::branch(ll.clone(), lrl.clone()),
+                    Node::branch(lrr.clone(), right),
+                )
+            }
+        } else if right.depth() > left.depth() + 1 {
+            let (rl, rr) = right.children().unwrap();
+            if rr.depth() >= rl.depth() {
+                Node::branch(Node::branch(left, rl.clone()), rr.clone())
+            } else {
+                let (rll, rlr) = rl.children().unwrap();
+                Node::branch(
+                    Node::branch(left, rll.clone()),
+                    Node::branch(rlr.clone(), rr.clone()),
+                )
+            }
+        } else {
+            Node::branch(left, right)
+        }
+    }
+
+    /// The text before and after the byte offset
+    fn split(node: &Arc<Node>, at: usize) -> (Arc<Node>, Arc<Node>) {
+        match &**node {
+            Node::Leaf(text) => (
+                Arc::new(Node::Leaf(text[..at].to_string())),
+                Arc::new(Node::Leaf(text[at..].to_string())),
+            ),
+            Node::Branch { left, right, .. } => {
+                if at <= left.len() {
+                    let (before, after) = Node::split(left, at);
+                    (before, Node::join(after, right.clone()))
+                } else {
+                    let (before, after) = Node::split(right, at - left.len());
+                    (Node::join(left.clone(), before), after)
+                }
+            }
+        }
+    }
+
+    /// Byte offset of the `n`th `\n`, counting from 0
+    fn newline_offset(&self, n: usize) -> Option<usize> {
+        match self {
+            Node::Leaf(text) => text.match_indices('\n').nth(n).map(|(i, _)| i),
+            Node::Branch { left, right, .. } => match n.checked_sub(left.newlines()) {
+                None => left.newline_offset(n),
+                Some(n) => right.newline_offset(n).map(|i| i + left.len()),
+            },
+        }
+    }
+
+    /// Number of `\n` before the byte offset
+    fn newlines_before(&self, offset: usize) -> usize {
+        match self {
+            Node::Leaf(text) => text.as_bytes()[..offset.min(text.len())]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count(),
+            Node::Branch { left, right, .. } => match offset.checked_sub(left.len()) {
+                None => left.newlines_before(offset),
+                Some(offset) => left.newlines() + right.newlines_before(offset),
+            },
+        }
+    }
+
+    fn is_char_boundary(&self, offset: usize) -> bool {
+        match self {
+            Node::Leaf(text) => text.is_char_boundary(offset),
+            Node::Branch { left, right, .. } => match offset.checked_sub(left.len()) {
+                None => left.is_char_boundary(offset),
+                Some(offset) => right.is_char_boundary(offset),
+            },
+        }
+    }
+
+    fn push_slice(&self, range: Range<usize>, out: &mut String) {
+        match self {
+            Node::Leaf(text) => out.push_str(&text[range]),
+            Node::Branch { left, right, .. } => {
+                let mid = left.len();
+                if range.start < mid {
+                    left.push_slice(range.start..range.end.min(mid), out);
+                }
+                if range.end > mid {
+                    right.push_slice(range.start.max(mid) - mid..range.end - mid, out);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rope {
+    pub fn new() -> Self {
+        Rope {
+            root: Arc::new(Node::Leaf(String::new())),
+        }
+    }
+
+    /// Length in bytes
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of lines, a text ending with `\n` has an empty last line
+    pub fn line_count(&self) -> usize {
+        self.root.newlines() + 1
+    }
+
+    /// Byte offset where the line starts, None past the last line
+    pub fn line_start(&self, line: usize) -> Option<usize> {
+        match line {
+            0 => Some(0),
+            _ => self.root.newline_offset(line - 1).map(|i| i + 1),
+        }
+    }
+
+    /// Line the byte offset is on
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.root.newlines_before(offset)
+    }
+
+    /// Text of the line without its line ending, None past the last line
+    pub fn line(&self, line: usize) -> Option<String> {
+        let start = self.line_start(line)?;
+        let end = self
+            .line_start(line + 1)
+            .map_or(self.len(), |next| next - 1);
+        let mut text = self.slice(start..end);
+        if text.ends_with('\r') {
+            text.pop();
+        }
+        Some(text)
+    }
+
+    /// Whether the byte offset is the start or end of a character, false past the end
+    pub fn is_char_boundary(&self, offset: usize) -> bool {
+        offset <= self.len() && self.root.is_char_boundary(offset)
+    }
+
+    /// Copy of the text in the byte range.
+    /// Panics like `str` slicing if the range is out of bounds or not on character boundaries
+    pub fn slice(&self, range: Range<usize>) -> String {
+        assert!(range.start <= range.end && range.end <= self.len());
+        let mut text = String::with_capacity(range.len());
+        self.root.push_slice(range, &mut text);
+        text
+    }
+
+    /// Replace the byte range with the text.
+    /// Panics like `String::replace_range` if the range is out of bounds or not on character
+    /// boundaries
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        assert!(
+            range.start <= range.end
+                && self.is_char_boundary(range.start)
+                && self.is_char_boundary(range.end),
+            "range {:?} is not on character boundaries of the text",
+            range
+        );
+        let (before, rest) = Node::split(&self.root, range.start);
+        let (_, after) = Node::split(&rest, range.end - range.start);
+        let inserted = Node::build(text);
+        self.root = Node::join(Node::join(before, inserted), after);
+    }
+
+    /// Whether both ropes are the same tree, ie. one is a clone of the other and neither was
+    /// edited since
+    pub fn ptr_eq(&self, other: &Rope) -> bool {
+        Arc::ptr_eq(&self.root, &other.root)
+    }
+
+    /// The text in order, one leaf at a time
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            stack: vec![&self.root],
+        }
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        Rope {
+            root: Node::build(text),
+        }
+    }
+}
+
+impl From<String> for Rope {
+    fn from(text: String) -> Self {
+        Rope::from(text.as_str())
+    }
+}
+
+impl Display for Rope {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for chunk in self.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the leaves of a rope
+pub struct Chunks<'a> {
+    stack: Vec<&'a Arc<Node>>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while let Some(node) = self.stack.pop() {
+            match &**node {
+                Node::Leaf(text) if text.is_empty() => (),
+                Node::Leaf(text) => return Some(text),
+                Node::Branch { left, right, .. } => {
+                    self.stack.push(right);
+                    self.stack.push(left);
+                }
+            }
+        }
+        None
+    }
+}
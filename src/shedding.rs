@@ -0,0 +1,117 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// Traffic to the client that can be dropped without breaking the protocol. Log lines
+/// mirrored with window/logMessage are dropped too, by lowering the level of the logger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionalTraffic {
+    Telemetry, // telemetry/event notifications
+    Refresh,   // workspace/*/refresh requests
+}
+
+/// When the client counts as slow, and when it counts as caught up again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SheddingPolicy {
+    pub slow_write: Duration, // writing a frame's output for longer than this is slow
+    pub max_queued: usize,    // more messages waiting to be written than this is slow
+    pub trip_after: u32,      // slow frames in a row before shedding starts
+    pub recover_after: u32,   // fast frames in a row before shedding stops
+}
+
+impl Default for SheddingPolicy {
+    fn default() -> Self {
+        SheddingPolicy {
+            slow_write: Duration::from_millis(50),
+            max_queued: 32,
+            trip_after: 5,
+            recover_after: 20,
+        }
+    }
+}
+
+/// A change in how the client keeps up, returned by `ClientHealth::record`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    Slowed { first: bool }, // shedding started, `first` if it never did before
+    Recovered,              // shedding stopped
+}
+
+/// Tracks how fast the client takes the server's output and decides when optional traffic
+/// is dropped. A single slow frame doesn't count, the client has to be slow for
+/// `trip_after` frames in a row, and fast again for `recover_after` frames
+#[derive(Debug, Clone, Default)]
+pub struct ClientHealth {
+    policy: SheddingPolicy,
+    slow_streak: u32,
+    fast_streak: u32,
+    shedding: bool,
+    stats: SheddingStats,
+}
+
+/// How often the client was slow and what was dropped because of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SheddingStats {
+    pub slow_frames: u64,
+    pub periods: u64, // times shedding started
+    pub shed: u64,    // optional messages dropped
+}
+
+impl Display for SheddingStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} slow frames, {} shedding periods, {} messages shed",
+            self.slow_frames, self.periods, self.shed
+        )
+    }
+}
+
+impl ClientHealth {
+    pub fn new(policy: SheddingPolicy) -> Self {
+        ClientHealth {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Record a handled frame, with the time spent writing its output and the number of
+    /// messages still waiting to be written to the client
+    pub fn record(&mut self, write: Duration, queued: usize) -> Option<HealthChange> {
+        if write > self.policy.slow_write || queued > self.policy.max_queued {
+            self.stats.slow_frames += 1;
+            self.slow_streak += 1;
+            self.fast_streak = 0;
+            if !self.shedding && self.slow_streak >= self.policy.trip_after {
+                self.shedding = true;
+                self.stats.periods += 1;
+                return Some(HealthChange::Slowed {
+                    first: self.stats.periods == 1,
+                });
+            }
+        } else {
+            self.fast_streak += 1;
+            self.slow_streak = 0;
+            if self.shedding && self.fast_streak >= self.policy.recover_after {
+                self.shedding = false;
+                return Some(HealthChange::Recovered);
+            }
+        }
+        None
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+    }
+
+    /// Whether the optional message should be sent, counting it as shed if not
+    pub fn admit(&mut self, _: OptionalTraffic) -> bool {
+        if self.shedding {
+            self.stats.shed += 1;
+        }
+        !self.shedding
+    }
+
+    pub fn stats(&self) -> SheddingStats {
+        self.stats
+    }
+}
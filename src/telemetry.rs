@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Whether `telemetry/event` notifications are sent, and how often. Off unless the user
+/// turns it on
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TelemetrySettings {
+    pub enabled: bool,
+    pub interval: u64, // frames handled between two events
+}
+
+impl Default for TelemetrySettings {
+    fn default() -> Self {
+        TelemetrySettings {
+            enabled: false,
+            interval: 100,
+        }
+    }
+}
+
+/// Time spent handling the messages of one method
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodLatency {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Body of a `telemetry/event`, covering the frames handled since the previous event
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TelemetryEvent {
+    pub requests: BTreeMap<String, u64>, // requests received, by method
+    pub notifications: BTreeMap<String, u64>, // notifications received, by method
+    pub parse_failures: u64,             // document syncs that were not a valid tree
+    pub latency: BTreeMap<String, MethodLatency>, // handler latency, by method
+}
+
+/// Counts collected between two telemetry events
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    requests: BTreeMap<String, u64>,
+    notifications: BTreeMap<String, u64>,
+    parse_failures: u64,
+    latency: BTreeMap<String, (u64, Duration, Duration)>, // count, total and max
+    frames: u64,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_message(&mut self, method: &str, is_request: bool) {
+        let counts = match is_request {
+            true => &mut self.requests,
+            false => &mut self.notifications,
+        };
+        *counts.entry(method.to_string()).or_default() += 1;
+    }
+
+    pub fn record_parse_failure(&mut self) {
+        self.parse_failures += 1;
+    }
+
+    /// Record the time spent handling a frame, which ends it
+    pub fn record_latency(&mut self, method: &str, handler: Duration) {
+        let (count, total, max) = self.latency.entry(method.to_string()).or_default();
+        *count += 1;
+        *total += handler;
+        *max = (*max).max(handler);
+        self.frames += 1;
+    }
+
+    /// Frames handled since the last event
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The event for everything recorded so far, starting over
+    pub fn take_event(&mut self) -> TelemetryEvent {
+        let telemetry = std::mem::take(self);
+        TelemetryEvent {
+            requests: telemetry.requests,
+            notifications: telemetry.notifications,
+            parse_failures: telemetry.parse_failures,
+            latency: telemetry
+                .latency
+                .into_iter()
+                .map(|(method, (count, total, max))| {
+                    let latency = MethodLatency {
+                        count,
+                        mean_ms: total.as_secs_f64() * 1000.0 / count as f64,
+                        max_ms: max.as_secs_f64() * 1000.0,
+                    };
+                    (method, latency)
+                })
+                .collect(),
+        }
+    }
+}
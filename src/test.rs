@@ -1,8 +1,100 @@
+#[cfg(test)]
+pub(crate) mod util {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::output::{MemoryWriter, MessageWriter, Output};
+
+    /// Small deterministic generator, so failures can be reproduced from the seed
+    pub struct XorShift(pub u64);
+
+    impl XorShift {
+        pub fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    /// Up to `max_pieces` pieces of text, multi-byte characters and both line breaks included
+    pub fn random_text(rng: &mut XorShift, max_pieces: usize) -> String {
+        let pieces = ["a", "abc", "Z", " ", "é", "€", "𝄞", "\n", "\r\n"];
+        (0..rng.next(max_pieces))
+            .map(|_| pieces[rng.next(pieces.len())])
+            .collect()
+    }
+
+    /// Content of the messages written with `output` while `f` runs. The writer it had is put
+    /// back after, even if `f` panics
+    pub fn capture(output: &Output, f: impl FnOnce()) -> Vec<String> {
+        struct Restore<'a>(&'a Output, Option<Box<dyn MessageWriter>>);
+
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                if let Some(writer) = self.1.take() {
+                    self.0.set_writer(writer);
+                }
+            }
+        }
+
+        let writer = MemoryWriter::new();
+        let _restore = Restore(output, Some(output.set_writer(Box::new(writer.clone()))));
+        f();
+        writer.take()
+    }
+
+    /// A context writing to memory instead of stdout, with the writer holding what it sent
+    pub fn context() -> (ServerContext, MemoryWriter) {
+        let context = ServerContext::new();
+        let writer = MemoryWriter::new();
+        context.output().set_writer(Box::new(writer.clone()));
+        (context, writer)
+    }
+
+    /// A context the client sent `initialize` to, without any capabilities
+    pub fn initialized() -> (ServerContext, MemoryWriter) {
+        let (mut context, writer) = context();
+        let initialize =
+            r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{"processId":1}}"#;
+        handle_message(
+            initialize.to_string(),
+            &mut EditorState::new(),
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        writer.take();
+        (context, writer)
+    }
+
+    /// The messages sent so far as json, removed from the writer
+    pub fn sent(writer: &MemoryWriter) -> Vec<Value> {
+        writer
+            .take()
+            .iter()
+            .map(|message| serde_json::from_str(message).unwrap())
+            .collect()
+    }
+
+    /// The response to the request with the id among the messages
+    pub fn response(messages: &[Value], id: impl Into<Value>) -> Value {
+        let id = id.into();
+        messages
+            .iter()
+            .find(|message| message["id"] == id && message.get("method").is_none())
+            .unwrap_or_else(|| panic!("no response to {} in {:?}", id, messages))
+            .clone()
+    }
+}
+
 #[cfg(test)]
 mod buffer_reader {
+    use std::io;
     use std::process::ExitCode;
 
-    use crate::rpc::BufferedReader;
+    use crate::rpc::{BufferedReader, FrameError, ReaderLimits};
 
     #[test]
     fn test_buffer_reader() -> ExitCode {
@@ -11,16 +103,16 @@ mod buffer_reader {
         let res = buff_reader.pop_message();
         match res {
             Err(e) => {
-                println!("\texpected parse successful, instead got{}", e.to_string());
-                return ExitCode::FAILURE;
+                println!("\texpected parse successful, instead got{}", e);
+                ExitCode::FAILURE
             }
             Ok(Some(content)) => {
                 assert_eq!(content, "{\"method\":\"hi\"}");
-                return ExitCode::SUCCESS;
+                ExitCode::SUCCESS
             }
             Ok(None) => {
                 println!("\texpected parse successful, instead got None");
-                return ExitCode::FAILURE;
+                ExitCode::FAILURE
             }
         }
     }
@@ -31,17 +123,160 @@ mod buffer_reader {
         buff_reader.write("Content-Length: 18\r\n\r\n{\"method\":\"hi\"}".as_bytes());
         let res = buff_reader.pop_message();
         match res {
-            Err(_e) => {
-                return ExitCode::FAILURE;
-            }
+            Err(_e) => ExitCode::FAILURE,
             Ok(Some(_content)) => {
                 println!("\texpected parse unsuccessful, instead got some");
-                return ExitCode::FAILURE;
+                ExitCode::FAILURE
             }
-            Ok(None) => {
-                return ExitCode::SUCCESS;
+            Ok(None) => ExitCode::SUCCESS,
+        }
+    }
+
+    #[test]
+    fn test_buffer_reader_several_messages() {
+        let mut buff_reader = BufferedReader::new();
+        buff_reader.write("Content-Length: 2\r\n\r\n{}Content-Length: 4\r\n\r\n[1]".as_bytes());
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+        assert_eq!(buff_reader.pop_message().unwrap(), None);
+        buff_reader.write("]Content-".as_bytes());
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("[1]]".to_string()));
+        assert_eq!(buff_reader.pop_message().unwrap(), None);
+    }
+
+    #[test]
+    fn test_buffer_reader_counts_bytes() {
+        let mut buff_reader = BufferedReader::new();
+        let message = "Content-Length: 4\r\n\r\n\"é\"Content-Length: 2\r\n\r\n{}".as_bytes();
+        // a chunk can end in the middle of a character
+        buff_reader.write(&message[..23]);
+        assert_eq!(buff_reader.pop_message().unwrap(), None);
+        buff_reader.write(&message[23..]);
+        assert_eq!(
+            buff_reader.pop_message().unwrap(),
+            Some("\"é\"".to_string())
+        );
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+        assert!(buff_reader.get_data().is_empty());
+        // popped bytes are dropped as more are written
+        for _ in 0..100 {
+            buff_reader.write(b"Content-Length: 2\r\n\r\n[]");
+            assert_eq!(buff_reader.pop_message().unwrap(), Some("[]".to_string()));
+        }
+        assert!(buff_reader.get_data().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_reader_byte_by_byte() {
+        let mut buff_reader = BufferedReader::new();
+        let messages = "Content-Length: 2\r\n\r\n{}X-Trace: 1\r\nContent-Length: 3\r\n\r\n[1]";
+        let mut popped = Vec::new();
+        // the end of the header is found even when it comes one byte at a time
+        for byte in messages.as_bytes() {
+            buff_reader.write(&[*byte]);
+            while let Some(content) = buff_reader.pop_message().unwrap() {
+                popped.push(content);
             }
         }
+        assert_eq!(popped, ["{}", "[1]"]);
+        assert!(buff_reader.get_data().is_empty());
+    }
+
+    #[test]
+    fn test_buffer_reader_io() {
+        let messages = "Content-Length: 2\r\n\r\n{}Content-Length: 3\r\n\r\n[1]";
+        let mut buff_reader = BufferedReader::new();
+        io::copy(&mut messages.as_bytes(), &mut buff_reader).unwrap();
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("[1]".to_string()));
+
+        let mut input = messages.as_bytes();
+        let mut buff_reader = BufferedReader::new();
+        assert_eq!(buff_reader.fill_from(&mut input).unwrap(), messages.len());
+        assert_eq!(buff_reader.fill_from(&mut input).unwrap(), 0);
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+    }
+
+    #[test]
+    fn test_buffer_reader_headers() {
+        let mut buff_reader = BufferedReader::new();
+        buff_reader.write(
+            "content-type: application/vscode-jsonrpc; charset=utf8\r\nX-Trace: 1\r\nCONTENT-LENGTH:2\r\n\r\n{}"
+                .as_bytes(),
+        );
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+
+        for header in [
+            "Content-Type: application/vscode-jsonrpc; charset=latin1\r\nContent-Length: 2",
+            "Content-Length: 2\r\nContent-Length: 2",
+            "Content-Type: application/vscode-jsonrpc",
+            "Content-Length: 2\r\nno colon",
+        ] {
+            let mut buff_reader = BufferedReader::new();
+            buff_reader.write(format!("{}\r\n\r\n{{}}", header).as_bytes());
+            assert!(buff_reader.pop_message().is_err(), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn test_buffer_reader_limits() {
+        let mut buff_reader = BufferedReader::with_limits(ReaderLimits {
+            max_content_length: 8,
+            max_buffered: 32,
+        });
+        // the content of a refused message is dropped as it comes, even across chunks
+        buff_reader.write(b"Content-Length: 13\r\n\r\n[1,2,");
+        assert!(matches!(
+            buff_reader.pop_message(),
+            Err(FrameError::ContentTooLarge {
+                length: 13,
+                limit: 8
+            })
+        ));
+        buff_reader.write(b"3,4,5,6]Content-Length: 2\r\n\r\n{}");
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+
+        // a header that doesn't end is dropped once it is over the limit
+        buff_reader.write(&[b'x'; 33]);
+        assert!(matches!(
+            buff_reader.pop_message(),
+            Err(FrameError::HeaderTooLarge { limit: 32 })
+        ));
+        assert!(buff_reader.get_data().is_empty());
+        buff_reader.write(b"Content-Length: 8\r\n\r\n[1,2,3]");
+        assert_eq!(buff_reader.pop_message().unwrap(), None);
+        buff_reader.write(b" ");
+        assert_eq!(
+            buff_reader.pop_message().unwrap(),
+            Some("[1,2,3] ".to_string())
+        );
+    }
+
+    #[test]
+    fn test_buffer_reader_resynchronizes() {
+        let mut buff_reader = BufferedReader::new();
+        buff_reader.write(b"Content-Length: 2\r\nno colon\r\n\r\n{}content-length: 2\r\n\r\n[]");
+        assert!(matches!(
+            buff_reader.pop_message(),
+            Err(FrameError::Malformed(_))
+        ));
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("[]".to_string()));
+
+        // the fields of the malformed header are not the start of the next message
+        buff_reader.write(b"bogus field\r\nContent-Length: 2\r\n\r\n{}");
+        assert!(matches!(
+            buff_reader.pop_message(),
+            Err(FrameError::Malformed(_))
+        ));
+        assert_eq!(buff_reader.pop_message().unwrap(), None);
+        assert!(buff_reader.get_data().is_empty());
+
+        // the next header may only have started to arrive
+        buff_reader.write(b"garbage\r\n\r\nConte");
+        assert!(buff_reader.pop_message().is_err());
+        assert_eq!(buff_reader.get_data(), b"Conte");
+        buff_reader.write(b"nt-Length: 2\r\n\r\n{}");
+        assert_eq!(buff_reader.pop_message().unwrap(), Some("{}".to_string()));
+        assert!(buff_reader.get_data().is_empty());
     }
 
     #[test]
@@ -50,15 +285,9 @@ mod buffer_reader {
         buff_reader.write("ABC \r\n\r\n".as_bytes());
         let res = buff_reader.pop_message();
         match res {
-            Err(_e) => {
-                return ExitCode::SUCCESS;
-            }
-            Ok(Some(_content)) => {
-                return ExitCode::FAILURE;
-            }
-            Ok(None) => {
-                return ExitCode::FAILURE;
-            }
+            Err(_e) => ExitCode::SUCCESS,
+            Ok(Some(_content)) => ExitCode::FAILURE,
+            Ok(None) => ExitCode::FAILURE,
         }
     }
 }
@@ -80,3 +309,6407 @@ mod states {
         assert_eq!(n3, String::from("D"));
     }
 }
+
+#[cfg(test)]
+mod progress {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_message, ProgressNotification, ProgressToken, ServerContext, WorkDoneProgressBegin,
+        WorkDoneProgressParams, WorkDoneProgressValue,
+    };
+    use crate::rpc::{json_from_string, json_to_string};
+    use crate::test::util::capture;
+
+    fn kinds(sent: &[String]) -> Vec<String> {
+        sent.iter()
+            .map(|message| {
+                let message: Value = serde_json::from_str(message).unwrap();
+                match message["params"]["value"]["kind"].as_str() {
+                    Some(kind) => kind.to_string(),
+                    None => message["method"].as_str().unwrap_or("response").to_string(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_progress_begin() {
+        let value = WorkDoneProgressValue::Begin(WorkDoneProgressBegin {
+            title: "Parsing".to_string(),
+            cancellable: None,
+            message: None,
+            percentage: Some(0),
+        });
+        let notification = ProgressNotification::new(ProgressToken::Number(1), value);
+        assert_eq!(
+            json_to_string(&notification),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"$/progress\",\"params\":{\"token\":1,\"value\":{\"kind\":\"begin\",\"title\":\"Parsing\",\"percentage\":0}}}"
+        );
+    }
+
+    #[test]
+    fn test_work_done_token() {
+        let params: WorkDoneProgressParams =
+            json_from_string("{\"workDoneToken\":\"abc\"}").unwrap();
+        assert_eq!(
+            params.work_done_token,
+            Some(ProgressToken::String("abc".to_string()))
+        );
+        let params: WorkDoneProgressParams = json_from_string("{}").unwrap();
+        assert_eq!(params.work_done_token, None);
+    }
+
+    #[test]
+    fn test_client_token_begins_at_once() {
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        let sent = capture(&output, || {
+            let token = Some(ProgressToken::Number(7));
+            let progress = context.start_progress(token, "Scanning", &mut logger);
+            progress.unwrap().end(None, &output, &mut logger);
+        });
+        assert_eq!(kinds(&sent), ["begin", "end"]);
+    }
+
+    #[test]
+    fn test_created_token_begins_once_created() {
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{"window":{"workDoneProgress":true}}}}"#;
+        let mut handle = |message: String, context: &mut ServerContext| {
+            handle_message(message, &mut editor_state, context, &mut Vec::new()).unwrap();
+        };
+        capture(&output, || handle(initialize.to_string(), &mut context));
+
+        // nothing is reported before the client created the token
+        let mut progress = None;
+        let sent = capture(&output, || {
+            progress = context.start_progress(None, "Scanning", &mut logger);
+            let progress = progress.as_ref().unwrap();
+            progress.report(None, Some(50), &output, &mut logger);
+        });
+        assert_eq!(kinds(&sent), ["window/workDoneProgress/create"]);
+        let create: Value = serde_json::from_str(&sent[0]).unwrap();
+        let created = format!(r#"{{"jsonrpc":"2.0","id":{},"result":null}}"#, create["id"]);
+        let sent = capture(&output, || {
+            handle(created, &mut context);
+            let progress = progress.take().unwrap();
+            progress.report(None, Some(50), &output, &mut logger);
+            progress.end(None, &output, &mut logger);
+        });
+        assert_eq!(kinds(&sent), ["begin", "report", "end"]);
+
+        // a progress that ended before it was created never begins
+        let sent = capture(&output, || {
+            let progress = context.start_progress(None, "Scanning", &mut logger);
+            progress.unwrap().end(None, &output, &mut logger);
+        });
+        let create: Value = serde_json::from_str(&sent[0]).unwrap();
+        let created = format!(r#"{{"jsonrpc":"2.0","id":{},"result":null}}"#, create["id"]);
+        let sent = capture(&output, || handle(created, &mut context));
+        assert!(sent.is_empty(), "{:?}", sent);
+    }
+}
+
+#[cfg(test)]
+mod show_message {
+    use crate::lsp::{MessageType, ShowMessageNotification};
+    use crate::rpc::{json_from_string, json_to_string};
+
+    #[test]
+    fn test_show_message() {
+        let notification =
+            ShowMessageNotification::new(MessageType::Error, "invalid tree".to_string());
+        let json = json_to_string(&notification);
+        assert_eq!(
+            json,
+            "{\"jsonrpc\":\"2.0\",\"method\":\"window/showMessage\",\"params\":{\"type\":1,\"message\":\"invalid tree\"}}"
+        );
+        let parsed: ShowMessageNotification = json_from_string(&json).unwrap();
+        assert_eq!(parsed.params.message_type, MessageType::Error);
+    }
+}
+
+#[cfg(test)]
+mod show_document {
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_message, Position, Range, ServerContext, ShowDocumentParams, ShowDocumentRequest,
+        PREVIEW_SUBTREE_COMMAND,
+    };
+    use crate::rpc::json_to_string;
+    use crate::test::util::capture;
+
+    #[test]
+    fn test_show_document() {
+        let params = ShowDocumentParams {
+            uri: "file:///tree.abc".to_string(),
+            take_focus: Some(true),
+            selection: Some(Range {
+                start: Position {
+                    line: 1,
+                    character: 0,
+                },
+                end: Position {
+                    line: 1,
+                    character: 1,
+                },
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            json_to_string(&ShowDocumentRequest::new(3, "window/showDocument", params)),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"window/showDocument\",\"id\":3,\"params\":{\"uri\":\"file:///tree.abc\",\"takeFocus\":true,\"selection\":{\"start\":{\"line\":1,\"character\":0},\"end\":{\"line\":1,\"character\":1}}}}"
+        );
+    }
+
+    #[test]
+    fn test_preview_shows_the_node() {
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c".to_string())
+            .unwrap();
+        let arguments = [json!("file:///a.abc"), json!({"line": 1, "character": 2})];
+        let preview = |editor_state: &mut EditorState, context: &mut ServerContext| {
+            let mut logger = Vec::new();
+            let handler = context.commands_mut().get(PREVIEW_SUBTREE_COMMAND).unwrap();
+            handler(&arguments, editor_state, context, &mut logger).unwrap()
+        };
+
+        // a client that can't be asked gets the preview only
+        let sent = capture(&output, || {
+            preview(&mut editor_state, &mut context);
+        });
+        assert!(sent.is_empty(), "{:?}", sent);
+
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{"window":{"showDocument":{"support":true}}}}}"#;
+        let sent = capture(&output, || {
+            handle_message(
+                initialize.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+            assert_eq!(preview(&mut editor_state, &mut context), json!("c"));
+        });
+        let request: Value = serde_json::from_str(&sent[1]).unwrap();
+        assert_eq!(request["method"], "window/showDocument");
+        assert_eq!(
+            request["params"],
+            json!({
+                "uri": "file:///a.abc",
+                "takeFocus": true,
+                "selection": {"start": {"line": 1, "character": 2}, "end": {"line": 1, "character": 3}}
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod log_message {
+    use crate::lsp::{LogMessageNotification, MessageType};
+    use crate::rpc::json_to_string;
+
+    #[test]
+    fn test_log_line_level() {
+        assert_eq!(
+            MessageType::from_log_line("[Error] Could not pop message"),
+            MessageType::Error
+        );
+        assert_eq!(
+            MessageType::from_log_line("[Method] initialize"),
+            MessageType::Log
+        );
+        assert_eq!(
+            MessageType::from_log_line("[DidChange] modify file successful"),
+            MessageType::Info
+        );
+    }
+
+    #[test]
+    fn test_log_message() {
+        let notification = LogMessageNotification::new(MessageType::Info, "hi".to_string());
+        assert_eq!(
+            json_to_string(&notification),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"window/logMessage\",\"params\":{\"type\":3,\"message\":\"hi\"}}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod uri {
+    use crate::editor::EditorState;
+    use crate::uri::{CaseSensitivity, Uri};
+
+    fn same(a: &str, b: &str, case_sensitivity: CaseSensitivity) -> bool {
+        Uri::new(a, case_sensitivity) == Uri::new(b, case_sensitivity)
+    }
+
+    #[test]
+    fn test_windows_uris() {
+        let uris = [
+            "file:///C:/x/Tree.abc",
+            "file:///c:/x/Tree.abc",
+            "file:///c%3A/x/Tree.abc",
+            "file:///C%3a/x/Tree.abc",
+            "FILE:///C:\\x\\Tree.abc",
+        ];
+        for uri in uris {
+            assert!(same(uris[0], uri, CaseSensitivity::Sensitive), "{}", uri);
+        }
+        assert!(!same(
+            "file:///C:/x/Tree.abc",
+            "file:///c:/X/tree.abc",
+            CaseSensitivity::Sensitive
+        ));
+        assert!(same(
+            "file:///C:/x/Tree.abc",
+            "file:///c%3A/X/tree.abc",
+            CaseSensitivity::Insensitive
+        ));
+    }
+
+    #[test]
+    fn test_macos_uris() {
+        assert!(same(
+            "file:///Users/Me/My%20Tree.abc",
+            "file:///users/me/my tree.abc",
+            CaseSensitivity::Insensitive
+        ));
+        assert!(!same(
+            "file:///Users/Me/tree.abc",
+            "file:///users/me/tree.abc",
+            CaseSensitivity::Sensitive
+        ));
+    }
+
+    #[test]
+    fn test_linux_uris() {
+        assert!(same(
+            "file:///home/me/a%2Db.abc",
+            "file:///home/me/a-b.abc",
+            CaseSensitivity::Sensitive
+        ));
+        // encoded separators must not turn into path segments
+        assert!(!same(
+            "file:///home/me/a%2Fb.abc",
+            "file:///home/me/a/b.abc",
+            CaseSensitivity::Sensitive
+        ));
+        assert_eq!(
+            Uri::new("file:///home/me/a%2fb.abc", CaseSensitivity::Sensitive).as_str(),
+            "file:///home/me/a%2Fb.abc"
+        );
+    }
+
+    #[test]
+    fn test_editor_state_lookup() {
+        let mut editor_state = EditorState::new();
+        editor_state.set_case_sensitivity(CaseSensitivity::Insensitive);
+        assert!(editor_state
+            .modify_file("file:///C:/Tree.abc".to_string(), "a".to_string())
+            .is_ok());
+        assert!(editor_state
+            .get_file_state("file:///c%3A/tree.abc".to_string())
+            .is_some());
+
+        // the file keeps the case it was opened with
+        editor_state.set_case_sensitivity(CaseSensitivity::Sensitive);
+        assert!(editor_state
+            .get_file_state("file:///C:/Tree.abc".to_string())
+            .is_some());
+        assert!(editor_state
+            .get_file_state("file:///c%3A/tree.abc".to_string())
+            .is_none());
+        let uris: Vec<&str> = editor_state
+            .documents()
+            .map(|(uri, _)| uri.original())
+            .collect();
+        assert_eq!(uris, vec!["file:///C:/Tree.abc"]);
+    }
+
+    #[test]
+    fn test_documents() {
+        let mut editor_state = EditorState::new();
+        assert!(editor_state.is_empty());
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///b.abc".to_string(), "b\nc".to_string())
+            .unwrap();
+        // only valid trees are documents, the text of the others is still kept
+        editor_state
+            .modify_file("file:///c.abc".to_string(), "c\nd e f".to_string())
+            .unwrap_err();
+        assert_eq!(editor_state.len(), 2);
+        let mut uris: Vec<_> = editor_state
+            .documents()
+            .map(|(uri, fs)| (uri.to_string(), fs.len()))
+            .collect();
+        uris.sort();
+        assert_eq!(
+            uris,
+            vec![
+                ("file:///a.abc".to_string(), 1),
+                ("file:///b.abc".to_string(), 2)
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod workspace {
+    use std::fs;
+
+    use crate::uri::{path_to_uri, uri_to_path};
+    use crate::workspace::{glob_match, ScanConfig, WorkspaceScanner};
+
+    #[test]
+    fn test_double_star_matches_components() {
+        for path in ["foo", "a/foo", "a/b/foo"] {
+            assert!(glob_match(b"**/foo", path.as_bytes()), "{}", path);
+        }
+        // the name must be the whole component
+        assert!(!glob_match(b"**/foo", b"a/xfoo"));
+        assert!(!glob_match(b"**/foo", b"xfoo"));
+        assert!(glob_match(b"a/**/b", b"a/b"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(!glob_match(b"a/**/b", b"a/xb"));
+        // without a slash after it `**` matches any part of the path
+        assert!(glob_match(b"a**", b"ab/c"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let root = std::env::temp_dir().join(format!("lsp-rs-scan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub/build")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.gen.abc\n").unwrap();
+        fs::write(root.join("sub/.gitignore"), "build\n").unwrap();
+        fs::write(root.join("a.abc"), "a\nb c").unwrap();
+        fs::write(root.join("b.gen.abc"), "a").unwrap();
+        fs::write(root.join("notes.txt"), "a").unwrap();
+        fs::write(root.join("sub/c.abc"), "c").unwrap();
+        fs::write(root.join("sub/build/d.abc"), "d").unwrap();
+        fs::write(root.join("target/e.abc"), "e").unwrap();
+        fs::write(root.join("sub/big.abc"), "a\nb c\nd e f g").unwrap();
+
+        let scanner = WorkspaceScanner::new(ScanConfig {
+            max_concurrent_reads: 2,
+            max_file_size: 8,
+            ..Default::default()
+        });
+        let (files, stats) = scanner.scan(&root);
+        let paths: Vec<_> = files.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![root.join("a.abc"), root.join("sub").join("c.abc")]
+        );
+        assert_eq!(files[0].1, "a\nb c");
+        assert_eq!(stats.files_read, 2);
+        assert_eq!(stats.skipped_too_large, 1);
+        assert_eq!(stats.large_valid, 1);
+        assert_eq!(stats.skipped_ignored, 1);
+        assert_eq!(stats.bytes_read, 6);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_path_uri_round_trip() {
+        let path = std::env::temp_dir().join("my tree#1.abc");
+        assert_eq!(uri_to_path(&path_to_uri(&path)).unwrap(), path);
+    }
+}
+
+#[cfg(test)]
+mod server_requests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, MessageType};
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_show_message_request_response() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let chosen = Rc::new(RefCell::new(None));
+
+        let chosen_clone = Rc::clone(&chosen);
+        let id = context.show_message_request(
+            MessageType::Info,
+            "Balance tree?".to_string(),
+            &["Yes", "No"],
+            move |action, _, _| *chosen_clone.borrow_mut() = action,
+            &mut logger,
+        );
+        assert_eq!(context.pending_request_count(), 1);
+        let request = &sent(&writer)[0];
+        assert_eq!(request["method"], "window/showMessageRequest");
+        assert_eq!(request["id"], id);
+        assert_eq!(request["params"]["message"], "Balance tree?");
+
+        let response = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{{\"title\":\"Yes\"}}}}",
+            id
+        );
+        handle_message(
+            response.clone(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(*chosen.borrow(), Some("Yes".to_string()));
+        assert_eq!(context.pending_request_count(), 0);
+
+        // the request was already answered
+        assert!(handle_message(response, &mut editor_state, &mut context, &mut logger).is_err());
+    }
+}
+
+#[cfg(test)]
+mod settings {
+    use crate::config::{HoverVerbosity, LogLevel, Settings};
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::rpc::json_from_string;
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_settings_from_value() {
+        let value = json_from_string(
+            "{\"lsp-rs\":{\"hoverVerbosity\":\"verbose\",\"logLevel\":\"error\"}}",
+        )
+        .unwrap();
+        let settings = Settings::from_value(&value).unwrap();
+        assert_eq!(settings.hover_verbosity, HoverVerbosity::Verbose);
+        assert_eq!(settings.log_level, LogLevel::Error);
+        assert_eq!(settings.max_file_size, Settings::default().max_file_size);
+
+        let value = json_from_string("{\"maxFileSize\":10}").unwrap();
+        assert_eq!(Settings::from_value(&value).unwrap().max_file_size, 10);
+
+        let value = json_from_string("{\"hoverVerbosity\":\"loud\"}").unwrap();
+        assert!(Settings::from_value(&value).is_err());
+    }
+
+    #[test]
+    fn test_did_change_configuration() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let message = "{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeConfiguration\",\"params\":{\"settings\":{\"lsp-rs\":{\"maxFileSize\":4}}}}";
+        handle_message(
+            message.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(context.settings().max_file_size, 4);
+
+        let open = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\",\"languageId\":\"abc\",\"version\":0,\"text\":\"a\\nb c\"}}}";
+        handle_message(
+            open.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .is_none());
+        let logs = String::from_utf8(logger).unwrap();
+        assert!(
+            logs.contains("is larger than maxFileSize (4 bytes) and is not parsed"),
+            "{}",
+            logs
+        );
+        // the client is told why the document is not parsed
+        let diagnostics = sent(&writer)
+            .into_iter()
+            .find(|message| message["method"] == "textDocument/publishDiagnostics")
+            .unwrap();
+        assert_eq!(diagnostics["params"]["diagnostics"][0]["code"], "file-size");
+    }
+}
+
+#[cfg(test)]
+mod completion {
+    use serde_json::{json, Value};
+
+    use crate::completion::{CompletionContext, CompletionEngine, CompletionSettings};
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::output::MemoryWriter;
+    use crate::test::util::{initialized, response, sent};
+
+    /// The result of the request, from the response sent
+    fn result(
+        message: String,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        writer: &MemoryWriter,
+    ) -> Value {
+        let id = serde_json::from_str::<Value>(&message).unwrap()["id"].clone();
+        handle_message(message, editor_state, context, &mut Vec::new()).unwrap();
+        response(&sent(writer), id)["result"].clone()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c\nd e".to_string())
+            .unwrap();
+        let completion = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"textDocument/completion\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"position\":{\"line\":1,\"character\":0}}}";
+        let list = result(
+            completion.to_string(),
+            &mut editor_state,
+            &mut context,
+            &writer,
+        );
+        let item = list["items"][0].clone();
+        assert!(item.get("detail").is_none());
+        assert!(item.get("documentation").is_none());
+        assert_eq!(item["data"]["index"], 1);
+
+        let resolve =
+            json!({"jsonrpc": "2.0", "id": 2, "method": "completionItem/resolve", "params": item});
+        let resolved = result(
+            resolve.to_string(),
+            &mut editor_state,
+            &mut context,
+            &writer,
+        );
+        assert_eq!(resolved["label"], item["label"]);
+        assert!(resolved["detail"].is_string());
+        let documentation = resolved["documentation"]["value"].as_str().unwrap();
+        assert_eq!(
+            documentation,
+            format!("{}\n  d\n  e", item["label"].as_str().unwrap().trim())
+        );
+
+        // items from other servers or older versions come back unchanged
+        let resolve = json!({"jsonrpc": "2.0", "id": 3, "method": "completionItem/resolve", "params": {"label": "x"}});
+        let resolved = result(
+            resolve.to_string(),
+            &mut editor_state,
+            &mut context,
+            &writer,
+        );
+        assert_eq!(resolved, json!({"label": "x"}));
+
+        // past the largest tree the items keep their detail and have nothing to resolve
+        let completion = "{\"jsonrpc\":\"2.0\",\"id\":4,\"method\":\"textDocument/completion\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"position\":{\"line\":64,\"character\":0}}}";
+        let list = result(
+            completion.to_string(),
+            &mut editor_state,
+            &mut context,
+            &writer,
+        );
+        let items = list["items"].as_array().unwrap();
+        assert!(!items.is_empty());
+        for item in items {
+            assert!(item.get("data").is_none());
+        }
+    }
+
+    fn labels(
+        editor_state: &EditorState,
+        settings: &CompletionSettings,
+        line: usize,
+        character: usize,
+    ) -> Vec<String> {
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        let context = CompletionContext {
+            file_state: fs,
+            editor_state,
+            line,
+            character,
+        };
+        CompletionEngine::new(settings)
+            .complete(&context)
+            .into_iter()
+            .map(|c| c.label)
+            .collect()
+    }
+
+    #[test]
+    fn test_complete_line() {
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "m\nc t\na".to_string())
+            .unwrap();
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        let settings = CompletionSettings {
+            workspace: false,
+            ..Default::default()
+        };
+        let engine = CompletionEngine::new(&settings);
+        let complete_line = |line, character| {
+            engine.complete_line(&CompletionContext {
+                file_state: fs,
+                editor_state: &editor_state,
+                line,
+                character,
+            })
+        };
+        // the first missing slot gets the smallest label between c and m, the rest their parent's
+        assert_eq!(complete_line(2, 1), Some(" d t t".to_string()));
+        assert_eq!(complete_line(2, 2), Some("d t t".to_string()));
+        assert_eq!(complete_line(2, 0), None);
+        assert_eq!(complete_line(1, 3), None);
+        assert_eq!(complete_line(64, 0), None);
+    }
+
+    #[test]
+    fn test_bst_candidates() {
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "m\nc t\na ".to_string())
+            .unwrap();
+        let settings = CompletionSettings {
+            workspace: false,
+            ..Default::default()
+        };
+        // slot 4 is the right child of c, so between c and m
+        assert_eq!(
+            labels(&editor_state, &settings, 2, 2),
+            vec!["d", "e", "f", "g", "h", "i", "j", "k", "l"]
+        );
+        // no tree is that deep
+        assert!(labels(&editor_state, &settings, 64, 0).is_empty());
+        assert!(labels(&editor_state, &settings, usize::MAX, 0).is_empty());
+    }
+
+    #[test]
+    fn test_merged_ranking() {
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "m\nc t\na ".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///b.abc".to_string(), "e\ne e".to_string())
+            .unwrap();
+        let ranked = labels(&editor_state, &CompletionSettings::default(), 2, 2);
+        let rank = |label: &str| ranked.iter().position(|l| l == label).unwrap();
+        // e is both a valid label and the most used one, c is used but not valid
+        assert_eq!(ranked[0], "e");
+        assert!(rank("d") < rank("c"));
+
+        let settings = CompletionSettings {
+            bst: false,
+            ..Default::default()
+        };
+        assert_eq!(labels(&editor_state, &settings, 2, 2)[0], "e");
+        assert!(!labels(&editor_state, &settings, 2, 2).contains(&"d".to_string()));
+    }
+
+    #[test]
+    fn test_structural_candidates() {
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "m\nc t".to_string())
+            .unwrap();
+        let settings = CompletionSettings {
+            bst: false,
+            workspace: false,
+            ..Default::default()
+        };
+        assert_eq!(labels(&editor_state, &settings, 1, 3), vec!["new level"]);
+        assert_eq!(labels(&editor_state, &settings, 1, 1), vec!["separator"]);
+    }
+}
+
+#[cfg(test)]
+mod disabled_features {
+    use serde_json::json;
+
+    use crate::completion::CompletionSettings;
+    use crate::config::Settings;
+    use crate::lsp::{ErrorCode, ErrorResponse, ResponseError};
+    use crate::rpc::json_to_string;
+
+    #[test]
+    fn test_disabled_hint() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.disabled_hint("textDocument/hover"), None);
+        assert_eq!(settings.disabled_hint("textDocument/completion"), None);
+
+        settings.enable_hover = false;
+        settings.completion = CompletionSettings {
+            structural: false,
+            bst: false,
+            workspace: false,
+        };
+        assert_eq!(
+            settings.disabled_hint("textDocument/hover").unwrap(),
+            "set lsp-rs.enableHover to true"
+        );
+        assert!(settings
+            .disabled_hint("textDocument/completion")
+            .unwrap()
+            .contains("lsp-rs.completion.bst"));
+        assert_eq!(settings.disabled_hint("textDocument/definition"), None);
+    }
+
+    #[test]
+    fn test_feature_flags() {
+        let value = json!({"features": {"callHierarchy": false}});
+        let settings = Settings::from_value(&value).unwrap();
+        assert!(settings.features.semantic_tokens);
+        for method in [
+            "textDocument/prepareCallHierarchy",
+            "callHierarchy/incomingCalls",
+            "callHierarchy/outgoingCalls",
+        ] {
+            assert_eq!(
+                settings.disabled_hint(method).unwrap(),
+                "set lsp-rs.features.callHierarchy to true"
+            );
+        }
+        assert_eq!(settings.disabled_hint("textDocument/moniker"), None);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let error = ResponseError {
+            code: ErrorCode::FeatureDisabled,
+            message: "disabled".to_string(),
+            data: Some(json!({ "hint": "enable it" })),
+        };
+        assert_eq!(
+            json_to_string(&ErrorResponse::new(4.into(), error)),
+            "{\"jsonrpc\":\"2.0\",\"id\":4,\"error\":{\"code\":-33050,\"message\":\"disabled\",\"data\":{\"hint\":\"enable it\"}}}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod configuration_pull {
+    use crate::config::HoverVerbosity;
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_pull_configuration() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        assert!(!context.pull_configuration(&mut logger));
+
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"workspace\":{\"configuration\":true}}}}";
+        let initialized = "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}";
+        for message in [initialize, initialized] {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        }
+        assert_eq!(context.pending_request_count(), 1);
+        let request = sent(&writer)
+            .into_iter()
+            .find(|message| message["method"] == "workspace/configuration")
+            .unwrap();
+        assert_eq!(request["params"]["items"][0]["section"], "lsp-rs");
+
+        let response = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":[{{\"hoverVerbosity\":\"minimal\"}}]}}",
+            request["id"]
+        );
+        handle_message(response, &mut editor_state, &mut context, &mut logger).unwrap();
+        assert_eq!(context.pending_request_count(), 0);
+        assert_eq!(context.settings().hover_verbosity, HoverVerbosity::Minimal);
+    }
+}
+
+#[cfg(test)]
+mod watched_files {
+    use std::fs;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::test::util::capture;
+    use crate::uri::path_to_uri;
+
+    fn change(uri: &str, change_type: u8) -> String {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeWatchedFiles\",\"params\":{{\"changes\":[{{\"uri\":\"{}\",\"type\":{}}}]}}}}",
+            uri, change_type
+        )
+    }
+
+    #[test]
+    fn test_reload_watched_files() {
+        let dir = std::env::temp_dir().join(format!("lsp-rs-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.abc");
+        let uri = path_to_uri(&path);
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+
+        capture(&output, || {
+            fs::write(&path, "a\nb c").unwrap();
+            handle_message(
+                change(&uri, 1),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+            let fs_state = editor_state.get_file_state(uri.clone()).unwrap();
+            assert_eq!(fs_state.get(2).unwrap(), "c");
+
+            // open documents are owned by the editor
+            editor_state.set_open(&uri, true);
+            fs::write(&path, "x\ny z").unwrap();
+            handle_message(
+                change(&uri, 2),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+            let fs_state = editor_state.get_file_state(uri.clone()).unwrap();
+            assert_eq!(fs_state.get(2).unwrap(), "c");
+
+            editor_state.set_open(&uri, false);
+            handle_message(
+                change(&uri, 2),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+            let fs_state = editor_state.get_file_state(uri.clone()).unwrap();
+            assert_eq!(fs_state.get(2).unwrap(), "z");
+
+            fs::remove_file(&path).unwrap();
+            handle_message(
+                change(&uri, 3),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+            assert!(editor_state.get_file_state(uri).is_none());
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod large_file {
+    use std::fs;
+    use std::io::{self, BufReader, Cursor, Read};
+
+    use crate::large_file::{validate_bytes, validate_file, validate_reader};
+
+    #[test]
+    fn test_validate_lines() {
+        let summary = validate_bytes(b"a\nb c\nd e f\n").unwrap();
+        assert_eq!((summary.lines, summary.nodes, summary.bytes), (3, 6, 12));
+        assert_eq!(validate_bytes(b"").unwrap().nodes, 0);
+
+        let error = validate_bytes(b"a\nb\nd e f").unwrap_err();
+        assert_eq!(error.line, 1);
+        let error = validate_bytes(b"a\nbxc").unwrap_err();
+        assert_eq!(error.line, 1);
+        let error = validate_bytes(b"a\nb c d").unwrap_err();
+        assert_eq!(error.line, 1);
+        // holes are not counted, and have nothing below them
+        assert_eq!(validate_bytes(b"a\n_ c\n_ _ f").unwrap().nodes, 3);
+        let error = validate_bytes(b"a\n_ c\nd").unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn test_validate_reader_matches_bytes() {
+        for content in ["a\r\nb c\r\n", "a\nb c\nd", "a\nb\nc", "ab"] {
+            let from_reader = validate_reader(Cursor::new(content)).unwrap();
+            assert_eq!(
+                from_reader,
+                validate_bytes(content.as_bytes()),
+                "{:?}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_lines_are_not_read_whole() {
+        // the lines never end, the reader stops at the width of their level
+        let error = validate_reader(BufReader::new(io::repeat(b'a')))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.line, 0);
+        assert_eq!(error.reason, "expected at most 1 characters, found more");
+        let endless = Cursor::new("a\nb c\n").chain(io::repeat(b'd'));
+        let error = validate_reader(BufReader::new(endless))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.line, 2);
+
+        // a short level is reported before the line after it
+        let endless = Cursor::new("a\nb\n").chain(io::repeat(b'c'));
+        let error = validate_reader(BufReader::new(endless))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn test_validate_file() {
+        let path = std::env::temp_dir().join(format!("lsp-rs-large-{}.abc", std::process::id()));
+        let mut content = String::from("a\n");
+        let mut width = 2;
+        // large enough to be mapped with the mmap feature
+        for _ in 0..19 {
+            content.push_str(&vec!["x"; width].join(" "));
+            content.push('\n');
+            width *= 2;
+        }
+        fs::write(&path, &content).unwrap();
+        let summary = validate_file(&path).unwrap().unwrap();
+        assert_eq!(summary.nodes, (1 << 20) - 1);
+        assert_eq!(summary.bytes, content.len() as u64);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod json_diff {
+    use serde_json::json;
+
+    use crate::testing::check::{compare, parse_transcript, CheckOptions, TranscriptEntry};
+    use crate::testing::diff::{diff, render, Change, DiffOptions};
+
+    #[test]
+    fn test_diff_paths() {
+        let expected = json!({"id": 1, "result": {"items": [{"label": "a"}, {"label": "b"}]}});
+        let actual =
+            json!({"id": 2, "result": {"items": [{"label": "a"}, {"label": "c", "kind": 12}]}});
+        let options = DiffOptions {
+            ignored_fields: vec!["id".to_string()],
+        };
+        let changes = diff(&expected, &actual, &options);
+        assert_eq!(
+            changes,
+            vec![
+                Change::Changed {
+                    path: "result.items[1].label".to_string(),
+                    expected: json!("b"),
+                    actual: json!("c"),
+                },
+                Change::Added {
+                    path: "result.items[1].kind".to_string(),
+                    value: json!(12),
+                },
+            ]
+        );
+        assert_eq!(
+            render(&changes, false),
+            "~ result.items[1].label: \"b\" -> \"c\"\n+ result.items[1].kind: 12\n"
+        );
+        assert!(diff(&expected, &expected, &DiffOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_compare_transcript() {
+        let transcript =
+            "// hover\n{\"in\": {\"id\": 1}}\n\n{\"out\": {\"id\": 1, \"result\": null}}\n";
+        let entries = parse_transcript(transcript).unwrap();
+        assert_eq!(entries[0], TranscriptEntry::In(json!({"id": 1})));
+        assert!(parse_transcript("{\"id\": 1}").is_err());
+
+        let options = CheckOptions {
+            color: false,
+            ..CheckOptions::default()
+        };
+        let expected = vec![json!({"id": 1, "result": null})];
+        let log = json!({"method": "window/logMessage", "params": {}});
+        let actual = vec![log.clone(), json!({"id": 1, "result": null})];
+        assert!(compare(&expected, &actual, &options).is_empty());
+
+        let reports = compare(&expected, &[log, json!({"id": 1, "result": 3})], &options);
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("~ result: null -> 3"), "{}", reports[0]);
+        assert_eq!(compare(&expected, &[], &options).len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod revalidation {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{DiagnosticRule, Settings};
+    use crate::debounce::Debouncer;
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_due_revalidations, handle_message};
+    use crate::rpc::json_from_string;
+    use crate::test::util::{context, sent};
+
+    fn did_change(version: i32, text: &str) -> String {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didChange\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\",\"version\":{}}},\"contentChanges\":[{{\"text\":{:?}}}]}}}}",
+            version, text
+        )
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new();
+        debouncer.schedule("a", 1, start + Duration::from_millis(10));
+        debouncer.schedule("b", 2, start + Duration::from_millis(5));
+        debouncer.schedule("a", 3, start + Duration::from_millis(20));
+        assert_eq!(
+            debouncer.next_deadline(),
+            Some(start + Duration::from_millis(5))
+        );
+        assert_eq!(
+            debouncer.take_due(start + Duration::from_millis(10)),
+            vec![("b", 2)]
+        );
+        assert_eq!(debouncer.cancel(&"a"), Some(3));
+        assert!(debouncer.is_empty());
+    }
+
+    #[test]
+    fn test_delay_per_rule() {
+        let value = json_from_string("{\"diagnostics\":{\"structureDelay\":50}}").unwrap();
+        let settings = Settings::from_value(&value).unwrap();
+        let diagnostics = settings.diagnostics;
+        assert_eq!(
+            diagnostics.delay(DiagnosticRule::Structure),
+            Duration::from_millis(50)
+        );
+        assert_eq!(
+            diagnostics.delay(DiagnosticRule::FileSize),
+            Settings::default()
+                .diagnostics
+                .delay(DiagnosticRule::FileSize)
+        );
+    }
+
+    #[test]
+    fn test_last_change_of_a_batch_decides() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let configuration = "{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeConfiguration\",\"params\":{\"settings\":{\"lsp-rs\":{\"maxFileSize\":8}}}}";
+        let batch = |version: i32, texts: [&str; 2]| {
+            format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didChange\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\",\"version\":{}}},\"contentChanges\":[{{\"text\":{:?}}},{{\"text\":{:?}}}]}}}}",
+                version, texts[0], texts[1]
+            )
+        };
+        // a change that isn't a tree, then one that is
+        let broken_then_fixed = batch(2, ["a\nbxc", "a\nb c"]);
+        // a change over maxFileSize, then one under it
+        let large_then_small = batch(3, ["a\nb c\nd e f", "a\nb d"]);
+        for message in [
+            configuration.to_string(),
+            broken_then_fixed,
+            large_then_small,
+        ] {
+            handle_message(message, &mut editor_state, &mut context, &mut logger).unwrap();
+            assert!(context.next_revalidation().is_none());
+        }
+        let fs_state = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs_state.get(2).unwrap(), "d");
+    }
+
+    #[test]
+    fn test_invalid_change_is_deferred() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+
+        handle_message(
+            did_change(2, "a\nb c"),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(context.next_revalidation().is_none());
+
+        handle_message(
+            did_change(3, "a\nbxc"),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let deadline = context.next_revalidation().unwrap();
+        assert!(deadline > Instant::now() + Duration::from_millis(100));
+
+        // the next keystroke fixes the tree, so nothing is reported
+        handle_message(
+            did_change(4, "a\nb d"),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(context.next_revalidation().is_none());
+
+        handle_message(
+            did_change(5, "a\nb d e"),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        handle_due_revalidations(&mut editor_state, &mut context, &mut logger, Instant::now());
+        assert!(context.next_revalidation().is_some());
+        handle_due_revalidations(
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+            Instant::now() + Duration::from_secs(1),
+        );
+        assert!(context.next_revalidation().is_none());
+        let published = sent(&writer)
+            .into_iter()
+            .rfind(|message| message["method"] == "textDocument/publishDiagnostics")
+            .unwrap();
+        assert_eq!(published["params"]["diagnostics"][0]["code"], "structure");
+        let fs_state = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs_state.get(2).unwrap(), "d");
+    }
+
+    #[test]
+    fn test_stale_version_is_ignored() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        for message in [
+            did_change(3, "a\nb c"),
+            did_change(2, "a"),
+            did_change(3, "b"),
+        ] {
+            handle_message(message, &mut editor_state, &mut context, &mut logger).unwrap();
+        }
+        let fs_state = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs_state.len(), 3);
+        assert_eq!(editor_state.version("file:///a.abc"), Some(3));
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("ignoring didChange of file:///a.abc with version 2"));
+
+        editor_state.set_open("file:///a.abc", false);
+        assert_eq!(editor_state.version("file:///a.abc"), None);
+        assert!(editor_state.advance_version("file:///a.abc", 1));
+    }
+}
+
+#[cfg(test)]
+mod commands {
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_message, ErrorCode, ServerContext, BALANCE_TREE_COMMAND, EXPORT_DOT_COMMAND,
+        NEW_EXAMPLE_COMMAND, PREVIEW_SUBTREE_COMMAND, RENAME_VALUE_COMMAND,
+    };
+    use crate::output::MemoryWriter;
+    use crate::test::util::{context, sent};
+
+    const URI: &str = "file:///a.abc";
+
+    /// The edit of the `workspace/applyEdit` request sent last
+    fn applied_edit(writer: &MemoryWriter) -> Value {
+        let request = sent(writer)
+            .into_iter()
+            .rfind(|message| message["method"] == "workspace/applyEdit")
+            .unwrap();
+        request["params"]["edit"]["changes"][URI][0].clone()
+    }
+
+    #[test]
+    fn test_register_command() {
+        let mut context = ServerContext::new();
+        let commands = context.commands_mut();
+        assert!(commands.register("lsp-rs.count", |_, _, _, _| Ok(json!(1))));
+        assert!(!commands.register(EXPORT_DOT_COMMAND, |_, _, _, _| Ok(Value::Null)));
+        assert_eq!(
+            commands.commands(),
+            vec![
+                BALANCE_TREE_COMMAND,
+                "lsp-rs.count",
+                EXPORT_DOT_COMMAND,
+                NEW_EXAMPLE_COMMAND,
+                PREVIEW_SUBTREE_COMMAND,
+                RENAME_VALUE_COMMAND
+            ]
+        );
+
+        let handler = context.commands_mut().get("lsp-rs.count").unwrap();
+        let result = handler(&[], &mut EditorState::new(), &mut context, &mut Vec::new());
+        assert_eq!(result.unwrap(), json!(1));
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let mut context = ServerContext::new();
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file(URI.to_string(), "a\nb \"".to_string())
+            .unwrap();
+        let handler = context.commands_mut().get(EXPORT_DOT_COMMAND).unwrap();
+        let result = handler(
+            &[json!(URI)],
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            result.unwrap(),
+            "digraph tree {\n    n0 [label=\"a\"];\n    n1 [label=\"b\"];\n    n0 -> n1;\n    n2 [label=\"\\\"\"];\n    n0 -> n2;\n}\n"
+        );
+
+        let error = handler(&[], &mut editor_state, &mut context, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidParams);
+    }
+
+    #[test]
+    fn test_balance_tree_applies_edit() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        editor_state
+            .modify_file(URI.to_string(), "a\nb c\nd".to_string())
+            .unwrap();
+        let handler = context.commands_mut().get(BALANCE_TREE_COMMAND).unwrap();
+
+        let error = handler(&[json!(URI)], &mut editor_state, &mut context, &mut logger);
+        assert_eq!(error.unwrap_err().code, ErrorCode::RequestFailed);
+        assert_eq!(context.pending_request_count(), 0);
+
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"workspace\":{\"applyEdit\":true}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let result = handler(&[json!(URI)], &mut editor_state, &mut context, &mut logger);
+        assert_eq!(result.unwrap(), Value::Null);
+        assert_eq!(context.pending_request_count(), 1);
+        let edit = applied_edit(&writer);
+        assert_eq!(edit["newText"], "c\nb d\na\n");
+        // the edit replaces the text up to its last character
+        assert_eq!(edit["range"]["end"], json!({"line": 2, "character": 1}));
+    }
+
+    #[test]
+    fn test_balance_tree_with_holes() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"workspace\":{\"applyEdit\":true}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        editor_state
+            .modify_file(URI.to_string(), "c\n_ a".to_string())
+            .unwrap();
+        let handler = context.commands_mut().get(BALANCE_TREE_COMMAND).unwrap();
+        let result = handler(&[json!(URI)], &mut editor_state, &mut context, &mut logger);
+        assert_eq!(result.unwrap(), Value::Null);
+        // the balanced tree has no holes left
+        assert_eq!(applied_edit(&writer)["newText"], "c\na\n");
+    }
+}
+
+#[cfg(test)]
+mod rename_value {
+    use serde_json::json;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, validate_label, ErrorCode, RENAME_VALUE_COMMAND};
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_validate_label() {
+        assert!(validate_label("x").is_ok());
+        assert!(validate_label(" ").is_err());
+        assert!(validate_label("xy").is_err());
+        assert!(validate_label("é").is_err());
+    }
+
+    #[test]
+    fn test_rename_open_documents() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        for (uri, text) in [
+            ("file:///a.abc", "a\nb a"),
+            ("file:///b.abc", "a"),
+            ("file:///c.abc", "a"),
+        ] {
+            editor_state
+                .modify_file(uri.to_string(), text.to_string())
+                .unwrap();
+        }
+        editor_state.set_open("file:///a.abc", true);
+        editor_state.set_open("file:///b.abc", true);
+        let index = editor_state.open_label_index();
+        assert_eq!(index["a"].len(), 2);
+        assert_eq!(index["b"].values().next().unwrap(), &vec![1]);
+
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"workspace\":{\"applyEdit\":true}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let handler = context.commands_mut().get(RENAME_VALUE_COMMAND).unwrap();
+        let error = handler(
+            &[json!("a"), json!("zz")],
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        );
+        assert_eq!(error.unwrap_err().code, ErrorCode::InvalidParams);
+        let unused = handler(
+            &[json!("q"), json!("z")],
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        );
+        assert_eq!(unused.unwrap(), json!(0));
+
+        sent(&writer);
+        let renamed = handler(
+            &[json!("a"), json!("z")],
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        );
+        assert_eq!(renamed.unwrap(), json!(3));
+        // only the open documents are edited
+        let request = &sent(&writer)[0];
+        assert_eq!(request["method"], "workspace/applyEdit");
+        let changes = request["params"]["edit"]["changes"].as_object().unwrap();
+        let mut uris: Vec<_> = changes.keys().collect();
+        uris.sort();
+        assert_eq!(uris, ["file:///a.abc", "file:///b.abc"]);
+    }
+}
+
+#[cfg(test)]
+mod new_example {
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_message, DocumentChange, ErrorCode, ResourceOperation, WorkspaceEdit,
+        NEW_EXAMPLE_COMMAND,
+    };
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_resource_operations() {
+        let edit: WorkspaceEdit = serde_json::from_value(json!({
+            "documentChanges": [
+                {"kind": "rename", "oldUri": "file:///a.abc", "newUri": "file:///b.abc"},
+                {"textDocument": {"uri": "file:///b.abc", "version": null}, "edits": []},
+                {"kind": "delete", "uri": "file:///c.abc", "options": {"recursive": true}}
+            ]
+        }))
+        .unwrap();
+        let changes = edit.document_changes.unwrap();
+        assert!(matches!(
+            &changes[0],
+            DocumentChange::Operation(ResourceOperation::Rename(rename)) if rename.new_uri == "file:///b.abc"
+        ));
+        assert!(matches!(&changes[1], DocumentChange::Edit(_)));
+        assert!(matches!(
+            &changes[2],
+            DocumentChange::Operation(ResourceOperation::Delete(_))
+        ));
+    }
+
+    #[test]
+    fn test_new_example() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let handler = context.commands_mut().get(NEW_EXAMPLE_COMMAND).unwrap();
+
+        let error = handler(&[], &mut editor_state, &mut context, &mut logger).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidParams);
+
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"rootUri\":\"file:///ws/\",\"capabilities\":{\"workspace\":{\"applyEdit\":true}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let error = handler(&[], &mut editor_state, &mut context, &mut logger).unwrap_err();
+        assert_eq!(error.code, ErrorCode::RequestFailed);
+
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"initialize\",\"params\":{\"processId\":1,\"rootUri\":\"file:///ws/\",\"capabilities\":{\"workspace\":{\"applyEdit\":true,\"workspaceEdit\":{\"resourceOperations\":[\"create\"]}}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        sent(&writer);
+        let result = handler(&[], &mut editor_state, &mut context, &mut logger);
+        assert_eq!(
+            result.unwrap(),
+            Value::String("file:///ws/example.abc".to_string())
+        );
+        assert_eq!(context.pending_request_count(), 1);
+        let request = &sent(&writer)[0];
+        assert_eq!(request["method"], "workspace/applyEdit");
+        let changes = &request["params"]["edit"]["documentChanges"];
+        assert_eq!(changes[0]["kind"], "create");
+        assert_eq!(changes[0]["uri"], "file:///ws/example.abc");
+    }
+}
+
+#[cfg(test)]
+mod embedder_handlers {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, RegisterHandlerError, ServerContext};
+    use crate::test::util::{initialized, response, sent};
+
+    #[test]
+    fn test_register_conflicts() {
+        let mut context = ServerContext::new();
+        assert_eq!(
+            context.register_request_handler("textDocument/hover", |_, _, _, _| Ok(Value::Null)),
+            Err(RegisterHandlerError::BuiltIn(
+                "textDocument/hover".to_string()
+            ))
+        );
+        assert!(context
+            .register_notification_handler("lsp-rs/ping", |_, _, _, _| ())
+            .is_ok());
+        assert_eq!(
+            context.register_request_handler("lsp-rs/ping", |_, _, _, _| Ok(Value::Null)),
+            Err(RegisterHandlerError::AlreadyRegistered(
+                "lsp-rs/ping".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_embedder_handlers_are_called() {
+        let (mut context, writer) = initialized();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        let received_clone = Rc::clone(&received);
+        context
+            .register_notification_handler("lsp-rs/ping", move |params, _, _, _| {
+                received_clone.borrow_mut().push(params)
+            })
+            .unwrap();
+        let received_clone = Rc::clone(&received);
+        context
+            .register_request_handler("textDocument/formatting", move |params, _, _, _| {
+                received_clone.borrow_mut().push(params);
+                Ok(json!([]))
+            })
+            .unwrap();
+
+        let ping = "{\"jsonrpc\":\"2.0\",\"method\":\"lsp-rs/ping\",\"params\":{\"n\":1}}";
+        handle_message(
+            ping.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let formatting =
+            "{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/formatting\",\"params\":{}}";
+        handle_message(
+            formatting.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(*received.borrow(), vec![json!({"n": 1}), json!({})]);
+        assert_eq!(response(&sent(&writer), 2)["result"], json!([]));
+
+        // formatting is registered with the client, the custom notification is not
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"textDocument\":{\"formatting\":{\"dynamicRegistration\":true}}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let initialized = "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}";
+        handle_message(
+            initialized.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(context.pending_request_count(), 1);
+        let request = sent(&writer)
+            .into_iter()
+            .find(|message| message["method"] == "client/registerCapability")
+            .unwrap();
+        let registrations = request["params"]["registrations"].as_array().unwrap();
+        assert_eq!(registrations.len(), 1);
+        assert_eq!(registrations[0]["method"], "textDocument/formatting");
+        assert_eq!(
+            context.register_notification_handler("lsp-rs/pong", |_, _, _, _| ()),
+            Err(RegisterHandlerError::Initialized)
+        );
+    }
+}
+
+#[cfg(test)]
+mod dynamic_registration {
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext, WATCHED_FILES_REGISTRATION};
+    use crate::test::util::{context, sent};
+
+    fn initialize(context: &mut ServerContext, editor_state: &mut EditorState, dynamic: bool) {
+        let messages = [
+            format!("{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{{\"processId\":1,\"capabilities\":{{\"workspace\":{{\"didChangeWatchedFiles\":{{\"dynamicRegistration\":{}}}}}}}}}}}", dynamic),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}".to_string(),
+        ];
+        for message in messages {
+            handle_message(message, editor_state, context, &mut Vec::new()).unwrap();
+        }
+    }
+
+    fn watch_files(enabled: bool) -> String {
+        format!("{{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeConfiguration\",\"params\":{{\"settings\":{{\"lsp-rs\":{{\"watchFiles\":{}}}}}}}}}", enabled)
+    }
+
+    #[test]
+    fn test_gated_on_client_flag() {
+        let (mut context, _) = context();
+        let mut editor_state = EditorState::new();
+        initialize(&mut context, &mut editor_state, false);
+        assert!(!context.supports_dynamic_registration("workspace/didChangeWatchedFiles"));
+        assert!(!context.is_registered(WATCHED_FILES_REGISTRATION));
+        assert_eq!(context.pending_request_count(), 0);
+    }
+
+    #[test]
+    fn test_toggle_file_watcher_at_runtime() {
+        let (mut context, writer) = context();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        initialize(&mut context, &mut editor_state, true);
+        assert!(context.is_registered(WATCHED_FILES_REGISTRATION));
+
+        handle_message(
+            watch_files(false),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(!context.is_registered(WATCHED_FILES_REGISTRATION));
+        let request = sent(&writer).pop().unwrap();
+        assert_eq!(request["method"], "client/unregisterCapability");
+        let unregistration = &request["params"]["unregisterations"][0];
+        assert_eq!(unregistration["id"], WATCHED_FILES_REGISTRATION);
+        assert!(!context.unregister_capability(WATCHED_FILES_REGISTRATION, &mut logger));
+
+        handle_message(
+            watch_files(true),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(context.is_registered(WATCHED_FILES_REGISTRATION));
+
+        // a failed registration is forgotten again
+        let id = context.next_request_id() - 1; // the registration was the last request sent
+        let error = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":-32603,\"message\":\"no\"}}}}",
+            id
+        );
+        handle_message(error, &mut editor_state, &mut context, &mut logger).unwrap();
+        assert!(!context.is_registered(WATCHED_FILES_REGISTRATION));
+    }
+}
+
+#[cfg(test)]
+mod trace {
+    use std::time::{Duration, Instant};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    #[cfg(feature = "tracing")]
+    use crate::test::util::capture;
+    use crate::test::util::{context, sent};
+    use crate::trace::{self, TraceId, TraceValue, Tracer};
+
+    #[test]
+    fn test_latency_is_attributed_to_frame() {
+        let mut tracer = Tracer::new();
+        assert_eq!(trace::tag(), "");
+
+        let received = Instant::now() - Duration::from_millis(20);
+        let span = tracer.start(received);
+        assert_eq!(span.id(), TraceId(1));
+        assert_eq!(trace::tag(), "(trace #1) ");
+        trace::record_write(Duration::from_millis(3));
+        let latency = tracer.finish(span);
+        assert!(latency.queue >= Duration::from_millis(20));
+        assert_eq!(latency.write, Duration::from_millis(3));
+        assert_eq!(trace::current(), None);
+
+        // writes outside a frame are not counted
+        trace::record_write(Duration::from_millis(5));
+        let span = tracer.start(Instant::now());
+        assert_eq!(span.id(), TraceId(2));
+        let latency = tracer.finish(span);
+        assert_eq!(latency.write, Duration::ZERO);
+
+        let stats = tracer.stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.max.write, Duration::from_millis(3));
+        assert!(stats.mean().queue >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_log_trace() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        // the params of the traces sent while handling the message
+        let mut send = |message: &str, context: &mut ServerContext| {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            sent(&writer)
+                .into_iter()
+                .filter(|message| message["method"] == "$/logTrace")
+                .map(|message| message["params"].clone())
+                .collect::<Vec<_>>()
+        };
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"trace\":\"messages\"}}";
+        send(initialize, &mut context);
+        assert_eq!(context.trace_value(), TraceValue::Messages);
+
+        let hover = "{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/hover\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"position\":{\"line\":0,\"character\":0}}}";
+        let traces = send(hover, &mut context);
+        assert_eq!(
+            traces[0]["message"],
+            "Received request 'textDocument/hover - (2)'."
+        );
+        assert!(traces.iter().all(|trace| trace.get("verbose").is_none()));
+
+        let set_trace =
+            "{\"jsonrpc\":\"2.0\",\"method\":\"$/setTrace\",\"params\":{\"value\":\"verbose\"}}";
+        send(set_trace, &mut context);
+        assert_eq!(context.trace_value(), TraceValue::Verbose);
+        let traces = send(hover, &mut context);
+        let verbose = traces[0]["verbose"].as_str().unwrap();
+        assert!(verbose.starts_with("Params: {"), "{}", verbose);
+
+        let set_trace =
+            "{\"jsonrpc\":\"2.0\",\"method\":\"$/setTrace\",\"params\":{\"value\":\"off\"}}";
+        send(set_trace, &mut context);
+        assert!(send(hover, &mut context).is_empty());
+    }
+
+    /// Names of the spans entered, and the methods recorded on them
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Spans {
+        names: std::sync::Mutex<Vec<&'static str>>,
+        entered: std::sync::Mutex<Vec<&'static str>>,
+        methods: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for &Spans {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "method" {
+                self.methods.lock().unwrap().push(format!("{:?}", value));
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Spans {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+            let mut names = self.names.lock().unwrap();
+            names.push(span.metadata().name());
+            tracing::span::Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, values: &tracing::span::Record) {
+            values.record(&mut &*self);
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            let name = self.names.lock().unwrap()[span.into_u64() as usize - 1];
+            self.entered.lock().unwrap().push(name);
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        let spans = std::sync::Arc::new(Spans::default());
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        tracing::subscriber::with_default(spans.clone(), || {
+            capture(&output, || {
+                let mut tracer = Tracer::new();
+                let span = tracer.start(Instant::now());
+                let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+                handle_message(
+                    shutdown.to_string(),
+                    &mut editor_state,
+                    &mut context,
+                    &mut Vec::new(),
+                )
+                .unwrap();
+                tracer.finish(span);
+            });
+        });
+        assert_eq!(
+            *spans.entered.lock().unwrap(),
+            ["frame", "handler", "write"]
+        );
+        assert_eq!(*spans.methods.lock().unwrap(), ["\"shutdown\""]);
+    }
+}
+
+#[cfg(test)]
+mod goto {
+    use crate::editor::{index_to_position, FileState};
+    use crate::lsp::{
+        first_occurrence, leaf_descendants, node_at, node_path, node_range, Location,
+        LocationResponse, Moniker, MonikerResponse, Position, UniquenessLevel, MONIKER_SCHEME,
+    };
+    use crate::rpc::json_to_string;
+
+    fn position(line: i32, character: i32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn test_node_positions() {
+        let fs = FileState::new("a\nb c\nd".to_string()).unwrap();
+        assert_eq!(node_at(&fs, &position(0, 0)), Some(0));
+        assert_eq!(node_at(&fs, &position(1, 2)), Some(2));
+        assert_eq!(node_at(&fs, &position(1, 1)), None);
+        assert_eq!(node_at(&fs, &position(2, 2)), None);
+        assert_eq!(node_at(&fs, &position(0, 2)), None);
+        assert_eq!(node_at(&fs, &position(64, 0)), None);
+        assert_eq!(node_at(&fs, &position(i32::MAX, 0)), None);
+        for index in 0..fs.len() {
+            assert_eq!(node_at(&fs, &node_range(index).start), Some(index));
+        }
+        assert_eq!(node_range(5).start, position(2, 4));
+    }
+
+    #[test]
+    fn test_leaf_descendants() {
+        let fs = FileState::new("a\nb c\nd e f".to_string()).unwrap();
+        assert_eq!(leaf_descendants(&fs, 0), vec![3, 4, 5]);
+        assert_eq!(leaf_descendants(&fs, 2), vec![5]);
+        assert_eq!(leaf_descendants(&fs, 4), vec![4]);
+        assert!(leaf_descendants(&fs, 9).is_empty());
+    }
+
+    #[test]
+    fn test_first_occurrence() {
+        let fs = FileState::new("a\nb a\nb c a".to_string()).unwrap();
+        assert_eq!(first_occurrence(&fs, 5), Some(0));
+        assert_eq!(first_occurrence(&fs, 3), Some(1));
+        assert_eq!(first_occurrence(&fs, 4), Some(4));
+        assert_eq!(first_occurrence(&fs, 6), None);
+    }
+
+    #[test]
+    fn test_index_to_position() {
+        assert_eq!(index_to_position(0), (0, 0));
+        assert_eq!(index_to_position(2), (1, 2));
+        assert_eq!(index_to_position(9), (3, 4));
+        let fs = FileState::new("a\nb c\nd".to_string()).unwrap();
+        assert_eq!(fs.position_of(3), Some((2, 0)));
+        assert_eq!(fs.position_of(4), None);
+    }
+
+    #[test]
+    fn test_node_path() {
+        assert_eq!(node_path(0), "");
+        assert_eq!(node_path(1), "L");
+        assert_eq!(node_path(2), "R");
+        assert_eq!(node_path(4), "LR");
+        assert_eq!(node_path(11), "RLL");
+        let moniker = Moniker {
+            scheme: MONIKER_SCHEME.to_string(),
+            identifier: node_path(5),
+            unique: UniquenessLevel::Document,
+            kind: None,
+        };
+        assert_eq!(
+            json_to_string(&MonikerResponse::new(3.into(), Some(vec![moniker]))),
+            "{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":[{\"scheme\":\"lsp-rs\",\"identifier\":\"RL\",\"unique\":\"document\"}]}"
+        );
+    }
+
+    #[test]
+    fn test_location_response() {
+        assert_eq!(
+            json_to_string(&LocationResponse::new(1.into(), None)),
+            "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}"
+        );
+        let location = Location {
+            uri: "file:///a.abc".to_string(),
+            range: node_range(0),
+        };
+        assert_eq!(
+            json_to_string(&LocationResponse::new(2.into(), Some(location))),
+            "{\"jsonrpc\":\"2.0\",\"id\":2,\"result\":{\"uri\":\"file:///a.abc\",\"range\":{\"start\":{\"line\":0,\"character\":0},\"end\":{\"line\":0,\"character\":1}}}}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod structured_logs {
+    use std::io::Write;
+
+    use crate::config::LogLevel;
+    use crate::logs::{excerpt, LogFilter, LogFormat, LogRecord};
+    use crate::lsp::{ClientLogger, MessageType};
+
+    #[test]
+    fn test_excerpt() {
+        assert_eq!(excerpt("{\"a\":[1,2]}", 5), "{\"a\":…");
+        assert_eq!(excerpt("ééé", 2), "éé…");
+        assert_eq!(excerpt("abc", 3), "abc");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut logger = ClientLogger::new(Vec::new(), MessageType::Error);
+        logger.set_format(LogFormat::Json);
+        writeln!(logger, "[Content] {{\"unmatched\": [").unwrap();
+        write!(logger, "no tag").unwrap();
+        writeln!(logger).unwrap();
+        logger.flush().unwrap();
+
+        let text = String::from_utf8(logger.into_inner()).unwrap();
+        let records: Vec<LogRecord> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].event, "Content");
+        assert_eq!(records[0].level, LogLevel::Log);
+        assert_eq!(records[0].payload_excerpt, "{\"unmatched\": [");
+        assert_eq!(records[1].event, "log");
+
+        let filter = LogFilter {
+            level: LogLevel::Info,
+            method: None,
+        };
+        assert!(!filter.matches(&records[0]));
+        assert!(filter.matches(&records[1]));
+    }
+}
+
+#[cfg(test)]
+mod preview {
+    use serde_json::{json, Value};
+
+    use crate::editor::{EditorState, FileState};
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::preview::subtree;
+    use crate::test::util::capture;
+
+    #[test]
+    fn test_subtree_depth_limit() {
+        let fs = FileState::new("a\nb c\nd e f g\nh".to_string()).unwrap();
+        assert_eq!(
+            subtree(&fs, 0, 2),
+            "a\n  b\n    … 2 more levels\n  c\n    … 1 more level"
+        );
+        assert_eq!(subtree(&fs, 1, 10), "b\n  d\n    h\n  e");
+        assert_eq!(subtree(&fs, 6, 1), "g");
+        assert_eq!(subtree(&fs, 0, 0), "a\n  … 3 more levels");
+    }
+
+    #[test]
+    fn test_inlay_hint_tooltips_are_limited() {
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c\nd e f g".to_string())
+            .unwrap();
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{},"initializationOptions":{"previewDepth":2}}}"#;
+        let hints = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/inlayHint","params":{"textDocument":{"uri":"file:///a.abc"},"range":{"start":{"line":0,"character":0},"end":{"line":1,"character":2}}}}"#;
+        let sent = capture(&output, || {
+            for message in [initialize, hints] {
+                let message = message.to_string();
+                handle_message(message, &mut editor_state, &mut context, &mut Vec::new()).unwrap();
+            }
+        });
+        let response: Value = serde_json::from_str(&sent[1]).unwrap();
+        // leaves have no hint, and c is past the range
+        assert_eq!(
+            response["result"],
+            json!([
+                {
+                    "position": {"line": 0, "character": 1},
+                    "label": "(7)",
+                    "tooltip": {"kind": "plaintext", "value": "a\n  b\n    … 1 more level\n  c\n    … 1 more level"},
+                    "paddingLeft": true
+                },
+                {
+                    "position": {"line": 1, "character": 1},
+                    "label": "(3)",
+                    "tooltip": {"kind": "plaintext", "value": "b\n  d\n  e"},
+                    "paddingLeft": true
+                }
+            ])
+        );
+    }
+}
+
+#[cfg(test)]
+mod tree_errors {
+    use serde_json::json;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_message, ErrorCode, Position, ResponseError, ServerContext, TreeError,
+        PREVIEW_SUBTREE_COMMAND,
+    };
+    use crate::test::util::{initialized, response, sent};
+
+    const URI: &str = "file:///a.abc";
+
+    fn hover(uri: &str) -> String {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":7,\"method\":\"textDocument/hover\",\"params\":{{\"textDocument\":{{\"uri\":\"{}\"}},\"position\":{{\"line\":0,\"character\":0}}}}}}",
+            uri
+        )
+    }
+
+    #[test]
+    fn test_error_data() {
+        let error: ResponseError = TreeError::DepthLimitExceeded {
+            uri: URI.to_string(),
+            depth: 5,
+            limit: 3,
+        }
+        .into();
+        assert_eq!(error.code, ErrorCode::DepthLimitExceeded);
+        assert!(error.code.is_tree_error());
+        assert!(!ErrorCode::InvalidParams.is_tree_error());
+        assert!(!ErrorCode::FeatureDisabled.is_tree_error());
+        assert_eq!(
+            error.data,
+            Some(json!({"kind": "depthLimitExceeded", "uri": URI, "depth": 5, "limit": 3}))
+        );
+
+        // codes are sent as their number, the ones the server doesn't define are kept
+        assert_eq!(json!(error.code), json!(-33003));
+        let error: ResponseError =
+            serde_json::from_value(json!({"code": -32601, "message": "no"})).unwrap();
+        assert_eq!(error.code, ErrorCode::MethodNotFound);
+        let error: ResponseError =
+            serde_json::from_value(json!({"code": 7, "message": "client"})).unwrap();
+        assert_eq!(error.code, ErrorCode::Other(7));
+        assert_eq!(json!(error.code), json!(7));
+    }
+
+    #[test]
+    fn test_not_found_and_not_a_tree() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        let mut logger = Vec::new();
+        handle_message(hover(URI), &mut editor_state, &mut context, &mut logger).unwrap();
+        assert_eq!(response(&sent(&writer), 7)["error"]["code"], -33000);
+
+        let open = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\",\"languageId\":\"abc\",\"version\":0,\"text\":\"a\\nbxc\"}}}";
+        let mut logger = Vec::new();
+        handle_message(
+            open.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        handle_message(hover(URI), &mut editor_state, &mut context, &mut logger).unwrap();
+        let error = &response(&sent(&writer), 7)["error"];
+        assert_eq!(error["code"], -33001);
+        let message = error["message"].as_str().unwrap();
+        assert!(
+            message.ends_with("line 1: nodes must be separated by a single space"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_preview_errors() {
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        editor_state
+            .modify_file(URI.to_string(), "a\nb c\nd".to_string())
+            .unwrap();
+        let handler = context.commands_mut().get(PREVIEW_SUBTREE_COMMAND).unwrap();
+
+        let position = Position {
+            line: 1,
+            character: 1,
+        };
+        let error = handler(
+            &[json!(URI), json!(position)],
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::PositionNotANode);
+
+        let error = handler(
+            &[json!(URI), json!(null), json!(9)],
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(error.code, ErrorCode::DepthLimitExceeded);
+        assert_eq!(error.data.unwrap()["limit"], json!(3));
+
+        let preview = handler(
+            &[json!(URI), json!({"line": 1, "character": 0}), json!(2)],
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        );
+        assert_eq!(preview.unwrap(), json!("b\n  d"));
+    }
+}
+
+#[cfg(test)]
+mod call_hierarchy {
+    use crate::editor::FileState;
+    use crate::lsp::{call_hierarchy_item, call_hierarchy_node, node_range, Position};
+
+    const URI: &str = "file:///a.abc";
+
+    #[test]
+    fn test_item_identity() {
+        let fs = FileState::new("a\nb c\nd e".to_string()).unwrap();
+        let item = call_hierarchy_item(URI, &fs, 4);
+        assert_eq!(item.name, "e");
+        assert_eq!(item.range, node_range(4));
+        assert_eq!(call_hierarchy_node(&fs, &item), Some(4));
+
+        // the stored index is used while its label is unchanged, then the position
+        let moved = FileState::new("a\nb c\ne d".to_string()).unwrap();
+        assert_eq!(call_hierarchy_node(&moved, &item), None);
+        let mut without_data = item.clone();
+        without_data.data = None;
+        assert_eq!(call_hierarchy_node(&fs, &without_data), Some(4));
+        without_data.selection_range.start = Position {
+            line: 2,
+            character: 0,
+        };
+        assert_eq!(call_hierarchy_node(&fs, &without_data), None);
+    }
+}
+
+#[cfg(test)]
+mod protocol_types {
+    use std::collections::BTreeSet;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, Position, Range};
+    use crate::test::util::context;
+    use crate::uri::Uri;
+
+    #[test]
+    fn test_client_capabilities_are_kept() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"textDocument\":{\"hover\":{\"contentFormat\":[\"markdown\",\"plaintext\"]},\"documentSymbol\":{\"dynamicRegistration\":true},\"completion\":{\"dynamicRegistration\":true,\"completionItem\":{\"snippetSupport\":true}}},\"workspace\":{\"workspaceFolders\":true},\"general\":{\"markdown\":{\"parser\":\"marked\"}}}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let capabilities = context.client_capabilities();
+        let text_document = capabilities.text_document.as_ref().unwrap();
+        assert_eq!(
+            text_document.hover.as_ref().unwrap().content_format,
+            Some(vec!["markdown".to_string(), "plaintext".to_string()])
+        );
+        let completion_item = text_document
+            .completion
+            .as_ref()
+            .unwrap()
+            .completion_item
+            .as_ref();
+        assert_eq!(completion_item.unwrap().snippet_support, Some(true));
+        assert!(text_document.other.contains_key("documentSymbol"));
+        assert_eq!(
+            text_document.dynamic_registration(),
+            vec!["completion".to_string(), "documentSymbol".to_string()]
+        );
+        let workspace = capabilities.workspace.as_ref().unwrap();
+        assert_eq!(workspace.workspace_folders, Some(true));
+        let general = capabilities.general.as_ref().unwrap();
+        assert_eq!(general.markdown.as_ref().unwrap().parser, "marked");
+    }
+
+    #[test]
+    fn test_position_and_range_text() {
+        let position: Position = "3:14".parse().unwrap();
+        assert_eq!(
+            position,
+            Position {
+                line: 3,
+                character: 14
+            }
+        );
+        assert_eq!(position.to_string(), "3:14");
+        assert!("3".parse::<Position>().is_err());
+        assert!("a:1".parse::<Position>().is_err());
+
+        let range: Range = "1:0-2:5".parse().unwrap();
+        assert_eq!(range.to_string(), "1:0-2:5");
+        assert_eq!(range.end.character, 5);
+        assert!("1:0".parse::<Range>().is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let positions: BTreeSet<Position> = ["2:0", "0:9", "0:1"]
+            .iter()
+            .map(|p| p.parse().unwrap())
+            .collect();
+        let sorted: Vec<String> = positions.iter().map(|p| p.to_string()).collect();
+        assert_eq!(sorted, vec!["0:1", "0:9", "2:0"]);
+        assert!("0:0-1:0".parse::<Range>().unwrap() < "0:0-1:1".parse::<Range>().unwrap());
+        assert!("0:1-0:2".parse::<Range>().unwrap() > "0:0-9:9".parse::<Range>().unwrap());
+
+        let uri: Uri = "FILE:///a%20b.abc".parse().unwrap();
+        assert_eq!(uri.to_string(), "file:///a b.abc");
+    }
+}
+
+#[cfg(test)]
+mod log_fallback {
+    use std::env;
+    use std::fs;
+
+    use crate::editor::EditorState;
+    use crate::logs::{open_log, LogTarget};
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_fallback_chain() {
+        let dir = env::temp_dir().join(format!("lsp-rs-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(open_log(None, &dir).target, LogTarget::Disabled);
+
+        let requested = dir.join("server.log");
+        let log = open_log(requested.to_str(), &dir);
+        assert_eq!(log.target, LogTarget::File(requested));
+        assert!(log.warning.is_none());
+
+        let missing = dir.join("missing").join("server.log");
+        let log = open_log(missing.to_str(), &dir);
+        assert_eq!(log.target, LogTarget::File(dir.join("server.log")));
+        assert!(log.warning.unwrap().contains("Could not create log file"));
+
+        let log = open_log(missing.to_str(), &dir.join("missing"));
+        assert_eq!(log.target, LogTarget::Stderr);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_warning_after_initialized() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        context.warn_once_initialized("log is elsewhere".to_string());
+        let initialized = "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}";
+        let mut logger = Vec::new();
+        handle_message(
+            initialized.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let shown = sent(&writer)
+            .into_iter()
+            .find(|message| message["method"] == "window/showMessage")
+            .unwrap();
+        assert_eq!(shown["params"]["message"], "log is elsewhere");
+    }
+}
+
+#[cfg(test)]
+mod linked_editing {
+    use serde_json::{json, Value};
+
+    use crate::editor::{EditorState, FileState};
+    use crate::lsp::handle_message;
+    use crate::test::util::{initialized, response, sent};
+
+    #[test]
+    fn test_duplicate_labels() {
+        let fs = FileState::new("a\nb a\nb c a".to_string()).unwrap();
+        assert_eq!(fs.occurrences(5), vec![0, 2, 5]);
+        assert_eq!(fs.occurrences(4), vec![4]);
+        assert!(fs.occurrences(9).is_empty());
+        let duplicates = fs.duplicate_labels();
+        assert_eq!(
+            duplicates.keys().copied().collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(duplicates["b"], vec![1, 3]);
+    }
+
+    #[test]
+    fn test_linked_editing_range() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb a".to_string())
+            .unwrap();
+        let request = |id: i64, character: i32| {
+            format!(
+                "{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"textDocument/linkedEditingRange\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\"}},\"position\":{{\"line\":1,\"character\":{}}}}}}}",
+                id, character
+            )
+        };
+        let mut logger = Vec::new();
+        handle_message(request(1, 2), &mut editor_state, &mut context, &mut logger).unwrap();
+        handle_message(request(2, 0), &mut editor_state, &mut context, &mut logger).unwrap();
+        let sent = sent(&writer);
+        let ranges = &response(&sent, 1)["result"]["ranges"];
+        assert_eq!(ranges[0]["start"], json!({"line": 0, "character": 0}));
+        assert_eq!(response(&sent, 2)["result"], Value::Null);
+    }
+}
+
+#[cfg(test)]
+mod shedding {
+    use std::io;
+    use std::time::Duration;
+
+    use crate::lsp::ServerContext;
+    use crate::output::{MessageWriter, Output};
+    use crate::shedding::{ClientHealth, HealthChange, OptionalTraffic, SheddingPolicy};
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_trip_and_recover() {
+        let policy = SheddingPolicy {
+            slow_write: Duration::from_millis(10),
+            max_queued: 2,
+            trip_after: 2,
+            recover_after: 3,
+        };
+        let slow = Duration::from_millis(20);
+        let fast = Duration::ZERO;
+        let mut health = ClientHealth::new(policy);
+        assert_eq!(health.record(slow, 0), None);
+        assert_eq!(health.record(fast, 0), None);
+        assert_eq!(health.record(slow, 0), None);
+        assert_eq!(
+            health.record(fast, 3),
+            Some(HealthChange::Slowed { first: true })
+        );
+        assert!(!health.admit(OptionalTraffic::Telemetry));
+        assert_eq!(health.record(fast, 0), None);
+        assert_eq!(health.record(fast, 0), None);
+        assert_eq!(health.record(fast, 0), Some(HealthChange::Recovered));
+        assert!(health.admit(OptionalTraffic::Refresh));
+
+        health.record(slow, 0);
+        assert_eq!(
+            health.record(slow, 0),
+            Some(HealthChange::Slowed { first: false })
+        );
+        let stats = health.stats();
+        assert_eq!((stats.slow_frames, stats.periods, stats.shed), (5, 2, 1));
+    }
+
+    #[test]
+    fn test_single_warning() {
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let slow = Duration::from_secs(1);
+        for _ in 0..5 {
+            context.record_frame(slow, &mut logger);
+        }
+        assert!(context.client_health().is_shedding());
+        for _ in 0..20 {
+            context.record_frame(Duration::ZERO, &mut logger);
+        }
+        for _ in 0..5 {
+            context.record_frame(slow, &mut logger);
+        }
+        let shown: Vec<_> = sent(&writer)
+            .into_iter()
+            .filter(|message| message["method"] == "window/showMessage")
+            .collect();
+        assert_eq!(shown.len(), 1);
+        let logged = String::from_utf8(logger).unwrap();
+        assert_eq!(logged.matches("[Warning] The client is slow").count(), 2);
+        assert!(logged.contains("[Info] The client caught up"));
+    }
+
+    /// Holds back the messages written, as a client that doesn't read would
+    struct Backlog(usize);
+
+    impl MessageWriter for Backlog {
+        fn write_message(&mut self, _: &[u8]) -> io::Result<()> {
+            self.0 += 1;
+            Ok(())
+        }
+
+        fn queued(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_slow_when_messages_queue_up() {
+        let mut context = ServerContext::new();
+        context.set_output(Output::new(Box::new(Backlog(40))));
+        let mut logger = Vec::new();
+        for _ in 0..5 {
+            context.record_frame(Duration::ZERO, &mut logger);
+        }
+        assert!(context.client_health().is_shedding());
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("40 messages queued"), "{}", logged);
+    }
+}
+
+#[cfg(test)]
+mod lazy_subsystems {
+    use std::time::Duration;
+
+    use crate::editor::EditorState;
+    use crate::lazy::Lazy;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, response, sent};
+
+    #[test]
+    fn test_lazy() {
+        let mut lazy = Lazy::new("numbers");
+        assert!(!lazy.is_initialized());
+        assert_eq!(*lazy.get_or_init(|| 1), 1);
+        assert_eq!(*lazy.get_or_init(|| 2), 1);
+        assert!(lazy.init_time().is_some());
+        assert_eq!(lazy.reset(), Some(1));
+        assert_eq!(lazy.get(), None);
+        lazy.set(3, Duration::from_millis(5));
+        assert_eq!(lazy.get(), Some(&3));
+        assert_eq!(lazy.init_time(), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_built_on_first_use() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{}}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c".to_string())
+            .unwrap();
+        let hover = "{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/hover\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"position\":{\"line\":1,\"character\":0}}}";
+        handle_message(
+            hover.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(context.lazy_init_times().is_empty());
+
+        let completion = "{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"textDocument/completion\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"position\":{\"line\":1,\"character\":0}}}";
+        handle_message(
+            completion.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let built: Vec<&str> = context
+            .lazy_init_times()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(built, vec!["completion", "workspace"]);
+        let initialized = response(&sent(&writer), 1);
+        assert!(initialized["result"]["serverInfo"]["startupTimeMs"].is_f64());
+    }
+
+    #[test]
+    fn test_scanned_after_initialized() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{}}}";
+        let initialized = "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}";
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(context.lazy_init_times().is_empty());
+        for _ in 0..2 {
+            handle_message(
+                initialized.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        }
+        let built: Vec<&str> = context
+            .lazy_init_times()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(built, vec!["workspace"]);
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("ignoring a repeated initialized"));
+    }
+}
+
+#[cfg(test)]
+mod subtree_metrics {
+    use crate::editor::FileState;
+
+    #[test]
+    fn test_subtree_size() {
+        let fs = FileState::new("a\nb c\nd e f g\nh i j".to_string()).unwrap();
+        assert_eq!(fs.subtree_size(0), 10);
+        assert_eq!(fs.subtree_size(1), 6);
+        assert_eq!(fs.subtree_size(2), 3);
+        assert_eq!(fs.subtree_size(4), 2);
+        assert_eq!(fs.subtree_size(9), 1);
+        assert_eq!(fs.subtree_size(10), 0);
+    }
+
+    #[test]
+    fn test_depth_height_and_leaves() {
+        let fs = FileState::new("a\nb _\nd e _ _\nh".to_string()).unwrap();
+        assert_eq!(fs.depth(0), Some(0));
+        assert_eq!(fs.depth(4), Some(2));
+        assert_eq!(fs.depth(7), Some(3));
+        assert_eq!(fs.depth(2), None);
+        assert_eq!(fs.height(0), 4);
+        assert_eq!(fs.height(4), 1);
+        assert_eq!(fs.height(2), 0);
+        assert_eq!(fs.subtree_size(0), 5);
+        assert!(fs.is_leaf(4) && fs.is_leaf(7));
+        assert!(!fs.is_leaf(3) && !fs.is_leaf(2) && !fs.is_leaf(100));
+    }
+}
+
+#[cfg(test)]
+mod inline_value {
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{initialized, response, sent};
+
+    #[test]
+    fn test_values_in_range() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c\nd".to_string())
+            .unwrap();
+        let request = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"textDocument/inlineValue\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\"},\"range\":{\"start\":{\"line\":1,\"character\":0},\"end\":{\"line\":1,\"character\":3}},\"context\":{\"frameId\":1,\"stoppedLocation\":{\"start\":{\"line\":0,\"character\":0},\"end\":{\"line\":0,\"character\":0}}}}}";
+        let mut logger = Vec::new();
+        handle_message(
+            request.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let values: Vec<_> = response(&sent(&writer), 1)["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value["text"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            values,
+            ["index 1, subtree size 2", "index 2, subtree size 1"]
+        );
+    }
+}
+
+#[cfg(test)]
+mod cli {
+    use crate::cli::{parse, validate, Command, ServeOptions, Transport, VersionInfo};
+    use crate::logs::LogFormat;
+    use crate::rpc::ReaderLimits;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]), Ok(Command::Serve(ServeOptions::default())));
+        assert_eq!(
+            parse(&args(&["--log-format", "json", "server.log"])),
+            Ok(Command::Serve(ServeOptions {
+                log_file: Some("server.log".to_string()),
+                log_format: LogFormat::Json,
+                session_file: None,
+                transport: Transport::Stdio,
+                reader_limits: ReaderLimits::default(),
+                record_file: None,
+            }))
+        );
+        assert_eq!(
+            parse(&args(&["--session", "session.json"])),
+            Ok(Command::Serve(ServeOptions {
+                session_file: Some("session.json".to_string()),
+                ..ServeOptions::default()
+            }))
+        );
+        assert!(parse(&args(&["--session"])).is_err());
+        assert_eq!(
+            parse(&args(&["--record", "frames.jsonl"])),
+            Ok(Command::Serve(ServeOptions {
+                record_file: Some("frames.jsonl".to_string()),
+                ..ServeOptions::default()
+            }))
+        );
+        for (flags, address) in [
+            (&["--port", "9257"][..], "127.0.0.1:9257"),
+            (&["--listen", "9257"][..], "127.0.0.1:9257"),
+            (&["--listen", "0.0.0.0:9257"][..], "0.0.0.0:9257"),
+        ] {
+            assert_eq!(
+                parse(&args(flags)),
+                Ok(Command::Serve(ServeOptions {
+                    transport: Transport::Tcp(address.to_string()),
+                    ..ServeOptions::default()
+                }))
+            );
+        }
+        assert!(parse(&args(&["--port", "http"])).is_err());
+        assert_eq!(
+            parse(&args(&[
+                "--max-message-size",
+                "1024",
+                "--max-buffer-size",
+                "64"
+            ])),
+            Ok(Command::Serve(ServeOptions {
+                reader_limits: ReaderLimits {
+                    max_content_length: 1024,
+                    max_buffered: 64,
+                },
+                ..ServeOptions::default()
+            }))
+        );
+        assert!(parse(&args(&["--max-message-size", "1MB"])).is_err());
+        assert_eq!(
+            parse(&args(&["--websocket", "9257"])).is_ok(),
+            cfg!(feature = "websocket")
+        );
+        for flags in [
+            &["--pipe", "/tmp/lsp.sock"][..],
+            &["--pipe=/tmp/lsp.sock"][..],
+        ] {
+            assert_eq!(
+                parse(&args(flags)),
+                Ok(Command::Serve(ServeOptions {
+                    transport: Transport::Pipe("/tmp/lsp.sock".to_string()),
+                    ..ServeOptions::default()
+                }))
+            );
+        }
+        assert_eq!(parse(&args(&["repl", "--help"])), Ok(Command::Help));
+        assert_eq!(
+            parse(&args(&["--version", "--json"])),
+            Ok(Command::Version { json: true })
+        );
+        assert_eq!(
+            parse(&args(&["check", "a.json", "--no-color"])),
+            Ok(Command::Check(args(&["a.json", "--no-color"])))
+        );
+        assert!(parse(&args(&["--verbose"]))
+            .unwrap_err()
+            .contains("unknown option"));
+        assert!(parse(&args(&["a.log", "b.log"])).is_err());
+        assert!(parse(&args(&["--json"])).is_err());
+        assert!(parse(&args(&["validate"])).is_err());
+    }
+
+    #[test]
+    fn test_version_json() {
+        let info = VersionInfo::current();
+        let value = serde_json::to_value(&info).unwrap();
+        assert_eq!(value["name"], "server");
+        let mut transports = serde_json::json!(["stdio", "tcp", "pipe"]);
+        if cfg!(feature = "websocket") {
+            transports.as_array_mut().unwrap().push("websocket".into());
+        }
+        assert_eq!(value["transports"], transports);
+        assert!(value.get("gitHash").is_some());
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = std::env::temp_dir().join(format!("lsp-rs-cli-{}.abc", std::process::id()));
+        std::fs::write(&path, "a\nb c\nd").unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let mut out = Vec::new();
+        let missing = format!("{}.missing", path);
+        assert_eq!(validate(&[path.clone(), missing], &mut out), 2);
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with(&format!("{}: ok, 3 levels, 4 nodes", path)),
+            "{}",
+            out
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod position_encoding {
+    use crate::editor::{EditorState, FileState, HOLE};
+    use crate::encoding::{
+        byte_to_column, column_to_byte, offset_at, offset_to_position, position_at,
+        position_to_offset, PositionEncoding,
+    };
+    use crate::line_index::LineIndex;
+    use crate::lsp::{node_range, Position};
+    use crate::rope::Rope;
+    use crate::test::util::{random_text, XorShift};
+
+    #[test]
+    fn test_negotiate() {
+        let encodings = |e: &[&str]| e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            PositionEncoding::negotiate(&encodings(&["utf-32", "utf-8"])),
+            PositionEncoding::Utf32
+        );
+        assert_eq!(
+            PositionEncoding::negotiate(&encodings(&["ucs-2", "utf-8"])),
+            PositionEncoding::Utf8
+        );
+        assert_eq!(PositionEncoding::negotiate(&[]), PositionEncoding::Utf16);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for seed in 1..500 {
+            let mut rng = XorShift(seed);
+            let text = random_text(&mut rng, 40);
+            for encoding in PositionEncoding::ALL {
+                for offset in 0..=text.len() {
+                    let valid = text.is_char_boundary(offset)
+                        && !(text[..offset].ends_with('\r') && text[offset..].starts_with('\n'));
+                    let position = position_at(&text, offset, encoding);
+                    assert_eq!(position.is_some(), valid, "{:?} at {}", text, offset);
+                    if let Some(position) = position {
+                        assert_eq!(
+                            offset_at(&text, position, encoding),
+                            Some(offset),
+                            "{:?} at {} ({:?})",
+                            text,
+                            position,
+                            encoding
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_index() {
+        for seed in 1..200 {
+            let mut rng = XorShift(seed);
+            let text = random_text(&mut rng, 40);
+            let lines = LineIndex::new(&Rope::from(text.as_str()));
+            assert_eq!(lines.line_count(), text.split('\n').count());
+            for encoding in PositionEncoding::ALL {
+                for offset in 0..=text.len() + 1 {
+                    let position = lines.position(offset, encoding);
+                    assert_eq!(position, position_at(&text, offset, encoding), "{:?}", text);
+                }
+                for line in 0..=lines.line_count() as i32 {
+                    for character in 0..=text.len() as i32 + 1 {
+                        let position = Position { line, character };
+                        assert_eq!(
+                            lines.offset(position, encoding),
+                            offset_at(&text, position, encoding),
+                            "{:?} at {:?} ({:?})",
+                            text,
+                            position,
+                            encoding
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_index_update() {
+        for seed in 1..300 {
+            let mut rng = XorShift(seed);
+            let mut text = Rope::from(random_text(&mut rng, 40).as_str());
+            let mut lines = LineIndex::new(&text);
+            for _ in 0..10 {
+                let mut bound = || loop {
+                    let offset = rng.next(text.len() + 1);
+                    if text.is_char_boundary(offset) {
+                        break offset;
+                    }
+                };
+                let (a, b) = (bound(), bound());
+                let removed = a.min(b)..a.max(b);
+                let inserted = random_text(&mut rng, 40);
+                let before = text.to_string();
+                text.replace(removed.clone(), &inserted);
+                lines.update(&text, removed.clone(), inserted.len());
+                assert_eq!(
+                    lines,
+                    LineIndex::new(&text),
+                    "{:?} with {:?} replaced by {:?}",
+                    before,
+                    removed,
+                    inserted
+                );
+            }
+        }
+
+        let mut editor_state = EditorState::new();
+        editor_state.set_text("a.abc", "a\r\nb\nc".to_string());
+        assert!(editor_state.edit_text("a.abc", 2..3, "é\n"));
+        let lines = editor_state.line_index("a.abc").unwrap();
+        // the `\r` is no longer part of a line ending
+        assert_eq!(lines.line_count(), 3);
+        let position = Position {
+            line: 0,
+            character: 3,
+        };
+        assert_eq!(lines.offset(position, PositionEncoding::Utf16), Some(4));
+        editor_state.remove_file("a.abc");
+        assert!(editor_state.line_index("a.abc").is_none());
+    }
+
+    #[test]
+    fn test_rope_positions() {
+        for seed in 1..200 {
+            let mut rng = XorShift(seed);
+            let text = random_text(&mut rng, 40);
+            let rope = Rope::from(text.as_str());
+            for encoding in PositionEncoding::ALL {
+                for offset in 0..=text.len() + 1 {
+                    let position = offset_to_position(&rope, offset, encoding);
+                    assert_eq!(position, position_at(&text, offset, encoding), "{:?}", text);
+                    if let Some(position) = position {
+                        assert_eq!(position_to_offset(&rope, position, encoding), Some(offset));
+                    }
+                }
+            }
+        }
+        let line = "a𝄞b";
+        assert_eq!(column_to_byte(line, 3, PositionEncoding::Utf16), Some(5));
+        assert_eq!(column_to_byte(line, 2, PositionEncoding::Utf16), None);
+        assert_eq!(column_to_byte(line, 2, PositionEncoding::Utf32), Some(5));
+        assert_eq!(column_to_byte(line, 5, PositionEncoding::Utf8), Some(5));
+        assert_eq!(byte_to_column(line, 5, PositionEncoding::Utf16), Some(3));
+        assert_eq!(byte_to_column(line, 2, PositionEncoding::Utf16), None);
+    }
+
+    #[test]
+    fn test_invalid_positions() {
+        let text = "𝄞a\nb";
+        let position = |line, character| Position { line, character };
+        assert_eq!(
+            offset_at(text, position(0, 1), PositionEncoding::Utf16),
+            None
+        );
+        assert_eq!(
+            offset_at(text, position(0, 2), PositionEncoding::Utf16),
+            Some(4)
+        );
+        assert_eq!(
+            offset_at(text, position(0, 1), PositionEncoding::Utf32),
+            Some(4)
+        );
+        assert_eq!(
+            offset_at(text, position(0, 4), PositionEncoding::Utf8),
+            Some(4)
+        );
+        assert_eq!(
+            offset_at(text, position(0, 9), PositionEncoding::Utf8),
+            None
+        );
+        assert_eq!(
+            offset_at(text, position(2, 0), PositionEncoding::Utf8),
+            None
+        );
+        assert_eq!(position_at(text, 99, PositionEncoding::Utf8), None);
+    }
+
+    #[test]
+    fn test_node_ranges_in_bounds() {
+        for seed in 1..200 {
+            let mut rng = XorShift(seed);
+            let nodes = 1 + rng.next(60);
+            // any label but the hole placeholder
+            let labels: Vec<char> = (0..nodes)
+                .map(|_| (b'!' + rng.next(94) as u8) as char)
+                .map(|c| if c == HOLE { '-' } else { c })
+                .collect();
+            let mut text = String::new();
+            let mut level = 0;
+            while (1 << level) - 1 < nodes {
+                let start = (1 << level) - 1;
+                let end = nodes.min((1 << (level + 1)) - 1);
+                let line: Vec<String> = labels[start..end].iter().map(|c| c.to_string()).collect();
+                text.push_str(&line.join(" "));
+                text.push('\n');
+                level += 1;
+            }
+            let fs = FileState::new(text.clone()).unwrap();
+            assert_eq!(fs.len(), nodes);
+            for index in 0..fs.len() {
+                let range = node_range(index);
+                assert!(range.start <= range.end);
+                for encoding in PositionEncoding::ALL {
+                    let start = offset_at(&text, range.start, encoding);
+                    let end = offset_at(&text, range.end, encoding);
+                    assert!(
+                        start.is_some() && end.is_some(),
+                        "{:?} {} in {:?}",
+                        range,
+                        index,
+                        text
+                    );
+                    assert_eq!(&text[start.unwrap()..end.unwrap()], fs.get(index).unwrap());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod file_operations {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, response, sent};
+
+    /// The logs and the messages sent while handling the message
+    type Handled = (String, Vec<Value>);
+
+    fn notify(method: &str, files: &str, editor_state: &mut EditorState) -> Handled {
+        let message = format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":{{\"files\":{}}}}}",
+            method, files
+        );
+        handle(message, editor_state)
+    }
+
+    fn request(method: &str, files: &str, editor_state: &mut EditorState) -> Handled {
+        let message = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"{}\",\"params\":{{\"files\":{}}}}}",
+            method, files
+        );
+        handle(message, editor_state)
+    }
+
+    fn handle(message: String, editor_state: &mut EditorState) -> Handled {
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        handle_message(message, editor_state, &mut context, &mut logger).unwrap();
+        (String::from_utf8(logger).unwrap(), sent(&writer))
+    }
+
+    fn editor_state() -> EditorState {
+        let mut editor_state = EditorState::new();
+        for uri in [
+            "file:///w/a.abc",
+            "file:///w/sub/b.abc",
+            "file:///w/subway.abc",
+        ] {
+            editor_state
+                .modify_file(uri.to_string(), "a".to_string())
+                .unwrap();
+        }
+        editor_state.set_open("file:///w/sub/b.abc", true);
+        editor_state
+    }
+
+    #[test]
+    fn test_rename_folder() {
+        let mut editor_state = editor_state();
+        let (logs, _) = notify(
+            "workspace/didRenameFiles",
+            "[{\"oldUri\":\"file:///w/sub\",\"newUri\":\"file:///w/moved\"}]",
+            &mut editor_state,
+        );
+        assert!(logs.contains("moving file:///w/sub/b.abc to file:///w/moved/b.abc"));
+        assert!(editor_state
+            .get_file_state("file:///w/sub/b.abc".to_string())
+            .is_none());
+        assert!(editor_state
+            .get_file_state("file:///w/moved/b.abc".to_string())
+            .is_some());
+        assert!(editor_state.is_open("file:///w/moved/b.abc"));
+        // a sibling sharing the folder's name as a prefix isn't inside it
+        assert!(editor_state
+            .get_file_state("file:///w/subway.abc".to_string())
+            .is_some());
+    }
+
+    #[test]
+    fn test_delete_files() {
+        let mut editor_state = editor_state();
+        let (_, sent) = notify(
+            "workspace/didDeleteFiles",
+            "[{\"uri\":\"file:///w/sub/\"},{\"uri\":\"file:///w/a.abc\"}]",
+            &mut editor_state,
+        );
+        // the diagnostics of the deleted files are cleared
+        let mut cleared: Vec<_> = sent
+            .iter()
+            .filter(|message| message["method"] == "textDocument/publishDiagnostics")
+            .inspect(|message| assert_eq!(message["params"]["diagnostics"], Value::Array(vec![])))
+            .map(|message| message["params"]["uri"].as_str().unwrap())
+            .collect();
+        cleared.sort();
+        assert_eq!(cleared, ["file:///w/a.abc", "file:///w/sub/b.abc"]);
+        assert!(!editor_state.is_open("file:///w/sub/b.abc"));
+        assert!(editor_state
+            .get_file_state("file:///w/a.abc".to_string())
+            .is_none());
+        assert_eq!(editor_state.remove_files("file:///w").len(), 1);
+    }
+
+    #[test]
+    fn test_will_requests() {
+        let mut editor_state = editor_state();
+        let (_, sent) = request(
+            "workspace/willRenameFiles",
+            "[{\"oldUri\":\"file:///w/a.abc\",\"newUri\":\"file:///w/c.abc\"}]",
+            &mut editor_state,
+        );
+        assert_eq!(response(&sent, 1)["result"], Value::Null);
+        // nothing moves before the client did the rename
+        assert!(editor_state
+            .get_file_state("file:///w/a.abc".to_string())
+            .is_some());
+        let (_, sent) = request(
+            "workspace/willDeleteFiles",
+            "[{\"uri\":\"file:///w/a.abc\"}]",
+            &mut editor_state,
+        );
+        assert_eq!(response(&sent, 1)["result"], Value::Null);
+    }
+}
+
+#[cfg(test)]
+mod telemetry {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::telemetry::Telemetry;
+    use crate::test::util::{context, sent};
+
+    #[test]
+    fn test_take_event() {
+        let mut telemetry = Telemetry::new();
+        telemetry.record_message("textDocument/hover", true);
+        telemetry.record_message("textDocument/hover", true);
+        telemetry.record_message("textDocument/didOpen", false);
+        telemetry.record_parse_failure();
+        telemetry.record_latency("textDocument/hover", Duration::from_millis(2));
+        telemetry.record_latency("textDocument/hover", Duration::from_millis(4));
+        assert_eq!(telemetry.frames(), 2);
+
+        let event = telemetry.take_event();
+        assert_eq!(event.requests["textDocument/hover"], 2);
+        assert_eq!(event.notifications["textDocument/didOpen"], 1);
+        assert_eq!(event.parse_failures, 1);
+        assert_eq!(event.latency["textDocument/hover"].count, 2);
+        assert_eq!(event.latency["textDocument/hover"].mean_ms, 3.0);
+        assert_eq!(event.latency["textDocument/hover"].max_ms, 4.0);
+        assert_eq!(telemetry.frames(), 0);
+    }
+
+    #[test]
+    fn test_opt_in() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let open = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\",\"languageId\":\"abc\",\"version\":0,\"text\":\"a\\nbxc\"}}}";
+        handle_message(
+            open.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        context.record_latency(
+            "textDocument/didOpen",
+            Duration::from_millis(1),
+            &mut logger,
+        );
+        assert!(sent(&writer)
+            .iter()
+            .all(|message| message["method"] != "telemetry/event"));
+
+        let configuration = "{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeConfiguration\",\"params\":{\"settings\":{\"lsp-rs\":{\"telemetry\":{\"enabled\":true,\"interval\":2}}}}}";
+        handle_message(
+            configuration.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        context.record_latency(
+            "workspace/didChangeConfiguration",
+            Duration::ZERO,
+            &mut logger,
+        );
+        handle_message(
+            open.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        sent(&writer);
+        context.record_latency(
+            "textDocument/didOpen",
+            Duration::from_millis(1),
+            &mut logger,
+        );
+        let event = sent(&writer).pop().unwrap();
+        assert_eq!(event["method"], "telemetry/event");
+        assert_eq!(event["params"]["parseFailures"], 1);
+        assert_eq!(
+            event["params"]["notifications"],
+            json!({"textDocument/didOpen": 1})
+        );
+    }
+}
+
+#[cfg(test)]
+mod hover {
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, response, sent};
+
+    fn hover(capabilities: &str, settings: &str, line: i32, character: i32) -> Value {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let initialize = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{{\"processId\":1,\"capabilities\":{},\"initializationOptions\":{}}}}}",
+            capabilities, settings
+        );
+        handle_message(initialize, &mut editor_state, &mut context, &mut logger).unwrap();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "*\nb c".to_string())
+            .unwrap();
+        let mut logger = Vec::new();
+        let hover = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/hover\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\"}},\"position\":{{\"line\":{},\"character\":{}}}}}}}",
+            line, character
+        );
+        handle_message(hover, &mut editor_state, &mut context, &mut logger).unwrap();
+        response(&sent(&writer), 2)["result"].clone()
+    }
+
+    #[test]
+    fn test_markup_kind() {
+        let markdown =
+            "{\"textDocument\":{\"hover\":{\"contentFormat\":[\"markdown\",\"plaintext\"]}}}";
+        let result = hover(markdown, "{}", 1, 0);
+        assert_eq!(result["contents"]["kind"], "markdown");
+        let value = result["contents"]["value"].as_str().unwrap();
+        assert!(value.contains("Parent: **\\*"), "{}", value);
+
+        let result = hover("{}", "{}", 1, 0);
+        assert_eq!(result["contents"]["kind"], "plaintext");
+        assert_eq!(result["range"]["end"], json!({"line": 1, "character": 1}));
+        let value = result["contents"]["value"].as_str().unwrap();
+        assert!(value.ends_with("Parent: *"), "{}", value);
+
+        // separators are not a node
+        let result = hover("{}", "{}", 1, 1);
+        assert!(result.get("range").is_none(), "{}", result);
+        let result = hover("{}", "{\"hoverVerbosity\":\"minimal\"}", 1, 1);
+        assert_eq!(result, Value::Null);
+
+        // past the end of the line there is nothing to show
+        assert_eq!(hover("{}", "{}", 0, 2), Value::Null);
+    }
+
+    #[test]
+    fn test_verbose_markdown() {
+        let markdown = "{\"textDocument\":{\"hover\":{\"contentFormat\":[\"markdown\"]}}}";
+        let result = hover(markdown, "{\"hoverVerbosity\":\"verbose\"}", 0, 0);
+        let value = result["contents"]["value"].as_str().unwrap();
+        assert!(value.contains("Left child: **b**"), "{}", value);
+        assert!(value.contains("Subtree:\n```\n"), "{}", value);
+    }
+}
+
+#[cfg(test)]
+mod references {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{initialized, sent};
+
+    fn references(extra_params: &str, include_declaration: bool) -> Vec<Value> {
+        references_in("file:///a.abc", extra_params, include_declaration)
+    }
+
+    fn references_in(uri: &str, extra_params: &str, include_declaration: bool) -> Vec<Value> {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb a".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///b.abc".to_string(), "b\na a".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///c.abc".to_string(), "c".to_string())
+            .unwrap();
+        let mut logger = Vec::new();
+        let message = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"textDocument/references\",\"params\":{{\"textDocument\":{{\"uri\":\"{}\"}},\"position\":{{\"line\":1,\"character\":2}},\"context\":{{\"includeDeclaration\":{}}}{}}}}}",
+            uri, include_declaration, extra_params
+        );
+        handle_message(message, &mut editor_state, &mut context, &mut logger).unwrap();
+        sent(&writer)
+    }
+
+    #[test]
+    fn test_references() {
+        let sent = references("", true);
+        assert_eq!(sent.len(), 1);
+        let uris: Vec<&str> = sent[0]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|location| location["uri"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "file:///a.abc",
+                "file:///a.abc",
+                "file:///b.abc",
+                "file:///b.abc"
+            ]
+        );
+
+        let sent = references("", false);
+        assert_eq!(sent[0]["result"].as_array().unwrap().len(), 3);
+        assert_eq!(sent[0]["result"][0]["range"]["start"]["character"], 2);
+    }
+
+    #[test]
+    fn test_references_other_spelling() {
+        // the document is the same as file:///a.abc, its references are listed once
+        let sent = references_in("FILE:///%61.abc", "", true);
+        assert_eq!(sent[0]["result"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_partial_results() {
+        let sent = references(",\"partialResultToken\":\"p\"", true);
+        assert_eq!(sent.len(), 3);
+        for chunk in &sent[..2] {
+            assert_eq!(chunk["method"], "$/progress");
+            assert_eq!(chunk["params"]["token"], "p");
+            assert_eq!(chunk["params"]["value"].as_array().unwrap().len(), 2);
+        }
+        assert_eq!(sent[2]["result"], Value::Array(Vec::new()));
+    }
+
+    fn semantic_tokens(extra_params: &str) -> Vec<Value> {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb a".to_string())
+            .unwrap();
+        let mut logger = Vec::new();
+        let message = format!(
+            "{{\"jsonrpc\":\"2.0\",\"id\":4,\"method\":\"textDocument/semanticTokens/full\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\"}}{}}}}}",
+            extra_params
+        );
+        handle_message(message, &mut editor_state, &mut context, &mut logger).unwrap();
+        sent(&writer)
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let sent = semantic_tokens("");
+        assert_eq!(sent.len(), 1);
+        // the root, then the leaves b and a, b declares its label and a doesn't
+        assert_eq!(
+            sent[0]["result"]["data"],
+            serde_json::json!([0, 0, 1, 0, 1, 1, 0, 1, 2, 1, 0, 2, 1, 2, 0])
+        );
+    }
+
+    #[test]
+    fn test_semantic_tokens_partial_results() {
+        let sent = semantic_tokens(",\"partialResultToken\":\"p\"");
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0]["params"]["value"]["data"],
+            serde_json::json!([0, 0, 1, 0, 1])
+        );
+        assert_eq!(
+            sent[1]["params"]["value"]["data"],
+            serde_json::json!([1, 0, 1, 2, 1, 0, 2, 1, 2, 0])
+        );
+        assert_eq!(sent[2]["result"]["data"], Value::Array(Vec::new()));
+    }
+}
+
+#[cfg(test)]
+mod workspace_symbols {
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::output::MemoryWriter;
+    use crate::test::util::{context, response, sent};
+
+    fn result(
+        message: Value,
+        editor_state: &mut EditorState,
+        context: &mut ServerContext,
+        writer: &MemoryWriter,
+    ) -> Value {
+        let id = message["id"].clone();
+        handle_message(message.to_string(), editor_state, context, &mut Vec::new()).unwrap();
+        response(&sent(writer), id)["result"].clone()
+    }
+
+    fn setup(capabilities: Value) -> (EditorState, ServerContext, MemoryWriter) {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"processId": 1, "capabilities": capabilities}});
+        result(initialize, &mut editor_state, &mut context, &writer);
+        editor_state
+            .modify_file("file:///b.abc".to_string(), "X\ny x".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nx c".to_string())
+            .unwrap();
+        (editor_state, context, writer)
+    }
+
+    #[test]
+    fn test_symbols_with_ranges() {
+        let (mut editor_state, mut context, writer) = setup(json!({}));
+        let query = json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {"query": "x"}});
+        let symbols = result(query, &mut editor_state, &mut context, &writer);
+        let symbols = symbols.as_array().unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols[0]["location"]["uri"], "file:///a.abc");
+        assert_eq!(symbols[0]["containerName"], "node 1");
+        assert_eq!(symbols[1]["name"], "X");
+        assert_eq!(symbols[2]["location"]["range"]["start"]["character"], 2);
+        assert!(symbols[2].get("data").is_none());
+    }
+
+    #[test]
+    fn test_resolve_ranges() {
+        let capabilities = json!({"workspace": {"symbol": {"resolveSupport": {"properties": ["location.range"]}}}});
+        let (mut editor_state, mut context, writer) = setup(capabilities);
+        let query = json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {"query": "c"}});
+        let symbols = result(query, &mut editor_state, &mut context, &writer);
+        let symbol = symbols[0].clone();
+        assert_eq!(symbol["location"], json!({"uri": "file:///a.abc"}));
+
+        let resolve = json!({"jsonrpc": "2.0", "id": 3, "method": "workspaceSymbol/resolve", "params": symbol});
+        let resolved = result(resolve, &mut editor_state, &mut context, &writer);
+        assert_eq!(
+            resolved["location"]["range"]["start"],
+            json!({"line": 1, "character": 2})
+        );
+
+        // the node changed label since the query, the symbol can't be resolved
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nx d".to_string())
+            .unwrap();
+        let resolve = json!({"jsonrpc": "2.0", "id": 4, "method": "workspaceSymbol/resolve", "params": symbol});
+        let resolved = result(resolve, &mut editor_state, &mut context, &writer);
+        assert_eq!(resolved["location"], json!({"uri": "file:///a.abc"}));
+    }
+}
+
+#[cfg(test)]
+mod refresh {
+    use std::time::Duration;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, RefreshKind, ServerContext};
+    use crate::output::MemoryWriter;
+    use crate::test::util::{context, sent};
+
+    fn initialized_context(editor_state: &mut EditorState) -> (ServerContext, MemoryWriter) {
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let initialize = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"processId\":1,\"capabilities\":{\"workspace\":{\"inlayHint\":{\"refreshSupport\":true},\"diagnostics\":{\"refreshSupport\":true},\"codeLens\":{\"refreshSupport\":false}}}}}";
+        let initialized = "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}";
+        for message in [initialize, initialized] {
+            handle_message(message.to_string(), editor_state, &mut context, &mut logger).unwrap();
+        }
+        sent(&writer);
+        (context, writer)
+    }
+
+    #[test]
+    fn test_refresh_on_configuration_change() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized_context(&mut editor_state);
+        assert!(context.supports_refresh(RefreshKind::InlayHint));
+        assert!(!context.supports_refresh(RefreshKind::CodeLens));
+
+        let mut logger = Vec::new();
+        let change = "{\"jsonrpc\":\"2.0\",\"method\":\"workspace/didChangeConfiguration\",\"params\":{\"settings\":{\"lsp-rs\":{\"previewDepth\":5}}}}";
+        handle_message(
+            change.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let refreshes: Vec<_> = sent(&writer)
+            .into_iter()
+            .filter(|message| message["method"].as_str().unwrap().ends_with("/refresh"))
+            .inspect(|message| assert!(message.get("params").is_none(), "{}", message))
+            .map(|message| message["method"].clone())
+            .collect();
+        assert_eq!(
+            refreshes,
+            [
+                "workspace/inlayHint/refresh",
+                "workspace/diagnostic/refresh"
+            ]
+        );
+
+        // the same settings again change nothing
+        let mut logger = Vec::new();
+        handle_message(
+            change.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        assert!(sent(&writer).is_empty());
+    }
+
+    #[test]
+    fn test_refresh_is_shed() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized_context(&mut editor_state);
+        let mut logger = Vec::new();
+        for _ in 0..5 {
+            context.record_frame(Duration::from_secs(1), &mut logger);
+        }
+        assert!(!context.refresh(RefreshKind::InlayHint, &mut logger));
+        assert_eq!(context.client_health().stats().shed, 1);
+    }
+}
+
+#[cfg(test)]
+mod diagnostics {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, sent};
+
+    /// Diagnostics published for the text, by a client with the capabilities
+    fn diagnostics(capabilities: &str, text: &str) -> Vec<Value> {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let initialize = format!("{{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{{\"processId\":1,\"capabilities\":{{\"textDocument\":{{\"publishDiagnostics\":{}}}}}}}}}", capabilities);
+        let open = format!("{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{{\"textDocument\":{{\"uri\":\"file:///a.abc\",\"languageId\":\"abc\",\"version\":1,\"text\":{}}}}}}}", Value::from(text));
+        let mut logger = Vec::new();
+        handle_message(initialize, &mut editor_state, &mut context, &mut logger).unwrap();
+        handle_message(open, &mut editor_state, &mut context, &mut logger).unwrap();
+        let sent = sent(&writer)
+            .into_iter()
+            .find(|sent| sent["method"] == "textDocument/publishDiagnostics")
+            .unwrap();
+        sent["params"]["diagnostics"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn test_line_and_parent() {
+        let sent = diagnostics(
+            "{\"relatedInformation\":true,\"tagSupport\":{\"valueSet\":[1,2]}}",
+            "a\nb c d",
+        );
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0]["severity"], 1);
+        assert_eq!(sent[0]["code"], "structure");
+        assert_eq!(sent[0]["range"]["start"]["line"], 1);
+        assert_eq!(sent[0]["range"]["end"]["character"], 5);
+        let related = &sent[0]["relatedInformation"][0];
+        assert_eq!(related["location"]["uri"], "file:///a.abc");
+        assert_eq!(related["location"]["range"]["start"]["line"], 0);
+        assert_eq!(related["location"]["range"]["end"]["character"], 1);
+
+        // the nodes past the end of the level are unnecessary
+        assert_eq!(sent[1]["severity"], 4);
+        assert_eq!(sent[1]["tags"], serde_json::json!([1]));
+        assert_eq!(sent[1]["range"]["start"]["character"], 3);
+        assert_eq!(sent[1]["range"]["end"]["character"], 5);
+    }
+
+    #[test]
+    fn test_without_capabilities() {
+        let sent = diagnostics("{}", "a\nb c d");
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].get("relatedInformation").is_none());
+        assert!(sent[0].get("tags").is_none());
+
+        // the first line has no parent
+        let sent = diagnostics("{\"relatedInformation\":true}", "a b");
+        assert_eq!(sent[0]["range"]["start"]["line"], 0);
+        assert!(sent[0].get("relatedInformation").is_none());
+
+        assert!(diagnostics("{}", "a\nb c").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod rope {
+    use crate::editor::{EditorState, FileState};
+    use crate::lsp::handle_message;
+    use crate::rope::Rope;
+    use crate::test::util::{context, random_text, XorShift};
+
+    fn random_boundary(rng: &mut XorShift, text: &str) -> usize {
+        let mut offset = rng.next(text.len() + 1);
+        while !text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    #[test]
+    fn test_random_edits() {
+        for seed in 1..100 {
+            let mut rng = XorShift(seed);
+            let mut expected = random_text(&mut rng, 3000);
+            let mut rope = Rope::from(expected.as_str());
+            for _ in 0..50 {
+                let a = random_boundary(&mut rng, &expected);
+                let b = random_boundary(&mut rng, &expected);
+                let range = a.min(b)..a.max(b);
+                let text = random_text(&mut rng, 20);
+                let before = rope.clone();
+                rope.replace(range.clone(), &text);
+                assert_eq!(before.len(), expected.len(), "clones are not changed");
+                expected.replace_range(range, &text);
+            }
+            assert_eq!(rope.to_string(), expected, "seed {}", seed);
+            assert_eq!(rope.len(), expected.len());
+            assert_eq!(rope.line_count(), expected.split('\n').count());
+            let mut start = 0;
+            for (i, line) in expected.split('\n').enumerate() {
+                assert_eq!(rope.line_start(i), Some(start), "seed {}", seed);
+                assert_eq!(rope.line_at(start), i);
+                assert_eq!(
+                    rope.line(i).unwrap(),
+                    line.strip_suffix('\r').unwrap_or(line)
+                );
+                start += line.len() + 1;
+            }
+            assert_eq!(rope.line_start(rope.line_count()), None);
+        }
+    }
+
+    /// Text of a tree with up to 5 levels, holes and orphans included
+    fn random_tree(rng: &mut XorShift) -> String {
+        let labels = ["a", "b", "_"];
+        (0..1 + rng.next(5))
+            .map(|d| {
+                let nodes: Vec<_> = (0..1 << d)
+                    .map(|_| labels[rng.next(labels.len())])
+                    .collect();
+                nodes.join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_random_tree_edits() {
+        let pieces = ["", "a", "_", " ", "b c", "\n", "\r\n", "\na b"];
+        for seed in 1..200 {
+            let mut rng = XorShift(seed);
+            let mut editor_state = EditorState::new();
+            let _ = editor_state.modify_file("file:///a.abc".to_string(), random_tree(&mut rng));
+            for _ in 0..20 {
+                let text = editor_state.text("file:///a.abc").unwrap().to_string();
+                let start = random_boundary(&mut rng, &text);
+                let end = (start + rng.next(3)).min(text.len());
+                let piece = pieces[rng.next(pieces.len())];
+                let Some(edited) = editor_state.edit_file("file:///a.abc", start..end, piece)
+                else {
+                    continue;
+                };
+                // re-parsing from the edit gives the tree parsing the whole text does
+                let text = editor_state.text("file:///a.abc").unwrap().clone();
+                let parsed = FileState::from_rope(text.clone());
+                assert_eq!(edited.is_ok(), parsed.is_ok(), "seed {} {:?}", seed, text);
+                let (Some(fs), Ok(parsed)) = (
+                    editor_state.get_file_state("file:///a.abc".to_string()),
+                    parsed,
+                ) else {
+                    continue;
+                };
+                assert_eq!(fs.len(), parsed.len(), "seed {} {:?}", seed, text);
+                for i in 0..fs.len() {
+                    assert_eq!(fs.get(i), parsed.get(i), "seed {} {:?}", seed, text);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_slice_and_boundaries() {
+        let rope = Rope::from("aé\nb".repeat(1000));
+        assert_eq!(rope.slice(0..4), "aé\n");
+        assert!(!rope.is_char_boundary(2));
+        assert!(rope.is_char_boundary(rope.len()));
+        assert!(!rope.is_char_boundary(rope.len() + 1));
+        assert!(rope.chunks().count() > 1);
+        assert!(Rope::new().is_empty());
+    }
+
+    #[test]
+    fn test_edit_file() {
+        let mut editor_state = EditorState::new();
+        assert!(editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c\n".to_string())
+            .is_ok());
+        let text = editor_state.text("file:///a.abc").unwrap().clone();
+        assert!(text.ptr_eq(
+            editor_state
+                .get_file_state("file:///a.abc".to_string())
+                .unwrap()
+                .text()
+        ));
+
+        // relabeling a node only re-parses its line
+        assert_eq!(
+            editor_state.edit_file("file:///a.abc", 4..5, "x"),
+            Some(Ok(()))
+        );
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.get(2).unwrap(), "x");
+        assert_eq!(fs.text().to_string(), "a\nb x\n");
+
+        // an edit that breaks the tree keeps the last tree, but the text follows the edit
+        let error = editor_state
+            .edit_file("file:///a.abc", 2..3, "bb")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!((error.line, error.character, error.expected), (1, 0, 3));
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.get(1).unwrap(), "b");
+        assert_eq!(
+            editor_state.text("file:///a.abc").unwrap().to_string(),
+            "a\nbb x\n"
+        );
+
+        // fixing it re-parses the text
+        assert_eq!(
+            editor_state.edit_file("file:///a.abc", 2..4, "y"),
+            Some(Ok(()))
+        );
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.get(1).unwrap(), "y");
+        assert_eq!(editor_state.edit_file("file:///a.abc", 3..100, ""), None);
+        assert_eq!(editor_state.edit_file("file:///b.abc", 0..0, ""), None);
+    }
+
+    #[test]
+    fn test_file_state_text() {
+        let fs = FileState::new("a\r\nb c".to_string()).unwrap();
+        assert_eq!(fs.line_count(), 2);
+        assert_eq!(fs.line(0).unwrap(), "a");
+        assert_eq!(fs.line(1).unwrap(), "b c");
+        assert_eq!(fs.line(2), None);
+        assert_eq!(fs.slice(1..4).unwrap(), "\r\nb");
+        assert_eq!(fs.slice(4..8), None);
+        // the line break counts once, as it would in the same document with `\n`
+        assert_eq!(fs.get_char_count(), 5);
+    }
+
+    #[test]
+    fn test_incremental_did_change() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let open = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\",\"languageId\":\"abc\",\"version\":1,\"text\":\"a\\nb c\"}}}";
+        let change = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didChange\",\"params\":{\"textDocument\":{\"uri\":\"file:///a.abc\",\"version\":2},\"contentChanges\":[{\"range\":{\"start\":{\"line\":1,\"character\":2},\"end\":{\"line\":1,\"character\":3}},\"text\":\"z\"},{\"range\":{\"start\":{\"line\":1,\"character\":3},\"end\":{\"line\":1,\"character\":3}},\"text\":\"\\nd\"}]}}";
+        for message in [open, change] {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        }
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.text().to_string(), "a\nb z\nd");
+        assert_eq!(fs.len(), 4);
+        assert_eq!(fs.get(3).unwrap(), "d");
+    }
+}
+
+#[cfg(test)]
+mod tree_mutation {
+    use crate::editor::{FileState, Side, TreeEditError};
+
+    #[test]
+    fn test_set_and_insert() {
+        let mut fs = FileState::new("a\nb".to_string()).unwrap();
+        fs.set_node(1, "x").unwrap();
+        assert_eq!(fs.set_node(2, "y"), Err(TreeEditError::NoSuchNode(2)));
+        assert_eq!(
+            fs.set_node(0, "ab"),
+            Err(TreeEditError::InvalidLabel("ab".to_string()))
+        );
+        assert_eq!(
+            fs.insert_leaf(0, Side::Left, "c"),
+            Err(TreeEditError::SlotTaken(1))
+        );
+        assert_eq!(
+            fs.insert_leaf(2, Side::Left, "c"),
+            Err(TreeEditError::NoSuchNode(2))
+        );
+        // the right child of the root is left as a hole
+        assert_eq!(fs.insert_leaf(1, Side::Left, "d"), Ok(3));
+        assert_eq!(fs.text().to_string(), "a\nx _\nd\n");
+        assert_eq!(fs.insert_leaf(0, Side::Right, "c"), Ok(2));
+        assert_eq!(fs.text().to_string(), "a\nx c\nd\n");
+        // the rendered text parses back to the same tree
+        let parsed = FileState::new(fs.text().to_string()).unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(parsed.get(3).unwrap(), "d");
+    }
+
+    #[test]
+    fn test_delete_subtree() {
+        let mut fs = FileState::new("a\nb c\nd e f".to_string()).unwrap();
+        assert_eq!(fs.delete_subtree(6), Err(TreeEditError::NoSuchNode(6)));
+        assert_eq!(fs.delete_subtree(5).unwrap(), vec!["f"]);
+        assert_eq!(fs.text().to_string(), "a\nb c\nd e\n");
+        // the subtree becomes holes
+        assert_eq!(fs.delete_subtree(1).unwrap(), vec!["b", "d", "e"]);
+        assert_eq!(fs.delete_subtree(1), Err(TreeEditError::NoSuchNode(1)));
+        assert_eq!(fs.text().to_string(), "a\n_ c\n");
+        let mut fs = FileState::new("a\nb c\nd".to_string()).unwrap();
+        fs.delete_subtree(3).unwrap();
+        assert_eq!(fs.delete_subtree(2).unwrap(), vec!["c"]);
+        assert_eq!(fs.text().to_string(), "a\nb\n");
+        assert_eq!(fs.delete_subtree(0).unwrap().len(), 2);
+        assert!(fs.is_empty());
+        assert_eq!(fs.text().to_string(), "");
+    }
+}
+
+#[cfg(test)]
+mod parse_errors {
+    use crate::editor::FileState;
+
+    #[test]
+    fn test_error_location() {
+        let error = FileState::new("a\nb c\nd e-f".to_string()).err().unwrap();
+        assert_eq!((error.line, error.character, error.expected), (2, 3, 7));
+        assert_eq!(
+            error.to_string(),
+            "line 2, character 3: nodes must be separated by a single space"
+        );
+        let error = FileState::new("a\nb\nc".to_string()).err().unwrap();
+        assert_eq!((error.line, error.character, error.expected), (1, 0, 3));
+        assert_eq!(
+            error.reason,
+            "only the last level may be short, expected 3 characters, found 1"
+        );
+        let error = FileState::new("a\nb c d".to_string()).err().unwrap();
+        assert_eq!(error.reason, "expected at most 3 characters, found 5");
+    }
+}
+
+#[cfg(test)]
+mod render {
+    use crate::editor::FileState;
+
+    #[test]
+    fn test_render_canonical_layout() {
+        let fs = FileState::new("a\r\nb c\r\nd  ".to_string()).unwrap();
+        assert_eq!(fs.render(), "a\r\nb c\r\nd\r\n");
+        let fs = FileState::new("a\n  c\n    f".to_string()).unwrap();
+        assert_eq!(fs.render(), "a\n_ c\n_ _ f\n");
+        // the rendered text is the same tree
+        let parsed = FileState::new(fs.render()).unwrap();
+        assert_eq!(parsed.render(), fs.render());
+        assert_eq!(FileState::new(String::new()).unwrap().render(), "");
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let fs = FileState::new("a\n_ c\n_ _ \\ f".to_string()).unwrap();
+        assert_eq!(
+            fs.to_dot(),
+            "digraph tree {\n    n0 [label=\"a\"];\n    n2 [label=\"c\"];\n    n0 -> n2;\n    n5 [label=\"\\\\\"];\n    n2 -> n5;\n    n6 [label=\"f\"];\n    n2 -> n6;\n}\n"
+        );
+        assert_eq!(
+            FileState::new(String::new()).unwrap().to_dot(),
+            "digraph tree {\n}\n"
+        );
+    }
+}
+
+#[cfg(test)]
+mod incomplete_trees {
+    use crate::editor::FileState;
+    use crate::lsp::handle_message;
+    use crate::test::util::context;
+    use crate::{editor::EditorState, preview};
+
+    #[test]
+    fn test_parse_holes() {
+        let fs = FileState::new("a\nb _\nd e".to_string()).unwrap();
+        assert_eq!(fs.len(), 5);
+        assert_eq!(fs.get(2), None);
+        assert_eq!(fs.right_child(0), None);
+        assert_eq!(fs.get(4).unwrap(), "e");
+        assert_eq!(fs.position_of(2), None);
+        // a space is a hole too
+        let fs = FileState::new("a\n  b\n_ _ c".to_string()).unwrap();
+        assert_eq!(fs.get(1), None);
+        assert_eq!(fs.get(5).unwrap(), "c");
+        // a node needs a parent
+        let error = FileState::new("a\n_ b\nc".to_string()).err().unwrap();
+        assert_eq!((error.line, error.character), (2, 0));
+        assert_eq!(error.reason, "c is below a hole, every node needs a parent");
+        assert!(FileState::new("_\nb".to_string()).is_err());
+        // the levels above the last are still full width
+        assert!(FileState::new("a\nb\nc".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_traversal_skips_holes() {
+        let fs = FileState::new("d\n_ f\n_ _ e g".to_string()).unwrap();
+        let labels = |nodes: Vec<(usize, &str)>| {
+            nodes
+                .into_iter()
+                .map(|(_, label)| label)
+                .collect::<String>()
+        };
+        assert_eq!(labels(fs.iter_bfs().collect()), "dfeg");
+        assert_eq!(labels(fs.iter_dfs_preorder().collect()), "dfeg");
+        assert_eq!(labels(fs.iter_dfs_inorder().collect()), "defg");
+        assert_eq!(labels(fs.leaves().collect()), "eg");
+        assert_eq!(preview::subtree(&fs, 0, 2), "d\n  f\n    … 1 more level");
+    }
+
+    #[test]
+    fn test_edit_into_hole() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.abc","languageId":"abc","version":1,"text":"a\nb c\nd"}}}"#;
+        let hole = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.abc","version":2},"contentChanges":[{"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":1}},"text":"_"}]}}"#;
+        for message in [open, hole] {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        }
+        // d would lose its parent, so the last valid tree is kept
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.get(1).unwrap(), "b");
+        let fill = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.abc","version":3},"contentChanges":[{"range":{"start":{"line":2,"character":0},"end":{"line":2,"character":1}},"text":"_"}]}}"#;
+        handle_message(
+            fill.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.text().to_string(), "a\n_ c\n_");
+    }
+}
+
+#[cfg(test)]
+mod language_ids {
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::test::util::{context, initialized, response, sent};
+
+    fn send(message: &str, editor_state: &mut EditorState, context: &mut ServerContext) -> String {
+        let mut logger = Vec::new();
+        handle_message(message.to_string(), editor_state, context, &mut logger).unwrap();
+        String::from_utf8(logger).unwrap()
+    }
+
+    #[test]
+    fn test_other_languages_are_plain_text() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///notes.txt","languageId":"plaintext","version":1,"text":"not a tree"}}}"#;
+        let logged = send(open, &mut editor_state, &mut context);
+        assert!(
+            !logged.contains("Not a valid complete binary tree"),
+            "{}",
+            logged
+        );
+        assert_eq!(
+            editor_state.language_id("file:///notes.txt"),
+            Some("plaintext")
+        );
+        assert!(!editor_state.is_tree_language("file:///notes.txt"));
+        assert!(editor_state
+            .get_file_state("file:///notes.txt".to_string())
+            .is_none());
+
+        let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///notes.txt","version":2},"contentChanges":[{"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":4}},"text":""}]}}"#;
+        send(change, &mut editor_state, &mut context);
+        let hover = r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///notes.txt"},"position":{"line":0,"character":0}}}"#;
+        send(hover, &mut editor_state, &mut context);
+        let hover = response(&sent(&writer), 3);
+        assert_eq!(hover["result"]["contents"]["value"], "Character count: 6");
+
+        let close = r#"{"jsonrpc":"2.0","method":"textDocument/didClose","params":{"textDocument":{"uri":"file:///notes.txt"}}}"#;
+        send(close, &mut editor_state, &mut context);
+        assert_eq!(editor_state.language_id("file:///notes.txt"), None);
+        assert!(editor_state.text("file:///notes.txt").is_none());
+    }
+
+    #[test]
+    fn test_tree_language_is_parsed() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.abc","languageId":"abc","version":1,"text":"a\nb c"}}}"#;
+        send(open, &mut editor_state, &mut context);
+        assert!(editor_state.is_tree_language("file:///a.abc"));
+        let diagnostics = &sent(&writer)[0];
+        assert_eq!(diagnostics["method"], "textDocument/publishDiagnostics");
+        assert_eq!(diagnostics["params"]["diagnostics"], serde_json::json!([]));
+        assert!(editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .is_some());
+    }
+}
+
+#[cfg(test)]
+mod dirty_state {
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::context;
+
+    #[test]
+    fn test_change_and_save() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = context();
+        let mut logger = Vec::new();
+        let uri = "file:///a.abc";
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.abc","languageId":"abc","version":1,"text":"a"}}}"#;
+        let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.abc","version":2},"contentChanges":[{"text":"b"}]}}"#;
+        let save = r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{"textDocument":{"uri":"file:///a.abc"}}}"#;
+        let mut send = |message: &str, editor_state: &mut EditorState| {
+            handle_message(message.to_string(), editor_state, &mut context, &mut logger).unwrap()
+        };
+        send(open, &mut editor_state);
+        assert!(!editor_state.is_dirty(uri));
+        send(change, &mut editor_state);
+        assert!(editor_state.is_dirty(uri));
+        send(save, &mut editor_state);
+        assert!(!editor_state.is_dirty(uri));
+        // a stale change is not applied, so it doesn't make the document dirty
+        send(change, &mut editor_state);
+        assert!(!editor_state.is_dirty(uri));
+        // only open documents can be dirty
+        editor_state.set_dirty("file:///b.abc", true);
+        assert!(!editor_state.is_dirty("file:///b.abc"));
+    }
+}
+
+#[cfg(test)]
+mod load_from_disk {
+    use std::fs;
+
+    use crate::editor::EditorState;
+    use crate::lru::LruSet;
+    use crate::lsp::{handle_message, ServerContext};
+    use crate::test::util::{capture, context, response, sent};
+    use crate::uri::path_to_uri;
+
+    #[test]
+    fn test_lru_set() {
+        let mut lru = LruSet::new();
+        assert!(lru.insert("a", 2).is_empty());
+        assert!(lru.insert("b", 2).is_empty());
+        assert!(lru.touch(&"a"));
+        assert_eq!(lru.insert("c", 2), vec!["b"]);
+        assert!(lru.contains(&"a") && lru.contains(&"c"));
+        assert_eq!(lru.insert("d", 1), vec!["a", "c"]);
+        assert!(lru.remove(&"d"));
+        assert!(lru.is_empty());
+    }
+
+    #[test]
+    fn test_unopened_files_are_read() {
+        let root = std::env::temp_dir().join(format!("lsp-rs-load-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.abc"), "a\nb c").unwrap();
+        fs::write(root.join("b.abc"), "b").unwrap();
+        let (a, b) = (
+            path_to_uri(&root.join("a.abc")),
+            path_to_uri(&root.join("b.abc")),
+        );
+
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let mut logger = Vec::new();
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{},"initializationOptions":{"diskCacheSize":1}}}"#;
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let hover = |uri: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{{"textDocument":{{"uri":"{}"}},"position":{{"line":1,"character":0}}}}}}"#,
+                uri
+            )
+        };
+        handle_message(hover(&a), &mut editor_state, &mut context, &mut logger).unwrap();
+        let hovered = response(&sent(&writer), 2);
+        assert_eq!(hovered["result"]["contents"]["value"], "Parent: a");
+        assert!(editor_state.get_file_state(a.clone()).is_some());
+
+        // the cache holds a single file, so reading b forgets a
+        handle_message(hover(&b), &mut editor_state, &mut context, &mut Vec::new()).unwrap();
+        assert!(editor_state.get_file_state(b.clone()).is_some());
+        assert!(editor_state.get_file_state(a.clone()).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_large_files_are_validated() {
+        let root = std::env::temp_dir().join(format!("lsp-rs-load-large-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.abc"), "a\nb c\nd e f g").unwrap();
+        fs::write(root.join("b.abc"), "a\nb\nd e f g").unwrap();
+
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        capture(&output, || {
+            let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{},"initializationOptions":{"maxFileSize":8}}}"#;
+            handle_message(
+                initialize.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            for name in ["a.abc", "b.abc"] {
+                let hover = format!(
+                    r#"{{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{{"textDocument":{{"uri":"{}"}},"position":{{"line":0,"character":0}}}}}}"#,
+                    path_to_uri(&root.join(name))
+                );
+                handle_message(hover, &mut editor_state, &mut context, &mut logger).unwrap();
+            }
+        });
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("a valid tree of 7 nodes"), "{}", logged);
+        assert!(logged.contains("not a tree, line 1"), "{}", logged);
+        assert!(editor_state
+            .get_file_state(path_to_uri(&root.join("a.abc")))
+            .is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod label_search {
+    use crate::editor::{EditorState, FileState};
+
+    #[test]
+    fn test_find() {
+        let fs = FileState::new("a\nb a\na _ c a".to_string()).unwrap();
+        assert_eq!(fs.find("a"), vec![0, 2, 3, 6]);
+        assert_eq!(fs.find("_"), Vec::<usize>::new());
+        assert_eq!(fs.find("z"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_find_in_all() {
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///b.abc".to_string(), "x\na".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb a".to_string())
+            .unwrap();
+        editor_state
+            .modify_file("file:///c.abc".to_string(), "c".to_string())
+            .unwrap();
+        let found: Vec<_> = editor_state
+            .find_in_all("a")
+            .into_iter()
+            .map(|(uri, nodes)| (uri.to_string(), nodes))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("file:///a.abc".to_string(), vec![0, 2]),
+                ("file:///b.abc".to_string(), vec![1]),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod rotation {
+    use crate::editor::{FileState, TreeEditError};
+
+    fn inorder(fs: &FileState) -> String {
+        fs.iter_dfs_inorder().map(|(_, label)| label).collect()
+    }
+
+    #[test]
+    fn test_rotations() {
+        let mut fs = FileState::new("a\nb c\nd e".to_string()).unwrap();
+        fs.rotate_right(0).unwrap();
+        assert_eq!(fs.text().to_string(), "b\nd a\n_ _ e c\n");
+        assert_eq!(inorder(&fs), "dbeac");
+        fs.rotate_left(0).unwrap();
+        assert_eq!(fs.text().to_string(), "a\nb c\nd e\n");
+        assert_eq!(fs.rotate_left(2), Err(TreeEditError::NoSuchNode(6)));
+        assert_eq!(fs.rotate_right(2), Err(TreeEditError::NoSuchNode(5)));
+        assert_eq!(fs.rotate_right(9), Err(TreeEditError::NoSuchNode(9)));
+        assert_eq!(fs.rotate_left(9), Err(TreeEditError::NoSuchNode(9)));
+    }
+
+    #[test]
+    fn test_balance() {
+        let mut fs = FileState::new("a\n_ c\n_ _ _ f\n".to_string()).unwrap();
+        fs.balance();
+        assert_eq!(fs.text().to_string(), "c\na f\n");
+        let mut fs = FileState::new("d\nb f\na c e g".to_string()).unwrap();
+        let text = fs.text().to_string();
+        fs.balance();
+        assert_eq!(fs.text().to_string(), text.clone() + "\n");
+        let mut fs = FileState::new(String::new()).unwrap();
+        fs.balance();
+        assert!(fs.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod traversal {
+    use crate::editor::FileState;
+
+    #[test]
+    fn test_orders() {
+        let fs = FileState::new("d\nb f\na c".to_string()).unwrap();
+        let labels = |nodes: Vec<(usize, &str)>| {
+            nodes
+                .into_iter()
+                .map(|(_, label)| label)
+                .collect::<String>()
+        };
+        assert_eq!(labels(fs.iter_bfs().collect()), "dbfac");
+        assert_eq!(labels(fs.iter_dfs_preorder().collect()), "dbacf");
+        assert_eq!(labels(fs.iter_dfs_inorder().collect()), "abcdf");
+        assert_eq!(
+            fs.leaves().map(|(i, _)| i).collect::<Vec<_>>(),
+            vec![3, 4, 2]
+        );
+
+        let empty = FileState::new(String::new()).unwrap();
+        assert_eq!(empty.iter_dfs_preorder().count(), 0);
+        assert_eq!(empty.iter_dfs_inorder().count(), 0);
+        assert_eq!(empty.leaves().count(), 0);
+    }
+}
+
+#[cfg(test)]
+mod shared_state {
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::editor::{EditorState, FileState, SharedEditorState};
+    use crate::lsp::handle_shared_message;
+    use crate::test::util::{initialized, response, sent};
+    use crate::uri::path_to_uri;
+
+    fn hover(uri: &str) -> String {
+        format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{{"textDocument":{{"uri":"{}"}},"position":{{"line":1,"character":0}}}}}}"#,
+            uri
+        )
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_concurrent_reads() {
+        assert_send_sync::<EditorState>();
+        assert_send_sync::<FileState>();
+
+        let state = SharedEditorState::default();
+        {
+            let mut editor_state = state.write();
+            editor_state
+                .modify_file("a.abc".to_string(), "b\na c".to_string())
+                .unwrap();
+            editor_state
+                .modify_file("b.abc".to_string(), "e\nd f".to_string())
+                .unwrap();
+        }
+
+        let leaves = thread::scope(|scope| {
+            let handles: Vec<_> = ["a.abc", "b.abc"]
+                .into_iter()
+                .map(|file_name| {
+                    let state = state.clone();
+                    scope.spawn(move || {
+                        let editor_state = state.read();
+                        let fs = editor_state.get_file_state(file_name.to_string()).unwrap();
+                        fs.leaves()
+                            .map(|(_, label)| label.to_string())
+                            .collect::<String>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(leaves, vec!["ac", "df"]);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let state = SharedEditorState::new(EditorState::new());
+        state
+            .write()
+            .modify_file("a.abc".to_string(), "b\na c".to_string())
+            .unwrap();
+        let snapshot = state.snapshot("a.abc").unwrap();
+        let text = state.text_snapshot("a.abc").unwrap();
+
+        // changes made after the snapshot was taken don't show up in it
+        thread::spawn({
+            let state = state.clone();
+            move || {
+                state
+                    .write()
+                    .modify_file("a.abc".to_string(), "x".to_string())
+                    .unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+        assert_eq!(snapshot.text().to_string(), "b\na c");
+        assert_eq!(text.to_string(), "b\na c");
+        assert_eq!(state.text_snapshot("a.abc").unwrap().to_string(), "x");
+        assert!(state.snapshot("b.abc").is_none());
+    }
+
+    #[test]
+    fn test_queries_take_the_read_lock() {
+        let state = SharedEditorState::default();
+        state
+            .write()
+            .modify_file("file:///a.abc".to_string(), "a\nb c".to_string())
+            .unwrap();
+        let (mut context, writer) = initialized();
+
+        // another thread reading the state doesn't hold up a hover, a write would wait for it
+        let (ready, wait) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let reader = thread::spawn({
+            let state = state.clone();
+            move || {
+                let _guard = state.read();
+                ready.send(()).unwrap();
+                released.recv_timeout(Duration::from_secs(5)).is_ok()
+            }
+        });
+        wait.recv().unwrap();
+        handle_shared_message(
+            hover("file:///a.abc"),
+            &state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        release.send(()).unwrap();
+        assert!(reader.join().unwrap());
+        let hovered = response(&sent(&writer), 1);
+        assert_eq!(hovered["result"]["contents"]["value"], "Parent: a");
+    }
+
+    #[test]
+    fn test_queries_read_unopened_files() {
+        let root = std::env::temp_dir().join(format!("lsp-rs-shared-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.abc"), "a\nb c").unwrap();
+        let uri = path_to_uri(&root.join("a.abc"));
+
+        let state = SharedEditorState::default();
+        let (mut context, writer) = initialized();
+        handle_shared_message(hover(&uri), &state, &mut context, &mut Vec::new()).unwrap();
+        let hovered = response(&sent(&writer), 1);
+        assert_eq!(hovered["result"]["contents"]["value"], "Parent: a");
+        assert!(state.snapshot(&uri).is_some());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tree_diff {
+    use crate::editor::{FileState, TreeChange};
+
+    fn diff(old: &str, new: &str) -> Vec<String> {
+        let old = FileState::new(old.to_string()).unwrap();
+        let new = FileState::new(new.to_string()).unwrap();
+        FileState::diff(&old, &new)
+            .iter()
+            .map(|change| change.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        assert!(diff("d\nb f\na c", "d\nb f\na c").is_empty());
+        assert_eq!(diff("d\nb f", "d\nb f\na c"), vec!["+3 a", "+4 c"]);
+        assert_eq!(diff("d\nb f\na c", "d\nb f"), vec!["-3 a", "-4 c"]);
+        assert_eq!(
+            diff("d\nb f\na c", "d\nx f\na _ g"),
+            vec!["~1 b -> x", "-4 c", "+5 g"]
+        );
+        assert_eq!(diff("", "a"), vec!["+0 a"]);
+
+        let old = FileState::new("a\nb c".to_string()).unwrap();
+        let new = FileState::new("a\nb".to_string()).unwrap();
+        let changes = FileState::diff(&old, &new);
+        assert_eq!(
+            changes,
+            vec![TreeChange::Removed {
+                index: 2,
+                label: "c".to_string()
+            }]
+        );
+        assert_eq!(changes[0].index(), 2);
+    }
+}
+
+#[cfg(test)]
+mod document_stats {
+    use std::collections::BTreeMap;
+
+    use serde_json::Value;
+
+    use crate::editor::{EditorState, FileState};
+    use crate::lsp::handle_message;
+    use crate::test::util::{capture, initialized};
+
+    #[test]
+    fn test_stats() {
+        let stats = FileState::new("a\nb a\n_ c a".to_string()).unwrap().stats();
+        assert_eq!(stats.node_count, 5);
+        assert_eq!(stats.leaf_count, 2);
+        assert_eq!(stats.max_depth, Some(2));
+        assert_eq!(stats.completeness, 5.0 / 7.0);
+        assert_eq!(
+            stats.labels,
+            BTreeMap::from([
+                ("a".to_string(), 3),
+                ("b".to_string(), 1),
+                ("c".to_string(), 1)
+            ])
+        );
+
+        let empty = FileState::new(String::new()).unwrap().stats();
+        assert_eq!((empty.node_count, empty.max_depth), (0, None));
+        assert_eq!(empty.completeness, 1.0);
+    }
+
+    #[test]
+    fn test_document_stats_request() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a\nb c".to_string())
+            .unwrap();
+        let request = |uri: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":2,"method":"lsp-rs/documentStats","params":{{"textDocument":{{"uri":"{}"}}}}}}"#,
+                uri
+            )
+        };
+        let sent = capture(&output, || {
+            for uri in ["file:///a.abc", "file:///missing.abc"] {
+                handle_message(
+                    request(uri),
+                    &mut editor_state,
+                    &mut context,
+                    &mut Vec::new(),
+                )
+                .unwrap();
+            }
+        });
+        assert_eq!(
+            sent[0],
+            r#"{"jsonrpc":"2.0","id":2,"result":{"nodeCount":3,"leafCount":2,"maxDepth":1,"completeness":1.0,"labels":{"a":1,"b":1,"c":1}}}"#
+        );
+        let response: Value = serde_json::from_str(&sent[1]).unwrap();
+        assert!(response["error"].is_object(), "{}", sent[1]);
+    }
+}
+
+#[cfg(test)]
+mod line_endings {
+    use crate::editor::{EditorState, FileState, LineEnding};
+    use crate::rope::Rope;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            LineEnding::detect(&Rope::from("a\r\nb c")),
+            LineEnding::CrLf
+        );
+        assert_eq!(
+            LineEnding::detect(&Rope::from("a\nb c\r\n")),
+            LineEnding::Lf
+        );
+        assert_eq!(LineEnding::detect(&Rope::from("a")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("\n")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("é\n")), LineEnding::Lf);
+    }
+
+    #[test]
+    fn test_parse_crlf() {
+        for text in ["a\r\nb c\r\n", "a\r\nb c", "a\r\nb c\r", "a\nb c\n"] {
+            let fs = FileState::new(text.to_string()).unwrap();
+            assert_eq!(fs.iter_bfs().count(), 3, "{:?}", text);
+        }
+        let fs = FileState::new("a\r\nb c\r\n".to_string()).unwrap();
+        assert_eq!(fs.get_char_count(), 6);
+        assert_eq!(fs.line_ending(), LineEnding::CrLf);
+    }
+
+    #[test]
+    fn test_edits_keep_line_endings() {
+        let mut fs = FileState::new("a\r\nb c\r\n".to_string()).unwrap();
+        fs.set_node(2, "d").unwrap();
+        assert_eq!(fs.text().to_string(), "a\r\nb d\r\n");
+        fs.delete_subtree(1).unwrap();
+        assert_eq!(fs.text().to_string(), "a\r\n_ d\r\n");
+
+        // a `\r` added before the first line break makes the document a `\r\n` one
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("a.abc".to_string(), "a\nb c".to_string())
+            .unwrap();
+        editor_state
+            .edit_file("a.abc", 1..1, "\r")
+            .unwrap()
+            .unwrap();
+        let fs = editor_state.get_file_state("a.abc".to_string()).unwrap();
+        assert_eq!(fs.line_ending(), LineEnding::CrLf);
+        assert_eq!(fs.render(), "a\r\nb c\r\n");
+    }
+}
+
+#[cfg(test)]
+mod text_decoding {
+    use std::fs;
+
+    use crate::editor::{EditorState, FileState};
+    use crate::encoding::{decode, strip_bom, TextEncoding};
+    use crate::large_file::validate_bytes;
+    use crate::lsp::handle_message;
+    use crate::test::util::{initialized, response, sent};
+    use crate::uri::path_to_uri;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode("a\nb é".as_bytes().to_vec()),
+            ("a\nb é".to_string(), TextEncoding::Utf8)
+        );
+        assert_eq!(
+            decode(b"a\nb \xe9".to_vec()),
+            ("a\nb é".to_string(), TextEncoding::Latin1)
+        );
+        assert_eq!(
+            decode(b"\xef\xbb\xbfa\nb \xe9".to_vec()),
+            ("\u{feff}a\nb é".to_string(), TextEncoding::Latin1)
+        );
+        assert_eq!(strip_bom("\u{feff}a".to_string()), ("a".to_string(), true));
+        assert_eq!(strip_bom("a".to_string()), ("a".to_string(), false));
+    }
+
+    #[test]
+    fn test_bom_is_recorded() {
+        let fs = FileState::new("\u{feff}a\nb c".to_string()).unwrap();
+        assert!(fs.has_bom());
+        assert_eq!(fs.text().to_string(), "a\nb c");
+        assert_eq!(fs.get(0).unwrap(), "a");
+        assert_eq!(fs.position_of(0), Some((0, 0)));
+        assert!(!FileState::new("a".to_string()).unwrap().has_bom());
+
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("a.abc".to_string(), "\u{feff}a\nb c".to_string())
+            .unwrap();
+        assert_eq!(editor_state.text("a.abc").unwrap().to_string(), "a\nb c");
+        // the text the tree was parsed from is the latest one, so edits are incremental
+        editor_state.edit_file("a.abc", 2..3, "d").unwrap().unwrap();
+        let fs = editor_state.get_file_state("a.abc".to_string()).unwrap();
+        assert!(fs.has_bom());
+        assert_eq!(fs.get(1).unwrap(), "d");
+
+        assert_eq!(validate_bytes(b"\xef\xbb\xbfa\nb c").unwrap().nodes, 3);
+    }
+
+    #[test]
+    fn test_latin1_file_from_disk() {
+        let root = std::env::temp_dir().join(format!("lsp-rs-latin1-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.abc"), b"a\nb \xe9\n").unwrap();
+        let uri = path_to_uri(&root.join("a.abc"));
+
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        let hover = format!(
+            r#"{{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{{"textDocument":{{"uri":"{}"}},"position":{{"line":1,"character":2}}}}}}"#,
+            uri
+        );
+        let mut logger = Vec::new();
+        handle_message(hover, &mut editor_state, &mut context, &mut logger).unwrap();
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("read it as Latin-1"), "{}", logged);
+        // labels are ascii, but the document is read as it is instead of with replacement
+        // characters
+        let error = &response(&sent(&writer), 2)["error"];
+        assert_eq!(error["code"], -33001);
+        assert!(
+            error["message"]
+                .as_str()
+                .unwrap()
+                .contains("not a valid complete binary tree"),
+            "{}",
+            error
+        );
+        assert_eq!(editor_state.text(&uri).unwrap().to_string(), "a\nb é\n");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod background_parsing {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::background::BackgroundParses;
+    use crate::editor::{EditorState, FileState};
+    use serde_json::json;
+
+    use crate::lsp::{handle_due_revalidations, handle_message, ServerContext};
+    use crate::rope::Rope;
+    use crate::test::util::{context, response, sent};
+
+    /// Handle revalidations until the background parses are done, returning the log
+    fn wait(editor_state: &mut EditorState, context: &mut ServerContext) -> String {
+        let mut logger = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while context.background_parse_count() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+            handle_due_revalidations(editor_state, context, &mut logger, Instant::now());
+        }
+        String::from_utf8(logger).unwrap()
+    }
+
+    #[test]
+    fn test_background_parses() {
+        let mut parses = BackgroundParses::new();
+        assert!(parses
+            .start("a".to_string(), Rope::from("a\nb"), 1)
+            .is_none());
+        assert_eq!(
+            parses.start("a".to_string(), Rope::from("a\nb c"), 2),
+            Some(1)
+        );
+        assert!(parses
+            .start("b".to_string(), Rope::from("a b"), 3)
+            .is_none());
+        assert!(parses.is_parsing("a"));
+        assert_eq!(parses.cancel("b"), Some(3));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut finished = Vec::new();
+        while finished.is_empty() && Instant::now() < deadline {
+            finished = parses.take_finished();
+        }
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].value, 2);
+        let fs = finished[0].result.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(fs.iter_bfs().count(), 3);
+        assert!(parses.is_empty());
+
+        // the thread of a dropped parse gives up
+        let parse = FileState::from_rope_until(Rope::from("a\nb c"), || true);
+        assert!(parse.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_large_document_is_indexed() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":1,"capabilities":{},"initializationOptions":{"lazyParseSize":5}}}"#;
+        handle_message(
+            initialize.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        let open = |text: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.abc","languageId":"abc","version":1,"text":"{}"}}}}}}"#,
+                text
+            )
+        };
+        let hover = r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.abc"},"position":{"line":1,"character":0}}}"#;
+
+        let mut logger = Vec::new();
+        handle_message(
+            open("a\\nb c"),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        handle_message(
+            hover.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let logged = String::from_utf8(logger).unwrap();
+        assert!(logged.contains("in the background"), "{}", logged);
+        let error = &response(&sent(&writer), 2)["error"];
+        assert_eq!(error["data"]["kind"], "indexingInProgress");
+        assert!(context.next_revalidation().is_some());
+
+        wait(&mut editor_state, &mut context);
+        let diagnostics = &sent(&writer)[0];
+        assert_eq!(diagnostics["method"], "textDocument/publishDiagnostics");
+        assert_eq!(diagnostics["params"]["diagnostics"], json!([]));
+        handle_message(
+            hover.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        let hover_result = &response(&sent(&writer), 2)["result"];
+        assert_eq!(hover_result["contents"]["value"], "Parent: a");
+
+        // a text that is not a tree is reported once it is parsed, the last tree is kept
+        handle_message(
+            open("a\\nb c d"),
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        wait(&mut editor_state, &mut context);
+        let diagnostics = &sent(&writer)[0]["params"]["diagnostics"];
+        let message = diagnostics[0]["message"].as_str().unwrap();
+        assert!(
+            message.contains("Not a valid complete binary tree"),
+            "{}",
+            message
+        );
+        let fs = editor_state
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.text().to_string(), "a\nb c");
+        assert!(context.next_revalidation().is_none());
+    }
+}
+
+#[cfg(test)]
+mod label_arena {
+    use crate::arena::LabelArena;
+
+    #[test]
+    fn test_arena() {
+        let mut arena: LabelArena = [Some("a"), None, Some("c")].into_iter().collect();
+        assert_eq!(arena.len(), 3);
+        assert_eq!(
+            arena.iter().collect::<Vec<_>>(),
+            vec![Some("a"), None, Some("c")]
+        );
+        assert_eq!(arena.count(0..10), 2);
+        assert_eq!(arena.get(3), None);
+
+        arena.set(1, Some("b"));
+        assert_eq!(arena.get(1), Some("b"));
+        assert_eq!(arena.take(0), Some("a".to_string()));
+        assert_eq!(arena.take(0), None);
+        arena.resize(6);
+        assert_eq!(arena.len(), 6);
+        arena.trim_holes();
+        assert_eq!(
+            arena.iter().collect::<Vec<_>>(),
+            vec![None, Some("b"), Some("c")]
+        );
+    }
+
+    #[test]
+    fn test_relabeling_compacts() {
+        let mut arena: LabelArena = [Some("a"), Some("b")].into_iter().collect();
+        // every relabel leaves the old label behind until the buffer is compacted
+        for label in ["c", "d", "e", "f", "g", "h"] {
+            arena.set(0, Some(label));
+            assert_eq!(arena.get(0), Some(label));
+            assert_eq!(arena.get(1), Some("b"));
+            assert!(arena.buffer_len() <= 4, "{:?}", arena);
+        }
+        arena.clear();
+        assert!(arena.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod session {
+    use crate::editor::EditorState;
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("lsp-rs-session-{}.json", std::process::id()));
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file(
+                "file:///a.abc".to_string(),
+                "\u{feff}a\r\nb c\r\n".to_string(),
+            )
+            .unwrap();
+        editor_state.set_open("file:///a.abc", true);
+        editor_state.set_version("file:///a.abc", 3);
+        editor_state.set_language_id("file:///a.abc", "abc");
+        // the text is no longer a tree, the last valid one is kept
+        editor_state.set_text("file:///a.abc", "a\nb".to_string());
+        editor_state.set_dirty("file:///a.abc", true);
+        editor_state.set_language_id("file:///notes.txt", "plaintext");
+        editor_state.set_text("file:///notes.txt", "hello".to_string());
+        editor_state.set_open("file:///notes.txt", true);
+        // closed files are not part of the session
+        editor_state
+            .modify_file("file:///closed.abc".to_string(), "x".to_string())
+            .unwrap();
+        editor_state.save_session(&path).unwrap();
+
+        let mut restored = EditorState::new();
+        assert_eq!(restored.restore_session(&path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert!(restored.is_open("file:///a.abc"));
+        assert_eq!(restored.version("file:///a.abc"), Some(3));
+        assert_eq!(restored.language_id("file:///a.abc"), Some("abc"));
+        assert!(restored.is_dirty("file:///a.abc"));
+        assert_eq!(restored.text("file:///a.abc").unwrap().to_string(), "a\nb");
+        let fs = restored
+            .get_file_state("file:///a.abc".to_string())
+            .unwrap();
+        assert_eq!(fs.text().to_string(), "a\r\nb c\r\n");
+        assert!(fs.has_bom());
+        assert_eq!(
+            restored.text("file:///notes.txt").unwrap().to_string(),
+            "hello"
+        );
+        assert!(restored
+            .get_file_state("file:///notes.txt".to_string())
+            .is_none());
+        assert!(restored
+            .get_file_state("file:///closed.abc".to_string())
+            .is_none());
+    }
+
+    #[test]
+    fn test_restore_keeps_open_documents() {
+        let path =
+            std::env::temp_dir().join(format!("lsp-rs-session-open-{}.json", std::process::id()));
+        let mut editor_state = EditorState::new();
+        editor_state
+            .modify_file("file:///a.abc".to_string(), "a".to_string())
+            .unwrap();
+        editor_state.set_open("file:///a.abc", true);
+        // a file that only shares the stem is not used as the temporary file
+        let sibling = path.with_extension("tmp");
+        std::fs::write(&sibling, "kept").unwrap();
+        editor_state.save_session(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&sibling).unwrap(), "kept");
+        std::fs::remove_file(&sibling).unwrap();
+
+        let mut reopened = EditorState::new();
+        reopened
+            .modify_file("file:///a.abc".to_string(), "b".to_string())
+            .unwrap();
+        reopened.set_open("file:///a.abc", true);
+        assert_eq!(reopened.restore_session(&path).unwrap(), 0);
+        assert_eq!(reopened.text("file:///a.abc").unwrap().to_string(), "b");
+
+        std::fs::write(&path, "{\"format\":0,\"documents\":[]}").unwrap();
+        assert!(reopened.restore_session(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(reopened
+            .restore_session(&path)
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound));
+    }
+}
+
+#[cfg(test)]
+mod error_responses {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{context, initialized, sent};
+
+    /// The response to the message, None if it got none
+    fn reply(message: &str) -> Option<Value> {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        assert!(handle_message(
+            message.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .is_err());
+        sent(&writer)
+            .into_iter()
+            .find(|message| message.get("method").is_none())
+    }
+
+    /// The code and the message of the error the response carries
+    fn error(response: Option<Value>) -> (Value, String) {
+        let error = &response.unwrap()["error"];
+        (
+            error["code"].clone(),
+            error["message"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let response = reply("{\"jsonrpc\":\"2.0\",");
+        assert_eq!(response.as_ref().unwrap()["id"], Value::Null);
+        let (code, message) = error(response);
+        assert_eq!(code, -32700);
+        assert_eq!(message, "EOF while parsing a value at line 1 column 17");
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let invalid = "expected a request, a notification or a response";
+        // no method, and not a response to a request of the server
+        let response = reply(r#"{"jsonrpc":"2.0","id":"3","params":{}}"#);
+        assert_eq!(response.as_ref().unwrap()["id"], "3");
+        assert_eq!(error(response), (Value::from(-32600), invalid.to_string()));
+
+        // neither a method nor an id
+        let response = reply(r#"{"jsonrpc":"2.0","params":{}}"#);
+        assert_eq!(response.as_ref().unwrap()["id"], Value::Null);
+        assert_eq!(error(response), (Value::from(-32600), invalid.to_string()));
+
+        // an id without a result or an error is not a response
+        let response = reply(r#"{"jsonrpc":"2.0","id":3,"params":{}}"#);
+        assert_eq!(response.as_ref().unwrap()["id"], 3);
+        assert_eq!(error(response), (Value::from(-32600), invalid.to_string()));
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let response = reply(
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{"position":1}}"#,
+        );
+        assert_eq!(response.as_ref().unwrap()["id"], 4);
+        let (code, message) = error(response);
+        assert_eq!(code, -32602);
+        assert!(
+            message.starts_with("Could not parse HoverRequest"),
+            "{}",
+            message
+        );
+
+        // a notification has no response to carry the error
+        let response = reply(r#"{"jsonrpc":"2.0","method":"textDocument/didClose","params":{}}"#);
+        assert!(response.is_none(), "{:?}", response);
+    }
+
+    #[test]
+    fn test_server_not_initialized() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = context();
+        let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let initialize =
+            r#"{"jsonrpc":"2.0","id":2,"method":"initialize","params":{"processId":1}}"#;
+        for message in [shutdown, initialize, shutdown] {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        }
+        let sent = sent(&writer);
+        let responses: Vec<_> = sent
+            .iter()
+            .filter(|message| message.get("method").is_none())
+            .collect();
+        let (code, message) = error(Some(responses[0].clone()));
+        assert_eq!(code, -32002);
+        assert_eq!(message, "shutdown was sent before initialize");
+        // once initialized the same request is answered
+        assert!(responses[1]["result"].is_object());
+        assert_eq!(responses[2]["id"], 1);
+        assert_eq!(responses[2]["result"], Value::Null);
+    }
+}
+
+#[cfg(test)]
+mod request_ids {
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, RequestId, RequestMessage};
+    use crate::rpc::json_from_string;
+    use crate::test::util::{initialized, response, sent};
+
+    #[test]
+    fn test_number_or_string() {
+        let request: RequestMessage =
+            json_from_string(r#"{"jsonrpc":"2.0","id":"a-1","method":"shutdown"}"#).unwrap();
+        assert_eq!(request.id, RequestId::from("a-1"));
+        let request: RequestMessage =
+            json_from_string(r#"{"jsonrpc":"2.0","id":7,"method":"shutdown"}"#).unwrap();
+        assert_eq!(request.id, RequestId::from(7));
+        assert_eq!(RequestId::from("a-1").to_string(), "\"a-1\"");
+    }
+
+    #[test]
+    fn test_string_id_is_answered() {
+        let mut editor_state = EditorState::new();
+        let (mut context, writer) = initialized();
+        let mut logger = Vec::new();
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.abc","languageId":"abc","version":0,"text":"a\nb c"}}}"#;
+        let hover = r#"{"jsonrpc":"2.0","id":"hover-1","method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.abc"},"position":{"line":0,"character":0}}}"#;
+        for message in [open, hover] {
+            handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        }
+        assert!(response(&sent(&writer), "hover-1").get("result").is_some());
+
+        // the server never sent a request with a string id
+        let response = r#"{"jsonrpc":"2.0","id":"hover-1","result":null}"#;
+        assert!(handle_message(
+            response.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut Vec::new(),
+        )
+        .is_err());
+    }
+}
+
+#[cfg(test)]
+mod incoming_messages {
+    use crate::lsp::{IncomingMessage, RequestId};
+
+    #[test]
+    fn test_kinds() {
+        let request =
+            r#"{"jsonrpc":"2.0","id":"1","method":"textDocument/hover","params":{"a":1}}"#;
+        match IncomingMessage::parse(request).unwrap() {
+            IncomingMessage::Request { id, method, params } => {
+                assert_eq!(id, RequestId::from("1"));
+                assert_eq!(method, "textDocument/hover");
+                assert_eq!(params.unwrap().get(), r#"{"a":1}"#);
+            }
+            message => panic!("expected a request, got {:?}", message),
+        }
+        let notification = r#"{"jsonrpc":"2.0","method":"initialized"}"#;
+        assert!(matches!(
+            IncomingMessage::parse(notification).unwrap(),
+            IncomingMessage::Notification { params, .. } if params.is_none()
+        ));
+        let response = r#"{"jsonrpc":"2.0","id":2,"result":null}"#;
+        assert!(matches!(
+            IncomingMessage::parse(response).unwrap(),
+            IncomingMessage::Response(response) if response.id == RequestId::from(2)
+        ));
+        let error = r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32800,"message":"cancelled"}}"#;
+        assert!(matches!(
+            IncomingMessage::parse(error).unwrap(),
+            IncomingMessage::Response(response) if response.error.is_some()
+        ));
+    }
+
+    #[test]
+    fn test_params_are_not_parsed() {
+        // params are kept as sent, even if they are not valid for the method
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{ "position" : [1, 2] }}"#;
+        match IncomingMessage::parse(request).unwrap() {
+            IncomingMessage::Request { params, .. } => {
+                assert_eq!(params.unwrap().get(), r#"{ "position" : [1, 2] }"#);
+            }
+            message => panic!("expected a request, got {:?}", message),
+        }
+        let notification = r#"{"jsonrpc":"2.0","method":"$/custom","params":null}"#;
+        assert!(matches!(
+            IncomingMessage::parse(notification).unwrap(),
+            IncomingMessage::Notification { params: Some(params), .. } if params.get() == "null"
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for message in [
+            r#"{"jsonrpc":"2.0","id":2}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":3}"#,
+            r#"{"jsonrpc":"2.0","id":[2],"method":"shutdown"}"#,
+            r#"[]"#,
+        ] {
+            assert!(IncomingMessage::parse(message).is_err(), "{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod output {
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use std::io::{self, Write};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    use crate::output::{BufferedWriter, FlushPolicy, MemoryWriter, MessageWriter, Output};
+    use crate::rpc::{encode_message, BufferedReader};
+    use crate::test::util::{capture, initialized};
+
+    #[derive(Clone, Default)]
+    struct SharedBytes(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBytes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_buffered_writer() {
+        for policy in [
+            FlushPolicy::EveryMessage,
+            FlushPolicy::WhenIdle { max_batch: 100 },
+        ] {
+            let out = SharedBytes::default();
+            let mut writer = BufferedWriter::new(out.clone(), policy);
+            let threads: Vec<_> = (0..4)
+                .map(|thread| {
+                    let mut writer = writer.clone();
+                    thread::spawn(move || {
+                        for i in 0..50 {
+                            let content = format!("{}-{}-{}", thread, i, "x".repeat(i * 10));
+                            writer
+                                .write_message(encode_message(content).as_bytes())
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            writer.flush().unwrap();
+
+            // every message is whole, and the messages of a thread are in order
+            let mut reader = BufferedReader::new();
+            reader.write(&out.0.lock().unwrap());
+            let mut next = [0; 4];
+            while let Some(content) = reader.pop_message().unwrap() {
+                let mut parts = content.splitn(3, '-');
+                let thread: usize = parts.next().unwrap().parse().unwrap();
+                let i: usize = parts.next().unwrap().parse().unwrap();
+                assert_eq!(parts.next().unwrap(), "x".repeat(i * 10));
+                assert_eq!(i, next[thread]);
+                next[thread] += 1;
+            }
+            assert_eq!(next, [50; 4]);
+        }
+    }
+
+    #[test]
+    fn test_buffered_writer_client_gone() {
+        let mut writer = BufferedWriter::new(Closed, FlushPolicy::default());
+        writer
+            .write_message(encode_message("{}".to_string()).as_bytes())
+            .unwrap();
+        assert!(writer.flush().is_err());
+    }
+
+    /// Blocks the writes until the sender is dropped
+    struct Gate(mpsc::Receiver<()>);
+
+    impl Write for Gate {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered_writer_queued() {
+        let (open, gate) = mpsc::channel();
+        let mut writer = BufferedWriter::new(Gate(gate), FlushPolicy::default());
+        // larger than the buffer, so the first message is stuck in the write
+        let message = encode_message("x".repeat(10_000));
+        for _ in 0..3 {
+            writer.write_message(message.as_bytes()).unwrap();
+        }
+        assert_eq!(writer.queued(), 3);
+        let output = Output::new(Box::new(writer.clone()));
+        assert_eq!(output.queued(), 3);
+
+        drop(open);
+        writer.flush().unwrap();
+        assert_eq!(writer.queued(), 0);
+        assert_eq!(output.queued(), 0);
+    }
+
+    #[test]
+    fn test_memory_writer() {
+        let mut writer = MemoryWriter::new();
+        writer
+            .write_message(encode_message("{}".to_string()).as_bytes())
+            .unwrap();
+        assert_eq!(writer.take(), vec!["{}".to_string()]);
+        assert!(writer.messages().is_empty());
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let writer = MemoryWriter::new();
+        context.set_output(Output::new(Box::new(writer.clone())));
+        let output = context.output().clone();
+        let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let sent = capture(&output, || {
+            handle_message(
+                shutdown.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        });
+        assert_eq!(sent, vec![r#"{"jsonrpc":"2.0","id":1,"result":null}"#]);
+
+        // the writer the output had is put back
+        output
+            .write_message(encode_message("{}".to_string()).as_bytes())
+            .unwrap();
+        assert_eq!(writer.take(), vec!["{}".to_string()]);
+    }
+}
+
+#[cfg(test)]
+mod transport {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    use serde_json::{json, Value};
+
+    use crate::cli::ServeOptions;
+    use crate::middleware::Observer;
+    use crate::output::{MemoryWriter, MessageWriter, Output};
+    use crate::record::{is_recording, transcript};
+    use crate::test::util::capture;
+    use crate::testing::check::TranscriptEntry;
+    use crate::transport::{serve, Server};
+
+    fn options(name: &str) -> ServeOptions {
+        let log = std::env::temp_dir().join(format!("lsp-rs-{}-{}.log", name, std::process::id()));
+        ServeOptions {
+            log_file: Some(log.to_string_lossy().to_string()),
+            ..ServeOptions::default()
+        }
+    }
+
+    const INITIALIZE: &str = "Content-Length: 71\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":0,\"method\":\"initialize\",\"params\":{\"processId\":1}}";
+    const SHUTDOWN: &str =
+        "Content-Length: 44\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"shutdown\"}";
+
+    /// A server the client sent `initialize` to, with its response left out of the output
+    fn initialized(options: &ServeOptions, output: Output) -> Server {
+        let mut server = Server::new(options, output.clone());
+        capture(&output, || {
+            server
+                .receive(Instant::now(), INITIALIZE.as_bytes())
+                .unwrap();
+        });
+        server
+    }
+
+    #[test]
+    fn test_server_receives_chunks() {
+        let output = Output::default();
+        let options = options("transport");
+        let mut server = initialized(&options, output.clone());
+        let sent = capture(&output, || {
+            server
+                .receive(Instant::now(), &SHUTDOWN.as_bytes()[..30])
+                .unwrap();
+        });
+        assert!(sent.is_empty());
+        let sent = capture(&output, || {
+            server
+                .receive(Instant::now(), &SHUTDOWN.as_bytes()[30..])
+                .unwrap();
+            assert!(server.next_revalidation().is_none());
+            server.finish();
+        });
+        // the frame is only handled once its second chunk arrives
+        assert_eq!(sent[0], r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    struct Gone;
+
+    impl MessageWriter for Gone {
+        fn write_message(&mut self, _: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_server_stops_once_client_gone() {
+        let options = options("gone");
+        let mut server = Server::new(&options, Output::new(Box::new(Gone)));
+        let error = server.receive(Instant::now(), SHUTDOWN.as_bytes());
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        server.finish();
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    #[derive(Clone, Default)]
+    struct Seen(Rc<RefCell<Vec<String>>>);
+
+    impl Observer for Seen {
+        fn on_incoming(&mut self, message: &str) {
+            self.0.borrow_mut().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_serve_with_middleware() {
+        let options = options("serve");
+        let memory = MemoryWriter::new();
+        let server = Server::new(&options, Output::new(Box::new(memory.clone())));
+        let seen = Seen::default();
+        server.middleware().add_observer(Box::new(seen.clone()));
+        serve(
+            server,
+            io::Cursor::new(format!("{}{}", INITIALIZE, SHUTDOWN)),
+        );
+        assert_eq!(
+            seen.0.borrow().last().unwrap(),
+            r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#
+        );
+        assert!(memory
+            .messages()
+            .contains(&r#"{"jsonrpc":"2.0","id":1,"result":null}"#.to_string()));
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    #[test]
+    fn test_server_resynchronizes() {
+        let output = Output::default();
+        let options = options("resynchronize");
+        let mut server = initialized(&options, output.clone());
+        let sent = capture(&output, || {
+            server
+                .receive(
+                    Instant::now(),
+                    format!("Content-Length: x\r\n\r\n{{}}{}", SHUTDOWN).as_bytes(),
+                )
+                .unwrap();
+            server.finish();
+        });
+        // errors are also mirrored to the client as logs
+        let messages: Vec<_> = sent
+            .into_iter()
+            .filter(|m| !m.contains("window/logMessage"))
+            .collect();
+        assert_eq!(
+            messages[0],
+            r#"{"error":{"code":-32700,"message":"Could not parse content length to number"},"id":null,"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(messages[1], r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    fn recording(name: &str, chunks: &[&[u8]]) -> String {
+        // the recording writer is kept, the messages themselves aren't checked
+        let output = Output::new(Box::new(MemoryWriter::new()));
+        let record =
+            std::env::temp_dir().join(format!("lsp-rs-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&record);
+        let options = ServeOptions {
+            record_file: Some(record.to_string_lossy().to_string()),
+            ..options(name)
+        };
+        let mut server = initialized(&options, output);
+        for chunk in chunks {
+            server.receive(Instant::now(), chunk).unwrap();
+        }
+        server.finish();
+        let recording = std::fs::read_to_string(&record).unwrap();
+        let _ = std::fs::remove_file(record);
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+        recording
+    }
+
+    #[test]
+    fn test_server_records_frames() {
+        let recording = recording("record", &[SHUTDOWN.as_bytes()]);
+        let lines: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // the bytes are recorded as they were read, header included
+        assert_eq!(lines[1]["dir"], "in");
+        assert_eq!(lines[1]["data"], SHUTDOWN);
+        assert!(lines.iter().all(|line| line["ts"].is_u64()));
+        let response = "Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}";
+        assert!(lines
+            .iter()
+            .any(|line| line["dir"] == "out" && line["data"] == response));
+
+        let entries = transcript(&recording).unwrap();
+        assert!(entries.contains(&TranscriptEntry::In(
+            json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})
+        )));
+        // the response is recorded, along with the log messages
+        assert!(entries.contains(&TranscriptEntry::Out(
+            json!({"jsonrpc": "2.0", "id": 1, "result": null})
+        )));
+    }
+
+    #[test]
+    fn test_server_records_skipped_frames() {
+        // split in the middle of the header, after a frame the reader can't parse
+        let malformed = format!("Content-Length: x\r\n\r\n{{}}{}", SHUTDOWN);
+        let (first, second) = malformed.as_bytes().split_at(30);
+        let recording = recording("record-skipped", &[first, second, &[0xff]]);
+        let read: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|line| line["dir"] == "in")
+            .map(|line| line["data"].clone())
+            .collect();
+        assert_eq!(read[0], INITIALIZE);
+        assert_eq!(read[1].as_str().unwrap().as_bytes(), first);
+        assert_eq!(read[2].as_str().unwrap().as_bytes(), second);
+        // bytes that are not utf-8 are kept as numbers
+        assert_eq!(read[3], json!([255]));
+
+        let entries = transcript(&recording).unwrap();
+        let inputs: Vec<_> = entries
+            .iter()
+            .filter(|entry| matches!(entry, TranscriptEntry::In(_)))
+            .collect();
+        assert_eq!(
+            inputs[1..],
+            [&TranscriptEntry::In(
+                json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})
+            )]
+        );
+        assert!(is_recording(&recording));
+        assert!(!is_recording(r#"{"in":{"jsonrpc":"2.0","method":"exit"}}"#));
+    }
+
+    #[test]
+    fn test_run_tcp() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = options("transport-tcp");
+        let server = {
+            let options = options.clone();
+            std::thread::spawn(move || crate::transport::run_tcp(&options, listener))
+        };
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(INITIALIZE.as_bytes()).unwrap();
+        client.write_all(SHUTDOWN.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(
+            received.contains(
+                "Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}"
+            ),
+            "{}",
+            received
+        );
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_pipe() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("lsp-rs-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let options = options("transport-pipe");
+        let server = {
+            let (options, path) = (options.clone(), path.to_string_lossy().to_string());
+            std::thread::spawn(move || crate::transport::run_pipe(&options, &path))
+        };
+        let (mut client, _) = listener.accept().unwrap();
+        client.write_all(INITIALIZE.as_bytes()).unwrap();
+        client.write_all(SHUTDOWN.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(
+            received.contains(
+                "Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}"
+            ),
+            "{}",
+            received
+        );
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn test_run_websocket() {
+        use std::net::{TcpListener, TcpStream};
+        use tungstenite::Message;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = options("transport-websocket");
+        let server = {
+            let options = options.clone();
+            std::thread::spawn(move || crate::websocket::run_websocket(&options, listener))
+        };
+        let stream = TcpStream::connect(address).unwrap();
+        let (mut client, _) = tungstenite::client(format!("ws://{}", address), stream).unwrap();
+        // the server answers pings while it waits for messages
+        client.send(Message::Ping(vec![1].into())).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Pong(vec![1].into()));
+        let initialize =
+            r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{"processId":1}}"#;
+        client.send(Message::text(initialize)).unwrap();
+        let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        client.send(Message::text(shutdown)).unwrap();
+        // every message is a text message without the header, the logs of initialize
+        // and its response come first
+        let response = Message::text(r#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        while client.read().unwrap() != response {}
+        client.close(None).unwrap();
+        while client.read().is_ok() {}
+        server.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_run_async() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let options = options("transport-async");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let received = runtime.block_on(async {
+            let (mut client, server_input) = tokio::io::duplex(1024);
+            let (server_output, mut client_output) = tokio::io::duplex(1024);
+            client.write_all(INITIALIZE.as_bytes()).await.unwrap();
+            client.write_all(SHUTDOWN.as_bytes()).await.unwrap();
+            drop(client);
+            // the responses are read as they come, they don't all fit the pipe
+            let mut received = String::new();
+            tokio::join!(
+                crate::transport::run_async(&options, server_input, server_output),
+                client_output.read_to_string(&mut received),
+            )
+            .1
+            .unwrap();
+            received
+        });
+        assert!(
+            received.contains(
+                "Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}"
+            ),
+            "{}",
+            received
+        );
+        let _ = std::fs::remove_file(options.log_file.unwrap());
+    }
+}
+
+#[cfg(test)]
+mod batches {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::test::util::{capture, initialized};
+
+    fn replies(message: &str) -> (Vec<String>, bool) {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let mut handled = false;
+        let sent = capture(&output, || {
+            handled = handle_message(
+                message.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .is_ok();
+        });
+        (sent, handled)
+    }
+
+    #[test]
+    fn test_batch_replies_with_array() {
+        let (messages, handled) = replies(
+            r#"[
+                {"jsonrpc":"2.0","id":1,"method":"shutdown"},
+                {"jsonrpc":"2.0","method":"$/setTrace","params":{"value":"off"}},
+                {"jsonrpc":"2.0","id":"two","method":"unknown/method"},
+                7
+            ]"#,
+        );
+        assert!(handled);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        let batch: Vec<Value> = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[0]["id"], 1);
+        assert_eq!(batch[0]["result"], Value::Null);
+        assert_eq!(batch[1]["id"], "two");
+        assert_eq!(batch[1]["error"]["code"], -32601);
+        assert_eq!(batch[2]["id"], Value::Null);
+        assert_eq!(batch[2]["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn test_batch_keeps_the_responses() {
+        // the responses are sent as the handlers made them, not decoded again
+        let (messages, _) = replies(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"shutdown"},{"jsonrpc":"2.0","id":2,"method":"shutdown"}]"#,
+        );
+        assert_eq!(
+            messages,
+            vec![
+                r#"[{"jsonrpc":"2.0","id":1,"result":null},{"jsonrpc":"2.0","id":2,"result":null}]"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_of_notifications() {
+        let (messages, handled) =
+            replies(r#"[{"jsonrpc":"2.0","method":"$/setTrace","params":{"value":"off"}}]"#);
+        assert!(handled);
+        assert!(messages.is_empty(), "{:?}", messages);
+    }
+
+    #[test]
+    fn test_invalid_batch() {
+        let (messages, handled) = replies("[]");
+        assert!(!handled);
+        let reply: Value = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(reply["error"]["code"], -32600);
+
+        let (messages, handled) = replies(r#"[{"jsonrpc":"2.0","#);
+        assert!(!handled);
+        let reply: Value = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(reply["error"]["code"], -32700);
+    }
+}
+
+#[cfg(test)]
+mod outgoing_requests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        handle_due_revalidations, handle_message, ErrorCode, MessageType, OutgoingRequests,
+        RequestId, ServerContext,
+    };
+    use crate::test::util::capture;
+
+    #[test]
+    fn test_outgoing_request_ids() {
+        let mut outgoing = OutgoingRequests::new();
+        let first = outgoing.insert("workspace/configuration", Box::new(|_, _, _| ()));
+        let second = outgoing.insert("window/showDocument", Box::new(|_, _, _| ()));
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(outgoing.len(), 2);
+
+        let (method, _) = outgoing.remove(&RequestId::Number(second)).unwrap();
+        assert_eq!(method, "window/showDocument");
+        assert!(outgoing.remove(&RequestId::Number(second)).is_none());
+        assert!(outgoing.remove(&RequestId::from("1")).is_none());
+        assert_eq!(outgoing.len(), 1);
+
+        // ids of answered requests are not reused
+        assert_eq!(outgoing.next_id(), 3);
+    }
+
+    #[test]
+    fn test_response_future() {
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let mut response = None;
+        let sent = capture(&output, || {
+            response =
+                Some(context.request("workspace/configuration", json!({"items": []}), &mut logger));
+        });
+        let request: Value = serde_json::from_str(&sent[0]).unwrap();
+        assert_eq!(request["id"], 1);
+        assert_eq!(request["method"], "workspace/configuration");
+        let mut response = pin!(response.unwrap());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(response.as_mut().poll(&mut cx).is_pending());
+        assert!(response.try_take().is_none());
+
+        handle_message(
+            r#"{"jsonrpc":"2.0","id":1,"result":[{"logLevel":"info"}]}"#.to_string(),
+            &mut editor_state,
+            &mut context,
+            &mut logger,
+        )
+        .unwrap();
+        let Poll::Ready(Ok(result)) = response.as_mut().poll(&mut cx) else {
+            panic!("the response was not delivered");
+        };
+        assert_eq!(result, json!([{"logLevel": "info"}]));
+        assert_eq!(context.pending_request_count(), 0);
+    }
+
+    #[test]
+    fn test_outgoing_request_timeout() {
+        let mut outgoing = OutgoingRequests::new();
+        let forever = outgoing.insert("window/showDocument", Box::new(|_, _, _| ()));
+        outgoing.set_timeout(Some(Duration::from_secs(1)));
+        let timed = outgoing.insert("workspace/configuration", Box::new(|_, _, _| ()));
+        let deadline = outgoing.next_deadline().unwrap();
+        assert!(outgoing.take_expired(Instant::now()).is_empty());
+        let expired = outgoing.take_expired(deadline);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, timed);
+        assert!(outgoing.remove(&RequestId::Number(timed)).is_none());
+        assert!(outgoing.remove(&RequestId::Number(forever)).is_some());
+    }
+
+    #[test]
+    fn test_response_future_timeout() {
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut editor_state = EditorState::new();
+        let mut logger = Vec::new();
+        let mut response = None;
+        let sent = capture(&output, || {
+            context.update_settings(&json!({"requestTimeout": 100}), &mut logger);
+            response =
+                Some(context.request("workspace/configuration", json!({"items": []}), &mut logger));
+            let deadline = context.next_revalidation().unwrap();
+            handle_due_revalidations(&mut editor_state, &mut context, &mut logger, deadline);
+        });
+        let Some(Err(error)) = response.unwrap().try_take() else {
+            panic!("the request did not time out");
+        };
+        assert_eq!(error.code, ErrorCode::RequestTimedOut);
+        // a code of the server's own range, not one reserved by JSON-RPC
+        assert_eq!(error.code.code(), -33051);
+        assert!(!error.code.is_tree_error());
+        assert_eq!(context.pending_request_count(), 0);
+        // the client is told it doesn't have to answer anymore
+        assert_eq!(
+            sent[1],
+            r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#
+        );
+    }
+
+    #[test]
+    fn test_user_requests_wait() {
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        capture(&output, || {
+            context.update_settings(&json!({"requestTimeout": 100}), &mut logger);
+            context.show_message_request(
+                MessageType::Info,
+                "Reload?".to_string(),
+                &["Yes", "No"],
+                |_, _, _| (),
+                &mut logger,
+            );
+        });
+        assert_eq!(context.pending_request_count(), 1);
+        assert_eq!(context.next_revalidation(), None);
+    }
+}
+
+#[cfg(test)]
+mod cancellation {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json::{json, Value};
+
+    use crate::cancel::CancellationRegistry;
+    use crate::cli::ServeOptions;
+    use crate::editor::EditorState;
+    use crate::lsp::{handle_message, RequestId};
+    use crate::output::Output;
+    use crate::rpc::{encode_message, ReaderLimits};
+    use crate::test::util::{capture, initialized};
+    use crate::transport::{Framer, Server};
+
+    fn responses(sent: Vec<String>) -> Vec<Value> {
+        sent.iter()
+            .map(|message| serde_json::from_str(message).unwrap())
+            .filter(|message: &Value| message.get("id").is_some())
+            .collect()
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = CancellationRegistry::new();
+        let id = RequestId::from(1);
+        let token = registry.begin(&id);
+        assert!(!token.is_cancelled());
+        assert!(registry.cancel(&id));
+        assert!(token.is_cancelled());
+        registry.finish(&id);
+        assert!(registry.is_empty());
+
+        // answered requests are not cancelled again
+        assert!(!registry.cancel(&id));
+        assert!(registry.is_empty());
+
+        let queued = RequestId::from("queued");
+        registry.queue(&queued);
+        assert!(registry.cancel(&queued));
+        assert!(registry.begin(&queued).is_cancelled());
+    }
+
+    #[test]
+    fn test_handler_checks_its_token() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        context
+            .register_request_handler("lsp-rs/slow", |_, _, context, _| {
+                // as if the client cancelled the request while the handler ran
+                context.cancellations().cancel(&RequestId::from(7));
+                context.cancellation().check()?;
+                Ok(Value::Null)
+            })
+            .unwrap();
+        let sent = capture(&output, || {
+            handle_message(
+                r#"{"jsonrpc":"2.0","id":7,"method":"lsp-rs/slow"}"#.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            // unknown or answered requests are ignored
+            handle_message(
+                r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":7}}"#.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        });
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        let response: Value = serde_json::from_str(&sent[0]).unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], -32800);
+        assert!(context.cancellations().is_empty());
+    }
+
+    #[test]
+    fn test_cancelled_while_handled() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        context
+            .register_request_handler("lsp-rs/slow", |_, _, context, _| {
+                let start = Instant::now();
+                while !context.cancellation().is_cancelled()
+                    && start.elapsed() < Duration::from_secs(5)
+                {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                context.cancellation().check()?;
+                Ok(Value::Null)
+            })
+            .unwrap();
+        let mut framer = Framer::new(ReaderLimits::default(), context.cancellations());
+        let request = encode_message(r#"{"jsonrpc":"2.0","id":7,"method":"lsp-rs/slow"}"#.into());
+        let frames = framer.push(request.as_bytes());
+        // the thread reading the input flips the token while the handler runs
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let cancel = r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":7}}"#;
+            framer.push(encode_message(cancel.into()).as_bytes());
+        });
+        let sent = capture(&output, || {
+            for frame in frames {
+                handle_message(
+                    frame.unwrap(),
+                    &mut editor_state,
+                    &mut context,
+                    &mut Vec::new(),
+                )
+                .unwrap();
+            }
+        });
+        reader.join().unwrap();
+        let responses = responses(sent);
+        assert_eq!(responses[0]["error"]["code"], -32800);
+        assert!(context.cancellations().is_empty());
+    }
+
+    #[test]
+    fn test_queued_request_cancelled() {
+        let log = std::env::temp_dir().join(format!("lsp-rs-cancel-{}.log", std::process::id()));
+        let options = ServeOptions {
+            log_file: Some(log.to_string_lossy().to_string()),
+            ..ServeOptions::default()
+        };
+        let output = Output::default();
+        let mut server = Server::new(&options, output.clone());
+        let chunk = [
+            json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {"processId": 1}}),
+            json!({"jsonrpc": "2.0", "id": "a", "method": "workspace/symbol", "params": {"query": ""}}),
+            json!({"jsonrpc": "2.0", "id": "b", "method": "workspace/symbol", "params": {"query": ""}}),
+            json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": "a"}}),
+        ]
+        .iter()
+        .map(|message| encode_message(message.to_string()))
+        .collect::<String>();
+        let sent = capture(&output, || {
+            server.receive(Instant::now(), chunk.as_bytes()).unwrap();
+            server.finish();
+        });
+        let _ = std::fs::remove_file(log);
+
+        let responses = responses(sent);
+        assert_eq!(responses.len(), 3, "{:?}", responses);
+        assert_eq!(responses[1]["id"], "a");
+        assert_eq!(responses[1]["error"]["code"], -32800);
+        assert_eq!(responses[2]["id"], "b");
+        assert!(responses[2]["result"].is_array());
+    }
+
+    #[test]
+    fn test_disabled_request_forgotten() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let mut framer = Framer::new(ReaderLimits::default(), context.cancellations());
+        let sent = capture(&output, || {
+            let configuration = r#"{"jsonrpc":"2.0","method":"workspace/didChangeConfiguration","params":{"settings":{"lsp-rs":{"enableHover":false}}}}"#;
+            let hover = r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.abc"},"position":{"line":0,"character":0}}}"#;
+            for message in [configuration, hover] {
+                for frame in framer.push(encode_message(message.to_string()).as_bytes()) {
+                    handle_message(
+                        frame.unwrap(),
+                        &mut editor_state,
+                        &mut context,
+                        &mut Vec::new(),
+                    )
+                    .unwrap();
+                }
+            }
+        });
+        let responses = responses(sent);
+        assert_eq!(responses[0]["error"]["code"], -33050);
+        assert!(context.cancellations().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod typed_messages {
+    use serde_json::Value;
+
+    use crate::editor::EditorState;
+    use crate::lsp::{
+        builtin_methods, handle_message, ConfigurationRequest, DidOpenTextDocumentNotification,
+        DocumentStatsRequest, LspNotification, LspRequest, ReferencesRequest, RegisterHandlerError,
+        ServerContext, ShutdownRequest, DOCUMENT_STATS_METHOD,
+    };
+    use crate::test::util::{capture, initialized};
+
+    #[test]
+    fn test_methods() {
+        assert_eq!(ShutdownRequest::METHOD, "shutdown");
+        assert_eq!(ReferencesRequest::METHOD, "textDocument/references");
+        assert_eq!(DocumentStatsRequest::METHOD, DOCUMENT_STATS_METHOD);
+        assert_eq!(
+            DidOpenTextDocumentNotification::METHOD,
+            "textDocument/didOpen"
+        );
+        assert_eq!(ConfigurationRequest::METHOD, "workspace/configuration");
+        assert!(builtin_methods().any(|method| method == ReferencesRequest::METHOD));
+        assert!(!builtin_methods().any(|method| method == ConfigurationRequest::METHOD));
+    }
+
+    #[test]
+    fn test_builtin_methods_are_not_replaced() {
+        let mut context = ServerContext::new();
+        for method in builtin_methods() {
+            assert_eq!(
+                context.register_request_handler(method, |_, _, _, _| Ok(Value::Null)),
+                Err(RegisterHandlerError::BuiltIn(method.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_error_names_the_message() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        let sent = capture(&output, || {
+            let error = handle_message(
+                r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{}}"#.to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("Could not parse DidOpenTextDocumentNotification"),
+                "{}",
+                error
+            );
+        });
+        assert!(sent.is_empty(), "{:?}", sent);
+
+        // a request is still answered
+        let sent = capture(&output, || {
+            handle_message(
+                r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/references","params":{}}"#
+                    .to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap_err();
+        });
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        let response: Value = serde_json::from_str(&sent[0]).unwrap();
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["code"], -32602);
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(
+            message.starts_with("Could not parse ReferencesRequest"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_unit_params() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        // shutdown has no params, clients send none, null or an empty object
+        let sent = capture(&output, || {
+            for params in ["", r#","params":null"#, r#","params":{}"#] {
+                let message = format!(
+                    r#"{{"jsonrpc":"2.0","id":1,"method":"shutdown"{}}}"#,
+                    params
+                );
+                handle_message(message, &mut editor_state, &mut context, &mut logger).unwrap();
+            }
+        });
+        assert_eq!(sent, vec![r#"{"jsonrpc":"2.0","id":1,"result":null}"#; 3]);
+    }
+
+    #[test]
+    fn test_request_without_id() {
+        let mut editor_state = EditorState::new();
+        let mut context = ServerContext::new();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        let sent = capture(&output, || {
+            let error = handle_message(
+                r#"{"jsonrpc":"2.0","method":"workspace/symbol","params":{"query":""}}"#
+                    .to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap_err();
+            assert!(error.to_string().contains("without an id"), "{}", error);
+        });
+        assert!(sent.is_empty(), "{:?}", sent);
+    }
+
+    #[test]
+    fn test_typed_response() {
+        let mut editor_state = EditorState::new();
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let mut logger = Vec::new();
+        let sent = capture(&output, || {
+            handle_message(
+                r#"{"jsonrpc":"2.0","id":"s","method":"workspace/symbol","params":{"query":""}}"#
+                    .to_string(),
+                &mut editor_state,
+                &mut context,
+                &mut logger,
+            )
+            .unwrap();
+        });
+        assert_eq!(sent, vec![r#"{"jsonrpc":"2.0","id":"s","result":[]}"#]);
+    }
+}
+
+#[cfg(test)]
+mod connection {
+    use serde_json::{json, Value};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::connection::{Connection, OutgoingMessage, OwnedMessage};
+    use crate::lsp::RequestId;
+    use crate::rpc::encode_message;
+
+    #[test]
+    fn test_memory() {
+        let (client, server) = Connection::memory();
+        client
+            .sender
+            .send(OutgoingMessage::Request {
+                id: RequestId::from(1),
+                method: "shutdown".to_string(),
+                params: Value::Null,
+            })
+            .unwrap();
+        match server.receiver.recv().unwrap() {
+            OwnedMessage::Request { id, method, params } => {
+                assert_eq!(id, RequestId::from(1));
+                assert_eq!(method, "shutdown");
+                assert!(params.is_null());
+            }
+            message => panic!("expected a request, got {:?}", message),
+        }
+        server
+            .sender
+            .send(OutgoingMessage::Response {
+                id: RequestId::from(1),
+                result: Ok(Value::Null),
+            })
+            .unwrap();
+        assert!(matches!(
+            client.receiver.recv().unwrap(),
+            OwnedMessage::Response(response) if response.id == RequestId::from(1)
+        ));
+    }
+
+    #[test]
+    fn test_from_io() {
+        let input = [
+            encode_message(r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#.to_string()),
+            "Content-Length: x\r\n\r\n".to_string(),
+            encode_message(r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string()),
+        ]
+        .concat();
+        let (connection, threads) =
+            Connection::from_io(std::io::Cursor::new(input.into_bytes()), Vec::new());
+        let methods: Vec<_> = connection
+            .receiver
+            .iter()
+            .map(|message| match message {
+                OwnedMessage::Notification { method, .. } => method,
+                OwnedMessage::Invalid(_) => "invalid".to_string(),
+                message => panic!("expected a notification, got {:?}", message),
+            })
+            .collect();
+        // the malformed frame is received as invalid, the ones after it still are read
+        assert_eq!(methods, ["initialized", "invalid", "exit"]);
+        drop(connection);
+        threads.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (client, client_threads) = Connection::tcp(client).unwrap();
+        let (server, server_threads) = Connection::tcp(server).unwrap();
+        client
+            .sender
+            .send(OutgoingMessage::Notification {
+                method: "$/progress".to_string(),
+                params: json!({ "token": "t", "value": 1 }),
+            })
+            .unwrap();
+        match server.receiver.recv().unwrap() {
+            OwnedMessage::Notification { method, params } => {
+                assert_eq!(method, "$/progress");
+                assert_eq!(params, json!({ "token": "t", "value": 1 }));
+            }
+            message => panic!("expected a notification, got {:?}", message),
+        }
+        // closing both ends stops the threads of both
+        drop(client);
+        drop(server);
+        client_threads.join().unwrap();
+        server_threads.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod middleware {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use crate::editor::EditorState;
+    use crate::lsp::handle_message;
+    use crate::middleware::{Interceptor, Observer, RawMessage};
+    use crate::test::util::{capture, initialized};
+
+    #[derive(Default)]
+    struct Recorder {
+        seen: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Interceptor for Recorder {
+        fn on_incoming(&mut self, message: &mut RawMessage) {
+            self.seen.borrow_mut().push(message.content.clone());
+            // requests to an old name of the method are rewritten
+            if let Some(mut json) = message.json() {
+                if json["method"] == "lsp-rs/stop" {
+                    json["method"] = json!("shutdown");
+                    message.set_json(&json);
+                }
+            }
+        }
+
+        fn on_outgoing(&mut self, message: &mut RawMessage) {
+            message.content = message.content.replace("null", "\"redacted\"");
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let recorder = Recorder::default();
+        let seen = recorder.seen.clone();
+        context
+            .output()
+            .middleware()
+            .add_interceptor(Box::new(recorder));
+        let message = r#"{"jsonrpc":"2.0","id":1,"method":"lsp-rs/stop"}"#;
+        let sent = capture(&output, || {
+            handle_message(
+                message.to_string(),
+                &mut EditorState::new(),
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        });
+        assert_eq!(*seen.borrow(), [message]);
+        assert_eq!(sent, [r#"{"jsonrpc":"2.0","id":1,"result":"redacted"}"#]);
+
+        // a context of its own has none
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let sent = capture(&output, || {
+            handle_message(
+                message.to_string(),
+                &mut EditorState::new(),
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        });
+        assert!(sent[0].contains("-32601"), "{:?}", sent);
+    }
+
+    #[derive(Default)]
+    struct Log {
+        lines: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Observer for Log {
+        fn on_incoming(&mut self, message: &str) {
+            self.lines.borrow_mut().push(format!("in {}", message));
+        }
+
+        fn on_outgoing(&mut self, message: &str) {
+            self.lines.borrow_mut().push(format!("out {}", message));
+        }
+    }
+
+    #[test]
+    fn test_observers_see_intercepted_messages() {
+        let (mut context, _) = initialized();
+        let output = context.output().clone();
+        let log = Log::default();
+        let lines = log.lines.clone();
+        output
+            .middleware()
+            .add_interceptor(Box::new(Recorder::default()));
+        output.middleware().add_observer(Box::new(log));
+        let sent = capture(&output, || {
+            handle_message(
+                r#"{"jsonrpc":"2.0","id":1,"method":"lsp-rs/stop"}"#.to_string(),
+                &mut EditorState::new(),
+                &mut context,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        });
+        assert_eq!(
+            *lines.borrow(),
+            [
+                r#"in {"id":1,"jsonrpc":"2.0","method":"shutdown"}"#,
+                r#"out {"jsonrpc":"2.0","id":1,"result":"redacted"}"#,
+            ]
+        );
+        assert_eq!(sent, [r#"{"jsonrpc":"2.0","id":1,"result":"redacted"}"#]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use serde_json::Value;
+
+use super::diff::{diff, render, DiffOptions};
+use crate::record;
+use crate::rpc::{encode_message, json_to_string, BufferedReader};
+
+/// One line of a transcript, `{"in": <message>}` is sent to the server and
+/// `{"out": <message>}` is what the server is expected to send back
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranscriptEntry {
+    In(Value),
+    Out(Value),
+}
+
+/// Options for comparing the server's output with the transcript
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    pub diff: DiffOptions,
+    pub color: bool,
+    pub skipped_methods: Vec<String>, // server messages with these methods are not compared
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            diff: DiffOptions::default(),
+            color: true,
+            skipped_methods: vec!["window/logMessage".to_string()],
+        }
+    }
+}
+
+/// Parse a transcript with one entry per line, empty lines and lines starting with `//` are
+/// skipped
+pub fn parse_transcript(content: &str) -> Result<Vec<TranscriptEntry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let value: Value =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        match (value.get("in"), value.get("out")) {
+            (Some(message), None) => entries.push(TranscriptEntry::In(message.clone())),
+            (None, Some(message)) => entries.push(TranscriptEntry::Out(message.clone())),
+            _ => {
+                return Err(format!(
+                    "line {}: expected an object with either \"in\" or \"out\"",
+                    i + 1
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Compare the messages the server sent with the expected ones, in order.
+/// Returns a report for every message that differs, empty if everything matched
+pub fn compare(expected: &[Value], actual: &[Value], options: &CheckOptions) -> Vec<String> {
+    let actual: Vec<&Value> = actual
+        .iter()
+        .filter(|message| {
+            let method = message.get("method").and_then(|m| m.as_str());
+            !method.is_some_and(|m| options.skipped_methods.iter().any(|s| s == m))
+        })
+        .collect();
+
+    let mut reports = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(expected), Some(actual)) => {
+                let changes = diff(expected, actual, &options.diff);
+                if !changes.is_empty() {
+                    reports.push(format!(
+                        "message {} differs in {} fields:\n{}",
+                        i,
+                        changes.len(),
+                        render(&changes, options.color)
+                    ));
+                }
+            }
+            (Some(expected), None) => {
+                reports.push(format!("message {} missing, expected {}", i, expected))
+            }
+            (None, Some(actual)) => {
+                reports.push(format!("message {} unexpected, got {}", i, actual))
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    reports
+}
+
+/// Run the server executable, send it the messages and collect everything it sends back
+pub fn run_server(executable: &Path, messages: &[Value]) -> io::Result<Vec<Value>> {
+    let mut child = Command::new(executable)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    for message in messages {
+        stdin.write_all(encode_message(json_to_string(message)).as_bytes())?;
+    }
+    drop(stdin); // closing stdin stops the server
+
+    let mut output = Vec::new();
+    child.stdout.take().unwrap().read_to_end(&mut output)?;
+    child.wait()?;
+
+    let mut reader = BufferedReader::new();
+    reader.write(&output);
+    let mut received = Vec::new();
+    while let Ok(Some(content)) = reader.pop_message() {
+        let value = serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        received.push(value);
+    }
+    Ok(received)
+}
+
+/// `check <transcript> [--ignore field,...] [--no-color]`: replay the transcript against this
+/// server and print the differences. Returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let mut options = CheckOptions::default();
+    let mut transcript_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-color" => options.color = false,
+            "--ignore" => match args.next() {
+                Some(fields) => options
+                    .diff
+                    .ignored_fields
+                    .extend(fields.split(',').map(|f| f.to_string())),
+                None => {
+                    eprintln!("--ignore expects a comma separated list of fields");
+                    return 2;
+                }
+            },
+            path if transcript_path.is_none() => transcript_path = Some(path.to_string()),
+            other => {
+                eprintln!("unexpected argument {}", other);
+                return 2;
+            }
+        }
+    }
+    let Some(transcript_path) = transcript_path else {
+        eprintln!("usage: check <transcript> [--ignore field,...] [--no-color]");
+        return 2;
+    };
+
+    let entries = match fs::read_to_string(&transcript_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| {
+            // a recording of a session is replayed like the transcript it holds
+            if record::is_recording(&content) {
+                record::transcript(&content)
+            } else {
+                parse_transcript(&content)
+            }
+        }) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("could not read transcript {}: {}", transcript_path, e);
+            return 2;
+        }
+    };
+    let (mut inputs, mut expected) = (Vec::new(), Vec::new());
+    for entry in entries {
+        match entry {
+            TranscriptEntry::In(message) => inputs.push(message),
+            TranscriptEntry::Out(message) => expected.push(message),
+        }
+    }
+
+    let actual = match env::current_exe().and_then(|exe| run_server(&exe, &inputs)) {
+        Ok(actual) => actual,
+        Err(e) => {
+            eprintln!("could not run the server: {}", e);
+            return 2;
+        }
+    };
+    let reports = compare(&expected, &actual, &options);
+    for report in &reports {
+        println!("{}", report);
+    }
+    if reports.is_empty() {
+        println!("{} messages matched", expected.len());
+        0
+    } else {
+        1
+    }
+}
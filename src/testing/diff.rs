@@ -0,0 +1,161 @@
+use std::fmt::Write;
+
+use serde_json::Value;
+
+/// A single difference between the expected and the actual json
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    Changed {
+        path: String,
+        expected: Value,
+        actual: Value,
+    },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. } | Change::Removed { path, .. } => path,
+            Change::Changed { path, .. } => path,
+        }
+    }
+}
+
+/// Options for comparing json values
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Object fields that are not compared at any depth, eg. ids or timestamps
+    pub ignored_fields: Vec<String>,
+}
+
+/// Structurally compare two json values, returning every field that was added, removed or
+/// changed. Paths look like `result.items[2].label`
+pub fn diff(expected: &Value, actual: &Value, options: &DiffOptions) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_at(String::new(), expected, actual, options, &mut changes);
+    changes
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn diff_at(
+    path: String,
+    expected: &Value,
+    actual: &Value,
+    options: &DiffOptions,
+    changes: &mut Vec<Change>,
+) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, expected_value) in expected {
+                if options.ignored_fields.contains(key) {
+                    continue;
+                }
+                match actual.get(key) {
+                    Some(actual_value) => diff_at(
+                        join(&path, key),
+                        expected_value,
+                        actual_value,
+                        options,
+                        changes,
+                    ),
+                    None => changes.push(Change::Removed {
+                        path: join(&path, key),
+                        value: expected_value.clone(),
+                    }),
+                }
+            }
+            for (key, actual_value) in actual {
+                if !expected.contains_key(key) && !options.ignored_fields.contains(key) {
+                    changes.push(Change::Added {
+                        path: join(&path, key),
+                        value: actual_value.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            for i in 0..expected.len().max(actual.len()) {
+                let item_path = format!("{}[{}]", path, i);
+                match (expected.get(i), actual.get(i)) {
+                    (Some(e), Some(a)) => diff_at(item_path, e, a, options, changes),
+                    (Some(e), None) => changes.push(Change::Removed {
+                        path: item_path,
+                        value: e.clone(),
+                    }),
+                    (None, Some(a)) => changes.push(Change::Added {
+                        path: item_path,
+                        value: a.clone(),
+                    }),
+                    (None, None) => unreachable!(),
+                }
+            }
+        }
+        (expected, actual) if expected != actual => changes.push(Change::Changed {
+            path,
+            expected: expected.clone(),
+            actual: actual.clone(),
+        }),
+        _ => (),
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Render the changes one per line, `-` for removed, `+` for added and `~` for changed fields,
+/// colored with ANSI escape codes if `color` is set
+pub fn render(changes: &[Change], color: bool) -> String {
+    let paint = |code: &str| {
+        if color {
+            code.to_string()
+        } else {
+            String::new()
+        }
+    };
+    let mut out = String::new();
+    for change in changes {
+        let path = if change.path().is_empty() {
+            "<root>"
+        } else {
+            change.path()
+        };
+        match change {
+            Change::Removed { value, .. } => {
+                writeln!(out, "{}- {}: {}{}", paint(RED), path, value, paint(RESET))
+            }
+            Change::Added { value, .. } => {
+                writeln!(out, "{}+ {}: {}{}", paint(GREEN), path, value, paint(RESET))
+            }
+            Change::Changed {
+                expected, actual, ..
+            } => writeln!(
+                out,
+                "{}~ {}: {} -> {}{}",
+                paint(YELLOW),
+                path,
+                expected,
+                actual,
+                paint(RESET)
+            ),
+        }
+        .unwrap();
+    }
+    out
+}
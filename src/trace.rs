@@ -0,0 +1,256 @@
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Id given to every frame read from the client, used to follow it through the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TraceId(pub u64);
+
+impl Display for TraceId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// How much the client wants to hear about the messages the server handles, set by
+/// `InitializeParams.trace` and `$/setTrace`, reported with `$/logTrace`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceValue {
+    #[default]
+    Off,
+    Messages, // one line per message
+    Verbose,  // one line per message, with its params
+}
+
+/// Frame being handled on this thread
+struct Frame {
+    id: TraceId,
+    method: Option<String>,
+    write: Duration, // time spent writing the frame's output so far
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Frame>> = const { RefCell::new(None) };
+}
+
+/// Trace id of the frame being handled on this thread
+pub fn current() -> Option<TraceId> {
+    CURRENT.with(|c| c.borrow().as_ref().map(|frame| frame.id))
+}
+
+/// Method of the frame being handled on this thread, once it is known
+pub fn current_method() -> Option<String> {
+    CURRENT.with(|c| c.borrow().as_ref().and_then(|frame| frame.method.clone()))
+}
+
+/// Attribute the frame being handled to the method
+pub fn set_method(method: &str) {
+    CURRENT.with(|c| {
+        if let Some(frame) = c.borrow_mut().as_mut() {
+            frame.method = Some(method.to_string());
+            #[cfg(feature = "tracing")]
+            frame.span.record("method", method);
+        }
+    });
+}
+
+/// A `tracing` span, entered until dropped. Does nothing without the `tracing` feature
+#[must_use]
+pub struct Entered {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+/// Enter the span of the handler of a message
+pub fn enter_handler(method: &str) -> Entered {
+    #[cfg(feature = "tracing")]
+    return Entered {
+        _span: tracing::info_span!("handler", method).entered(),
+    };
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = method;
+        Entered {}
+    }
+}
+
+/// Enter the span of writing a message of `bytes` bytes to the client
+pub fn enter_write(bytes: usize) -> Entered {
+    #[cfg(feature = "tracing")]
+    return Entered {
+        _span: tracing::info_span!("write", bytes).entered(),
+    };
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = bytes;
+        Entered {}
+    }
+}
+
+/// `(trace #n) ` for the frame being handled, to prefix log lines with. Empty outside a frame
+pub fn tag() -> String {
+    current()
+        .map(|id| format!("(trace {}) ", id))
+        .unwrap_or_default()
+}
+
+/// Count time spent writing to the client towards the frame being handled
+pub fn record_write(duration: Duration) {
+    CURRENT.with(|c| {
+        if let Some(frame) = c.borrow_mut().as_mut() {
+            frame.write += duration;
+        }
+    });
+}
+
+/// Where the time between reading a frame and finishing it went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub queue: Duration,   // read, waiting for earlier frames to be handled
+    pub handler: Duration, // handling the frame, without writing the output
+    pub write: Duration,   // writing the output to the client
+}
+
+impl Latency {
+    pub fn total(&self) -> Duration {
+        self.queue + self.handler + self.write
+    }
+}
+
+impl Display for Latency {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "total {:?} (queue {:?}, handler {:?}, write {:?})",
+            self.total(),
+            self.queue,
+            self.handler,
+            self.write
+        )
+    }
+}
+
+/// A frame being handled, started by `Tracer::start`
+pub struct Span {
+    id: TraceId,
+    received: Instant,
+    started: Instant,
+    _entered: Entered, // the `frame` span, the handler and writes are inside it
+}
+
+impl Span {
+    pub fn id(&self) -> TraceId {
+        self.id
+    }
+}
+
+/// Latency of every frame handled so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub frames: u64,
+    pub total: Latency, // summed over all frames
+    pub max: Latency,   // largest of each part, not necessarily from the same frame
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Latency) {
+        self.frames += 1;
+        self.total.queue += latency.queue;
+        self.total.handler += latency.handler;
+        self.total.write += latency.write;
+        self.max.queue = self.max.queue.max(latency.queue);
+        self.max.handler = self.max.handler.max(latency.handler);
+        self.max.write = self.max.write.max(latency.write);
+    }
+
+    pub fn mean(&self) -> Latency {
+        let frames = self.frames.max(1) as u32;
+        Latency {
+            queue: self.total.queue / frames,
+            handler: self.total.handler / frames,
+            write: self.total.write / frames,
+        }
+    }
+}
+
+impl Display for LatencyStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, mean {}, max {}",
+            self.frames,
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+/// Hands out trace ids and collects the latency of the frames
+#[derive(Debug, Default)]
+pub struct Tracer {
+    next_id: u64,
+    stats: LatencyStats,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start handling a frame that was read from the client at `received`.
+    /// Log lines and writes on this thread are attributed to it until `finish`
+    pub fn start(&mut self, received: Instant) -> Span {
+        self.next_id += 1;
+        let id = TraceId(self.next_id);
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "frame",
+            trace_id = id.0,
+            method = tracing::field::Empty,
+            queue_us = received.elapsed().as_micros() as u64
+        );
+        CURRENT.with(|c| {
+            *c.borrow_mut() = Some(Frame {
+                id,
+                method: None,
+                write: Duration::ZERO,
+                #[cfg(feature = "tracing")]
+                span: span.clone(),
+            })
+        });
+        Span {
+            id,
+            received,
+            started: Instant::now(),
+            _entered: Entered {
+                #[cfg(feature = "tracing")]
+                _span: span.entered(),
+            },
+        }
+    }
+
+    /// Finish handling the frame, returning and recording its latency
+    pub fn finish(&mut self, span: Span) -> Latency {
+        let handled = span.started.elapsed();
+        let write = CURRENT.with(|c| match c.take() {
+            Some(frame) if frame.id == span.id => frame.write,
+            _ => Duration::ZERO,
+        });
+        let latency = Latency {
+            queue: span.started.saturating_duration_since(span.received),
+            handler: handled.saturating_sub(write),
+            write,
+        };
+        self.stats.record(latency);
+        latency
+    }
+
+    pub fn stats(&self) -> &LatencyStats {
+        &self.stats
+    }
+}
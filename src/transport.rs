@@ -0,0 +1,473 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+#[cfg(feature = "async")]
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Instant;
+
+use crate::cancel::CancellationRegistry;
+use crate::cli::ServeOptions;
+use crate::editor::{EditorState, SharedEditorState};
+use crate::logs;
+use crate::lsp::{
+    handle_due_revalidations, handle_shared_message, reply_to_skipped_frame, track_cancellation,
+    ClientLogger, MessageType, ServerContext,
+};
+use crate::middleware::Middleware;
+use crate::output::{BufferedWriter, FlushPolicy, MessageWriter, Output};
+use crate::record::Recorder;
+use crate::rpc::{BufferedReader, FrameError, ReaderLimits};
+use crate::trace::{self, Tracer};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// A server talking to a client, whatever carries the bytes. The transport feeds it the
+/// chunks it reads, wakes it up for `next_revalidation`, and writes what it sends with the
+/// `Output` it was given
+pub struct Server {
+    editor_state: SharedEditorState, // shared so handlers can move off this thread
+    context: ServerContext,          // state of the server itself, eg. client capabilities
+    logger: ClientLogger<Box<dyn Write>>,
+    framer: Framer, // in case messages come in chunks
+    tracer: Tracer, // gives every frame a trace id and records its latency
+    session_file: Option<String>,
+}
+
+impl Server {
+    /// Open the log and restore the session of the options. Messages for the client are
+    /// written with `output`, which is wrapped to record them when recording
+    pub fn new(options: &ServeOptions, output: Output) -> Self {
+        let (mut logger, warning) = open_logger(options);
+        logger.set_output(output.clone());
+        let editor_state = SharedEditorState::new(EditorState::new());
+        let mut context = ServerContext::new();
+        if let Some(warning) = warning {
+            context.warn_once_initialized(warning);
+        }
+        if let Some(session) = &options.session_file {
+            match editor_state.write().restore_session(Path::new(session)) {
+                Ok(restored) => writeln!(
+                    &mut logger,
+                    "[Session] restored {} documents from {}",
+                    restored, session
+                )
+                .unwrap(),
+                // there is no session the first time
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => context.warn_once_initialized(format!(
+                    "Could not restore the session {}: {}",
+                    session, e
+                )),
+            }
+        }
+        let recorder = options.record_file.as_ref().and_then(|record| {
+            match Recorder::open(Path::new(record)) {
+                Ok(recorder) => Some(recorder),
+                Err(e) => {
+                    context.warn_once_initialized(format!("Could not record to {}: {}", record, e));
+                    None
+                }
+            }
+        });
+        if let Some(recorder) = &recorder {
+            output.wrap(|writer| recorder.writer(writer));
+        }
+        context.set_output(output);
+        let framer = Framer {
+            recorder,
+            ..Framer::new(options.reader_limits, context.cancellations())
+        };
+        Server {
+            editor_state,
+            context,
+            logger,
+            framer,
+            tracer: Tracer::new(),
+            session_file: options.session_file.clone(),
+        }
+    }
+
+    /// The interceptors and observers of the messages the server receives and sends
+    pub fn middleware(&self) -> &Middleware {
+        self.context.output().middleware()
+    }
+
+    /// Handle every message completed by the chunk, read at `received` to measure how long
+    /// its frames waited. The rest of the chunk is kept until the next one. Requests that are
+    /// cancelled by a later message of the chunk are answered as cancelled without running.
+    /// Fails once the client can't be written to, there is no point in reading more
+    pub fn receive(&mut self, received: Instant, chunk: &[u8]) -> io::Result<()> {
+        let frames = self.framer.push(chunk);
+        self.handle_frames(received, frames)
+    }
+
+    /// Handle the frames a `Framer` split, like `receive`
+    pub fn handle_frames(&mut self, received: Instant, frames: Vec<Frame>) -> io::Result<()> {
+        for frame in frames {
+            match frame {
+                Ok(content) => self.handle(received, content),
+                // the reader skipped the message, the ones after it are still read
+                Err(e) => {
+                    writeln!(&mut self.logger, "[Error] Could not pop message: {}", e).unwrap();
+                    reply_to_skipped_frame(&e, self.context.output(), &mut self.logger);
+                }
+            }
+        }
+        self.write_error()
+    }
+
+    /// A framer sharing the tokens of the requests and the recording with the server, for the
+    /// thread reading the input
+    pub fn framer(&self) -> Framer {
+        Framer {
+            recorder: self.framer.recorder.clone(),
+            ..Framer::new(self.framer.limits, self.context.cancellations())
+        }
+    }
+
+    fn handle(&mut self, received: Instant, content: String) {
+        let span = self.tracer.start(received);
+        if let Err(e) = handle_shared_message(
+            content,
+            &self.editor_state,
+            &mut self.context,
+            &mut self.logger,
+        ) {
+            writeln!(&mut self.logger, "[Error] Error handling message {}", e).unwrap()
+        }
+        let id = span.id();
+        let method = trace::current_method();
+        let latency = self.tracer.finish(span);
+        writeln!(&mut self.logger, "[Trace] {} {}", id, latency).unwrap();
+        if let Some(method) = method {
+            self.context
+                .record_latency(&method, latency.handler, &mut self.logger);
+        }
+        self.context.record_frame(latency.write, &mut self.logger);
+        // a slow client only gets errors mirrored until it catches up
+        self.logger
+            .set_level(if self.context.client_health().is_shedding() {
+                MessageType::Error
+            } else {
+                self.context.settings().log_level.into()
+            });
+    }
+
+    /// When the server wants to be woken up even if nothing is read, see `revalidate`
+    pub fn next_revalidation(&self) -> Option<Instant> {
+        self.context.next_revalidation()
+    }
+
+    /// Run the re-validations that are due, fails like `receive`
+    pub fn revalidate(&mut self) -> io::Result<()> {
+        handle_due_revalidations(
+            &mut self.editor_state.write(),
+            &mut self.context,
+            &mut self.logger,
+            Instant::now(),
+        );
+        self.write_error()
+    }
+
+    /// The error of the first message that could not be written, logged
+    fn write_error(&mut self) -> io::Result<()> {
+        match self.context.output().take_error() {
+            Some(e) => {
+                writeln!(
+                    &mut self.logger,
+                    "[Error] Could not write to the client: {}",
+                    e
+                )
+                .unwrap();
+                Err(e)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Save the session and log the metrics, once the client is gone
+    pub fn finish(mut self) {
+        if let Some(session) = &self.session_file {
+            if let Err(e) = self.editor_state.read().save_session(Path::new(session)) {
+                writeln!(
+                    &mut self.logger,
+                    "[Error] Could not save the session {}: {}",
+                    session, e
+                )
+                .unwrap();
+            }
+        }
+        writeln!(
+            &mut self.logger,
+            "[Metrics] latency: {}",
+            self.tracer.stats()
+        )
+        .unwrap();
+        writeln!(
+            &mut self.logger,
+            "[Metrics] shedding: {}",
+            self.context.client_health().stats()
+        )
+        .unwrap();
+        for (name, time) in self.context.lazy_init_times() {
+            writeln!(&mut self.logger, "[Metrics] built {} in {:?}", name, time).unwrap();
+        }
+    }
+}
+
+/// The content of a message read, or why the reader skipped it
+pub type Frame = Result<String, FrameError>;
+
+/// Splits the bytes read into frames. The tokens of the requests read are made right away and
+/// flipped once their cancellation is read, so a framer on the thread reading the input
+/// cancels requests while the server is busy with earlier ones
+pub struct Framer {
+    reader: BufferedReader,
+    limits: ReaderLimits,
+    cancellations: CancellationRegistry,
+    recorder: Option<Recorder>, // of the bytes read, the ones written are recorded by the writer
+}
+
+impl Framer {
+    pub fn new(limits: ReaderLimits, cancellations: CancellationRegistry) -> Self {
+        Framer {
+            reader: BufferedReader::with_limits(limits),
+            limits,
+            cancellations,
+            recorder: None,
+        }
+    }
+
+    /// The frames completed by the chunk, the rest of it is kept until the next one
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Frame> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_in(chunk);
+        }
+        self.reader.write(chunk);
+        let mut frames = Vec::new();
+        loop {
+            match self.reader.pop_message() {
+                Ok(Some(content)) => {
+                    track_cancellation(&content, &self.cancellations);
+                    frames.push(Ok(content));
+                }
+                Ok(None) => break,
+                Err(e) => frames.push(Err(e)),
+            }
+        }
+        frames
+    }
+}
+
+/// Read the input until it ends or `handle` returns false, handing it the frames completed by
+/// every chunk with the time the chunk was read
+pub(crate) fn read_frames(
+    mut input: impl Read,
+    framer: &mut Framer,
+    mut handle: impl FnMut(Instant, Vec<Frame>) -> bool,
+) -> io::Result<()> {
+    let mut buff = [0; 4096];
+    loop {
+        let n = input.read(&mut buff)?;
+        if n == 0 || !handle(Instant::now(), framer.push(&buff[..n])) {
+            return Ok(());
+        }
+    }
+}
+
+/// Serve the client until `input` ends, messages for the client are written with `writer`
+pub fn run(
+    options: &ServeOptions,
+    input: impl Read + Send + 'static,
+    writer: Box<dyn MessageWriter>,
+) {
+    serve(Server::new(options, Output::new(writer)), input);
+}
+
+/// Serve the client with the server until `input` ends, eg. once interceptors were added to
+/// it. The input is read on its own thread, so the server can wake up for delayed
+/// re-validations
+pub fn serve(mut server: Server, input: impl Read + Send + 'static) {
+    let output = server.context.output().clone();
+
+    // frames are split on the reading thread, so cancellations are seen while the server is
+    // busy, and stamped with the time they were read, to measure how long they wait
+    let mut framer = server.framer();
+    let (chunk_sender, chunk_receiver) = std_mpsc::channel::<(Instant, Vec<Frame>)>();
+    thread::spawn(move || {
+        read_frames(input, &mut framer, |received, frames| {
+            chunk_sender.send((received, frames)).is_ok()
+        })
+    });
+
+    loop {
+        let chunk = match server.next_revalidation() {
+            Some(deadline) => {
+                chunk_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => chunk_receiver
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let served = match chunk {
+            // a single read may hold several messages, all of them are handled
+            Ok((received, frames)) => server.handle_frames(received, frames),
+            Err(RecvTimeoutError::Timeout) => server.revalidate(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if served.is_err() {
+            break;
+        }
+    }
+    server.finish();
+    // the client may be gone already, there is no one left to tell
+    let _ = output.flush();
+}
+
+/// Serve over stdin and stdout
+pub fn run_stdio(options: &ServeOptions) {
+    run(
+        options,
+        io::stdin(),
+        Box::new(BufferedWriter::new(io::stdout(), FlushPolicy::default())),
+    );
+}
+
+/// Serve the first client that connects to the listener over its connection
+pub fn run_tcp(options: &ServeOptions, listener: TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?; // messages are already batched, don't hold them back
+    let writer = BufferedWriter::new(stream.try_clone()?, FlushPolicy::default());
+    run(options, stream, Box::new(writer));
+    Ok(())
+}
+
+/// Serve the client over the unix domain socket it listens on
+#[cfg(unix)]
+pub fn run_pipe(options: &ServeOptions, path: &str) -> io::Result<()> {
+    let stream = std::os::unix::net::UnixStream::connect(path)?;
+    let writer = BufferedWriter::new(stream.try_clone()?, FlushPolicy::default());
+    run(options, stream, Box::new(writer));
+    Ok(())
+}
+
+/// Serve the client over the named pipe it created
+#[cfg(windows)]
+pub fn run_pipe(options: &ServeOptions, path: &str) -> io::Result<()> {
+    let pipe = crate::named_pipe::NamedPipe::connect(path)?;
+    let writer = BufferedWriter::new(pipe.clone(), FlushPolicy::default());
+    run(options, pipe, Box::new(writer));
+    Ok(())
+}
+
+/// There are no pipes to serve over on this platform
+#[cfg(not(any(unix, windows)))]
+pub fn run_pipe(_: &ServeOptions, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pipes are not supported on this platform",
+    ))
+}
+
+/// Logger for the options, with a warning if the log file could not be created
+pub fn open_logger(options: &ServeOptions) -> (ClientLogger<Box<dyn Write>>, Option<String>) {
+    let log = logs::open_log(options.log_file.as_deref(), &env::temp_dir());
+    let mut logger = ClientLogger::new(log.writer, MessageType::Info);
+    logger.set_format(options.log_format);
+    if let Some(warning) = &log.warning {
+        writeln!(&mut logger, "[Warning] {}", warning).unwrap();
+    }
+    (logger, log.warning)
+}
+
+/// Hands the messages to the task writing them, so a slow client doesn't hold up the reads
+#[cfg(feature = "async")]
+struct ChannelWriter {
+    sender: UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>, // messages the writing task didn't write yet
+}
+
+#[cfg(feature = "async")]
+impl MessageWriter for ChannelWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(encoded_message.to_vec()).map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone")
+        })
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Serve over an async reader and writer until the input ends. Only the transport is async:
+/// reads, re-validation timers and writes are awaited on the current thread, while
+/// `handle_message` and the handlers stay synchronous and run between awaits, so a handler
+/// can't await a client response, see `ServerContext::send_request` for callbacks instead.
+/// Must run on a current thread runtime, the server's state is not `Send`
+#[cfg(feature = "async")]
+pub async fn run_async<R, W>(options: &ServeOptions, mut input: R, mut out: W)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let written = queued.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if out.write_all(&message).await.is_err() || out.flush().await.is_err() {
+                break;
+            }
+            written.fetch_sub(1, Ordering::Relaxed);
+        }
+    });
+    let writer_output = ChannelWriter { sender, queued };
+    let mut server = Server::new(options, Output::new(Box::new(writer_output)));
+    let mut buff = [0; 512];
+    loop {
+        let deadline = server.next_revalidation();
+        let served = tokio::select! {
+            read = input.read(&mut buff) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => server.receive(Instant::now(), &buff[..n]),
+            },
+            _ = sleep_until(deadline) => server.revalidate(),
+        };
+        if served.is_err() {
+            break;
+        }
+    }
+    // the channel closes with the server's output, the task writes what is left and stops
+    server.finish();
+    let _ = writer.await;
+}
+
+/// Wait until the deadline, forever if there is none
+#[cfg(feature = "async")]
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serve over stdin and stdout with `run_async`
+#[cfg(feature = "async")]
+pub fn run_async_stdio(options: &ServeOptions) {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not start the runtime")
+        .block_on(run_async(options, tokio::io::stdin(), tokio::io::stdout()))
+}
@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+use std::convert::Infallible;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Whether two uris that only differ in case refer to the same document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+    /// Insensitive on Windows and macOS, whose default file systems ignore case
+    Platform,
+}
+
+impl CaseSensitivity {
+    fn is_sensitive(self) -> bool {
+        match self {
+            CaseSensitivity::Sensitive => true,
+            CaseSensitivity::Insensitive => false,
+            CaseSensitivity::Platform => !cfg!(any(windows, target_os = "macos")),
+        }
+    }
+}
+
+/// Normalized document uri, used as the key when looking up documents.
+/// Clients spell the same file differently (`file:///C:/x`, `file:///c%3A/x`, `file:///C:\x`),
+/// so the uri is normalized before it is compared or hashed. The uri as the client spelled it
+/// is kept, for messages to the client and to normalize it again
+#[derive(Debug, Clone)]
+pub struct Uri {
+    key: String,      // normalized, what is compared
+    original: String, // as given to `new`
+}
+
+impl Uri {
+    pub fn new(uri: &str, case_sensitivity: CaseSensitivity) -> Self {
+        Uri {
+            key: normalize(uri, case_sensitivity),
+            original: uri.to_string(),
+        }
+    }
+
+    /// The normalized uri
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    /// The uri as the client spelled it
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+}
+
+impl PartialEq for Uri {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Uri {}
+
+impl Hash for Uri {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl PartialOrd for Uri {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Uri {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// The key of the uri, see `Uri`
+fn normalize(uri: &str, case_sensitivity: CaseSensitivity) -> String {
+    let (scheme, rest) = match uri.split_once(':') {
+        Some((scheme, rest)) if is_scheme(scheme) => (scheme.to_ascii_lowercase(), rest),
+        _ => return percent_decode(uri),
+    };
+    let mut rest = percent_decode(rest);
+
+    if scheme == "file" {
+        rest = rest.replace('\\', "/");
+        // windows drive letters are case insensitive, use lower case like vscode does
+        let bytes = rest.as_bytes();
+        if bytes.len() >= 5
+            && rest.starts_with("///")
+            && bytes[3].is_ascii_alphabetic()
+            && bytes[4] == b':'
+        {
+            rest.replace_range(3..4, &rest[3..4].to_ascii_lowercase());
+        }
+        if !case_sensitivity.is_sensitive() {
+            rest = rest.to_lowercase();
+        }
+    }
+    format!("{}:{}", scheme, rest)
+}
+
+impl Display for Uri {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+/// Parses with the platform's case sensitivity, any string is a uri once normalized
+impl FromStr for Uri {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Uri::new(s, CaseSensitivity::Platform))
+    }
+}
+
+fn is_scheme(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+/// Decode percent encoded characters, except the ones that would change the uri's structure
+/// if decoded. Those keep their encoding with upper case hex digits
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) if matches!(b, b'/' | b'?' | b'#' | b'%') => {
+                decoded.extend_from_slice(format!("%{:02X}", b).as_bytes());
+                i += 3;
+            }
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Convert a file system path to a `file://` uri
+pub fn path_to_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/'); // windows paths start with the drive letter
+    }
+    for c in path.chars() {
+        match c {
+            ' ' => uri.push_str("%20"),
+            '#' => uri.push_str("%23"),
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3F"),
+            c => uri.push(c),
+        }
+    }
+    uri
+}
+
+/// Convert a `file://` uri to a file system path, None for other schemes
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let (scheme, rest) = uri.split_once(':')?;
+    if !scheme.eq_ignore_ascii_case("file") {
+        return None;
+    }
+    let path = rest.strip_prefix("//").unwrap_or(rest);
+    // skip the authority, which is empty for local files
+    let path = &path[path.find('/').unwrap_or(0)..];
+    let mut path = full_percent_decode(path);
+    let bytes = path.as_bytes();
+    if cfg!(windows) && bytes.len() >= 3 && bytes[0] == b'/' && bytes[2] == b':' {
+        path.remove(0);
+    }
+    Some(PathBuf::from(path))
+}
+
+fn full_percent_decode(s: &str) -> String {
+    percent_decode(s)
+        .replace("%2F", "/")
+        .replace("%3F", "?")
+        .replace("%23", "#")
+        .replace("%25", "%")
+}
@@ -0,0 +1,163 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::cli::ServeOptions;
+use crate::output::MessageWriter;
+use crate::rpc::{decode_message, encode_message};
+use crate::transport::run;
+
+/// Serve the first client that connects to the listener over a websocket, eg. an editor in
+/// a browser. Every LSP message is a text message of its own, without the header. One thread
+/// blocks reading the socket and another writes to it, the server talks to them through
+/// channels
+pub fn run_websocket(options: &ServeOptions, listener: TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    let stream = SharedStream::new(stream)?;
+    let writing = stream.try_clone()?;
+    let socket =
+        tungstenite::accept(stream.try_clone()?).map_err(|e| io::Error::other(e.to_string()))?;
+    let (incoming_sender, incoming) = mpsc::channel();
+    let (outgoing, outgoing_receiver) = mpsc::channel();
+    let reader = thread::spawn(move || read_socket(socket, incoming_sender));
+    let writer = thread::spawn(move || {
+        let socket = WebSocket::from_raw_socket(writing, Role::Server, None);
+        write_socket(socket, outgoing_receiver)
+    });
+    run(
+        options,
+        ChannelReader::new(incoming),
+        Box::new(TextWriter(outgoing)),
+    );
+    let _ = writer.join();
+    // the client may never answer the close, the read is cut short
+    let _ = stream.shutdown();
+    let _ = reader.join();
+    Ok(())
+}
+
+/// Send the messages from the client to the server until either is done. They are encoded
+/// with their header, like on the other transports
+fn read_socket(mut socket: WebSocket<SharedStream>, incoming: Sender<Vec<u8>>) {
+    loop {
+        let content = match socket.read() {
+            Ok(Message::Text(text)) => text.to_string(),
+            Ok(Message::Binary(data)) => String::from_utf8_lossy(&data).to_string(),
+            // pings are answered by the socket itself
+            Ok(_) => continue,
+            Err(_) => return, // closed by the client
+        };
+        if incoming.send(encode_message(content).into_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Send the messages from the server to the client, closing the socket once the server is done
+fn write_socket(mut socket: WebSocket<SharedStream>, outgoing: Receiver<String>) {
+    for text in outgoing {
+        if socket.send(Message::text(text)).is_err() {
+            return;
+        }
+    }
+    let _ = socket.close(None);
+    let _ = socket.flush();
+}
+
+/// The tcp stream under both sockets: the one reading writes the answers to pings and the
+/// close, the other the server's messages. Every write is written whole under the lock, and a
+/// socket only writes whole frames, so the frames of both don't interleave
+struct SharedStream {
+    read: TcpStream,
+    write: Arc<Mutex<TcpStream>>,
+}
+
+impl SharedStream {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(SharedStream {
+            read: stream.try_clone()?,
+            write: Arc::new(Mutex::new(stream)),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(SharedStream {
+            read: self.read.try_clone()?,
+            write: self.write.clone(),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.read.shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        stream.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+/// Reads the chunks sent to the channel, ends once the sender is dropped
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    start: usize, // bytes of `chunk` already read
+}
+
+impl ChannelReader {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            start: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => (self.chunk, self.start) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.start);
+        buf[..n].copy_from_slice(&self.chunk[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// Sends the content of every message to the socket thread, without its header
+struct TextWriter(Sender<String>);
+
+impl MessageWriter for TextWriter {
+    fn write_message(&mut self, encoded_message: &[u8]) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "not an encoded message");
+        let (content, _) = decode_message(encoded_message)
+            .map_err(|_| invalid())?
+            .ok_or_else(invalid)?;
+        self.0
+            .send(content)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone"))
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use crate::encoding::decode;
+use crate::large_file::{self, LineError, LineSummary};
+
+/// Options for scanning a workspace folder
+pub struct ScanConfig {
+    pub max_concurrent_reads: usize, // files read at the same time
+    pub max_file_size: u64,          // files larger than this (in bytes) are skipped
+    pub extensions: Vec<String>,     // only files with these extensions are read
+    pub respect_gitignore: bool,
+    pub validate_large_files: bool, // stream validate files over max_file_size instead of skipping them
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            max_concurrent_reads: 8,
+            max_file_size: 16 << 20,
+            extensions: vec!["abc".to_string()],
+            respect_gitignore: true,
+            validate_large_files: true,
+        }
+    }
+}
+
+/// Statistics of a finished scan
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScanStats {
+    pub directories: usize,
+    pub files_seen: usize,
+    pub files_read: usize,
+    pub bytes_read: u64,
+    pub skipped_ignored: usize,
+    pub skipped_too_large: usize,
+    pub large_valid: usize,   // files over max_file_size that are valid trees
+    pub large_invalid: usize, // files over max_file_size that are not valid trees
+    pub errors: usize,
+}
+
+impl Display for ScanStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "read {} of {} files ({} bytes) in {} directories, skipped {} ignored and {} too large ({} valid, {} invalid), {} errors",
+            self.files_read,
+            self.files_seen,
+            self.bytes_read,
+            self.directories,
+            self.skipped_ignored,
+            self.skipped_too_large,
+            self.large_valid,
+            self.large_invalid,
+            self.errors
+        )
+    }
+}
+
+enum ReadResult {
+    Content(Vec<u8>),
+    Validated(Result<LineSummary, LineError>), // large files are only validated, not kept
+}
+
+/// Walks a workspace folder and reads the matching files, with one reader thread for each of
+/// the `max_concurrent_reads` reads in flight
+pub struct WorkspaceScanner {
+    config: ScanConfig,
+}
+
+impl WorkspaceScanner {
+    pub fn new(config: ScanConfig) -> Self {
+        WorkspaceScanner { config }
+    }
+
+    /// Scan the folder, returning the path and content of every file read
+    pub fn scan(&self, root: &Path) -> (Vec<(PathBuf, String)>, ScanStats) {
+        let mut stats = ScanStats::default();
+        let mut candidates = Vec::new();
+        self.walk(root, &mut Vec::new(), &mut candidates, &mut stats);
+
+        let (path_sender, path_receiver) = mpsc::channel::<(PathBuf, bool)>();
+        let path_receiver = Mutex::new(path_receiver);
+        let (result_sender, result_receiver) = mpsc::channel();
+        for candidate in candidates {
+            path_sender.send(candidate).unwrap();
+        }
+        drop(path_sender);
+
+        thread::scope(|s| {
+            for _ in 0..self.config.max_concurrent_reads.max(1) {
+                let result_sender = result_sender.clone();
+                let path_receiver = &path_receiver;
+                s.spawn(move || loop {
+                    let Ok((path, large)) = path_receiver.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let result = if large {
+                        large_file::validate_file(&path).map(ReadResult::Validated)
+                    } else {
+                        fs::read(&path).map(ReadResult::Content)
+                    };
+                    result_sender.send((path, result)).unwrap();
+                });
+            }
+        });
+        drop(result_sender);
+
+        let mut files = Vec::new();
+        for (path, result) in result_receiver {
+            match result {
+                Ok(ReadResult::Content(content)) => {
+                    stats.files_read += 1;
+                    stats.bytes_read += content.len() as u64;
+                    files.push((path, decode(content).0));
+                }
+                Ok(ReadResult::Validated(Ok(_))) => stats.large_valid += 1,
+                Ok(ReadResult::Validated(Err(_))) => stats.large_invalid += 1,
+                Err(_) => stats.errors += 1,
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        (files, stats)
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        ignores: &mut Vec<Gitignore>,
+        candidates: &mut Vec<(PathBuf, bool)>,
+        stats: &mut ScanStats,
+    ) {
+        stats.directories += 1;
+        let Ok(entries) = fs::read_dir(dir) else {
+            stats.errors += 1;
+            return;
+        };
+        let pushed_ignore = self.config.respect_gitignore
+            && match fs::read_to_string(dir.join(".gitignore")) {
+                Ok(content) => {
+                    ignores.push(Gitignore::new(dir, &content));
+                    true
+                }
+                Err(_) => false,
+            };
+
+        let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
+        entries.sort_by_key(|e| e.path());
+        for entry in entries {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                stats.errors += 1;
+                continue;
+            };
+            let is_dir = metadata.is_dir();
+            if is_dir && entry.file_name() == ".git" {
+                continue;
+            }
+            if !is_dir {
+                stats.files_seen += 1;
+            }
+            if ignores.iter().rev().find_map(|g| g.matches(&path, is_dir)) == Some(true) {
+                if !is_dir {
+                    stats.skipped_ignored += 1;
+                }
+                continue;
+            }
+
+            if is_dir {
+                self.walk(&path, ignores, candidates, stats);
+            } else if !self.has_extension(&path) {
+                continue;
+            } else if metadata.len() > self.config.max_file_size {
+                stats.skipped_too_large += 1;
+                if self.config.validate_large_files {
+                    candidates.push((path, true));
+                }
+            } else {
+                candidates.push((path, false));
+            }
+        }
+
+        if pushed_ignore {
+            ignores.pop();
+        }
+    }
+
+    fn has_extension(&self, path: &Path) -> bool {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        self.config.extensions.iter().any(|e| e == extension)
+    }
+}
+
+/// Patterns of a single `.gitignore` file, relative to the directory containing it
+struct Gitignore {
+    base: PathBuf,
+    patterns: Vec<GitignorePattern>,
+}
+
+struct GitignorePattern {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool, // pattern contains a `/`, so it matches the path relative to `base`
+}
+
+impl Gitignore {
+    fn new(base: &Path, content: &str) -> Self {
+        let mut patterns = Vec::new();
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            patterns.push(GitignorePattern {
+                glob: line.trim_start_matches('/').to_string(),
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+        Gitignore {
+            base: base.to_path_buf(),
+            patterns,
+        }
+    }
+
+    /// Some(true) if ignored, Some(false) if explicitly re-included, None if no pattern matched
+    fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let name = relative.rsplit('/').next().unwrap_or(&relative);
+
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| {
+                (!p.dir_only || is_dir)
+                    && if p.anchored {
+                        glob_match(p.glob.as_bytes(), relative.as_bytes())
+                    } else {
+                        glob_match(p.glob.as_bytes(), name.as_bytes())
+                    }
+            })
+            .map(|p| !p.negated)
+    }
+}
+
+/// Match a gitignore style glob, `*` and `?` don't match `/` while `**` does
+pub(crate) fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        // `**/` matches whole components, so what follows starts at the path or after a `/`
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => match rest[1..].strip_prefix(b"/") {
+            Some(rest) => (0..=text.len())
+                .filter(|&i| i == 0 || text[i - 1] == b'/')
+                .any(|i| glob_match(rest, &text[i..])),
+            None => (0..=text.len()).any(|i| glob_match(&rest[1..], &text[i..])),
+        },
+        Some((b'*', rest)) => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        Some((b'?', rest)) => {
+            text.first().is_some_and(|&c| c != b'/') && glob_match(rest, &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}